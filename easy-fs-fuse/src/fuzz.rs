@@ -0,0 +1,574 @@
+//! Model-based fuzzing of easy-fs. Random operations run on an image and
+//! on `Model`, a plain map of what the image should hold, and their results
+//! are compared as they go. Every write of a run is logged by
+//! `RecordingDevice`, so that what a power cut at any of them leaves can be
+//! rebuilt afterwards, reopened and checked.
+//!
+//! A failure names its seed, which `EFS_FUZZ_SEED` runs again alone.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex},
+};
+use easy_fs::{fsck, BlockDevice, EasyFileSystem, FsError, FsckProblem, Inode};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::BLOCK_SZ;
+
+/// Block indices where a file goes on to its indirect1, indirect2 and
+/// indirect3 blocks, around which offsets are drawn
+const BOUNDS: [usize; 3] = [22, 22 + 128, 22 + 128 + 128 * 128];
+const IMAGE_BLOCKS: usize = 8192;
+/// Longest write, within one transaction of easy-fs whatever its offset
+const MAX_WRITE: usize = 31 * BLOCK_SZ;
+/// Few names, so that operations run into each other
+const NAMES: [&str; 4] = ["a", "b", "c", "d"];
+
+/// The seeds to run, the one of `EFS_FUZZ_SEED` if set
+pub fn seeds() -> Vec<u64> {
+    match std::env::var("EFS_FUZZ_SEED") {
+        Ok(seed) => vec![seed.parse().expect("EFS_FUZZ_SEED is not a number")],
+        Err(_) => (0..4).collect(),
+    }
+}
+
+enum Event {
+    Write(usize, Vec<u8>),
+    Flush,
+}
+
+/// An image in memory logging every block written and every flush
+pub struct RecordingDevice {
+    id: usize,
+    image: Mutex<Vec<u8>>,
+    log: Mutex<Vec<Event>>,
+}
+
+/// Id of the next `RecordingDevice`, never reused. Ids are handed out
+/// from the top down, away from the descriptors `BlockFile` goes by.
+static NEXT_DEVICE_ID: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+impl RecordingDevice {
+    fn new(image: Vec<u8>) -> Arc<Self> {
+        Arc::new(Self {
+            id: NEXT_DEVICE_ID.fetch_sub(1, Ordering::Relaxed),
+            image: Mutex::new(image),
+            log: Mutex::new(Vec::new()),
+        })
+    }
+    fn image(&self) -> Vec<u8> {
+        self.image.lock().unwrap().clone()
+    }
+    fn writes(&self) -> usize {
+        self.log.lock().unwrap().iter().filter(|event| matches!(event, Event::Write(..))).count()
+    }
+    fn take_log(&self) -> Vec<Event> {
+        std::mem::take(&mut self.log.lock().unwrap())
+    }
+}
+
+impl BlockDevice for RecordingDevice {
+    fn device_id(&self) -> usize {
+        self.id
+    }
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> easy_fs::Result<()> {
+        let image = self.image.lock().unwrap();
+        buf.copy_from_slice(&image[block_id * BLOCK_SZ..(block_id + 1) * BLOCK_SZ]);
+        Ok(())
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> easy_fs::Result<()> {
+        self.image.lock().unwrap()[block_id * BLOCK_SZ..(block_id + 1) * BLOCK_SZ].copy_from_slice(buf);
+        self.log.lock().unwrap().push(Event::Write(block_id, buf.to_vec()));
+        Ok(())
+    }
+    fn flush(&self) -> easy_fs::Result<()> {
+        self.log.lock().unwrap().push(Event::Flush);
+        Ok(())
+    }
+    fn num_blocks(&self) -> Option<usize> {
+        Some(self.image.lock().unwrap().len() / BLOCK_SZ)
+    }
+}
+
+/// What a power cut once the first `cut` writes of `log` are issued leaves
+/// of `base`, the image before them.
+///
+/// What was written before the last flush is there. Of the writes since,
+/// any may have been dropped: each block is left at a random one of its
+/// versions, the one from before them included, which also tears a run of
+/// blocks written together anywhere. A block itself is never half written,
+/// easy-fs takes block writes to be atomic as sector writes are.
+fn crash_image(base: &[u8], log: &[Event], cut: usize, rng: &mut StdRng) -> Vec<u8> {
+    let mut image = base.to_vec();
+    let mut pending: BTreeMap<usize, Vec<&[u8]>> = BTreeMap::new();
+    let mut writes = 0;
+    for event in log {
+        // a flush following the last write issued may not be reached either
+        if writes == cut {
+            break;
+        }
+        match event {
+            Event::Write(block_id, data) => {
+                pending.entry(*block_id).or_default().push(data);
+                writes += 1;
+            }
+            Event::Flush => {
+                for (block_id, versions) in std::mem::take(&mut pending) {
+                    image[block_id * BLOCK_SZ..(block_id + 1) * BLOCK_SZ].copy_from_slice(versions.last().unwrap());
+                }
+            }
+        }
+    }
+    for (block_id, versions) in pending {
+        let kept = rng.gen_range(0..=versions.len());
+        if kept > 0 {
+            image[block_id * BLOCK_SZ..(block_id + 1) * BLOCK_SZ].copy_from_slice(versions[kept - 1]);
+        }
+    }
+    image
+}
+
+#[derive(Clone, PartialEq)]
+enum Node {
+    File(Vec<u8>),
+    Dir,
+}
+
+impl fmt::Debug for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Node::File(data) => write!(f, "File({} bytes)", data.len()),
+            Node::Dir => write!(f, "Dir"),
+        }
+    }
+}
+
+/// What an image should hold, by absolute path
+#[derive(Clone, PartialEq, Debug)]
+struct Model(BTreeMap<String, Node>);
+
+enum Op {
+    Create(String),
+    Mkdir(String),
+    Write(String, usize, Vec<u8>),
+    Read(String, usize, usize),
+    Truncate(String, usize),
+    Clear(String),
+    Unlink(String),
+    Rmdir(String),
+}
+
+impl Op {
+    fn path(&self) -> &str {
+        match self {
+            Op::Create(path) | Op::Mkdir(path) | Op::Clear(path) | Op::Unlink(path) | Op::Rmdir(path) => path,
+            Op::Write(path, ..) | Op::Read(path, ..) | Op::Truncate(path, _) => path,
+        }
+    }
+}
+
+impl fmt::Debug for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Op::Create(path) => write!(f, "create {}", path),
+            Op::Mkdir(path) => write!(f, "mkdir {}", path),
+            Op::Write(path, offset, data) => write!(f, "write {} bytes at {} of {}", data.len(), offset, path),
+            Op::Read(path, offset, len) => write!(f, "read {} bytes at {} of {}", len, offset, path),
+            Op::Truncate(path, size) => write!(f, "truncate {} to {}", path, size),
+            Op::Clear(path) => write!(f, "clear {}", path),
+            Op::Unlink(path) => write!(f, "unlink {}", path),
+            Op::Rmdir(path) => write!(f, "rmdir {}", path),
+        }
+    }
+}
+
+#[derive(PartialEq)]
+enum Reply {
+    Done,
+    Wrote(usize),
+    Read(Vec<u8>),
+}
+
+impl fmt::Debug for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Reply::Done => write!(f, "done"),
+            Reply::Wrote(len) => write!(f, "wrote {} bytes", len),
+            Reply::Read(data) => write!(f, "read {} bytes", data.len()),
+        }
+    }
+}
+
+/// The parent directory and the name of an absolute path
+fn split(path: &str) -> (&str, &str) {
+    let pos = path.rfind('/').unwrap();
+    (if pos == 0 { "/" } else { &path[..pos] }, &path[pos + 1..])
+}
+
+fn join(dir: &str, name: &str) -> String {
+    format!("{}/{}", dir.trim_end_matches('/'), name)
+}
+
+impl Model {
+    fn new() -> Self {
+        Self(BTreeMap::from([(String::from("/"), Node::Dir)]))
+    }
+    /// Resolve a path the way `Inode::find` does
+    fn lookup(&self, path: &str) -> Result<&Node, FsError> {
+        let mut node = &self.0["/"];
+        let mut prefix = String::new();
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            if *node != Node::Dir {
+                return Err(FsError::NotDir);
+            }
+            prefix = join(&prefix, name);
+            node = self.0.get(&prefix).ok_or(FsError::NotFound)?;
+        }
+        Ok(node)
+    }
+    fn file_mut(&mut self, path: &str) -> Result<&mut Vec<u8>, FsError> {
+        self.lookup(path)?;
+        match self.0.get_mut(path) {
+            Some(Node::File(data)) => Ok(data),
+            _ => Err(FsError::IsDir),
+        }
+    }
+    fn is_empty_dir(&self, path: &str) -> bool {
+        !self.0.keys().any(|other| other != path && split(other).0 == path)
+    }
+    /// Check the parent of `path` is a directory, and look `path` up in it
+    fn entry(&self, path: &str) -> Result<Option<&Node>, FsError> {
+        if *self.lookup(split(path).0)? != Node::Dir {
+            return Err(FsError::NotDir);
+        }
+        Ok(self.0.get(path))
+    }
+    fn apply(&mut self, op: &Op) -> Result<Reply, FsError> {
+        match op {
+            Op::Create(path) | Op::Mkdir(path) => {
+                if self.entry(path)?.is_some() {
+                    return Err(FsError::Exists);
+                }
+                let node = if matches!(op, Op::Create(_)) { Node::File(Vec::new()) } else { Node::Dir };
+                self.0.insert(path.clone(), node);
+                Ok(Reply::Done)
+            }
+            Op::Write(path, offset, data) => {
+                let file = self.file_mut(path)?;
+                if file.len() < offset + data.len() {
+                    file.resize(offset + data.len(), 0);
+                }
+                file[*offset..offset + data.len()].copy_from_slice(data);
+                Ok(Reply::Wrote(data.len()))
+            }
+            Op::Read(path, offset, len) => {
+                let file = self.file_mut(path)?;
+                let start = (*offset).min(file.len());
+                let end = (offset + len).min(file.len());
+                Ok(Reply::Read(file[start..end].to_vec()))
+            }
+            Op::Truncate(path, size) => {
+                self.file_mut(path)?.resize(*size, 0);
+                Ok(Reply::Done)
+            }
+            Op::Clear(path) => {
+                self.file_mut(path)?.clear();
+                Ok(Reply::Done)
+            }
+            Op::Unlink(path) => match self.entry(path)? {
+                None => Err(FsError::NotFound),
+                Some(Node::Dir) => Err(FsError::IsDir),
+                Some(Node::File(_)) => {
+                    self.0.remove(path);
+                    Ok(Reply::Done)
+                }
+            },
+            Op::Rmdir(path) => match self.entry(path)? {
+                None => Err(FsError::NotFound),
+                Some(Node::File(_)) => Err(FsError::NotDir),
+                Some(Node::Dir) if !self.is_empty_dir(path) => Err(FsError::NotEmpty),
+                Some(Node::Dir) => {
+                    self.0.remove(path);
+                    Ok(Reply::Done)
+                }
+            },
+        }
+    }
+    fn paths(&self, node: Node) -> Vec<&String> {
+        self.0
+            .iter()
+            .filter(|(_, other)| std::mem::discriminant(*other) == std::mem::discriminant(&node))
+            .map(|(path, _)| path)
+            .collect()
+    }
+    fn size(&self, path: &str) -> usize {
+        match self.0.get(path) {
+            Some(Node::File(data)) => data.len(),
+            _ => 0,
+        }
+    }
+}
+
+/// An offset of a file of `size` bytes: inside, at its end, or around where
+/// its blocks move to the next level of indirection
+fn gen_offset(rng: &mut StdRng, size: usize) -> usize {
+    match rng.gen_range(0..4) {
+        0 => rng.gen_range(0..=size),
+        1 => size,
+        _ => (BOUNDS[rng.gen_range(0..BOUNDS.len())] * BLOCK_SZ + rng.gen_range(0..4 * BLOCK_SZ)).saturating_sub(2 * BLOCK_SZ),
+    }
+}
+
+/// A random operation, on what `model` holds mostly
+fn gen_op(rng: &mut StdRng, model: &Model) -> Op {
+    let dirs = model.paths(Node::Dir);
+    let files = model.paths(Node::File(Vec::new()));
+    // a name in a directory, or now and then under a file
+    let new_path = |rng: &mut StdRng| {
+        let parent = match files.is_empty() || rng.gen_bool(0.9) {
+            true => dirs[rng.gen_range(0..dirs.len())],
+            false => files[rng.gen_range(0..files.len())],
+        };
+        join(parent, NAMES[rng.gen_range(0..NAMES.len())])
+    };
+    let pick = |rng: &mut StdRng, paths: &[&String]| match paths.is_empty() || rng.gen_bool(0.2) {
+        true => new_path(rng),
+        false => paths[rng.gen_range(0..paths.len())].clone(),
+    };
+    let subdirs: Vec<&String> = dirs.iter().copied().filter(|path| *path != "/").collect();
+    match rng.gen_range(0..100) {
+        0..=14 => Op::Create(pick(rng, &[])),
+        15..=22 => Op::Mkdir(pick(rng, &[])),
+        23..=52 => {
+            let path = pick(rng, &files);
+            let offset = gen_offset(rng, model.size(&path));
+            let max_len = if rng.gen_bool(0.2) { MAX_WRITE } else { 2 * BLOCK_SZ };
+            let mut data = vec![0u8; rng.gen_range(1..=max_len)];
+            rng.fill(&mut data[..]);
+            Op::Write(path, offset, data)
+        }
+        // directories hold no data a model could predict
+        53..=67 if !files.is_empty() => {
+            let path = files[rng.gen_range(0..files.len())].clone();
+            let offset = gen_offset(rng, model.size(&path));
+            Op::Read(path, offset, rng.gen_range(0..=MAX_WRITE))
+        }
+        53..=77 => {
+            let path = pick(rng, &files);
+            let size = gen_offset(rng, model.size(&path));
+            Op::Truncate(path, size)
+        }
+        78..=81 => Op::Clear(pick(rng, &files)),
+        82..=92 => Op::Unlink(pick(rng, &files)),
+        _ => Op::Rmdir(pick(rng, &subdirs)),
+    }
+}
+
+/// Run `op` on the image
+fn exec(root_inode: &Inode, op: &Op) -> Result<Reply, FsError> {
+    match op {
+        Op::Create(path) => {
+            let (parent, name) = split(path);
+            root_inode.find(parent)?.create(name).map(|_| Reply::Done)
+        }
+        Op::Mkdir(path) => {
+            let (parent, name) = split(path);
+            root_inode.find(parent)?.mkdir(name).map(|_| Reply::Done)
+        }
+        Op::Write(path, offset, data) => root_inode.find(path)?.write_at(*offset, data).map(Reply::Wrote),
+        Op::Read(path, offset, len) => {
+            let mut buf = vec![0u8; *len];
+            let len = root_inode.find(path)?.read_at(*offset, &mut buf)?;
+            buf.truncate(len);
+            Ok(Reply::Read(buf))
+        }
+        Op::Truncate(path, size) => root_inode.find(path)?.truncate(*size as u32).map(|()| Reply::Done),
+        Op::Clear(path) => root_inode.find(path)?.clear().map(|()| Reply::Done),
+        Op::Unlink(path) => {
+            let (parent, name) = split(path);
+            root_inode.find(parent)?.unlink(name).map(|()| Reply::Done)
+        }
+        Op::Rmdir(path) => {
+            let (parent, name) = split(path);
+            root_inode.find(parent)?.rmdir(name).map(|()| Reply::Done)
+        }
+    }
+}
+
+/// Read what the image holds from `path` down into `tree`
+fn read_tree(inode: &Inode, path: &str, tree: &mut BTreeMap<String, Node>) -> easy_fs::Result<()> {
+    if inode.is_dir()? {
+        tree.insert(path.to_string(), Node::Dir);
+        for name in inode.ls()? {
+            read_tree(&*inode.find(&name)?, &join(path, &name), tree)?;
+        }
+    } else {
+        let mut data = vec![0u8; inode.stat()?.size as usize];
+        let len = inode.read_at(0, &mut data)?;
+        data.truncate(len);
+        tree.insert(path.to_string(), Node::File(data));
+    }
+    Ok(())
+}
+
+fn image_model(root_inode: &Inode) -> easy_fs::Result<Model> {
+    let mut tree = BTreeMap::new();
+    read_tree(root_inode, "/", &mut tree)?;
+    Ok(Model(tree))
+}
+
+/// The first path where the image and `model` part, if any
+fn first_difference(image: &Model, model: &Model) -> Option<String> {
+    let paths = image.0.keys().chain(model.0.keys());
+    paths
+        .filter(|path| image.0.get(*path) != model.0.get(*path))
+        .min()
+        .map(|path| format!("{}: {:?} in the image, {:?} expected", path, image.0.get(path), model.0.get(path)))
+}
+
+/// What is at `path` in the image, as `Model` holds it
+fn image_node(root_inode: &Inode, path: &str) -> Option<Node> {
+    let inode = root_inode.find(path).ok()?;
+    let mut tree = BTreeMap::new();
+    if inode.is_dir().unwrap() {
+        return Some(Node::Dir);
+    }
+    read_tree(&inode, path, &mut tree).unwrap();
+    tree.remove(path)
+}
+
+/// A new image, its fs and root inode
+fn new_image() -> (Arc<RecordingDevice>, Arc<spin::Mutex<EasyFileSystem>>, Inode) {
+    let device = RecordingDevice::new(vec![0u8; IMAGE_BLOCKS * BLOCK_SZ]);
+    EasyFileSystem::create(device.clone(), IMAGE_BLOCKS as u32, 1).unwrap();
+    let efs = EasyFileSystem::open(device.clone()).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    (device, efs, root_inode)
+}
+
+/// Run the next random operation on both the image and `model`, checking
+/// they agree on its result and on what it touched
+fn step(seed: u64, step: usize, rng: &mut StdRng, root_inode: &Inode, model: &mut Model) -> Op {
+    let op = gen_op(rng, model);
+    let expected = model.apply(&op);
+    let got = exec(root_inode, &op);
+    assert!(
+        got == expected,
+        "seed {} step {}: {:?} gave {:?}, {:?} expected",
+        seed, step, op, got, expected,
+    );
+    let path = op.path();
+    assert!(
+        image_node(root_inode, path) == model.0.get(path).cloned(),
+        "seed {} step {}: after {:?}, {:?} in the image, {:?} expected",
+        seed, step, op, image_node(root_inode, path), model.0.get(path),
+    );
+    op
+}
+
+/// Run `ops` random operations from `seed` on a new image and on the model,
+/// then check the whole image, reopened as well
+pub fn run(seed: u64, ops: usize) {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let (device, efs, root_inode) = new_image();
+    let mut model = Model::new();
+    for i in 0..ops {
+        step(seed, i, &mut rng, &root_inode, &mut model);
+    }
+    if let Some(difference) = first_difference(&image_model(&root_inode).unwrap(), &model) {
+        panic!("seed {}: {}", seed, difference);
+    }
+    drop(root_inode);
+    drop(efs);
+    let report = fsck(&(device.clone() as Arc<dyn BlockDevice>), false).unwrap();
+    assert!(report.is_clean(), "seed {}: {:?}", seed, report.problems);
+    let efs = EasyFileSystem::open(device).unwrap();
+    if let Some(difference) = first_difference(&image_model(&EasyFileSystem::root_inode(&efs)).unwrap(), &model) {
+        panic!("seed {} reopened: {}", seed, difference);
+    }
+}
+
+/// Run `ops` random operations from `seed` on a new image, then cut the
+/// power at `crashes` random writes of the run and check each image left:
+/// reopened, it must be consistent and hold what the model held before or
+/// after the operation cut off.
+///
+/// Some operations commit in parts, and leave more behind:
+/// - shrinking a file releases its blocks a few at a time, and may leave
+///   any size in between
+/// - removing the last link of an inode is committed before its blocks are
+///   released, and may leave an orphan inode, with its blocks and itself
+///   counted as used, for fsck to reclaim
+pub fn run_crashes(seed: u64, ops: usize, crashes: usize) {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let (device, _efs, root_inode) = new_image();
+    let base = device.image();
+    device.take_log();
+    let mut model = Model::new();
+    let mut run = Vec::new();
+    // the writes issued before each operation, and before the end
+    let mut starts = Vec::new();
+    for i in 0..ops {
+        starts.push(device.writes());
+        run.push(step(seed, i, &mut rng, &root_inode, &mut model));
+    }
+    starts.push(device.writes());
+    let log = device.take_log();
+    let mut cuts: Vec<usize> = (0..crashes).map(|_| rng.gen_range(0..=starts[ops])).collect();
+    cuts.sort_unstable();
+    // step the model along to the operation each cut falls in
+    let mut model = Model::new();
+    let mut i = 0;
+    for cut in cuts {
+        while i < ops && starts[i + 1] <= cut {
+            model.apply(&run[i]).ok();
+            i += 1;
+        }
+        let mut after = model.clone();
+        if i < ops {
+            after.apply(&run[i]).ok();
+        }
+        let op = run.get(i);
+        let context = format!("seed {} cut at write {} in {:?}", seed, cut, op);
+        let crashed = RecordingDevice::new(crash_image(&base, &log, cut, &mut rng));
+        let efs = EasyFileSystem::open(crashed.clone()).unwrap_or_else(|error| panic!("{}: {:?}", context, error));
+        let image = image_model(&EasyFileSystem::root_inode(&efs)).unwrap_or_else(|error| panic!("{}: {:?}", context, error));
+        drop(efs);
+        let shrunk = |image: &Model| match (op, model.0.get(op.map_or("", Op::path))) {
+            (Some(Op::Truncate(path, _) | Op::Clear(path)), Some(Node::File(before))) => match image.0.get(path) {
+                Some(Node::File(data)) if data.len() <= before.len() && data[..] == before[..data.len()] => {
+                    let mut shrunk = model.clone();
+                    shrunk.0.insert(path.clone(), Node::File(data.clone()));
+                    shrunk == after || shrunk == *image
+                }
+                _ => false,
+            },
+            _ => false,
+        };
+        assert!(
+            image == model || image == after || shrunk(&image),
+            "{}: {}",
+            context,
+            first_difference(&image, &after).unwrap_or_default(),
+        );
+        let report = fsck(&(crashed.clone() as Arc<dyn BlockDevice>), true).unwrap();
+        // an orphan still holds its blocks, which reachable inodes do not account for
+        let orphaned = matches!(op, Some(Op::Unlink(_) | Op::Rmdir(_)))
+            && report.problems.iter().any(|problem| matches!(problem, FsckProblem::OrphanInode { .. }));
+        assert!(
+            report.problems.iter().all(|problem| orphaned && matches!(
+                problem,
+                FsckProblem::OrphanInode { .. }
+                    | FsckProblem::LeakedBlock { .. }
+                    | FsckProblem::WrongFreeInodes { .. }
+                    | FsckProblem::WrongFreeBlocks { .. }
+            )),
+            "{}: {:?}",
+            context,
+            report.problems,
+        );
+        let report = fsck(&(crashed as Arc<dyn BlockDevice>), false).unwrap();
+        assert!(report.is_clean(), "{} repaired: {:?}", context, report.problems);
+    }
+}
@@ -1,34 +1,87 @@
-use std::{fs::{read_dir, File, OpenOptions}, io::{Seek, SeekFrom, Write, Read}, sync::{Arc, Mutex}};
-use bitflags::bitflags;
-use clap::{App, Arg};
-use easy_fs::{BlockDevice, EasyFileSystem};
+use std::{collections::HashMap, fs::{read_dir, File, OpenOptions}, io::{Seek, SeekFrom, Write, Read}, os::unix::io::AsRawFd, path::Path, sync::{atomic::{AtomicU32, Ordering}, Arc, Mutex}, time::{SystemTime, UNIX_EPOCH}};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use easy_fs::{file_blocks, fsck, BlockDevice, DiskInodeType, EasyFileSystem, FsError, IndirectMap, Inode, WritePolicy};
+
+mod mount;
+#[cfg(test)]
+mod fuzz;
 
 const BLOCK_SZ: usize = 512;
 
 struct BlockFile(Mutex<File>);
 
 impl BlockDevice for BlockFile {
-    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+    /// The descriptor of the image, which is not reused while it is open
+    fn device_id(&self) -> usize {
+        self.0.lock().unwrap().as_raw_fd() as usize
+    }
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> easy_fs::Result<()> {
+        assert_eq!(buf.len(), BLOCK_SZ, "Not a complete block!");
+        self.read_blocks(block_id, buf)
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> easy_fs::Result<()> {
+        assert_eq!(buf.len(), BLOCK_SZ, "Not a complete block!");
+        self.write_blocks(block_id, buf)
+    }
+    /// A run of blocks is one seek and one read, past the end of the image
+    /// is an error
+    fn read_blocks(&self, start_block_id: usize, buf: &mut [u8]) -> easy_fs::Result<()> {
         let mut file = self.0.lock().unwrap();
-        file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
-            .expect("Error when seeking!");
-        assert_eq!(file.read(buf).unwrap(), BLOCK_SZ, "Not a complete block!");
+        file.seek(SeekFrom::Start((start_block_id * BLOCK_SZ) as u64))
+            .and_then(|_| file.read_exact(buf))
+            .map_err(|_| FsError::Io)
     }
-    fn write_block(&self, block_id: usize, buf: &[u8]) {
+    fn write_blocks(&self, start_block_id: usize, buf: &[u8]) -> easy_fs::Result<()> {
         let mut file = self.0.lock().unwrap();
-        file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
-            .expect("Error when seeking!");
-        assert_eq!(file.write(buf).unwrap(), BLOCK_SZ, "Not a complete block!");
+        file.seek(SeekFrom::Start((start_block_id * BLOCK_SZ) as u64))
+            .and_then(|_| file.write_all(buf))
+            .map_err(|_| FsError::Io)
+    }
+    fn flush(&self) -> easy_fs::Result<()> {
+        self.0.lock().unwrap().sync_data().map_err(|_| FsError::Io)
+    }
+    fn num_blocks(&self) -> Option<usize> {
+        let file = self.0.lock().unwrap();
+        file.metadata().ok().map(|metadata| metadata.len() as usize / BLOCK_SZ)
     }
 }
 
-fn main() {
-    easy_fs_pack().expect("Error when packing easy-fs!");
+/// An image opened read-only: what opening or reading it writes, such as
+/// a journal replay or access times, is kept in memory instead
+struct ReadOnlyImage {
+    image: BlockFile,
+    written: Mutex<HashMap<usize, Vec<u8>>>,
+}
+
+impl BlockDevice for ReadOnlyImage {
+    fn device_id(&self) -> usize {
+        self.image.device_id()
+    }
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> easy_fs::Result<()> {
+        match self.written.lock().unwrap().get(&block_id) {
+            Some(block) => {
+                buf.copy_from_slice(block);
+                Ok(())
+            }
+            None => self.image.read_block(block_id, buf),
+        }
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> easy_fs::Result<()> {
+        self.written.lock().unwrap().insert(block_id, buf.to_vec());
+        Ok(())
+    }
+    fn num_blocks(&self) -> Option<usize> {
+        self.image.num_blocks()
+    }
+}
+
+/// Stamp inodes with the host time
+fn host_clock() -> u32 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32
 }
 
-/// package all apps into efs
-fn easy_fs_pack() -> std::io::Result<()> {
-    let matches = App::new("EasyFileSystem packer")
+fn app() -> App<'static, 'static> {
+    App::new("EasyFileSystem packer")
         .arg(
             Arg::with_name("source")
                 .short("s")
@@ -43,46 +96,580 @@ fn easy_fs_pack() -> std::io::Result<()> {
                 .takes_value(true)
                 .help("Executable target dir(with backslash)"),
         )
-        .get_matches();
-    let src_path = matches.value_of("source").unwrap();
-    let target_path = matches.value_of("target").unwrap();
-    println!("src_path = {}\ntarget_path = {}", src_path, target_path);
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .takes_value(true)
+                .help("Image to write, fs.img in the target dir by default"),
+        )
+        .arg(
+            Arg::with_name("size")
+                .long("size")
+                .takes_value(true)
+                .help("Image size in bytes, with an optional K, M or G suffix, 16M by default"),
+        )
+        .arg(
+            Arg::with_name("inodes")
+                .long("inodes")
+                .takes_value(true)
+                .help("Files and directories the image holds at least, 4095 by default"),
+        )
+        .arg(
+            Arg::with_name("dir")
+                .short("d")
+                .long("dir")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Host directory packed with everything below it into the root"),
+        )
+        .arg(
+            Arg::with_name("manifest")
+                .short("m")
+                .long("manifest")
+                .takes_value(true)
+                .help("File listing more to pack, a `<path> [<host path>]` line each"),
+        )
+        .arg(
+            Arg::with_name("timestamp")
+                .long("timestamp")
+                .takes_value(true)
+                .help("Time stamped on every inode, $SOURCE_DATE_EPOCH or the current time by default"),
+        )
+        .subcommand(
+            SubCommand::with_name("fsck")
+                .about("Check an easy-fs image, and repair it with --repair")
+                .arg(Arg::with_name("image").required(true).help("Image to check"))
+                .arg(
+                    Arg::with_name("repair")
+                        .short("r")
+                        .long("repair")
+                        .help("Repair the problems found"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("ls")
+                .about("List a directory of an image and everything below it")
+                .arg(Arg::with_name("image").required(true).help("Image to read"))
+                .arg(Arg::with_name("path").help("Directory or file to list, / by default")),
+        )
+        .subcommand(
+            SubCommand::with_name("cat")
+                .about("Print a file of an image")
+                .arg(Arg::with_name("image").required(true).help("Image to read"))
+                .arg(Arg::with_name("path").required(true).help("File to print")),
+        )
+        .subcommand(
+            SubCommand::with_name("get")
+                .about("Copy a file out of an image")
+                .arg(Arg::with_name("image").required(true).help("Image to read"))
+                .arg(Arg::with_name("path").required(true).help("File to copy"))
+                .arg(Arg::with_name("dest").help("Host file to write, the file name by default")),
+        )
+        .subcommand(
+            SubCommand::with_name("put")
+                .about("Copy a host file into an image, replacing the file there")
+                .arg(Arg::with_name("image").required(true).help("Image to write"))
+                .arg(Arg::with_name("source").required(true).help("Host file to copy"))
+                .arg(Arg::with_name("path").help("Path in the image, the root and file name by default")),
+        )
+        .subcommand(
+            SubCommand::with_name("rm")
+                .about("Remove a file or an empty directory from an image")
+                .arg(Arg::with_name("image").required(true).help("Image to write"))
+                .arg(Arg::with_name("path").required(true).help("File or directory to remove")),
+        )
+        .subcommand(
+            SubCommand::with_name("info")
+                .about("Show the super block, the bitmap usage and the inode table of an image")
+                .arg(Arg::with_name("image").required(true).help("Image to read")),
+        )
+        .subcommand(
+            SubCommand::with_name("dump-inode")
+                .about("Show an inode and the blocks it maps")
+                .arg(Arg::with_name("image").required(true).help("Image to read"))
+                .arg(Arg::with_name("inode").required(true).help("Inode number")),
+        )
+        .subcommand(
+            SubCommand::with_name("mount")
+                .about("Serve an easy-fs image through FUSE until it is unmounted")
+                .arg(Arg::with_name("image").required(true).help("Image to mount"))
+                .arg(Arg::with_name("mountpoint").required(true).help("Directory to mount it on")),
+        )
+}
+
+fn main() {
+    let matches = app().get_matches();
+    match matches.subcommand() {
+        ("fsck", Some(matches)) => {
+            let clean = easy_fs_fsck(matches).expect("Error when checking easy-fs!");
+            if !clean {
+                std::process::exit(1);
+            }
+        }
+        ("ls", Some(matches)) => easy_fs_ls(matches).expect("Error when listing easy-fs!"),
+        ("cat", Some(matches)) => easy_fs_get(matches, true).expect("Error when reading easy-fs!"),
+        ("get", Some(matches)) => easy_fs_get(matches, false).expect("Error when reading easy-fs!"),
+        ("put", Some(matches)) => easy_fs_put(matches).expect("Error when writing easy-fs!"),
+        ("rm", Some(matches)) => easy_fs_rm(matches).expect("Error when writing easy-fs!"),
+        ("info", Some(matches)) => easy_fs_info(matches).expect("Error when reading easy-fs!"),
+        ("dump-inode", Some(matches)) => easy_fs_dump_inode(matches).expect("Error when reading easy-fs!"),
+        ("mount", Some(matches)) => easy_fs_mount(matches).expect("Error when mounting easy-fs!"),
+        _ => easy_fs_pack(&matches).expect("Error when packing easy-fs!"),
+    }
+}
+
+/// Check an image, true if nothing is left to repair
+fn easy_fs_fsck(matches: &ArgMatches) -> std::io::Result<bool> {
+    let image = matches.value_of("image").unwrap();
+    let repair = matches.is_present("repair");
+    let f = OpenOptions::new().read(true).write(repair).open(image)?;
+    let block_file: Arc<dyn BlockDevice> = Arc::new(BlockFile(Mutex::new(f)));
+    let report = fsck(&block_file, repair).map_err(|error| std::io::Error::other(error.to_string()))?;
+    for problem in report.problems.iter() {
+        println!("{}", problem);
+    }
+    match (report.is_clean(), report.repaired) {
+        (true, _) => println!("{}: clean", image),
+        (false, true) => println!("{}: {} problems repaired", image, report.problems.len()),
+        (false, false) => println!("{}: {} problems found", image, report.problems.len()),
+    }
+    Ok(report.repaired)
+}
+
+fn fs_error(error: FsError) -> std::io::Error {
+    std::io::Error::other(error.to_string())
+}
+
+/// Open the image of a subcommand, read-only unless `writable`
+fn open_image(image: &str, writable: bool) -> std::io::Result<Arc<spin::Mutex<EasyFileSystem>>> {
+    let f = OpenOptions::new().read(true).write(writable).open(image)?;
+    let block_file: Arc<dyn BlockDevice> = if writable {
+        Arc::new(BlockFile(Mutex::new(f)))
+    } else {
+        Arc::new(ReadOnlyImage {
+            image: BlockFile(Mutex::new(f)),
+            written: Mutex::new(HashMap::new()),
+        })
+    };
+    let efs = EasyFileSystem::open(block_file).map_err(fs_error)?;
+    efs.lock().set_clock(host_clock);
+    Ok(efs)
+}
+
+/// Split a path of an image into its parent directory and final component
+fn split_path(path: &str) -> (&str, &str) {
+    match path.trim_end_matches('/').rfind('/') {
+        Some(pos) => (&path[..pos + 1], path[pos + 1..].trim_end_matches('/')),
+        None => ("", path.trim_end_matches('/')),
+    }
+}
+
+/// Mount an image on the host, every operation committed as it ends so
+/// that the image is consistent whenever the mount goes away
+fn easy_fs_mount(matches: &ArgMatches) -> std::io::Result<()> {
+    let efs = open_image(matches.value_of("image").unwrap(), true)?;
+    mount::serve(EasyFileSystem::root_inode(&efs), matches.value_of("mountpoint").unwrap())
+}
+
+/// List a path and, for a directory, everything below it
+fn easy_fs_ls(matches: &ArgMatches) -> std::io::Result<()> {
+    let efs = open_image(matches.value_of("image").unwrap(), false)?;
+    let path = matches.value_of("path").unwrap_or("/");
+    let inode = EasyFileSystem::root_inode(&efs).find(path).map_err(fs_error)?;
+    for line in list(&inode, path).map_err(fs_error)? {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+/// A line per inode from `inode` down, with its mode, links and size
+fn list(inode: &Arc<Inode>, path: &str) -> easy_fs::Result<Vec<String>> {
+    let stat = inode.stat()?;
+    let is_dir = stat.type_ == DiskInodeType::Directory;
+    let mut lines = vec![format!(
+        "{}{:03o} {:>3} {:>10} {}",
+        if is_dir { 'd' } else { '-' },
+        stat.mode,
+        stat.nlink,
+        stat.size,
+        path,
+    )];
+    if is_dir {
+        for name in inode.ls()? {
+            let child_path = format!("{}/{}", path.trim_end_matches('/'), name);
+            lines.extend(list(&inode.find(&name)?, &child_path)?);
+        }
+    }
+    Ok(lines)
+}
+
+/// The whole contents of a file of an image
+fn read_file(inode: &Arc<Inode>) -> easy_fs::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let len = inode.read_at(data.len(), &mut buf)?;
+        if len == 0 {
+            return Ok(data);
+        }
+        data.extend_from_slice(&buf[..len]);
+    }
+}
+
+/// Copy a file out of an image, to stdout for `cat`
+fn easy_fs_get(matches: &ArgMatches, cat: bool) -> std::io::Result<()> {
+    let efs = open_image(matches.value_of("image").unwrap(), false)?;
+    let path = matches.value_of("path").unwrap();
+    let data = read_file(&EasyFileSystem::root_inode(&efs).find(path).map_err(fs_error)?).map_err(fs_error)?;
+    if cat {
+        std::io::stdout().write_all(&data)
+    } else {
+        std::fs::write(matches.value_of("dest").unwrap_or(split_path(path).1), data)
+    }
+}
+
+/// Write `data` to the file at `path` of an image, created if missing
+fn put_file(root_inode: &Inode, path: &str, data: &[u8], mode: u16) -> easy_fs::Result<()> {
+    let (parent, name) = split_path(path);
+    let dir = root_inode.find(parent)?;
+    let file = match dir.find(name) {
+        Ok(file) => {
+            file.truncate(0)?;
+            file
+        }
+        Err(FsError::NotFound) => dir.create(name)?,
+        Err(error) => return Err(error),
+    };
+    file.chmod(mode)?;
+    // a short write is out of space
+    if file.write_at(0, data)? < data.len() {
+        return Err(FsError::NoSpace);
+    }
+    Ok(())
+}
+
+/// Copy a host file into an image, with its permission bits
+fn easy_fs_put(matches: &ArgMatches) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let efs = open_image(matches.value_of("image").unwrap(), true)?;
+    let source = matches.value_of("source").unwrap();
+    let data = std::fs::read(source)?;
+    let mode = std::fs::metadata(source)?.permissions().mode() as u16 & 0o7777;
+    let path = match matches.value_of("path") {
+        Some(path) => path.to_string(),
+        None => format!("/{}", split_path(source).1),
+    };
+    put_file(&EasyFileSystem::root_inode(&efs), &path, &data, mode).map_err(fs_error)
+}
+
+fn easy_fs_rm(matches: &ArgMatches) -> std::io::Result<()> {
+    let efs = open_image(matches.value_of("image").unwrap(), true)?;
+    let path = matches.value_of("path").unwrap();
+    let (parent, name) = split_path(path);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let dir = root_inode.find(parent).map_err(fs_error)?;
+    let is_dir = dir.find(name).and_then(|inode| inode.is_dir()).map_err(fs_error)?;
+    if is_dir {
+        dir.rmdir(name)
+    } else {
+        dir.unlink(name)
+    }.map_err(fs_error)
+}
+
+fn easy_fs_info(matches: &ArgMatches) -> std::io::Result<()> {
+    let efs = open_image(matches.value_of("image").unwrap(), false)?;
+    let super_block = efs.lock().super_block().map_err(fs_error)?;
+    println!("{:#?}", super_block);
+    // the areas follow one another after the super block
+    let areas = [
+        ("inode bitmap", super_block.inode_bitmap_blocks),
+        ("inode area", super_block.inode_area_blocks),
+        ("journal", super_block.journal_blocks),
+        ("data bitmap", super_block.data_bitmap_blocks),
+        ("data area", super_block.data_area_blocks),
+    ];
+    let mut start = 1;
+    for (name, blocks) in areas {
+        println!("{:<12} blocks {}..{}", name, start, start + blocks);
+        start += blocks;
+    }
+    let stat = efs.lock().stat().map_err(fs_error)?;
+    println!("inodes: {} of {} used", stat.inodes - stat.free_inodes, stat.inodes);
+    println!("data blocks: {} of {} used", stat.data_blocks - stat.free_blocks, stat.data_blocks);
+    println!("{:>6} {:>4} {:>5} {:>10} {:>6}", "inode", "type", "nlink", "size", "blocks");
+    for inode_id in 0..stat.inodes {
+        let inode = match EasyFileSystem::get_inode(&efs, inode_id) {
+            Ok(inode) => inode,
+            Err(FsError::NotFound) => continue,
+            Err(error) => return Err(fs_error(error)),
+        };
+        let stat = inode.stat().map_err(fs_error)?;
+        let type_ = match stat.type_ {
+            DiskInodeType::File => "file",
+            DiskInodeType::Directory => "dir",
+        };
+        println!("{:>6} {:>4} {:>5} {:>10} {:>6}", inode_id, type_, stat.nlink, stat.size, stat.blocks);
+    }
+    Ok(())
+}
+
+/// Block ids with runs in a row shortened to `first-last`, `0` for a hole
+fn block_list(block_ids: &[u32]) -> String {
+    let mut runs: Vec<(u32, u32)> = Vec::new();
+    for block_id in block_ids.iter().copied() {
+        match runs.last_mut() {
+            Some((_, last)) if *last != 0 && block_id == *last + 1 => *last = block_id,
+            _ => runs.push((block_id, block_id)),
+        }
+    }
+    let runs: Vec<String> = runs
+        .into_iter()
+        .map(|(first, last)| if first == last { first.to_string() } else { format!("{}-{}", first, last) })
+        .collect();
+    runs.join(" ")
+}
+
+/// Lines showing an indirect block, indented by its depth, then the
+/// blocks under each of its entries
+fn indirect_lines(name: &str, map: &IndirectMap, depth: usize) -> Vec<String> {
+    let indent = "  ".repeat(depth);
+    if map.block_id == 0 {
+        return vec![format!("{}{}: none", indent, name)];
+    }
+    let mut lines = vec![format!("{}{} @{}: {}", indent, name, map.block_id, block_list(&map.entries))];
+    for (i, child) in map.children.iter().enumerate() {
+        lines.extend(indirect_lines(&format!("[{}]", i), child, depth + 1));
+    }
+    lines
+}
+
+fn easy_fs_dump_inode(matches: &ArgMatches) -> std::io::Result<()> {
+    let efs = open_image(matches.value_of("image").unwrap(), false)?;
+    let inode_id = matches
+        .value_of("inode")
+        .unwrap()
+        .parse()
+        .map_err(|_| std::io::Error::other("not an inode number"))?;
+    let inode = EasyFileSystem::get_inode(&efs, inode_id).map_err(fs_error)?;
+    println!("{:#?}", inode.stat().map_err(fs_error)?);
+    let map = inode.block_map().map_err(fs_error)?;
+    println!("direct: {}", block_list(&map.direct));
+    for (name, indirect) in [("indirect1", &map.indirect1), ("indirect2", &map.indirect2), ("indirect3", &map.indirect3)] {
+        for line in indirect_lines(name, indirect, 0) {
+            println!("{}", line);
+        }
+    }
+    Ok(())
+}
+
+/// Parse an image size in bytes, with an optional K, M or G suffix
+fn parse_size(size: &str) -> std::io::Result<usize> {
+    let (digits, unit) = match size.char_indices().last() {
+        Some((pos, 'K')) => (&size[..pos], 1 << 10),
+        Some((pos, 'M')) => (&size[..pos], 1 << 20),
+        Some((pos, 'G')) => (&size[..pos], 1 << 30),
+        _ => (size, 1),
+    };
+    match digits.parse::<usize>() {
+        Ok(n) if n * unit % BLOCK_SZ == 0 => Ok(n * unit),
+        _ => Err(std::io::Error::other(format!("{} is not a size in whole blocks", size))),
+    }
+}
+
+/// The time stamped on every inode of a reproducible image
+static PACK_TIME: AtomicU32 = AtomicU32::new(0);
+
+fn pack_clock() -> u32 {
+    PACK_TIME.load(Ordering::Relaxed)
+}
+
+/// Puts host files into a new image, each as a whole or not at all
+struct Packer {
+    efs: Arc<spin::Mutex<EasyFileSystem>>,
+    root_inode: Arc<Inode>,
+}
+
+impl Packer {
+    /// The directory at `path` of the image, made with its parents if missing
+    fn dir(&self, path: &str) -> std::io::Result<Arc<Inode>> {
+        let mut dir = self.root_inode.clone();
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            dir = match dir.find(name) {
+                Ok(inode) => inode,
+                Err(FsError::NotFound) => dir.mkdir(name).map_err(|error| pack_error(path, error))?,
+                Err(error) => return Err(pack_error(path, error)),
+            };
+        }
+        if !dir.is_dir().map_err(fs_error)? {
+            return Err(pack_error(path, FsError::NotDir));
+        }
+        Ok(dir)
+    }
+    /// Copy the host file `host_path` to `path` of the image
+    fn file(&self, host_path: &Path, path: &str) -> std::io::Result<()> {
+        let mut all_data: Vec<u8> = Vec::new();
+        File::open(host_path)?.read_to_end(&mut all_data)?;
+        // refuse a file that does not fit rather than pack part of it
+        let free_blocks = self.efs.lock().stat().map_err(fs_error)?.free_blocks as usize;
+        if file_blocks(all_data.len()) > free_blocks {
+            return Err(std::io::Error::other(format!(
+                "{} does not fit in the {} blocks left",
+                host_path.display(),
+                free_blocks
+            )));
+        }
+        let (parent, name) = split_path(path);
+        let inode = self.dir(parent)?.create(name).map_err(|error| pack_error(path, error))?;
+        // a short write is out of space
+        if inode.write_at(0, all_data.as_slice()).map_err(|error| pack_error(path, error))? < all_data.len() {
+            return Err(pack_error(path, FsError::NoSpace));
+        }
+        Ok(())
+    }
+    /// Copy the host directory `host_dir` and everything below it to `path`
+    /// of the image, in name order so that the same tree packs the same
+    fn tree(&self, host_dir: &Path, path: &str) -> std::io::Result<()> {
+        self.dir(path)?;
+        let mut entries = read_dir(host_dir)?.collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let name = entry.file_name().into_string().map_err(|name| {
+                std::io::Error::other(format!("{:?} is not a UTF-8 name", name))
+            })?;
+            let child_path = format!("{}/{}", path.trim_end_matches('/'), name);
+            // links are followed, what they point to is packed
+            if std::fs::metadata(entry.path())?.is_dir() {
+                self.tree(&entry.path(), &child_path)?;
+            } else {
+                self.file(&entry.path(), &child_path)?;
+            }
+        }
+        Ok(())
+    }
+    /// Pack what a manifest lists, a line per entry:
+    ///
+    /// - `<path> <host path>` copies a host file, or a host directory and
+    ///   everything below it, relative to the manifest
+    /// - `<path>` makes an empty directory
+    ///
+    /// Blank lines and lines starting with `#` are skipped.
+    fn manifest(&self, manifest: &Path) -> std::io::Result<()> {
+        let base = manifest.parent().unwrap_or(Path::new(""));
+        for (line_no, line) in std::fs::read_to_string(manifest)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields[..] {
+                [path] => self.dir(path).map(|_| ())?,
+                [path, host_path] => {
+                    let host_path = base.join(host_path);
+                    if host_path.is_dir() {
+                        self.tree(&host_path, path)?;
+                    } else {
+                        self.file(&host_path, path)?;
+                    }
+                }
+                _ => {
+                    return Err(std::io::Error::other(format!(
+                        "{}:{}: expected a path and at most one host path",
+                        manifest.display(),
+                        line_no + 1
+                    )))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn pack_error(path: &str, error: FsError) -> std::io::Error {
+    std::io::Error::other(format!("{}: {}", path, error))
+}
+
+/// Build an image from the apps of `--source`/`--target`, the host trees of
+/// `--dir` and the entries of `--manifest`, in that order
+fn easy_fs_pack(matches: &ArgMatches) -> std::io::Result<()> {
+    let size = parse_size(matches.value_of("size").unwrap_or("16M"))?;
+    let inodes: usize = matches
+        .value_of("inodes")
+        .unwrap_or("4095")
+        .parse()
+        .map_err(|_| std::io::Error::other("--inodes is not a number"))?;
+    let output = match (matches.value_of("output"), matches.value_of("target")) {
+        (Some(output), _) => output.to_string(),
+        (None, Some(target_path)) => format!("{}{}", target_path, "fs.img"),
+        (None, None) => return Err(std::io::Error::other("no image to write, give --output or --target")),
+    };
+    println!("output = {}", output);
     let block_file = Arc::new(BlockFile(Mutex::new({
         let f = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(format!("{}{}", target_path, "fs.img"))?;
-        f.set_len(16 * 2048 * 512).unwrap();  // 16MiB
+            .truncate(true)
+            .open(&output)?;
+        f.set_len(size as u64).unwrap();
         f
     })));
-    // 16MiB, at most 4095 files
-    let efs = EasyFileSystem::create(block_file, 16 * 2048, 1);
-    let root_inode = Arc::new(EasyFileSystem::root_inode(&efs));
-    let apps: Vec<_> = read_dir(src_path)
-        .unwrap()
-        .into_iter()
-        .map(|dir_entry| {
-            let mut name_with_ext = dir_entry.unwrap().file_name().into_string().unwrap();
-            name_with_ext.drain(name_with_ext.find('.').unwrap()..name_with_ext.len());
-            name_with_ext
-        })
-        .collect();
-    for app in apps {
-        // load app data from host file system
-        let mut host_file = File::open(format!("{}{}", target_path, app)).unwrap();
-        let mut all_data: Vec<u8> = Vec::new();
-        host_file.read_to_end(&mut all_data).unwrap();
-        // create a file in easy-fs
-        let inode = root_inode.create(app.as_str()).unwrap();
-        // write data to easy-fs
-        inode.write_at(0, all_data.as_slice());
+    // the root directory takes an inode too
+    let inode_bitmap_blocks = (inodes + 1).div_ceil(BLOCK_SZ * 8);
+    let efs = EasyFileSystem::create(block_file, (size / BLOCK_SZ) as u32, inode_bitmap_blocks as u32)
+        .map_err(|error| std::io::Error::other(format!("{} bytes and {} inodes: {}", size, inodes, error)))?;
+    // a fixed time makes the same input pack to the same bytes
+    let timestamp = matches
+        .value_of("timestamp")
+        .map(String::from)
+        .or_else(|| std::env::var("SOURCE_DATE_EPOCH").ok());
+    match timestamp {
+        Some(timestamp) => {
+            let timestamp = timestamp
+                .parse()
+                .map_err(|_| std::io::Error::other(format!("{} is not a timestamp", timestamp)))?;
+            PACK_TIME.store(timestamp, Ordering::Relaxed);
+            efs.lock().set_clock(pack_clock);
+        }
+        None => efs.lock().set_clock(host_clock),
     }
-    // // list apps
-    // for app in root_inode.ls() {
-    //     println!("{}", app);
-    // }
-
+    // nothing to keep safe until the image is complete
+    efs.lock().set_write_policy(WritePolicy::WriteBack).unwrap();
+    let packer = Packer {
+        root_inode: Arc::new(EasyFileSystem::root_inode(&efs)),
+        efs: efs.clone(),
+    };
+    if let (Some(src_path), Some(target_path)) = (matches.value_of("source"), matches.value_of("target")) {
+        println!("src_path = {}\ntarget_path = {}", src_path, target_path);
+        let mut apps: Vec<_> = read_dir(src_path)
+            .unwrap()
+            .map(|dir_entry| {
+                // only the last extension goes, "a.test.rs" is app "a.test"
+                let path = dir_entry.unwrap().path();
+                path.file_stem().unwrap().to_str().unwrap().to_string()
+            })
+            .collect();
+        apps.sort();
+        for app in apps {
+            packer.file(Path::new(&format!("{}{}", target_path, app)), &app)?;
+        }
+    }
+    for host_dir in matches.values_of("dir").into_iter().flatten() {
+        packer.tree(Path::new(host_dir), "/")?;
+    }
+    if let Some(manifest) = matches.value_of("manifest") {
+        packer.manifest(Path::new(manifest))?;
+    }
+    efs.lock().sync().unwrap();
+    let stat = efs.lock().stat().unwrap();
+    println!(
+        "{} of {} blocks and {} of {} inodes used",
+        stat.data_blocks - stat.free_blocks,
+        stat.data_blocks,
+        stat.inodes - stat.free_inodes,
+        stat.inodes,
+    );
     Ok(())
 }
 
@@ -93,41 +680,42 @@ fn efs_test() -> std::io::Result<()> {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(true)
             .open("target/fs.img")?;
         f.set_len(8192 * 512).unwrap();
         f
     })));
-    EasyFileSystem::create(block_file.clone(), 4096, 1);
-    let efs = EasyFileSystem::open(block_file.clone());
+    EasyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+    let efs = EasyFileSystem::open(block_file.clone()).unwrap();
     let root_inode = EasyFileSystem::root_inode(&efs);
-    root_inode.create("filea");
-    root_inode.create("fileb");
-    for name in root_inode.ls() {
+    root_inode.create("filea").unwrap();
+    root_inode.create("fileb").unwrap();
+    for name in root_inode.ls().unwrap() {
         println!("{}", name);
     }
     let filea = root_inode.find("filea").unwrap();
     let greet_str = "Hello, world!";
-    filea.write_at(0, greet_str.as_bytes());
+    filea.write_at(0, greet_str.as_bytes()).unwrap();
     // let mut buffer = [0u8; 512];
     let mut buffer = [0u8; 233];
-    let len = filea.read_at(0, &mut buffer);
+    let len = filea.read_at(0, &mut buffer).unwrap();
     assert_eq!(greet_str, core::str::from_utf8(&buffer[..len]).unwrap(),);
 
     let mut random_str_test = |len: usize| {
-        filea.clear();
-        assert_eq!(filea.read_at(0, &mut buffer), 0, );
+        filea.clear().unwrap();
+        assert_eq!(filea.read_at(0, &mut buffer).unwrap(), 0, );
         let mut str = String::new();
         use rand;
         // random digit
         for _ in 0..len {
-            str.push(char::from('0' as u8 + rand::random::<u8>() % 10));
+            str.push(char::from(b'0' + rand::random::<u8>() % 10));
         }
-        filea.write_at(0, str.as_bytes());
+        filea.write_at(0, str.as_bytes()).unwrap();
         let mut read_buffer = [0u8; 127];
         let mut offset = 0usize;
         let mut read_str = String::new();
         loop {
-            let len = filea.read_at(offset, &mut read_buffer);
+            let len = filea.read_at(offset, &mut read_buffer).unwrap();
             if len == 0 {
                 break;
             }
@@ -156,6 +744,1726 @@ fn efs_test() -> std::io::Result<()> {
     Ok(())
 }
 
+#[test]
+fn efs_dir_test() -> std::io::Result<()> {
+    use easy_fs::FsError;
+    let block_file = Arc::new(BlockFile(Mutex::new({
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open("target/fs_dir.img")?;
+        f.set_len(8192 * 512).unwrap();
+        f
+    })));
+    EasyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+    let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let bin = root_inode.mkdir("bin").unwrap();
+    let data = root_inode.mkdir("data").unwrap();
+    assert!(bin.is_dir().unwrap());
+    assert_eq!(root_inode.mkdir("bin").err(), Some(FsError::Exists));
+    assert_eq!(root_inode.create("a/b").err(), Some(FsError::InvalidName));
+    assert_eq!(root_inode.create(&"n".repeat(256)).err(), Some(FsError::NameTooLong));
+    bin.create("cat").unwrap().write_at(0, b"meow").unwrap();
+    data.mkdir("sub").unwrap().create("x").unwrap().write_at(0, b"xx").unwrap();
+    assert_eq!(root_inode.ls().unwrap(), vec!["bin", "data"]);
+    assert_eq!(bin.ls().unwrap(), vec!["cat"]);
+
+    let mut buffer = [0u8; 16];
+    let cat = root_inode.find("/bin/cat").unwrap();
+    assert!(cat.is_file().unwrap());
+    let len = cat.read_at(0, &mut buffer).unwrap();
+    assert_eq!(&buffer[..len], b"meow");
+    let x = bin.find("../data/./sub/x").unwrap();
+    let len = x.read_at(0, &mut buffer).unwrap();
+    assert_eq!(&buffer[..len], b"xx");
+    assert_eq!(bin.find("..").unwrap().inode_id(), root_inode.inode_id());
+    assert_eq!(root_inode.find("/..").unwrap().inode_id(), 0);
+    assert_eq!(root_inode.find("bin/cat/x").err(), Some(FsError::NotDir));
+    assert_eq!(root_inode.find("bin/dog").err(), Some(FsError::NotFound));
+    Ok(())
+}
+
+#[test]
+fn efs_unlink_link_rename_test() -> std::io::Result<()> {
+    use easy_fs::FsError;
+    let block_file = Arc::new(BlockFile(Mutex::new({
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open("target/fs_link.img")?;
+        f.set_len(8192 * 512).unwrap();
+        f
+    })));
+    EasyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+    let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let mut buffer = [0u8; 16];
+
+    // an unlinked inode is reclaimed and handed out again
+    let filea = root_inode.create("filea").unwrap();
+    filea.write_at(0, &[1u8; 100 * BLOCK_SZ]).unwrap();
+    let filea_id = filea.inode_id();
+    root_inode.unlink("filea").unwrap();
+    assert_eq!(root_inode.unlink("filea"), Err(FsError::NotFound));
+    assert!(root_inode.find("filea").is_err());
+    assert!(root_inode.ls().unwrap().is_empty());
+    drop(filea);
+    assert_eq!(root_inode.create("fileb").unwrap().inode_id(), filea_id);
+
+    // hard links share data and keep it alive
+    let fileb = root_inode.find("fileb").unwrap();
+    fileb.write_at(0, b"linked").unwrap();
+    let dir = root_inode.mkdir("dir").unwrap();
+    assert_eq!(root_inode.nlink().unwrap(), 3);
+    dir.link("b", &fileb).unwrap();
+    assert_eq!(root_inode.link("dir2", &dir), Err(FsError::IsDir));
+    assert_eq!(fileb.nlink().unwrap(), 2);
+    root_inode.unlink("fileb").unwrap();
+    let b = root_inode.find("dir/b").unwrap();
+    assert_eq!(b.nlink().unwrap(), 1);
+    let len = b.read_at(0, &mut buffer).unwrap();
+    assert_eq!(&buffer[..len], b"linked");
+
+    // rename within and across directories
+    dir.rename("b", &dir, "c").unwrap();
+    assert_eq!(dir.ls().unwrap(), vec!["c"]);
+    dir.rename("c", &root_inode, "top").unwrap();
+    assert!(dir.ls().unwrap().is_empty());
+    assert_eq!(root_inode.find("top").unwrap().inode_id(), filea_id);
+    root_inode.create("other").unwrap().write_at(0, b"other").unwrap();
+    root_inode.rename("other", &root_inode, "top").unwrap();
+    let len = root_inode.find("top").unwrap().read_at(0, &mut buffer).unwrap();
+    assert_eq!(&buffer[..len], b"other");
+
+    // moving a directory updates its ".." and both link counts
+    let sub = root_inode.mkdir("sub").unwrap();
+    assert_eq!(root_inode.rename("dir", &dir, "self"), Err(FsError::Invalid));
+    root_inode.rename("dir", &sub, "moved").unwrap();
+    assert_eq!(root_inode.nlink().unwrap(), 3);
+    assert_eq!(sub.nlink().unwrap(), 3);
+    assert_eq!(root_inode.find("sub/moved/..").unwrap().inode_id(), sub.inode_id());
+
+    // only empty directories can be removed
+    sub.find("moved").unwrap().create("f").unwrap();
+    assert_eq!(sub.rmdir("moved"), Err(FsError::NotEmpty));
+    assert_eq!(sub.unlink("moved"), Err(FsError::IsDir));
+    root_inode.find("sub/moved").unwrap().unlink("f").unwrap();
+    sub.rmdir("moved").unwrap();
+    assert_eq!(sub.nlink().unwrap(), 2);
+    assert_eq!(root_inode.ls().unwrap(), vec!["top", "sub"]);
+    Ok(())
+}
+
+#[test]
+fn efs_unlink_open_test() -> std::io::Result<()> {
+    use easy_fs::FsError;
+    let block_file = Arc::new(BlockFile(Mutex::new({
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open("target/fs_unlink_open.img")?;
+        f.set_len(8192 * 512).unwrap();
+        f
+    })));
+    EasyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+    let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let free_blocks = efs.lock().stat().unwrap().free_blocks;
+    let mut buffer = [0u8; 4 * BLOCK_SZ];
+
+    // an unlinked file still open keeps its inode and data
+    let filea = root_inode.create("filea").unwrap();
+    filea.write_at(0, &[1u8; 4 * BLOCK_SZ]).unwrap();
+    root_inode.unlink("filea").unwrap();
+    assert_eq!(filea.nlink().unwrap(), 0);
+    assert!(efs.lock().stat().unwrap().free_blocks < free_blocks);
+
+    // written through its handle, it does not meet a file made afterwards
+    let fileb = root_inode.create("fileb").unwrap();
+    assert_ne!(fileb.inode_id(), filea.inode_id());
+    fileb.write_at(0, &[2u8; 4 * BLOCK_SZ]).unwrap();
+    assert_eq!(filea.write_at(2 * BLOCK_SZ, &[3u8; 2 * BLOCK_SZ]).unwrap(), 2 * BLOCK_SZ);
+    assert_eq!(filea.read_at(0, &mut buffer).unwrap(), 4 * BLOCK_SZ);
+    assert!(buffer[..2 * BLOCK_SZ].iter().all(|byte| *byte == 1));
+    assert!(buffer[2 * BLOCK_SZ..].iter().all(|byte| *byte == 3));
+    assert_eq!(fileb.read_at(0, &mut buffer).unwrap(), 4 * BLOCK_SZ);
+    assert!(buffer.iter().all(|byte| *byte == 2));
+    assert_eq!(root_inode.link("filea", &filea), Err(FsError::NotFound));
+
+    // the last handle gives the inode back
+    let filea_id = filea.inode_id();
+    drop(filea);
+    root_inode.unlink("fileb").unwrap();
+    drop(fileb);
+    assert_eq!(efs.lock().stat().unwrap().free_blocks, free_blocks);
+    assert_eq!(root_inode.create("filec").unwrap().inode_id(), filea_id);
+
+    // a removed directory still open takes no new entries
+    let dir = root_inode.mkdir("dir").unwrap();
+    root_inode.rmdir("dir").unwrap();
+    assert_eq!(dir.create("x").err(), Some(FsError::NotFound));
+    assert_eq!(dir.find("..").err(), Some(FsError::NotFound));
+    assert_eq!(root_inode.rename("filec", &dir, "x"), Err(FsError::NotFound));
+    let dir_id = dir.inode_id();
+    drop(dir);
+    assert_eq!(root_inode.mkdir("dir2").unwrap().inode_id(), dir_id);
+
+    // nothing is left behind for fsck to find
+    drop(root_inode);
+    drop(efs);
+    let block_file: Arc<dyn BlockDevice> = block_file;
+    let report = fsck(&block_file, false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+    Ok(())
+}
+
+#[test]
+fn efs_stat_test() -> std::io::Result<()> {
+    use easy_fs::DiskInodeType;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    static NOW: AtomicU32 = AtomicU32::new(100);
+    fn test_clock() -> u32 {
+        NOW.load(Ordering::SeqCst)
+    }
+    let block_file = Arc::new(BlockFile(Mutex::new({
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open("target/fs_stat.img")?;
+        f.set_len(8192 * 512).unwrap();
+        f
+    })));
+    EasyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+    let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+    efs.lock().set_clock(test_clock);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+
+    let filea = root_inode.create("filea").unwrap();
+    let stat = filea.stat().unwrap();
+    assert_eq!(stat.inode_id, filea.inode_id());
+    assert_eq!(stat.type_, DiskInodeType::File);
+    assert_eq!((stat.mode, stat.nlink, stat.uid, stat.gid), (0o644, 1, 0, 0));
+    assert_eq!((stat.size, stat.blocks), (0, 0));
+    assert_eq!((stat.atime, stat.mtime, stat.ctime), (100, 100, 100));
+    assert_eq!(root_inode.stat().unwrap().mtime, 100);
+
+    NOW.store(200, Ordering::SeqCst);
+    filea.write_at(0, &[0u8; 30 * BLOCK_SZ]).unwrap();
+    let stat = filea.stat().unwrap();
+    assert_eq!((stat.size, stat.blocks), (30 * BLOCK_SZ as u32, 31));
+    assert_eq!((stat.atime, stat.mtime, stat.ctime), (100, 200, 200));
+    NOW.store(300, Ordering::SeqCst);
+    filea.read_at(0, &mut [0u8; 16]).unwrap();
+    assert_eq!(filea.stat().unwrap().atime, 300);
+
+    NOW.store(400, Ordering::SeqCst);
+    filea.chmod(0o100600).unwrap();
+    filea.chown(1000, 100).unwrap();
+    let stat = filea.stat().unwrap();
+    assert_eq!((stat.mode, stat.uid, stat.gid, stat.ctime), (0o600, 1000, 100, 400));
+    filea.set_times(1, 2).unwrap();
+    assert_eq!((filea.stat().unwrap().atime, filea.stat().unwrap().mtime), (1, 2));
+
+    let dir = root_inode.mkdir("dir").unwrap().stat().unwrap();
+    assert_eq!(dir.type_, DiskInodeType::Directory);
+    assert_eq!((dir.mode, dir.nlink), (0o755, 2));
+    Ok(())
+}
+
+#[test]
+fn efs_truncate_test() -> std::io::Result<()> {
+    let block_file = Arc::new(BlockFile(Mutex::new({
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open("target/fs_truncate.img")?;
+        f.set_len(8192 * 512).unwrap();
+        f
+    })));
+    // about 3000 data blocks, two 2000-block files only fit if blocks are released
+    EasyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+    let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let filea = root_inode.create("filea").unwrap();
+    let data: Vec<u8> = (0..2000 * BLOCK_SZ).map(|i| (i % 251) as u8).collect();
+    filea.write_at(0, &data).unwrap();
+
+    let check = |len: usize| {
+        let mut read = vec![0u8; len + BLOCK_SZ];
+        assert_eq!(filea.read_at(0, &mut read).unwrap(), len);
+        assert_eq!(&read[..len], &data[..len]);
+    };
+    for len in [300 * BLOCK_SZ, 151 * BLOCK_SZ + 7, 151 * BLOCK_SZ, 100 * BLOCK_SZ, 23 * BLOCK_SZ, 10 * BLOCK_SZ + BLOCK_SZ / 2] {
+        filea.truncate(len as u32).unwrap();
+        let stat = filea.stat().unwrap();
+        assert_eq!(stat.size as usize, len);
+        assert_eq!(stat.blocks as usize, file_blocks(len));
+        check(len);
+    }
+    assert_eq!(filea.stat().unwrap().blocks, 11);
+    // growing again reads zeros past the old end
+    filea.truncate(12 * BLOCK_SZ as u32).unwrap();
+    let mut read = vec![0xffu8; 2 * BLOCK_SZ];
+    assert_eq!(filea.read_at(10 * BLOCK_SZ, &mut read).unwrap(), 2 * BLOCK_SZ);
+    assert_eq!(&read[..BLOCK_SZ / 2], &data[10 * BLOCK_SZ..10 * BLOCK_SZ + BLOCK_SZ / 2]);
+    assert!(read[BLOCK_SZ / 2..].iter().all(|b| *b == 0));
+
+    let fileb = root_inode.create("fileb").unwrap();
+    fileb.write_at(0, &data).unwrap();
+    let mut read = vec![0u8; data.len()];
+    assert_eq!(fileb.read_at(0, &mut read).unwrap(), data.len());
+    assert_eq!(read, data);
+    filea.truncate(0).unwrap();
+    assert_eq!(filea.stat().unwrap().blocks, 0);
+    Ok(())
+}
+
+#[test]
+fn efs_large_file_test() -> std::io::Result<()> {
+    let block_file = Arc::new(BlockFile(Mutex::new({
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open("target/fs_large.img")?;
+        f.set_len(24576 * 512).unwrap();
+        f
+    })));
+    // about 24000 data blocks, two 16834-block files only fit if blocks are released
+    EasyFileSystem::create(block_file.clone(), 24576, 1).unwrap();
+    let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let filea = root_inode.create("filea").unwrap();
+    // 22 direct + 128 indirect1 + 16384 indirect2, the rest goes to indirect3
+    let blocks = 22 + 128 + 16384 + 300;
+    let data: Vec<u8> = (0..blocks * BLOCK_SZ).map(|i| (i % 251) as u8).collect();
+    for chunk in (0..data.len()).step_by(64 * BLOCK_SZ) {
+        let end = (chunk + 64 * BLOCK_SZ).min(data.len());
+        assert_eq!(filea.write_at(chunk, &data[chunk..end]).unwrap(), end - chunk);
+    }
+    let mut read = vec![0u8; data.len()];
+    assert_eq!(filea.read_at(0, &mut read).unwrap(), data.len());
+    assert!(read == data);
+
+    // shrink into indirect2 and grow back into indirect3 through the zeroed tail
+    let len = (22 + 128 + 1000) * BLOCK_SZ + 3;
+    filea.truncate(len as u32).unwrap();
+    filea.truncate(data.len() as u32).unwrap();
+    assert_eq!(filea.read_at(0, &mut read).unwrap(), data.len());
+    assert!(read[..len] == data[..len]);
+    assert!(read[len..].iter().all(|b| *b == 0));
+
+    filea.clear().unwrap();
+    let fileb = root_inode.create("fileb").unwrap();
+    assert_eq!(fileb.write_at(0, &data).unwrap(), data.len());
+    assert_eq!(fileb.read_at(0, &mut read).unwrap(), data.len());
+    assert!(read == data);
+    Ok(())
+}
+
+#[test]
+fn efs_sparse_test() -> std::io::Result<()> {
+    let block_file = Arc::new(BlockFile(Mutex::new({
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open("target/fs_sparse.img")?;
+        f.set_len(8192 * 512).unwrap();
+        f
+    })));
+    // about 3000 data blocks, far less than an 8MiB file takes when dense
+    EasyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+    let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let filea = root_inode.create("filea").unwrap();
+    let end = 8 * 1024 * 1024;
+    assert_eq!(filea.write_at(end - 5, b"tail!").unwrap(), 5);
+    let stat = filea.stat().unwrap();
+    assert_eq!(stat.size as usize, end);
+    // the data block, indirect2 and one of its indirect1
+    assert_eq!(stat.blocks, 3);
+    let mut read = vec![0xffu8; 3 * BLOCK_SZ];
+    assert_eq!(filea.read_at(4 * 1024 * 1024, &mut read).unwrap(), read.len());
+    assert!(read.iter().all(|b| *b == 0));
+    let mut read = [0u8; 5];
+    assert_eq!(filea.read_at(end - 5, &mut read).unwrap(), 5);
+    assert_eq!(&read, b"tail!");
+
+    // filling a hole takes only the blocks written
+    assert_eq!(filea.write_at(BLOCK_SZ + 1, b"middle").unwrap(), 6);
+    assert_eq!(filea.stat().unwrap().blocks, 4);
+    let mut read = [0xffu8; BLOCK_SZ + 7];
+    assert_eq!(filea.read_at(0, &mut read).unwrap(), read.len());
+    assert!(read[..BLOCK_SZ + 1].iter().all(|b| *b == 0));
+    assert_eq!(&read[BLOCK_SZ + 1..], b"middle");
+
+    // growing leaves a hole, shrinking releases only what was allocated
+    filea.truncate(2 * end as u32).unwrap();
+    assert_eq!(filea.stat().unwrap().blocks, 4);
+    filea.truncate(BLOCK_SZ as u32 + 3).unwrap();
+    assert_eq!(filea.stat().unwrap().blocks, 1);
+    let mut read = [0xffu8; 8];
+    assert_eq!(filea.read_at(BLOCK_SZ, &mut read).unwrap(), 3);
+    assert_eq!(&read[..3], b"\0mi");
+    filea.truncate(0).unwrap();
+    assert_eq!(filea.stat().unwrap().blocks, 0);
+    Ok(())
+}
+
+#[test]
+fn efs_journal_test() -> std::io::Result<()> {
+    use easy_fs::Inode;
+    use std::collections::HashMap;
+    /// Writes past the first `budget` ones only reach a volatile overlay,
+    /// leaving the image as if the power was cut right then
+    struct CrashingBlockFile {
+        block_file: BlockFile,
+        budget: Mutex<usize>,
+        lost: Mutex<HashMap<usize, Vec<u8>>>,
+    }
+    impl BlockDevice for CrashingBlockFile {
+        fn device_id(&self) -> usize {
+            self.block_file.device_id()
+        }
+        fn read_block(&self, block_id: usize, buf: &mut [u8]) -> easy_fs::Result<()> {
+            match self.lost.lock().unwrap().get(&block_id) {
+                Some(data) => {
+                    buf.copy_from_slice(data);
+                    Ok(())
+                }
+                None => self.block_file.read_block(block_id, buf),
+            }
+        }
+        fn write_block(&self, block_id: usize, buf: &[u8]) -> easy_fs::Result<()> {
+            let mut budget = self.budget.lock().unwrap();
+            if *budget > 0 {
+                *budget -= 1;
+                self.block_file.write_block(block_id, buf)
+            } else {
+                self.lost.lock().unwrap().insert(block_id, buf.to_vec());
+                Ok(())
+            }
+        }
+    }
+    fn open_image(path: &str) -> std::io::Result<BlockFile> {
+        let f = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(BlockFile(Mutex::new(f)))
+    }
+    fn snapshot(root_inode: &Inode) -> Vec<(String, Vec<u8>)> {
+        let mut names = root_inode.ls().unwrap();
+        names.sort();
+        names.into_iter().map(|name| {
+            let inode = root_inode.find(&name).unwrap();
+            let mut data = vec![0u8; inode.stat().unwrap().size as usize];
+            assert_eq!(inode.read_at(0, &mut data).unwrap(), data.len());
+            (name, data)
+        }).collect()
+    }
+
+    let base = "target/fs_journal_base.img";
+    let image = "target/fs_journal.img";
+    {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(base)?;
+        f.set_len(4096 * 512).unwrap();
+        let block_file = Arc::new(BlockFile(Mutex::new(f)));
+        EasyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+        let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        root_inode.create("keep").unwrap().write_at(0, b"kept").unwrap();
+    }
+    let data: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+    let run = |budget: usize| -> std::io::Result<usize> {
+        std::fs::copy(base, image)?;
+        let device = Arc::new(CrashingBlockFile {
+            block_file: open_image(image)?,
+            budget: Mutex::new(budget),
+            lost: Mutex::new(HashMap::new()),
+        });
+        let efs = EasyFileSystem::open(device.clone()).unwrap();
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        root_inode.create("new").unwrap();
+        root_inode.find("new").unwrap().write_at(0, &data).unwrap();
+        root_inode.unlink("keep").unwrap();
+        let left = *device.budget.lock().unwrap();
+        Ok(budget - left)
+    };
+    // what a crash may leave: the image before or after each operation
+    let kept = (String::from("keep"), b"kept".to_vec());
+    let states = [
+        vec![kept.clone()],
+        vec![kept.clone(), (String::from("new"), Vec::new())],
+        vec![kept, (String::from("new"), data.clone())],
+        vec![(String::from("new"), data.clone())],
+    ];
+    let writes = run(usize::MAX)?;
+    for budget in 0..=writes {
+        run(budget)?;
+        let efs = EasyFileSystem::open(Arc::new(open_image(image)?)).unwrap();
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        let state = snapshot(&root_inode);
+        assert!(states.contains(&state), "unexpected image after {} writes", budget);
+        // no block is both free and in use
+        let after = root_inode.create("after").unwrap();
+        assert_eq!(after.write_at(0, &data).unwrap(), data.len());
+        let mut expected = state;
+        expected.push((String::from("after"), data.clone()));
+        expected.sort();
+        assert!(snapshot(&root_inode) == expected, "corrupted image after {} writes", budget);
+    }
+    Ok(())
+}
+
+#[test]
+fn efs_fsck_test() -> std::io::Result<()> {
+    use easy_fs::FsckProblem;
+    let path = "target/fs_fsck.img";
+    let open_image = || -> std::io::Result<Arc<dyn BlockDevice>> {
+        let f = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(Arc::new(BlockFile(Mutex::new(f))))
+    };
+    // raw access to the image, as the corruption would come from outside
+    let poke = |pos: usize, value: u32| -> std::io::Result<()> {
+        let mut f = OpenOptions::new().write(true).open(path)?;
+        f.seek(SeekFrom::Start(pos as u64))?;
+        f.write_all(&value.to_ne_bytes())
+    };
+    let peek = |pos: usize| -> std::io::Result<u32> {
+        let mut f = File::open(path)?;
+        f.seek(SeekFrom::Start(pos as u64))?;
+        let mut bytes = [0u8; 4];
+        f.read_exact(&mut bytes)?;
+        Ok(u32::from_ne_bytes(bytes))
+    };
+    // 1 inode bitmap block, 1024 inode blocks, 128 journal blocks, 1 data bitmap block
+    let inode_pos = |inode_id: u32| (2 + inode_id as usize / 4) * BLOCK_SZ + (inode_id as usize % 4) * 128;
+    let direct_pos = |inode_id: u32, i: usize| inode_pos(inode_id) + 4 + 4 * i;
+    let nlink_pos = |inode_id: u32| inode_pos(inode_id) + 108;
+    let data_bitmap_pos = 1154 * BLOCK_SZ;
+    let data_area_start = 1155;
+
+    {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        f.set_len(4096 * 512).unwrap();
+    }
+    let (g_id, h_id, free_blocks) = {
+        let block_file = open_image()?;
+        EasyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+        let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        let d = root_inode.mkdir("d").unwrap();
+        let data = vec![7u8; 3 * BLOCK_SZ];
+        let f = d.create("f").unwrap();
+        f.write_at(0, &data).unwrap();
+        let g = root_inode.create("g").unwrap();
+        g.write_at(0, &data).unwrap();
+        let h = root_inode.create("h").unwrap();
+        h.write_at(0, &data).unwrap();
+        d.link("g2", &g).unwrap();
+        let free_blocks = efs.lock().stat().unwrap().free_blocks;
+        (g.inode_id(), h.inode_id(), free_blocks)
+    };
+    let report = fsck(&open_image()?, false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+
+    poke(nlink_pos(g_id), 5)?;
+    let shared = peek(direct_pos(g_id, 0))?;
+    let lost = peek(direct_pos(h_id, 0))?;
+    poke(direct_pos(h_id, 0), shared)?;
+    let bad = peek(direct_pos(h_id, 1))?;
+    poke(direct_pos(h_id, 1), 9999)?;
+    // inode 100 taken but unused, the block after the last one used taken too
+    poke(BLOCK_SZ + 12, 1 << 4)?;
+    let next = data_area_start + 32;
+    assert_eq!(peek(data_bitmap_pos + 4)?, 0);
+    poke(data_bitmap_pos + 4, 1)?;
+
+    let report = fsck(&open_image()?, false).unwrap();
+    assert!(!report.repaired);
+    for problem in [
+        FsckProblem::WrongLinkCount { inode_id: g_id, nlink: 5, links: 2 },
+        FsckProblem::DoublyReferencedBlock { inode_id: h_id, block_id: shared },
+        FsckProblem::BadBlockPointer { inode_id: h_id, block_id: 9999 },
+        FsckProblem::LeakedBlock { block_id: lost },
+        FsckProblem::LeakedBlock { block_id: bad },
+        FsckProblem::LeakedBlock { block_id: next },
+        // the blocks of h no longer pointed to are free once repaired
+        FsckProblem::WrongFreeBlocks { free_blocks, counted: free_blocks + 2 },
+        FsckProblem::OrphanInode { inode_id: 100 },
+    ] {
+        assert!(report.problems.contains(&problem), "{} not found", problem);
+    }
+    assert_eq!(report.problems.len(), 8, "{:?}", report.problems);
+    // nothing was written without repair
+    assert_eq!(fsck(&open_image()?, false).unwrap().problems, report.problems);
+
+    let report = fsck(&open_image()?, true).unwrap();
+    assert!(report.repaired);
+    assert!(fsck(&open_image()?, false).unwrap().is_clean());
+
+    let efs = EasyFileSystem::open(open_image()?).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let g = root_inode.find("g").unwrap();
+    assert_eq!(g.nlink().unwrap(), 2);
+    // the bad pointers of h became holes
+    let mut read = vec![0xffu8; 3 * BLOCK_SZ];
+    let h = root_inode.find("h").unwrap();
+    assert_eq!(h.read_at(0, &mut read).unwrap(), read.len());
+    assert!(read[..2 * BLOCK_SZ].iter().all(|b| *b == 0));
+    assert!(read[2 * BLOCK_SZ..].iter().all(|b| *b == 7));
+    let f = root_inode.find("d/f").unwrap();
+    assert_eq!(f.read_at(0, &mut read).unwrap(), read.len());
+    assert!(read.iter().all(|b| *b == 7));
+    drop(efs);
+
+    // dangling dirent: the inode of h is given back behind its dirent
+    poke(BLOCK_SZ, peek(BLOCK_SZ)? & !(1 << h_id))?;
+    let report = fsck(&open_image()?, true).unwrap();
+    assert!(report.problems.contains(&FsckProblem::DanglingDirent {
+        dir: 0,
+        name: String::from("h"),
+        inode_id: h_id,
+    }));
+    assert!(fsck(&open_image()?, false).unwrap().is_clean());
+
+    // a journal header logging more than the journal holds, or a block in
+    // the journal itself, is refused before anything is read from the log
+    let journal_pos = 1026 * BLOCK_SZ;
+    for (count, block_id) in [(1000, 0), (1, 1026)] {
+        poke(journal_pos, count)?;
+        poke(journal_pos + 4, block_id)?;
+        assert_eq!(EasyFileSystem::open(open_image()?).err(), Some(FsError::Corrupt));
+        assert_eq!(fsck(&open_image()?, true).unwrap().problems, [FsckProblem::BadJournal]);
+        assert!(fsck(&open_image()?, false).unwrap().is_clean());
+    }
+
+    // a size past what an inode can map is corruption, not a panic
+    poke(inode_pos(g_id), u32::MAX)?;
+    let efs = EasyFileSystem::open(open_image()?).unwrap();
+    let g = EasyFileSystem::root_inode(&efs).find("g").unwrap();
+    assert_eq!(g.read_at(easy_fs::MAX_FILE_SIZE, &mut read).err(), Some(FsError::Corrupt));
+    drop(g);
+    drop(efs);
+
+    poke(0, 0)?;
+    let report = fsck(&open_image()?, true).unwrap();
+    assert!(!report.repaired);
+    assert_eq!(report.problems, [FsckProblem::BadSuperBlock("not an easy-fs image")]);
+    Ok(())
+}
+
+#[test]
+fn efs_write_back_test() -> std::io::Result<()> {
+    struct CountingBlockFile {
+        block_file: BlockFile,
+        reads: Mutex<usize>,
+        writes: Mutex<usize>,
+    }
+    impl BlockDevice for CountingBlockFile {
+        fn device_id(&self) -> usize {
+            self.block_file.device_id()
+        }
+        fn read_block(&self, block_id: usize, buf: &mut [u8]) -> easy_fs::Result<()> {
+            *self.reads.lock().unwrap() += 1;
+            self.block_file.read_block(block_id, buf)
+        }
+        fn write_block(&self, block_id: usize, buf: &[u8]) -> easy_fs::Result<()> {
+            *self.writes.lock().unwrap() += 1;
+            self.block_file.write_block(block_id, buf)
+        }
+    }
+    let path = "target/fs_write_back.img";
+    let open_image = || -> std::io::Result<Arc<CountingBlockFile>> {
+        let f = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(Arc::new(CountingBlockFile {
+            block_file: BlockFile(Mutex::new(f)),
+            reads: Mutex::new(0),
+            writes: Mutex::new(0),
+        }))
+    };
+    {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        f.set_len(8192 * 512).unwrap();
+    }
+    EasyFileSystem::create(open_image()?, 4096, 1).unwrap();
+    let appends = |policy: WritePolicy, name: &str| {
+        let block_file = open_image().unwrap();
+        let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+        efs.lock().set_write_policy(policy).unwrap();
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        let file = root_inode.create(name).unwrap();
+        for i in 0..64 {
+            file.write_at(i * 100, &[i as u8; 100]).unwrap();
+        }
+        let writes = *block_file.writes.lock().unwrap();
+        (writes, efs)
+    };
+    let (write_through, _) = appends(WritePolicy::WriteThrough, "through");
+    let (write_back, efs) = appends(WritePolicy::WriteBack, "back");
+    assert!(write_back * 10 < write_through, "{} vs {}", write_back, write_through);
+
+    // nothing of "back" reached the image before fsync
+    let on_disk = |name: &str| -> std::io::Result<Option<Vec<u8>>> {
+        let efs = EasyFileSystem::open(open_image()?).unwrap();
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        Ok(root_inode.find(name).ok().map(|file| {
+            let mut buf = vec![0u8; 6400];
+            let len = file.read_at(0, &mut buf).unwrap();
+            buf.truncate(len);
+            buf
+        }))
+    };
+    assert_eq!(on_disk("through")?.unwrap().len(), 6400);
+    assert!(on_disk("back")?.is_none());
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    root_inode.find("back").unwrap().fsync().unwrap();
+    let data = on_disk("back")?.unwrap();
+    assert_eq!(data.len(), 6400);
+    assert!(data.chunks(100).enumerate().all(|(i, chunk)| chunk.iter().all(|b| *b == i as u8)));
+
+    // pending operations are committed before they outgrow the journal
+    let big = root_inode.create("big").unwrap();
+    let data: Vec<u8> = (0..400 * BLOCK_SZ).map(|i| (i / BLOCK_SZ) as u8).collect();
+    assert_eq!(big.write_at(0, &data).unwrap(), data.len());
+    let persisted = on_disk("big")?.unwrap().len();
+    assert!(persisted > 0 && persisted < 400 * BLOCK_SZ);
+    // switching back to write-through makes everything durable
+    efs.lock().set_write_policy(WritePolicy::WriteThrough).unwrap();
+    let efs = EasyFileSystem::open(open_image()?).unwrap();
+    let big = EasyFileSystem::root_inode(&efs).find("big").unwrap();
+    let mut read = vec![0u8; data.len()];
+    assert_eq!(big.read_at(0, &mut read).unwrap(), data.len());
+    assert!(read == data);
+    drop(big);
+    drop(efs);
+
+    // a file read again is read from the cache if it fits
+    let rereads = |cache_blocks: usize| {
+        let block_file = open_image().unwrap();
+        let efs = EasyFileSystem::open_with_cache(block_file.clone(), cache_blocks).unwrap();
+        let back = EasyFileSystem::root_inode(&efs).find("back").unwrap();
+        let mut buf = vec![0u8; 6400];
+        back.read_at(0, &mut buf).unwrap();
+        let reads = *block_file.reads.lock().unwrap();
+        back.read_at(0, &mut buf).unwrap();
+        let rereads = *block_file.reads.lock().unwrap() - reads;
+        rereads
+    };
+    assert_eq!(rereads(64), 0);
+    assert!(rereads(4) >= 13);
+    Ok(())
+}
+
+#[test]
+fn efs_multi_image_test() -> std::io::Result<()> {
+    let open_image = |path: &str, create: bool| -> std::io::Result<Arc<BlockFile>> {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(create)
+            .truncate(create)
+            .open(path)?;
+        if create {
+            f.set_len(4096 * 512).unwrap();
+        }
+        Ok(Arc::new(BlockFile(Mutex::new(f))))
+    };
+    let paths = ["target/fs_multi_a.img", "target/fs_multi_b.img"];
+    let devices = [open_image(paths[0], true)?, open_image(paths[1], true)?];
+    // the same blocks of both images are used the same way, with other data
+    let filesystems: Vec<_> = devices
+        .iter()
+        .map(|device| EasyFileSystem::create(device.clone(), 4096, 1).unwrap())
+        .collect();
+    for (i, efs) in filesystems.iter().enumerate() {
+        let root_inode = EasyFileSystem::root_inode(efs);
+        let file = root_inode.create("file").unwrap();
+        file.write_at(0, &[i as u8 + 1; 3 * BLOCK_SZ]).unwrap();
+        root_inode.mkdir(["a", "b"][i]).unwrap();
+    }
+    for (i, efs) in filesystems.iter().enumerate() {
+        let root_inode = EasyFileSystem::root_inode(efs);
+        assert_eq!(root_inode.ls().unwrap(), ["file", ["a", "b"][i]]);
+        let mut buf = [0u8; 3 * BLOCK_SZ];
+        assert_eq!(root_inode.find("file").unwrap().read_at(0, &mut buf).unwrap(), buf.len());
+        assert!(buf.iter().all(|b| *b == i as u8 + 1));
+    }
+    // unmounting gives the device back
+    drop(filesystems);
+    for device in devices.iter() {
+        assert_eq!(Arc::strong_count(device), 1);
+    }
+    for (i, path) in paths.iter().enumerate() {
+        let efs = EasyFileSystem::open(open_image(path, false)?).unwrap();
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        assert!(root_inode.find(["a", "b"][i]).unwrap().is_dir().unwrap());
+    }
+    // repairing one image leaves what another has pending in the cache
+    let efs = EasyFileSystem::open(open_image(paths[0], false)?).unwrap();
+    efs.lock().set_write_policy(WritePolicy::WriteBack).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    root_inode.find("file").unwrap().write_at(0, &[9u8; BLOCK_SZ]).unwrap();
+    let dirty_blocks = root_inode.statfs().unwrap().dirty_blocks;
+    assert!(dirty_blocks > 0);
+    let other: Arc<dyn BlockDevice> = open_image(paths[1], false)?;
+    assert!(fsck(&other, true).unwrap().is_clean());
+    assert_eq!(root_inode.statfs().unwrap().dirty_blocks, dirty_blocks);
+    Ok(())
+}
+
+#[test]
+fn efs_no_space_test() -> std::io::Result<()> {
+    use easy_fs::FsError;
+    let block_file: Arc<dyn BlockDevice> = Arc::new(BlockFile(Mutex::new({
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open("target/fs_no_space.img")?;
+        f.set_len(1255 * 512).unwrap();
+        f
+    })));
+    // 100 data blocks, the root directory takes one
+    assert_eq!(EasyFileSystem::create(block_file.clone(), 1150, 1).err(), Some(FsError::NoSpace));
+    EasyFileSystem::create(block_file.clone(), 1255, 1).unwrap();
+    let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let big = root_inode.create("big").unwrap();
+    let data = vec![3u8; 200 * BLOCK_SZ];
+    // a short write up to the last free block, indirect blocks included
+    let len = big.write_at(0, &data).unwrap();
+    assert!(len > 90 * BLOCK_SZ && len < 99 * BLOCK_SZ);
+    assert_eq!(len % BLOCK_SZ, 0);
+    assert_eq!(big.stat().unwrap().size as usize, len);
+    assert_eq!(big.write_at(len, &data), Err(FsError::NoSpace));
+    assert_eq!(big.stat().unwrap().size as usize, len);
+    // a new directory needs a block, the inode taken for it is given back
+    assert_eq!(root_inode.mkdir("dir").err(), Some(FsError::NoSpace));
+    assert!(root_inode.find("dir").is_err());
+    assert_eq!(root_inode.create("empty").unwrap().inode_id(), 2);
+    drop(efs);
+    assert!(fsck(&block_file, false).unwrap().is_clean());
+
+    let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let big = root_inode.find("big").unwrap();
+    let mut read = vec![0u8; data.len()];
+    assert_eq!(big.read_at(0, &mut read).unwrap(), len);
+    assert!(read[..len] == data[..len]);
+    // freeing space makes it usable again
+    big.truncate(0).unwrap();
+    assert!(root_inode.mkdir("dir").is_ok());
+    assert_eq!(big.write_at(0, &data[..50 * BLOCK_SZ]), Ok(50 * BLOCK_SZ));
+    Ok(())
+}
+
+#[test]
+fn efs_io_error_test() -> std::io::Result<()> {
+    use easy_fs::FsError;
+    /// Transfers fail once `budget` writes are done, and every read too
+    /// while `broken` is set
+    struct FailingBlockFile {
+        block_file: BlockFile,
+        budget: Mutex<usize>,
+        broken: Mutex<bool>,
+    }
+    impl BlockDevice for FailingBlockFile {
+        fn device_id(&self) -> usize {
+            self.block_file.device_id()
+        }
+        fn read_block(&self, block_id: usize, buf: &mut [u8]) -> easy_fs::Result<()> {
+            if *self.broken.lock().unwrap() {
+                return Err(FsError::Io);
+            }
+            self.block_file.read_block(block_id, buf)
+        }
+        fn write_block(&self, block_id: usize, buf: &[u8]) -> easy_fs::Result<()> {
+            let mut budget = self.budget.lock().unwrap();
+            if *budget == 0 {
+                return Err(FsError::Io);
+            }
+            *budget -= 1;
+            self.block_file.write_block(block_id, buf)
+        }
+        fn num_blocks(&self) -> Option<usize> {
+            self.block_file.num_blocks()
+        }
+    }
+    let path = "target/fs_io_error.img";
+    let open_image = || -> std::io::Result<Arc<FailingBlockFile>> {
+        let f = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(Arc::new(FailingBlockFile {
+            block_file: BlockFile(Mutex::new(f)),
+            budget: Mutex::new(usize::MAX),
+            broken: Mutex::new(false),
+        }))
+    };
+    let data = vec![5u8; 20 * BLOCK_SZ];
+    let mut outcomes = [0usize; 3];
+    // cut the writes off at each point of creating and writing a file
+    for budget in (0..160).step_by(3) {
+        {
+            let f = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)?;
+            f.set_len(2048 * 512).unwrap();
+        }
+        let block_file = open_image()?;
+        let efs = EasyFileSystem::create(block_file.clone(), 2048, 1).unwrap();
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        root_inode.create("a").unwrap().write_at(0, b"kept").unwrap();
+        *block_file.budget.lock().unwrap() = budget;
+        let result = root_inode.create("b").and_then(|b| b.write_at(0, &data));
+        assert!(matches!(result, Ok(_) | Err(FsError::Io)));
+        *block_file.budget.lock().unwrap() = usize::MAX;
+        // whatever failed left no trace, a later operation goes on from there
+        let check = |root_inode: &easy_fs::Inode| -> usize {
+            let mut buf = [0u8; 4];
+            assert_eq!(root_inode.find("a").unwrap().read_at(0, &mut buf).unwrap(), 4);
+            assert_eq!(&buf, b"kept");
+            match root_inode.find("b") {
+                Err(FsError::NotFound) => 0,
+                Ok(b) => {
+                    let mut read = vec![0u8; data.len()];
+                    match b.read_at(0, &mut read).unwrap() {
+                        0 => 1,
+                        len => {
+                            assert_eq!(len, data.len());
+                            assert!(read == data);
+                            2
+                        }
+                    }
+                }
+                Err(error) => panic!("{:?}", error),
+            }
+        };
+        let outcome = check(&root_inode);
+        root_inode.create("c").unwrap();
+        drop(root_inode);
+        drop(efs);
+        let report = fsck(&(block_file.clone() as Arc<dyn BlockDevice>), false).unwrap();
+        assert!(report.is_clean(), "budget {}: {:?}", budget, report.problems);
+        let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        assert_eq!(check(&root_inode), outcome);
+        assert!(root_inode.find("c").is_ok());
+        outcomes[outcome] += 1;
+    }
+    assert!(outcomes.iter().all(|count| *count > 0), "{:?}", outcomes);
+
+    // an image that can not be read is refused, not panicked on
+    let block_file = open_image()?;
+    *block_file.broken.lock().unwrap() = true;
+    assert_eq!(EasyFileSystem::open(block_file.clone()).err(), Some(FsError::Io));
+    assert_eq!(fsck(&(block_file.clone() as Arc<dyn BlockDevice>), false).err(), Some(FsError::Io));
+    // nor is one cut short
+    OpenOptions::new().write(true).open(path)?.set_len(1024 * 512)?;
+    assert_eq!(EasyFileSystem::open(open_image()?).err(), Some(FsError::Corrupt));
+    assert_eq!(EasyFileSystem::create(open_image()?, 2048, 1).err(), Some(FsError::NoSpace));
+    Ok(())
+}
+
+#[test]
+fn efs_large_dir_test() -> std::io::Result<()> {
+    struct CountingBlockFile {
+        block_file: BlockFile,
+        reads: Mutex<usize>,
+    }
+    impl BlockDevice for CountingBlockFile {
+        fn device_id(&self) -> usize {
+            self.block_file.device_id()
+        }
+        fn read_block(&self, block_id: usize, buf: &mut [u8]) -> easy_fs::Result<()> {
+            *self.reads.lock().unwrap() += 1;
+            self.block_file.read_block(block_id, buf)
+        }
+        fn write_block(&self, block_id: usize, buf: &[u8]) -> easy_fs::Result<()> {
+            self.block_file.write_block(block_id, buf)
+        }
+    }
+    let path = "target/fs_large_dir.img";
+    let open_image = || -> std::io::Result<Arc<CountingBlockFile>> {
+        let f = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(Arc::new(CountingBlockFile {
+            block_file: BlockFile(Mutex::new(f)),
+            reads: Mutex::new(0),
+        }))
+    };
+    {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        f.set_len(8192 * 512).unwrap();
+    }
+    const FILES: usize = 2000;
+    EasyFileSystem::create(open_image()?, 8192, 1).unwrap();
+    let block_file = open_image()?;
+    let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+    efs.lock().set_write_policy(WritePolicy::WriteBack).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let mut ids: Vec<u32> = (0..FILES)
+        .map(|i| root_inode.create(&format!("f{}", i)).unwrap().inode_id())
+        .collect();
+    let sub = root_inode.mkdir("sub").unwrap();
+    efs.lock().sync().unwrap();
+    let dir_size = root_inode.stat().unwrap().size;
+    assert_eq!(dir_size as usize, ((FILES + 3) * 32).div_ceil(BLOCK_SZ) * BLOCK_SZ);
+
+    // a lookup reads about nothing once the directory is indexed
+    for (i, inode_id) in ids.iter().enumerate() {
+        assert_eq!(root_inode.find(&format!("f{}", i)).unwrap().inode_id(), *inode_id);
+    }
+    *block_file.reads.lock().unwrap() = 0;
+    let lookups = (0..FILES).step_by(10).map(|i| {
+        assert_eq!(root_inode.find(&format!("f{}", i)).unwrap().inode_id(), ids[i]);
+    }).count();
+    let reads = *block_file.reads.lock().unwrap();
+    assert!(reads < lookups * 4, "{} reads for {} lookups", reads, lookups);
+
+    // removed slots are reused, lowest first
+    for i in (0..FILES).step_by(3) {
+        root_inode.unlink(&format!("f{}", i)).unwrap();
+        assert_eq!(root_inode.find(&format!("f{}", i)).err(), Some(FsError::NotFound));
+    }
+    for i in (0..FILES).step_by(3) {
+        ids[i] = root_inode.create(&format!("g{}", i)).unwrap().inode_id();
+    }
+    assert_eq!(root_inode.stat().unwrap().size, dir_size);
+    // across directories, and over an existing file
+    root_inode.rename("f1", &sub, "h1").unwrap();
+    assert_eq!(root_inode.find("f1").err(), Some(FsError::NotFound));
+    assert_eq!(sub.find("h1").unwrap().inode_id(), ids[1]);
+    sub.rename("h1", &root_inode, "f2").unwrap();
+    assert_eq!(sub.find("h1").err(), Some(FsError::NotFound));
+    ids[2] = ids[1];
+    ids[1] = root_inode.create("f1").unwrap().inode_id();
+
+    let check = |root_inode: &easy_fs::Inode| {
+        assert_eq!(root_inode.ls().unwrap().len(), FILES + 1);
+        for (i, inode_id) in ids.iter().enumerate().skip(1) {
+            let name = format!("{}{}", if i % 3 == 0 { "g" } else { "f" }, i);
+            assert_eq!(root_inode.find(&name).unwrap().inode_id(), *inode_id, "{}", name);
+        }
+        assert!(root_inode.find("sub/..").unwrap().is_dir().unwrap());
+    };
+    check(&root_inode);
+    efs.lock().sync().unwrap();
+    drop(sub);
+    drop(root_inode);
+    drop(efs);
+    let report = fsck(&(open_image()? as Arc<dyn BlockDevice>), false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+    let efs = EasyFileSystem::open(open_image()?).unwrap();
+    check(&EasyFileSystem::root_inode(&efs));
+    Ok(())
+}
+
+#[test]
+fn efs_long_name_test() -> std::io::Result<()> {
+    use easy_fs::{FsckProblem, FEATURE_LONG_NAMES, LONG_NAME_LENGTH_LIMIT, NAME_LENGTH_LIMIT};
+    let path = "target/fs_long_name.img";
+    let open_image = || -> std::io::Result<Arc<dyn BlockDevice>> {
+        let f = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(Arc::new(BlockFile(Mutex::new(f))))
+    };
+    let poke = |pos: usize, value: u32| -> std::io::Result<()> {
+        let mut f = OpenOptions::new().write(true).open(path)?;
+        f.seek(SeekFrom::Start(pos as u64))?;
+        f.write_all(&value.to_ne_bytes())
+    };
+    {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        f.set_len(4096 * 512).unwrap();
+    }
+    let names: Vec<String> = [1, 27, 28, 100, 200, LONG_NAME_LENGTH_LIMIT]
+        .iter()
+        .enumerate()
+        .map(|(i, len)| format!("{}{}", i, "n".repeat(len - 1)))
+        .collect();
+    EasyFileSystem::create(open_image()?, 4096, 1).unwrap();
+    let efs = EasyFileSystem::open(open_image()?).unwrap();
+    assert_eq!(efs.lock().name_limit(), LONG_NAME_LENGTH_LIMIT);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    assert_eq!(root_inode.create(&"n".repeat(256)).err(), Some(FsError::NameTooLong));
+    let dir = root_inode.mkdir(&names[4]).unwrap();
+    for (i, name) in names.iter().enumerate() {
+        dir.create(name).unwrap().write_at(0, &[i as u8]).unwrap();
+    }
+    let mut ls = dir.ls().unwrap();
+    ls.sort();
+    assert_eq!(ls, names);
+    let read = |path: &str| -> u8 {
+        let mut buf = [0u8; 1];
+        root_inode.find(path).unwrap().read_at(0, &mut buf).unwrap();
+        buf[0]
+    };
+    assert_eq!(read(&format!("{}/{}", names[4], names[5])), 5);
+    // the slots of a removed record are reused, joined to those next to it
+    let size = dir.stat().unwrap().size;
+    dir.unlink(&names[3]).unwrap();
+    dir.unlink(&names[4]).unwrap();
+    dir.create(&"x".repeat(240)).unwrap();
+    assert_eq!(dir.stat().unwrap().size, size);
+    dir.rename(&names[5], &root_inode, &names[3]).unwrap();
+    assert_eq!(root_inode.rename(&names[4], &dir, &names[2]).err(), Some(FsError::Invalid));
+    root_inode.rename(&names[3], &dir, &names[1]).unwrap();
+    assert_eq!(read(&format!("{}/{}", names[4], names[1])), 5);
+    assert!(root_inode.find(&format!("{}/..", names[4])).unwrap().is_dir().unwrap());
+    drop(dir);
+    drop(root_inode);
+    drop(efs);
+    assert!(fsck(&open_image()?, false).unwrap().is_clean());
+    let efs = EasyFileSystem::open(open_image()?).unwrap();
+    let dir = EasyFileSystem::root_inode(&efs).find(&names[4]).unwrap();
+    let mut ls = dir.ls().unwrap();
+    ls.sort();
+    assert_eq!(ls, [names[0].clone(), names[1].clone(), names[2].clone(), "x".repeat(240)]);
+    drop(dir);
+    drop(efs);
+
+    // a broken record takes the rest of its block, as orphans
+    let root_data = 1155 * BLOCK_SZ;
+    poke(root_data + 2 * 32 + 4, 0)?;
+    let report = fsck(&open_image()?, true).unwrap();
+    assert!(report.problems.contains(&FsckProblem::BadDirentName { dir: 0, index: 2 }), "{:?}", report.problems);
+    assert!(fsck(&open_image()?, false).unwrap().is_clean());
+    let efs = EasyFileSystem::open(open_image()?).unwrap();
+    assert!(EasyFileSystem::root_inode(&efs).ls().unwrap().is_empty());
+    drop(efs);
+    // a feature not known is refused
+    poke(32, FEATURE_LONG_NAMES | 1 << 7)?;
+    assert_eq!(EasyFileSystem::open(open_image()?).err(), Some(FsError::Unsupported));
+    assert_eq!(
+        fsck(&open_image()?, false).unwrap().problems,
+        [FsckProblem::BadSuperBlock("unsupported features")],
+    );
+    // so is an image from before layouts had a version
+    poke(32, FEATURE_LONG_NAMES)?;
+    poke(24, 0)?;
+    assert_eq!(EasyFileSystem::open(open_image()?).err(), Some(FsError::Unsupported));
+
+    // an image without the feature keeps fixed dirents
+    EasyFileSystem::create_with_features(open_image()?, 4096, 1, 0).unwrap();
+    let efs = EasyFileSystem::open(open_image()?).unwrap();
+    assert_eq!(efs.lock().name_limit(), NAME_LENGTH_LIMIT);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    assert_eq!(root_inode.create(&names[2]).err(), Some(FsError::NameTooLong));
+    let dir = root_inode.mkdir(&names[1]).unwrap();
+    dir.create(&names[0]).unwrap();
+    root_inode.create("b").unwrap();
+    root_inode.rename("b", &dir, "c").unwrap();
+    assert_eq!(root_inode.stat().unwrap().size, 4 * 32);
+    assert_eq!(dir.ls().unwrap(), [names[0].clone(), String::from("c")]);
+    drop(dir);
+    drop(root_inode);
+    drop(efs);
+    assert!(fsck(&open_image()?, false).unwrap().is_clean());
+    Ok(())
+}
+
+#[test]
+fn efs_version1_test() -> std::io::Result<()> {
+    use easy_fs::{FsckProblem, NAME_LENGTH_LIMIT};
+    use std::convert::TryInto;
+    let path = "target/fs_version1.img";
+    let open_image = || -> std::io::Result<Arc<dyn BlockDevice>> {
+        let f = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(Arc::new(BlockFile(Mutex::new(f))))
+    };
+    {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        f.set_len(4096 * 512).unwrap();
+    }
+    EasyFileSystem::create_with_features(open_image()?, 4096, 1, 0).unwrap();
+    // what version 1 made of the same geometry: no journal between the
+    // inode area and the data bitmap, nor fields past the version
+    let journal_start = 1026 * BLOCK_SZ;
+    let journal_end = journal_start + 128 * BLOCK_SZ;
+    let image = std::fs::read(path)?;
+    let mut image = [&image[..journal_start], &image[journal_end..]].concat();
+    // the block of the root directory moves down along
+    let root_pos = 2 * BLOCK_SZ + 4;
+    let root_block = u32::from_ne_bytes(image[root_pos..root_pos + 4].try_into().unwrap());
+    let mut poke = |pos: usize, value: u32| image[pos..pos + 4].copy_from_slice(&value.to_ne_bytes());
+    poke(root_pos, root_block - 128);
+    poke(4, 4096 - 128);
+    for field in [28, 32, 36, 40] {
+        poke(field, 0);
+    }
+    poke(24, 1);
+    std::fs::write(path, &image)?;
+
+    // the free counters it did not keep are all fsck finds
+    let report = fsck(&open_image()?, false).unwrap();
+    assert!(report.problems.iter().all(|problem| matches!(
+        problem,
+        FsckProblem::WrongFreeInodes { .. } | FsckProblem::WrongFreeBlocks { .. }
+    )), "{:?}", report.problems);
+    let data: Vec<u8> = (0..200 * BLOCK_SZ).map(|i| (i % 253) as u8).collect();
+    {
+        let efs = EasyFileSystem::open(open_image()?).unwrap();
+        assert_eq!(efs.lock().name_limit(), NAME_LENGTH_LIMIT);
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        assert!(root_inode.ls().unwrap().is_empty());
+        let name = "n".repeat(NAME_LENGTH_LIMIT);
+        assert_eq!(root_inode.create(&format!("{}n", name)).err(), Some(FsError::NameTooLong));
+        // more blocks than a journal could take are written in place
+        let file = root_inode.mkdir("d").unwrap().create(&name).unwrap();
+        assert_eq!(file.write_at(0, &data).unwrap(), data.len());
+    }
+    let report = fsck(&open_image()?, false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+    let efs = EasyFileSystem::open(open_image()?).unwrap();
+    let file = EasyFileSystem::root_inode(&efs).find(&format!("d/{}", "n".repeat(NAME_LENGTH_LIMIT))).unwrap();
+    let mut read = vec![0u8; data.len()];
+    assert_eq!(file.read_at(0, &mut read).unwrap(), data.len());
+    assert!(read == data);
+    drop(file);
+    drop(efs);
+    // the image is still of version 1
+    assert_eq!(std::fs::read(path)?[24..28], 1u32.to_ne_bytes());
+    Ok(())
+}
+
+#[test]
+fn efs_statfs_test() -> std::io::Result<()> {
+    use easy_fs::{FsckProblem, FsStat, LONG_NAME_LENGTH_LIMIT};
+    let path = "target/fs_statfs.img";
+    let open_image = || -> std::io::Result<Arc<dyn BlockDevice>> {
+        let f = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(Arc::new(BlockFile(Mutex::new(f))))
+    };
+    let poke = |pos: usize, value: u32| -> std::io::Result<()> {
+        let mut f = OpenOptions::new().write(true).open(path)?;
+        f.seek(SeekFrom::Start(pos as u64))?;
+        f.write_all(&value.to_ne_bytes())
+    };
+    {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        f.set_len(4096 * 512).unwrap();
+    }
+    EasyFileSystem::create(open_image()?, 4096, 1).unwrap();
+    let efs = EasyFileSystem::open(open_image()?).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    // 1 super block, 1 inode bitmap block, 1024 inode blocks, 128 journal
+    // blocks and 1 data bitmap block; the root takes an inode and a block
+    let empty = FsStat {
+        total_blocks: 4096,
+        data_blocks: 4096 - 1155,
+        free_blocks: 4096 - 1155 - 1,
+        inodes: 4096,
+        free_inodes: 4095,
+        name_limit: LONG_NAME_LENGTH_LIMIT as u32,
+        dirty_blocks: 0,
+    };
+    assert_eq!(efs.lock().stat().unwrap(), empty);
+    let file = root_inode.create("file").unwrap();
+    file.write_at(0, &[1u8; 10 * BLOCK_SZ]).unwrap();
+    root_inode.mkdir("dir").unwrap();
+    let used = root_inode.statfs().unwrap();
+    assert_eq!(used.free_blocks, empty.free_blocks - 11);
+    assert_eq!(used.free_inodes, empty.free_inodes - 2);
+    drop(file);
+    drop(root_inode);
+    drop(efs);
+    let efs = EasyFileSystem::open(open_image()?).unwrap();
+    assert_eq!(efs.lock().stat().unwrap(), used);
+    // filled up, the counter runs out with the bitmap
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let big = root_inode.create("big").unwrap();
+    let data = vec![2u8; 4096 * BLOCK_SZ];
+    assert!(big.write_at(0, &data).unwrap() < data.len());
+    assert_eq!(efs.lock().stat().unwrap().free_blocks, 0);
+    root_inode.unlink("big").unwrap();
+    drop(big);
+    assert_eq!(efs.lock().stat().unwrap(), used);
+    drop(root_inode);
+    drop(efs);
+
+    // counters gone wrong, as a version without them would leave them,
+    // are reported by fsck and set right by open
+    poke(36, 0)?;
+    poke(40, 7)?;
+    let report = fsck(&open_image()?, false).unwrap();
+    assert_eq!(report.problems, [
+        FsckProblem::WrongFreeInodes { free_inodes: 0, counted: used.free_inodes },
+        FsckProblem::WrongFreeBlocks { free_blocks: 7, counted: used.free_blocks },
+    ]);
+    let efs = EasyFileSystem::open(open_image()?).unwrap();
+    assert_eq!(efs.lock().stat().unwrap(), used);
+    drop(efs);
+    assert!(fsck(&open_image()?, false).unwrap().is_clean());
+    Ok(())
+}
+
+#[test]
+fn efs_fsync_test() -> std::io::Result<()> {
+    struct CountingBlockFile {
+        block_file: BlockFile,
+        writes: Mutex<usize>,
+    }
+    impl BlockDevice for CountingBlockFile {
+        fn device_id(&self) -> usize {
+            self.block_file.device_id()
+        }
+        fn read_block(&self, block_id: usize, buf: &mut [u8]) -> easy_fs::Result<()> {
+            self.block_file.read_block(block_id, buf)
+        }
+        fn write_block(&self, block_id: usize, buf: &[u8]) -> easy_fs::Result<()> {
+            *self.writes.lock().unwrap() += 1;
+            self.block_file.write_block(block_id, buf)
+        }
+    }
+    let path = "target/fs_fsync.img";
+    {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        f.set_len(4096 * 512).unwrap();
+    }
+    let f = OpenOptions::new().read(true).write(true).open(path)?;
+    let block_file = Arc::new(CountingBlockFile {
+        block_file: BlockFile(Mutex::new(f)),
+        writes: Mutex::new(0),
+    });
+    EasyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+    let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let a = root_inode.create("a").unwrap();
+    let b = root_inode.create("b").unwrap();
+    efs.lock().set_write_policy(WritePolicy::WriteBack).unwrap();
+    // blocks written by `f`
+    let writes = |f: &dyn Fn()| {
+        let before = *block_file.writes.lock().unwrap();
+        f();
+        *block_file.writes.lock().unwrap() - before
+    };
+
+    // an inode with nothing pending is not synced for another's sake
+    a.write_at(0, &[1u8; 3 * BLOCK_SZ]).unwrap();
+    assert_eq!(writes(&|| b.fsync().unwrap()), 0);
+    assert_eq!(writes(&|| b.fdatasync().unwrap()), 0);
+    assert!(writes(&|| a.fdatasync().unwrap()) > 0);
+    assert_eq!(writes(&|| a.fsync().unwrap()), 0);
+    // a change of the disk inode alone waits for fsync
+    a.chmod(0o600).unwrap();
+    assert_eq!(writes(&|| a.fdatasync().unwrap()), 0);
+    assert!(writes(&|| a.fsync().unwrap()) > 0);
+    // truncating changes the data, unlinking only the links of the file
+    b.truncate(BLOCK_SZ as u32).unwrap();
+    assert!(writes(&|| b.fdatasync().unwrap()) > 0);
+    let c = root_inode.create("c").unwrap();
+    root_inode.sync_fs().unwrap();
+    root_inode.link("d", &c).unwrap();
+    root_inode.unlink("c").unwrap();
+    assert_eq!(writes(&|| c.fdatasync().unwrap()), 0);
+    assert!(writes(&|| root_inode.fdatasync().unwrap()) > 0);
+    // the fs is synced as a whole, what was pending is counted until then
+    b.write_at(0, &[2u8; BLOCK_SZ]).unwrap();
+    assert!(root_inode.statfs().unwrap().dirty_blocks > 0);
+    assert!(writes(&|| root_inode.sync_fs().unwrap()) > 0);
+    assert_eq!(root_inode.statfs().unwrap().dirty_blocks, 0);
+    assert_eq!(writes(&|| b.fsync().unwrap()), 0);
+    drop((a, b, c, root_inode, efs));
+    let block_file: Arc<dyn BlockDevice> = block_file;
+    assert!(fsck(&block_file, false).unwrap().is_clean());
+    Ok(())
+}
+
+#[test]
+fn efs_fuse_session_test() -> std::io::Result<()> {
+    use mount::Session;
+    use std::convert::TryInto;
+    let path = "target/fs_fuse_session.img";
+    {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        f.set_len(4096 * 512).unwrap();
+    }
+    let f = OpenOptions::new().read(true).write(true).open(path)?;
+    let block_file: Arc<dyn BlockDevice> = Arc::new(BlockFile(Mutex::new(f)));
+    EasyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+    let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+    let mut session = Session::new(EasyFileSystem::root_inode(&efs));
+    // a request as the kernel sends it, and the error and body of its reply
+    let mut unique = 0u64;
+    let mut request = |opcode: u32, node_id: u64, body: &[u8]| -> Option<(i32, Vec<u8>)> {
+        unique += 1;
+        let mut request = Vec::new();
+        request.extend_from_slice(&(40 + body.len() as u32).to_ne_bytes());
+        request.extend_from_slice(&opcode.to_ne_bytes());
+        request.extend_from_slice(&unique.to_ne_bytes());
+        request.extend_from_slice(&node_id.to_ne_bytes());
+        request.extend_from_slice(&[0; 16]);
+        request.extend_from_slice(body);
+        let reply = session.handle(&request)?;
+        assert_eq!(u32::from_ne_bytes(reply[0..4].try_into().unwrap()) as usize, reply.len());
+        assert_eq!(u64::from_ne_bytes(reply[8..16].try_into().unwrap()), unique);
+        Some((i32::from_ne_bytes(reply[4..8].try_into().unwrap()), reply[16..].to_vec()))
+    };
+    let u32s = |values: &[u32]| values.iter().flat_map(|value| value.to_ne_bytes()).collect::<Vec<u8>>();
+    let u64_at = |bytes: &[u8], pos: usize| u64::from_ne_bytes(bytes[pos..pos + 8].try_into().unwrap());
+
+    // INIT: version 7.31 at most, writes up to 128KiB
+    let (error, init) = request(26, 0, &u32s(&[7, 38, 65536, 0])).unwrap();
+    assert_eq!(error, 0);
+    assert_eq!(&init[0..8], &u32s(&[7, 31])[..]);
+    // LOOKUP of a name not there
+    assert_eq!(request(1, 1, b"missing\0").unwrap().0, -libc::ENOENT);
+    // CREATE with mode 0644, then WRITE and READ through the new node
+    let mut body = u32s(&[0, 0o100644, 0o022, 0]);
+    body.extend_from_slice(b"file\0");
+    let (error, created) = request(35, 1, &body).unwrap();
+    assert_eq!(error, 0);
+    let file = u64_at(&created, 0);
+    assert_eq!(created.len(), 128 + 16);
+    let mut body = vec![0u8; 8];
+    body.extend_from_slice(&100u64.to_ne_bytes());
+    body.extend_from_slice(&u32s(&[5, 0, 0, 0, 0, 0]));
+    body.extend_from_slice(b"hello");
+    let (error, written) = request(16, file, &body).unwrap();
+    assert_eq!((error, &written[..4]), (0, &u32s(&[5])[..]));
+    let mut read = vec![0u8; 8];
+    read.extend_from_slice(&98u64.to_ne_bytes());
+    read.extend_from_slice(&u32s(&[100, 0, 0, 0, 0, 0]));
+    assert_eq!(request(15, file, &read).unwrap(), (0, b"\0\0hello".to_vec()));
+    // GETATTR: size and mode as set
+    let (error, attr) = request(3, file, &[0; 16]).unwrap();
+    assert_eq!(error, 0);
+    assert_eq!(u64_at(&attr, 16 + 8), 105);
+    assert_eq!(&attr[16 + 60..16 + 64], &u32s(&[0o100644])[..]);
+    // MKDIR and READDIR, which lists ".", ".." and both names
+    let mut body = u32s(&[0o755, 0]);
+    body.extend_from_slice(b"dir\0");
+    let (error, dir) = request(9, 1, &body).unwrap();
+    assert_eq!(error, 0);
+    let mut body = vec![0u8; 16];
+    body.extend_from_slice(&u32s(&[4096, 0, 0, 0, 0, 0]));
+    let (error, dirents) = request(28, 1, &body).unwrap();
+    assert_eq!(error, 0);
+    let mut names = Vec::new();
+    let mut pos = 0;
+    while pos < dirents.len() {
+        let len = u32::from_ne_bytes(dirents[pos + 16..pos + 20].try_into().unwrap()) as usize;
+        names.push(String::from_utf8(dirents[pos + 24..pos + 24 + len].to_vec()).unwrap());
+        pos += (24 + len).next_multiple_of(8);
+    }
+    assert_eq!(names, [".", "..", "file", "dir"]);
+    // UNLINK and RMDIR; the file is still open and read through its node
+    assert_eq!(u64_at(&created, 8), 0);
+    assert_eq!(request(10, 1, b"file\0").unwrap().0, 0);
+    assert_eq!(request(11, 1, b"dir\0").unwrap().0, 0);
+    assert_eq!(request(1, 1, b"file\0").unwrap().0, -libc::ENOENT);
+    assert_eq!(request(15, file, &read).unwrap(), (0, b"\0\0hello".to_vec()));
+    // a new file taking the inode of the directory is another node of
+    // another generation, the unlinked file is kept while open
+    let mut body = u32s(&[0, 0o100644, 0o022, 0]);
+    body.extend_from_slice(b"again\0");
+    let (error, again) = request(35, 1, &body).unwrap();
+    assert_eq!(error, 0);
+    assert_eq!(u64_at(&again, 40), u64_at(&dir, 40));
+    assert_ne!(u64_at(&again, 0), u64_at(&dir, 0));
+    assert_eq!(u64_at(&again, 8), 1);
+    // RELEASE of its last handle drops the unlinked node, FORGET gets no reply
+    assert_eq!(request(18, file, &[0; 24]).unwrap().0, 0);
+    assert_eq!(request(3, file, &[0; 16]).unwrap().0, -libc::ENOENT);
+    assert!(request(2, file, &1u64.to_ne_bytes()).is_none());
+    let again = u64_at(&again, 0);
+    assert_eq!(request(3, again, &[0; 16]).unwrap().0, 0);
+    // BATCH_FORGET of all its lookups drops the other
+    let mut body = u32s(&[1, 0]);
+    body.extend_from_slice(&again.to_ne_bytes());
+    body.extend_from_slice(&1u64.to_ne_bytes());
+    assert!(request(42, 0, &body).is_none());
+    assert_eq!(request(3, again, &[0; 16]).unwrap().0, -libc::ENOENT);
+    // a short request is refused rather than read past
+    assert_eq!(request(16, 1, &[0; 4]).unwrap().0, -libc::EINVAL);
+    assert_eq!(request(38, 0, &[]).unwrap().0, 0);
+    assert!(session.destroyed);
+    drop((session, efs));
+    assert!(fsck(&block_file, false).unwrap().is_clean());
+    Ok(())
+}
+
+#[test]
+fn efs_inspect_test() -> std::io::Result<()> {
+    let path = "target/fs_inspect.img";
+    {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        f.set_len(4096 * 512).unwrap();
+        let block_file: Arc<dyn BlockDevice> = Arc::new(BlockFile(Mutex::new(f)));
+        EasyFileSystem::create(block_file, 4096, 1).unwrap();
+    }
+    assert_eq!(split_path("/d/b"), ("/d/", "b"));
+    assert_eq!(split_path("/d/"), ("/", "d"));
+    assert_eq!(split_path("a"), ("", "a"));
+    assert_eq!(block_list(&[7, 8, 9, 0, 0, 12, 11]), "7-9 0 0 12 11");
+    let data: Vec<u8> = (0..3000u32).map(|i| i as u8).collect();
+    {
+        let efs = open_image(path, true)?;
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        put_file(&root_inode, "/a", b"replaced", 0o644).unwrap();
+        put_file(&root_inode, "/a", &data, 0o600).unwrap();
+        root_inode.mkdir("d").unwrap();
+        put_file(&root_inode, "/d/b", b"hello", 0o644).unwrap();
+        assert!(put_file(&root_inode, "/e/b", b"", 0o644) == Err(FsError::NotFound));
+    }
+    // reading through a read-only open leaves the image as it was
+    let before = std::fs::read(path)?;
+    {
+        let efs = open_image(path, false)?;
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        let lines = list(&root_inode.find("/").unwrap(), "/").unwrap();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with('d') && lines[0].ends_with(" /"));
+        assert!(lines[1].starts_with("-600") && lines[1].ends_with(" 3000 /a"));
+        assert!(lines[2].ends_with(" /d"));
+        assert!(lines[3].ends_with(" 5 /d/b"));
+        let a = root_inode.find("/a").unwrap();
+        assert_eq!(read_file(&a).unwrap(), data);
+        let map = EasyFileSystem::get_inode(&efs, a.stat().unwrap().inode_id).unwrap().block_map().unwrap();
+        assert_eq!(map.direct.len(), 6);
+        assert_eq!(block_list(&map.direct).matches('-').count(), 1);
+        assert_eq!(map.indirect1.block_id, 0);
+        assert!(EasyFileSystem::get_inode(&efs, 100).err() == Some(FsError::NotFound));
+    }
+    assert!(std::fs::read(path)? == before);
+    Ok(())
+}
+
+#[test]
+fn efs_pack_test() -> std::io::Result<()> {
+    assert_eq!(parse_size("16M")?, 16 << 20);
+    assert_eq!(parse_size("1024")?, 1024);
+    assert!(parse_size("1000").is_err() && parse_size("M").is_err());
+    // a host tree with fixtures, and a manifest next to it
+    let host = Path::new("target/pack_src");
+    let _ = std::fs::remove_dir_all(host);
+    std::fs::create_dir_all(host.join("tree/sub/deep"))?;
+    std::fs::write(host.join("tree/b.txt"), b"bee")?;
+    std::fs::write(host.join("tree/a.bin"), vec![7u8; 5000])?;
+    std::fs::write(host.join("tree/sub/deep/c"), b"sea")?;
+    std::fs::write(host.join("extra"), b"extra")?;
+    std::fs::write(
+        host.join("manifest"),
+        "# fixtures\n\n/etc/extra extra\n/empty\n/copy tree/sub\n",
+    )?;
+    let pack = |output: &str| {
+        let matches = app().get_matches_from(vec![
+            "easy-fs-fuse", "-o", output, "--size", "2M", "--inodes", "5000", "--timestamp", "1000",
+            "-d", "target/pack_src/tree", "-m", "target/pack_src/manifest",
+        ]);
+        easy_fs_pack(&matches)
+    };
+    pack("target/fs_pack1.img")?;
+    pack("target/fs_pack2.img")?;
+    let image = std::fs::read("target/fs_pack1.img")?;
+    assert_eq!(image.len(), 2 << 20);
+    assert!(image == std::fs::read("target/fs_pack2.img")?);
+    let efs = open_image("target/fs_pack1.img", false)?;
+    assert_eq!(efs.lock().stat().unwrap().inodes, 8192);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let paths: Vec<String> = list(&root_inode.find("/").unwrap(), "/")
+        .unwrap()
+        .iter()
+        .map(|line| line.rsplit(' ').next().unwrap().to_string())
+        .collect();
+    assert_eq!(
+        paths,
+        ["/", "/a.bin", "/b.txt", "/sub", "/sub/deep", "/sub/deep/c", "/etc", "/etc/extra", "/empty", "/copy", "/copy/deep", "/copy/deep/c"],
+    );
+    assert_eq!(read_file(&root_inode.find("/a.bin").unwrap()).unwrap(), vec![7u8; 5000]);
+    assert_eq!(read_file(&root_inode.find("/copy/deep/c").unwrap()).unwrap(), b"sea");
+    assert_eq!(root_inode.find("/etc/extra").unwrap().stat().unwrap().mtime, 1000);
+    // a path packed twice is refused
+    std::fs::write(host.join("manifest"), "/b.txt extra\n")?;
+    assert!(pack("target/fs_pack3.img").is_err());
+    // a file whose data fits the free blocks but not its indirect blocks
+    // is refused as a whole
+    {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open("target/fs_pack4.img")?;
+        f.set_len(2048 * BLOCK_SZ as u64).unwrap();
+        let block_file: Arc<dyn BlockDevice> = Arc::new(BlockFile(Mutex::new(f)));
+        let efs = EasyFileSystem::create(block_file, 2048, 1).unwrap();
+        let packer = Packer {
+            root_inode: Arc::new(EasyFileSystem::root_inode(&efs)),
+            efs: efs.clone(),
+        };
+        let free_blocks = efs.lock().stat().unwrap().free_blocks as usize;
+        std::fs::write(host.join("big"), vec![1u8; free_blocks * BLOCK_SZ])?;
+        let error = packer.file(&host.join("big"), "/big").unwrap_err();
+        assert!(error.to_string().contains("does not fit"));
+        assert!(packer.root_inode.find("big").err() == Some(FsError::NotFound));
+        assert_eq!(efs.lock().stat().unwrap().free_blocks as usize, free_blocks);
+    }
+    Ok(())
+}
+
+#[test]
+fn efs_fuzz_test() {
+    for seed in fuzz::seeds() {
+        fuzz::run(seed, 300);
+    }
+}
+
+#[test]
+fn efs_crash_fuzz_test() {
+    for seed in fuzz::seeds() {
+        fuzz::run_crashes(seed, 80, 60);
+    }
+}
+
+#[test]
+fn efs_alloc_test() -> std::io::Result<()> {
+    let path = "target/fs_alloc.img";
+    let open_image = || -> std::io::Result<Arc<dyn BlockDevice>> {
+        let f = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(Arc::new(BlockFile(Mutex::new(f))))
+    };
+    let peek = |pos: usize| -> std::io::Result<u32> {
+        let mut f = File::open(path)?;
+        f.seek(SeekFrom::Start(pos as u64))?;
+        let mut bytes = [0u8; 4];
+        f.read_exact(&mut bytes)?;
+        Ok(u32::from_ne_bytes(bytes))
+    };
+    let inode_pos = |inode_id: u32| (2 + inode_id as usize / 4) * BLOCK_SZ + (inode_id as usize % 4) * 128;
+    let direct_pos = |inode_id: u32, i: usize| inode_pos(inode_id) + 4 + 4 * i;
+    let indirect1_pos = |inode_id: u32| inode_pos(inode_id) + 92;
+    {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        f.set_len(4096 * 512).unwrap();
+    }
+    EasyFileSystem::create(open_image()?, 4096, 1).unwrap();
+    let block_file = open_image()?;
+    let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+
+    // a large write is laid out in sequence, indirect block included
+    let a = root_inode.create("a").unwrap();
+    a.write_at(0, &vec![1u8; 100 * BLOCK_SZ]).unwrap();
+    // files appended in turn keep their blocks together in runs
+    let b = root_inode.create("b").unwrap();
+    let c = root_inode.create("c").unwrap();
+    for i in 0..5 {
+        b.write_at(i * 4 * BLOCK_SZ, &[2u8; 4 * BLOCK_SZ]).unwrap();
+        c.write_at(i * 4 * BLOCK_SZ, &[3u8; 4 * BLOCK_SZ]).unwrap();
+    }
+    drop(efs);
+    let a_blocks = (0..22).map(|i| peek(direct_pos(a.inode_id(), i))).collect::<std::io::Result<Vec<_>>>()?;
+    assert!(a_blocks.windows(2).all(|pair| pair[1] == pair[0] + 1));
+    let indirect1 = peek(indirect1_pos(a.inode_id()))?;
+    assert_eq!(indirect1, a_blocks[21] + 1);
+    assert_eq!(peek(indirect1 as usize * BLOCK_SZ)?, indirect1 + 1);
+    for file in [&b, &c] {
+        let blocks = (0..20).map(|i| peek(direct_pos(file.inode_id(), i))).collect::<std::io::Result<Vec<_>>>()?;
+        for run in blocks.chunks(4) {
+            assert!(run.windows(2).all(|pair| pair[1] == pair[0] + 1));
+        }
+    }
+    drop((a, b, c, root_inode));
+    assert!(fsck(&block_file, false).unwrap().is_clean());
+
+    // free space left in pieces is still used up, by shorter runs
+    let efs = EasyFileSystem::open(open_image()?).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let mut i = 0;
+    while let Ok(file) = root_inode.create(&format!("small{}", i)) {
+        if file.write_at(0, &[4u8; BLOCK_SZ]).is_err() {
+            break;
+        }
+        i += 1;
+    }
+    for j in (0..i).step_by(2) {
+        root_inode.unlink(&format!("small{}", j)).unwrap();
+    }
+    let free_blocks = efs.lock().stat().unwrap().free_blocks as usize;
+    let big = root_inode.create("big").unwrap();
+    let data = vec![5u8; free_blocks * BLOCK_SZ];
+    let len = big.write_at(0, &data).unwrap();
+    // every free block is taken, for data or indirect blocks
+    assert_eq!(big.stat().unwrap().blocks as usize, free_blocks);
+    assert!(len > (free_blocks - 16) * BLOCK_SZ);
+    assert_eq!(efs.lock().stat().unwrap().free_blocks, 0);
+    let mut read = vec![0u8; len];
+    assert_eq!(big.read_at(0, &mut read).unwrap(), len);
+    assert!(read.iter().all(|byte| *byte == 5));
+    drop((big, root_inode, efs));
+    assert!(fsck(&open_image()?, false).unwrap().is_clean());
+    Ok(())
+}
 
 // #[test]
 // fn mgd_test() {
@@ -0,0 +1,779 @@
+//! Serving an easy-fs image through FUSE, speaking the kernel protocol on
+//! `/dev/fuse` directly. Requests are handled one at a time by `Session`,
+//! which knows nothing of the device and can be fed requests by tests.
+//!
+//! The protocol is spoken by hand rather than through `fuser` or libfuse:
+//! the few requests easy-fs can serve fit in a page of code, the tool
+//! stays a build of `libc` alone with no system library to link, and
+//! the session is driven by plain byte buffers, which tests use without
+//! mounting anything.
+
+use std::{
+    collections::HashMap,
+    convert::{TryFrom, TryInto},
+    ffi::CString,
+    io,
+    os::unix::io::RawFd,
+    process::Command,
+    sync::Arc,
+};
+use easy_fs::{DiskInodeType, FsError, Inode, InodeStat};
+
+/// Version of the protocol spoken, the kernel adapts to an older one
+const FUSE_KERNEL_VERSION: u32 = 7;
+const FUSE_KERNEL_MINOR_VERSION: u32 = 31;
+/// Largest write the kernel is told to send
+const MAX_WRITE: usize = 128 * 1024;
+/// A request holds at most a write and its headers
+pub const REQUEST_BUF_SZ: usize = MAX_WRITE + 4096;
+/// Node id of the root directory, inode 0 of the image
+const FUSE_ROOT_ID: u64 = 1;
+const FUSE_BIG_WRITES: u32 = 1 << 5;
+
+const FUSE_LOOKUP: u32 = 1;
+const FUSE_FORGET: u32 = 2;
+const FUSE_GETATTR: u32 = 3;
+const FUSE_SETATTR: u32 = 4;
+const FUSE_MKNOD: u32 = 8;
+const FUSE_MKDIR: u32 = 9;
+const FUSE_UNLINK: u32 = 10;
+const FUSE_RMDIR: u32 = 11;
+const FUSE_RENAME: u32 = 12;
+const FUSE_LINK: u32 = 13;
+const FUSE_OPEN: u32 = 14;
+const FUSE_READ: u32 = 15;
+const FUSE_WRITE: u32 = 16;
+const FUSE_STATFS: u32 = 17;
+const FUSE_RELEASE: u32 = 18;
+const FUSE_FSYNC: u32 = 20;
+const FUSE_FLUSH: u32 = 25;
+const FUSE_INIT: u32 = 26;
+const FUSE_OPENDIR: u32 = 27;
+const FUSE_READDIR: u32 = 28;
+const FUSE_RELEASEDIR: u32 = 29;
+const FUSE_FSYNCDIR: u32 = 30;
+const FUSE_ACCESS: u32 = 34;
+const FUSE_CREATE: u32 = 35;
+const FUSE_INTERRUPT: u32 = 36;
+const FUSE_DESTROY: u32 = 38;
+const FUSE_BATCH_FORGET: u32 = 42;
+const FUSE_RENAME2: u32 = 45;
+
+const FATTR_MODE: u32 = 1 << 0;
+const FATTR_UID: u32 = 1 << 1;
+const FATTR_GID: u32 = 1 << 2;
+const FATTR_SIZE: u32 = 1 << 3;
+const FATTR_ATIME: u32 = 1 << 4;
+const FATTR_MTIME: u32 = 1 << 5;
+const FATTR_ATIME_NOW: u32 = 1 << 7;
+const FATTR_MTIME_NOW: u32 = 1 << 8;
+
+const FUSE_FSYNC_FDATASYNC: u32 = 1 << 0;
+
+/// Seconds the kernel may keep attributes and entries, nothing changes
+/// the image behind the mount
+const TTL: u64 = 1;
+
+/// Size of `struct fuse_in_header`
+const IN_HEADER_SZ: usize = 40;
+/// Size of `struct fuse_out_header`
+const OUT_HEADER_SZ: usize = 16;
+
+/// The negated errno a file system error is reported to the kernel as
+fn errno(error: FsError) -> i32 {
+    match error {
+        FsError::NoSpace => libc::ENOSPC,
+        FsError::NameTooLong => libc::ENAMETOOLONG,
+        FsError::InvalidName | FsError::Invalid | FsError::Unsupported => libc::EINVAL,
+        FsError::NotFound => libc::ENOENT,
+        FsError::NotDir => libc::ENOTDIR,
+        FsError::IsDir => libc::EISDIR,
+        FsError::NotEmpty => libc::ENOTEMPTY,
+        FsError::Exists => libc::EEXIST,
+        FsError::CrossDevice => libc::EXDEV,
+        FsError::FileTooLarge => libc::EFBIG,
+        FsError::Corrupt => libc::EUCLEAN,
+        FsError::Io => libc::EIO,
+        FsError::Busy => libc::EBUSY,
+    }
+}
+
+/// Fields of a request, read in order; a short request is `EINVAL`
+struct Args<'a>(&'a [u8]);
+
+impl<'a> Args<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], i32> {
+        if self.0.len() < len {
+            return Err(libc::EINVAL);
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+    fn u32(&mut self) -> Result<u32, i32> {
+        Ok(u32::from_ne_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    fn u64(&mut self) -> Result<u64, i32> {
+        Ok(u64::from_ne_bytes(self.take(8)?.try_into().unwrap()))
+    }
+    /// A NUL terminated name
+    fn name(&mut self) -> Result<&'a str, i32> {
+        let len = self.0.iter().position(|byte| *byte == 0).ok_or(libc::EINVAL)?;
+        let name = std::str::from_utf8(self.take(len)?).map_err(|_| libc::EINVAL)?;
+        self.take(1)?;
+        Ok(name)
+    }
+}
+
+/// Body of a reply, built field by field
+#[derive(Default)]
+struct Reply(Vec<u8>);
+
+impl Reply {
+    fn u16(mut self, value: u16) -> Self {
+        self.0.extend_from_slice(&value.to_ne_bytes());
+        self
+    }
+    fn u32(mut self, value: u32) -> Self {
+        self.0.extend_from_slice(&value.to_ne_bytes());
+        self
+    }
+    fn u64(mut self, value: u64) -> Self {
+        self.0.extend_from_slice(&value.to_ne_bytes());
+        self
+    }
+    fn bytes(mut self, bytes: &[u8]) -> Self {
+        self.0.extend_from_slice(bytes);
+        self
+    }
+    /// `struct fuse_attr`
+    fn attr(self, stat: &InodeStat) -> Self {
+        let type_mode = match stat.type_ {
+            DiskInodeType::File => libc::S_IFREG,
+            DiskInodeType::Directory => libc::S_IFDIR,
+        };
+        self.u64(ino_of(stat.inode_id))
+            .u64(stat.size as u64)
+            .u64(stat.blocks as u64)
+            .u64(stat.atime as u64)
+            .u64(stat.mtime as u64)
+            .u64(stat.ctime as u64)
+            .u32(0)
+            .u32(0)
+            .u32(0)
+            .u32(type_mode | stat.mode as u32)
+            .u32(stat.nlink)
+            .u32(stat.uid as u32)
+            .u32(stat.gid as u32)
+            .u32(0)
+            .u32(easy_fs::BLOCK_SZ as u32)
+            .u32(0)
+    }
+    /// `struct fuse_attr_out`
+    fn attr_out(self, stat: &InodeStat) -> Self {
+        self.u64(TTL).u32(0).u32(0).attr(stat)
+    }
+    /// `struct fuse_entry_out`
+    fn entry(self, node_id: u64, generation: u64, stat: &InodeStat) -> Self {
+        self.u64(node_id)
+            .u64(generation)
+            .u64(TTL)
+            .u64(TTL)
+            .u32(0)
+            .u32(0)
+            .attr(stat)
+    }
+    /// `struct fuse_open_out`, no file handle is kept
+    fn open_out(self) -> Self {
+        self.u64(0).u32(0).u32(0)
+    }
+}
+
+/// The inode number shown to programs, which 0 can not be
+fn ino_of(inode_id: u32) -> u64 {
+    inode_id as u64 + 1
+}
+
+/// Seconds since the epoch, for times set to now
+fn now() -> u32 {
+    super::host_clock()
+}
+
+/// An inode the kernel was told of
+struct Node {
+    inode: Arc<Inode>,
+    /// Entries replied for it and not forgotten yet
+    lookups: u64,
+    /// Handles opened on it and not released yet
+    opens: u64,
+}
+
+/// The state of a mount: the inodes the kernel was told of by node id.
+///
+/// A node id is never given out twice, so a freed inode number taken by a
+/// new file is not mistaken for the node the kernel may still hold; its
+/// generation changes too. A node is dropped once forgotten by the kernel,
+/// or once its last link is gone and no handle is open on it.
+pub struct Session {
+    nodes: HashMap<u64, Node>,
+    /// The node id of each inode the kernel knows of by its links
+    node_ids: HashMap<u32, u64>,
+    /// Generations of the inode numbers freed so far, 0 for the others
+    generations: HashMap<u32, u64>,
+    next_node_id: u64,
+    /// The kernel asked for the mount to end
+    pub destroyed: bool,
+}
+
+impl Session {
+    pub fn new(root_inode: Inode) -> Self {
+        let mut node_ids = HashMap::new();
+        node_ids.insert(root_inode.inode_id(), FUSE_ROOT_ID);
+        let mut nodes = HashMap::new();
+        nodes.insert(FUSE_ROOT_ID, Node {
+            inode: Arc::new(root_inode),
+            lookups: 1,
+            opens: 0,
+        });
+        Self {
+            nodes,
+            node_ids,
+            generations: HashMap::new(),
+            next_node_id: FUSE_ROOT_ID + 1,
+            destroyed: false,
+        }
+    }
+    fn inode(&self, node_id: u64) -> Result<Arc<Inode>, i32> {
+        self.nodes.get(&node_id).map(|node| node.inode.clone()).ok_or(libc::ENOENT)
+    }
+    /// Tell the kernel of an inode, which it refers to by node id from then on
+    fn entry(&mut self, inode: Arc<Inode>) -> Result<Reply, i32> {
+        let stat = inode.stat().map_err(errno)?;
+        let node_id = match self.node_ids.get(&stat.inode_id) {
+            Some(&node_id) => node_id,
+            None => {
+                let node_id = self.next_node_id;
+                self.next_node_id += 1;
+                self.node_ids.insert(stat.inode_id, node_id);
+                self.nodes.insert(node_id, Node {
+                    inode,
+                    lookups: 0,
+                    opens: 0,
+                });
+                node_id
+            }
+        };
+        self.nodes.get_mut(&node_id).unwrap().lookups += 1;
+        let generation = self.generations.get(&stat.inode_id).copied().unwrap_or(0);
+        Ok(Reply::default().entry(node_id, generation, &stat))
+    }
+    /// The kernel dropped `lookups` of the entries of `node_id`
+    fn forget(&mut self, node_id: u64, lookups: u64) {
+        if let Some(node) = self.nodes.get_mut(&node_id) {
+            node.lookups = node.lookups.saturating_sub(lookups);
+            if node.lookups == 0 && node_id != FUSE_ROOT_ID {
+                let inode_id = node.inode.inode_id();
+                self.nodes.remove(&node_id);
+                if self.node_ids.get(&inode_id) == Some(&node_id) {
+                    self.node_ids.remove(&inode_id);
+                }
+            }
+        }
+    }
+    /// The inode `inode_id` may have lost its last link: its number is free
+    /// for a new file of another generation, and the node is dropped unless
+    /// still open, when the release drops it
+    fn unlinked(&mut self, inode_id: u32) {
+        let node_id = match self.node_ids.get(&inode_id) {
+            Some(&node_id) => node_id,
+            None => return,
+        };
+        if self.nodes[&node_id].inode.nlink().unwrap_or(0) > 0 {
+            return;
+        }
+        self.node_ids.remove(&inode_id);
+        *self.generations.entry(inode_id).or_insert(0) += 1;
+        if self.nodes[&node_id].opens == 0 {
+            self.nodes.remove(&node_id);
+        }
+    }
+    /// The inode id of `name` in the directory `node_id`, if there is one
+    fn child_id(&self, node_id: u64, name: &str) -> Option<u32> {
+        self.inode(node_id).ok()?.find(name).ok().map(|inode| inode.inode_id())
+    }
+    /// Handle a request, returning the reply to it; requests such as
+    /// `FORGET` get none.
+    pub fn handle(&mut self, request: &[u8]) -> Option<Vec<u8>> {
+        let mut args = Args(request);
+        let header = (|| Ok::<_, i32>((args.u32()?, args.u32()?, args.u64()?, args.u64()?)))();
+        let (_len, opcode, unique, node_id) = header.ok()?;
+        // uid, gid, pid and padding
+        args.take(IN_HEADER_SZ - 24).ok()?;
+        match opcode {
+            FUSE_FORGET => {
+                self.forget(node_id, args.u64().ok()?);
+                return None;
+            }
+            FUSE_BATCH_FORGET => {
+                let count = args.u32().ok()?;
+                args.u32().ok()?;
+                for _ in 0..count {
+                    let node_id = args.u64().ok()?;
+                    let lookups = args.u64().ok()?;
+                    self.forget(node_id, lookups);
+                }
+                return None;
+            }
+            FUSE_INTERRUPT => return None,
+            _ => {}
+        }
+        let result = self.dispatch(opcode, node_id, args);
+        let (error, body) = match result {
+            Ok(reply) => (0, reply.0),
+            Err(errno) => (-errno, Vec::new()),
+        };
+        let mut reply = Vec::with_capacity(OUT_HEADER_SZ + body.len());
+        reply.extend_from_slice(&((OUT_HEADER_SZ + body.len()) as u32).to_ne_bytes());
+        reply.extend_from_slice(&error.to_ne_bytes());
+        reply.extend_from_slice(&unique.to_ne_bytes());
+        reply.extend_from_slice(&body);
+        Some(reply)
+    }
+    fn dispatch(&mut self, opcode: u32, node_id: u64, mut args: Args) -> Result<Reply, i32> {
+        match opcode {
+            FUSE_INIT => {
+                let major = args.u32()?;
+                let _minor = args.u32()?;
+                let max_readahead = args.u32()?;
+                let flags = args.u32()?;
+                if major < FUSE_KERNEL_VERSION {
+                    return Err(libc::EPROTO);
+                }
+                // the kernel takes the older of the two minor versions
+                Ok(Reply::default()
+                    .u32(FUSE_KERNEL_VERSION)
+                    .u32(FUSE_KERNEL_MINOR_VERSION)
+                    .u32(max_readahead)
+                    .u32(flags & FUSE_BIG_WRITES)
+                    .u16(0)
+                    .u16(0)
+                    .u32(MAX_WRITE as u32)
+                    .u32(1_000_000_000)
+                    .u16(0)
+                    .u16(0)
+                    .u32(0)
+                    .bytes(&[0; 28]))
+            }
+            FUSE_DESTROY => {
+                self.destroyed = true;
+                Ok(Reply::default())
+            }
+            FUSE_LOOKUP => {
+                let name = args.name()?;
+                let inode = self.inode(node_id)?.find(name).map_err(errno)?;
+                self.entry(inode)
+            }
+            FUSE_GETATTR => {
+                let stat = self.inode(node_id)?.stat().map_err(errno)?;
+                Ok(Reply::default().attr_out(&stat))
+            }
+            FUSE_SETATTR => {
+                let valid = args.u32()?;
+                let _padding = args.u32()?;
+                let _fh = args.u64()?;
+                let size = args.u64()?;
+                let _lock_owner = args.u64()?;
+                let atime = args.u64()?;
+                let mtime = args.u64()?;
+                let _ctime = args.u64()?;
+                args.take(12)?;
+                let mode = args.u32()?;
+                let _unused = args.u32()?;
+                let uid = args.u32()?;
+                let gid = args.u32()?;
+                let inode = self.inode(node_id)?;
+                if valid & FATTR_SIZE != 0 {
+                    let size = u32::try_from(size).map_err(|_| libc::EFBIG)?;
+                    inode.truncate(size).map_err(errno)?;
+                }
+                if valid & FATTR_MODE != 0 {
+                    inode.chmod(mode as u16 & 0o7777).map_err(errno)?;
+                }
+                if valid & (FATTR_UID | FATTR_GID) != 0 {
+                    let stat = inode.stat().map_err(errno)?;
+                    let uid = if valid & FATTR_UID != 0 { uid as u16 } else { stat.uid };
+                    let gid = if valid & FATTR_GID != 0 { gid as u16 } else { stat.gid };
+                    inode.chown(uid, gid).map_err(errno)?;
+                }
+                if valid & (FATTR_ATIME | FATTR_MTIME | FATTR_ATIME_NOW | FATTR_MTIME_NOW) != 0 {
+                    let stat = inode.stat().map_err(errno)?;
+                    let time = |set: u32, set_now: u32, time: u64, old: u32| {
+                        if valid & set_now != 0 {
+                            now()
+                        } else if valid & set != 0 {
+                            time as u32
+                        } else {
+                            old
+                        }
+                    };
+                    inode.set_times(
+                        time(FATTR_ATIME, FATTR_ATIME_NOW, atime, stat.atime),
+                        time(FATTR_MTIME, FATTR_MTIME_NOW, mtime, stat.mtime),
+                    ).map_err(errno)?;
+                }
+                let stat = inode.stat().map_err(errno)?;
+                Ok(Reply::default().attr_out(&stat))
+            }
+            FUSE_MKNOD | FUSE_MKDIR | FUSE_CREATE => {
+                let (mode, umask) = match opcode {
+                    FUSE_MKNOD => {
+                        let mode = args.u32()?;
+                        let _rdev = args.u32()?;
+                        let umask = args.u32()?;
+                        args.u32()?;
+                        (mode, umask)
+                    }
+                    FUSE_MKDIR => (args.u32()?, args.u32()?),
+                    _ => {
+                        let _flags = args.u32()?;
+                        let mode = args.u32()?;
+                        let umask = args.u32()?;
+                        args.u32()?;
+                        (mode, umask)
+                    }
+                };
+                // only regular files and directories are kept on easy-fs
+                if opcode == FUSE_MKNOD && mode & libc::S_IFMT != libc::S_IFREG {
+                    return Err(libc::EPERM);
+                }
+                let name = args.name()?;
+                let dir = self.inode(node_id)?;
+                let inode = if opcode == FUSE_MKDIR {
+                    dir.mkdir(name)
+                } else {
+                    dir.create(name)
+                }.map_err(errno)?;
+                inode.chmod((mode & !umask & 0o7777) as u16).map_err(errno)?;
+                let inode_id = inode.inode_id();
+                let reply = self.entry(inode)?;
+                if opcode != FUSE_CREATE {
+                    return Ok(reply);
+                }
+                let node_id = self.node_ids[&inode_id];
+                self.nodes.get_mut(&node_id).unwrap().opens += 1;
+                Ok(reply.open_out())
+            }
+            FUSE_UNLINK | FUSE_RMDIR => {
+                let name = args.name()?;
+                let dir = self.inode(node_id)?;
+                let removed = self.child_id(node_id, name);
+                if opcode == FUSE_UNLINK {
+                    dir.unlink(name)
+                } else {
+                    dir.rmdir(name)
+                }.map_err(errno)?;
+                if let Some(inode_id) = removed {
+                    self.unlinked(inode_id);
+                }
+                Ok(Reply::default())
+            }
+            FUSE_RENAME | FUSE_RENAME2 => {
+                let new_dir = args.u64()?;
+                if opcode == FUSE_RENAME2 {
+                    // neither RENAME_NOREPLACE nor RENAME_EXCHANGE
+                    if args.u32()? != 0 {
+                        return Err(libc::EINVAL);
+                    }
+                    args.u32()?;
+                }
+                let old_name = args.name()?;
+                let new_name = args.name()?;
+                let replaced = self.child_id(new_dir, new_name);
+                let new_dir = self.inode(new_dir)?;
+                self.inode(node_id)?.rename(old_name, &new_dir, new_name).map_err(errno)?;
+                if let Some(inode_id) = replaced {
+                    self.unlinked(inode_id);
+                }
+                Ok(Reply::default())
+            }
+            FUSE_LINK => {
+                let target = self.inode(args.u64()?)?;
+                let name = args.name()?;
+                self.inode(node_id)?.link(name, &target).map_err(errno)?;
+                self.entry(target)
+            }
+            FUSE_OPEN | FUSE_OPENDIR => {
+                self.nodes.get_mut(&node_id).ok_or(libc::ENOENT)?.opens += 1;
+                Ok(Reply::default().open_out())
+            }
+            FUSE_RELEASE | FUSE_RELEASEDIR => {
+                if let Some(node) = self.nodes.get_mut(&node_id) {
+                    node.opens = node.opens.saturating_sub(1);
+                    let inode_id = node.inode.inode_id();
+                    // the last handle of an unlinked inode
+                    if node.opens == 0 && self.node_ids.get(&inode_id) != Some(&node_id) {
+                        self.nodes.remove(&node_id);
+                    }
+                }
+                Ok(Reply::default())
+            }
+            FUSE_READ => {
+                let _fh = args.u64()?;
+                let offset = args.u64()?;
+                let size = args.u32()?;
+                let mut buf = vec![0u8; size as usize];
+                let len = self.inode(node_id)?.read_at(offset as usize, &mut buf).map_err(errno)?;
+                buf.truncate(len);
+                Ok(Reply(buf))
+            }
+            FUSE_WRITE => {
+                let _fh = args.u64()?;
+                let offset = args.u64()?;
+                let size = args.u32()?;
+                // write flags, lock owner, flags and padding
+                args.take(20)?;
+                let data = args.take(size as usize)?;
+                let len = self.inode(node_id)?.write_at(offset as usize, data).map_err(errno)?;
+                Ok(Reply::default().u32(len as u32).u32(0))
+            }
+            FUSE_READDIR => {
+                let _fh = args.u64()?;
+                let offset = args.u64()? as usize;
+                let size = args.u32()? as usize;
+                let dir = self.inode(node_id)?;
+                let mut names = vec![String::from("."), String::from("..")];
+                names.extend(dir.ls().map_err(errno)?);
+                let mut reply = Reply::default();
+                // an entry's offset is where the next call goes on from
+                for (i, name) in names.iter().enumerate().skip(offset) {
+                    let stat = dir.find(name).and_then(|inode| inode.stat()).map_err(errno)?;
+                    let type_ = match stat.type_ {
+                        DiskInodeType::File => libc::DT_REG,
+                        DiskInodeType::Directory => libc::DT_DIR,
+                    };
+                    let len = (24 + name.len()).next_multiple_of(8);
+                    if reply.0.len() + len > size {
+                        break;
+                    }
+                    reply = reply
+                        .u64(ino_of(stat.inode_id))
+                        .u64(i as u64 + 1)
+                        .u32(name.len() as u32)
+                        .u32(type_ as u32)
+                        .bytes(name.as_bytes())
+                        .bytes(&[0; 7][..len - 24 - name.len()]);
+                }
+                Ok(reply)
+            }
+            FUSE_STATFS => {
+                let stat = self.inode(node_id)?.statfs().map_err(errno)?;
+                Ok(Reply::default()
+                    .u64(stat.data_blocks as u64)
+                    .u64(stat.free_blocks as u64)
+                    .u64(stat.free_blocks as u64)
+                    .u64(stat.inodes as u64)
+                    .u64(stat.free_inodes as u64)
+                    .u32(easy_fs::BLOCK_SZ as u32)
+                    .u32(stat.name_limit)
+                    .u32(easy_fs::BLOCK_SZ as u32)
+                    .u32(0)
+                    .bytes(&[0; 24]))
+            }
+            FUSE_FSYNC | FUSE_FSYNCDIR => {
+                let _fh = args.u64()?;
+                let flags = args.u32()?;
+                let inode = self.inode(node_id)?;
+                if flags & FUSE_FSYNC_FDATASYNC != 0 {
+                    inode.fdatasync()
+                } else {
+                    inode.fsync()
+                }.map_err(errno)?;
+                Ok(Reply::default())
+            }
+            // permissions are checked by the kernel, see `default_permissions`
+            FUSE_FLUSH | FUSE_ACCESS => Ok(Reply::default()),
+            _ => Err(libc::ENOSYS),
+        }
+    }
+}
+
+fn last_error() -> io::Error {
+    io::Error::last_os_error()
+}
+
+fn c_string(s: &str) -> io::Result<CString> {
+    CString::new(s).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))
+}
+
+/// A connection to the FUSE driver for a mount point, unmounted on drop
+pub struct FuseDevice {
+    fd: RawFd,
+    mountpoint: String,
+    /// Mounted by `fusermount`, which unmounts it too
+    fusermount: Option<&'static str>,
+}
+
+impl FuseDevice {
+    /// Mount a FUSE file system on `mountpoint`: directly as root,
+    /// through the setuid `fusermount` otherwise
+    pub fn mount(mountpoint: &str) -> io::Result<Self> {
+        match Self::mount_directly(mountpoint) {
+            Err(error) if error.raw_os_error() == Some(libc::EPERM) => Self::mount_with_fusermount(mountpoint),
+            result => result,
+        }
+    }
+    fn mount_directly(mountpoint: &str) -> io::Result<Self> {
+        let fd = unsafe { libc::open(c_string("/dev/fuse")?.as_ptr(), libc::O_RDWR | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(last_error());
+        }
+        let device = Self {
+            fd,
+            mountpoint: String::from(mountpoint),
+            fusermount: None,
+        };
+        let options = format!(
+            "fd={},rootmode=40000,user_id={},group_id={},default_permissions",
+            fd,
+            unsafe { libc::getuid() },
+            unsafe { libc::getgid() },
+        );
+        let result = unsafe {
+            libc::mount(
+                c_string("easy-fs")?.as_ptr(),
+                c_string(mountpoint)?.as_ptr(),
+                c_string("fuse.easy-fs")?.as_ptr(),
+                libc::MS_NOSUID | libc::MS_NODEV,
+                c_string(&options)?.as_ptr() as *const libc::c_void,
+            )
+        };
+        if result < 0 {
+            let error = last_error();
+            // nothing to unmount
+            unsafe { libc::close(device.fd) };
+            std::mem::forget(device);
+            return Err(error);
+        }
+        Ok(device)
+    }
+    /// `fusermount` mounts and passes the opened `/dev/fuse` back over the
+    /// socket named by `_FUSE_COMMFD`
+    fn mount_with_fusermount(mountpoint: &str) -> io::Result<Self> {
+        let mut fds = [0; 2];
+        if unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) } < 0 {
+            return Err(last_error());
+        }
+        let [theirs, ours] = fds;
+        let mut last = io::Error::from(io::ErrorKind::NotFound);
+        let mut fusermount = None;
+        for program in ["fusermount3", "fusermount"] {
+            let status = Command::new(program)
+                .args(["-o", "fsname=easy-fs,subtype=easy-fs,default_permissions", "--", mountpoint])
+                .env("_FUSE_COMMFD", theirs.to_string())
+                .status();
+            match status {
+                Ok(status) if status.success() => {
+                    fusermount = Some(program);
+                    break;
+                }
+                Ok(status) => last = io::Error::other(format!("{} failed: {}", program, status)),
+                Err(error) => last = error,
+            }
+        }
+        unsafe { libc::close(theirs) };
+        let result = match fusermount {
+            Some(program) => receive_fd(ours).map(|fd| Self {
+                fd,
+                mountpoint: String::from(mountpoint),
+                fusermount: Some(program),
+            }),
+            None => Err(last),
+        };
+        unsafe { libc::close(ours) };
+        result
+    }
+    /// Read the next request into `buf`, `None` once unmounted
+    pub fn receive(&self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        loop {
+            let len = unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if len >= 0 {
+                return Ok(Some(len as usize));
+            }
+            let error = last_error();
+            match error.raw_os_error() {
+                // a request interrupted before it was read
+                Some(libc::ENOENT) | Some(libc::EINTR) | Some(libc::EAGAIN) => continue,
+                Some(libc::ENODEV) => return Ok(None),
+                _ => return Err(error),
+            }
+        }
+    }
+    pub fn send(&self, reply: &[u8]) -> io::Result<()> {
+        let len = unsafe { libc::write(self.fd, reply.as_ptr() as *const libc::c_void, reply.len()) };
+        if len < 0 {
+            let error = last_error();
+            // the request was interrupted meanwhile, no one waits for it
+            if error.raw_os_error() != Some(libc::ENOENT) {
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for FuseDevice {
+    /// Unmount lazily, which is harmless once unmounted from outside
+    fn drop(&mut self) {
+        match self.fusermount {
+            Some(program) => {
+                let _ = Command::new(program).args(["-u", "-z", "--", &self.mountpoint]).status();
+            }
+            None => {
+                if let Ok(mountpoint) = c_string(&self.mountpoint) {
+                    unsafe { libc::umount2(mountpoint.as_ptr(), libc::MNT_DETACH) };
+                }
+            }
+        }
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// Receive a file descriptor sent as `SCM_RIGHTS` over a unix socket
+fn receive_fd(socket: RawFd) -> io::Result<RawFd> {
+    let mut byte = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr() as *mut libc::c_void,
+        iov_len: byte.len(),
+    };
+    let mut control = [0u8; 64];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = control.len() as _;
+    if unsafe { libc::recvmsg(socket, &mut msg, 0) } < 0 {
+        return Err(last_error());
+    }
+    let cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    if cmsg.is_null() || unsafe { (*cmsg).cmsg_type } != libc::SCM_RIGHTS {
+        return Err(io::Error::other("fusermount sent no file descriptor"));
+    }
+    Ok(unsafe { std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const RawFd) })
+}
+
+/// Serve the file system of `root_inode` on `mountpoint` until unmounted
+pub fn serve(root_inode: Inode, mountpoint: &str) -> io::Result<()> {
+    let device = FuseDevice::mount(mountpoint)?;
+    let mut session = Session::new(root_inode);
+    let mut buf = vec![0u8; REQUEST_BUF_SZ];
+    while !session.destroyed {
+        let len = match device.receive(&mut buf)? {
+            Some(len) => len,
+            None => break,
+        };
+        if let Some(reply) = session.handle(&buf[..len]) {
+            device.send(&reply)?;
+        }
+    }
+    Ok(())
+}
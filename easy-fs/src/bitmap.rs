@@ -1,72 +1,216 @@
 use alloc::sync::Arc;
 
-use crate::{block_cache::get_block_cache, BlockDevice, BLOCK_SZ};
+use crate::{block_cache::get_block_cache, error::Result, BlockDevice, BLOCK_SZ};
 
 
-const BLOCK_BITS: usize = BLOCK_SZ * 8;
+pub const BLOCK_BITS: usize = BLOCK_SZ * 8;
 // 512 bytes
 type BitmapBlock = [u64; 64];
 
 pub struct Bitmap {
     start_block_id: usize,
     blocks: usize,
+    /// Bits in use, those past them in the last block are never allocated
+    bits: usize,
+    /// Where a search without a goal starts, just after the last bits taken
+    hint: usize,
+    /// No bit below it is empty
+    first_empty: usize,
 }
 
 impl Bitmap {
-    pub fn new(start_block_id: usize, blocks: usize) -> Self {
+    pub fn new(start_block_id: usize, blocks: usize, bits: usize) -> Self {
+        assert!(bits <= blocks * BLOCK_BITS);
         Self {
             start_block_id,
             blocks,
+            bits,
+            hint: 0,
+            first_empty: 0,
         }
     }
-    // read bitmap in blocks, find first bit empty, mark it and return
-    pub fn alloc(&self, block_device: &Arc<dyn BlockDevice>) -> Option<usize> {
-        for block_id in 0..self.blocks {
-            let pos = get_block_cache(
-                block_id + self.start_block_id,
+    /// Take the lowest empty bit and mark it, `None` once every bit is
+    /// taken. The search starts past the bits known to be taken rather than
+    /// at the first block.
+    pub fn alloc(&mut self, block_device: &Arc<dyn BlockDevice>) -> Result<Option<usize>> {
+        let bit = self.alloc_contiguous(block_device, Some(self.first_empty), 1)?.map(|(bit, _)| bit);
+        if let Some(bit) = bit {
+            self.first_empty = bit + 1;
+        }
+        Ok(bit)
+    }
+    /// Take up to `n` empty bits in a row and mark them, returning the first
+    /// one and how many were taken; `None` once every bit is taken.
+    ///
+    /// The first run of `n` from `goal` on is preferred, wrapping around;
+    /// failing that, the longest run there is. Without a goal the search
+    /// starts after the last bits taken, so that bits are handed out
+    /// next-fit rather than always scanning from the first block.
+    pub fn alloc_contiguous(
+        &mut self,
+        block_device: &Arc<dyn BlockDevice>,
+        goal: Option<usize>,
+        n: usize,
+    ) -> Result<Option<(usize, usize)>> {
+        assert!(n > 0);
+        let goal = goal.unwrap_or(self.hint);
+        let goal = if goal < self.bits { goal } else { 0 };
+        let run = match self.find_run(block_device, goal, self.bits, n)? {
+            Some(run) if run.1 == n => Some(run),
+            after => {
+                // a run does not wrap around, the bits before the goal are
+                // searched on their own
+                let before = self.find_run(block_device, 0, goal, n)?;
+                match (after, before) {
+                    (Some(after), Some(before)) if before.1 > after.1 => Some(before),
+                    (after, before) => after.or(before),
+                }
+            }
+        };
+        if let Some((first, len)) = run {
+            self.mark(block_device, first, len)?;
+            self.hint = first + len;
+        }
+        Ok(run)
+    }
+    /// The first run of `n` empty bits in `from..to`, or the longest
+    /// shorter one, as its first bit and length
+    fn find_run(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        from: usize,
+        to: usize,
+        n: usize,
+    ) -> Result<Option<(usize, usize)>> {
+        let mut best: Option<(usize, usize)> = None;
+        let (mut run_start, mut run_len) = (from, 0);
+        let mut bit = from;
+        while bit < to {
+            let block_pos = bit / BLOCK_BITS;
+            let bitmap_block = get_block_cache(
+                self.start_block_id + block_pos,
                 Arc::clone(block_device),
-            )
-            .lock()
-            .modify(0, |bitmap_block: &mut BitmapBlock| {
-                if let Some((bits64_pos, inner_pos)) = bitmap_block
-                    .iter()
-                    .enumerate()
-                    .find(|(_, bits64)| **bits64 != u64::MAX)
-                    .map(|(bits64_pos, bits64)| {
-                        (bits64_pos, bits64.trailing_ones() as usize)
-                    }) {
-                    // modify cache
-                    bitmap_block[bits64_pos] |= 1u64 << inner_pos;
-                    Some(block_id * BLOCK_BITS + bits64_pos * 64 + inner_pos as usize)
+            )?.lock().read(0, |bitmap_block: &BitmapBlock| *bitmap_block);
+            let block_end = to.min((block_pos + 1) * BLOCK_BITS);
+            while bit < block_end {
+                let inner_pos = bit % 64;
+                let bits64 = bitmap_block[(bit % BLOCK_BITS) / 64] >> inner_pos;
+                let avail = (64 - inner_pos).min(block_end - bit);
+                if bits64 & 1 == 0 {
+                    let zeros = (bits64.trailing_zeros() as usize).min(avail);
+                    if run_len == 0 {
+                        run_start = bit;
+                    }
+                    run_len += zeros;
+                    if run_len >= n {
+                        return Ok(Some((run_start, n)));
+                    }
+                    bit += zeros;
                 } else {
-                    None
+                    if run_len > best.map_or(0, |(_, len)| len) {
+                        best = Some((run_start, run_len));
+                    }
+                    run_len = 0;
+                    bit += (bits64.trailing_ones() as usize).min(avail);
                 }
-            });
-            if pos.is_some() {
-                return pos;
             }
         }
-        None
+        if run_len > best.map_or(0, |(_, len)| len) {
+            best = Some((run_start, run_len));
+        }
+        Ok(best)
     }
-    pub fn dealloc(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) {
+    /// Set `len` bits from `first` on, one bitmap block at a time
+    fn mark(&self, block_device: &Arc<dyn BlockDevice>, first: usize, len: usize) -> Result<()> {
+        let mut bit = first;
+        while bit < first + len {
+            let block_pos = bit / BLOCK_BITS;
+            let block_end = (first + len).min((block_pos + 1) * BLOCK_BITS);
+            get_block_cache(
+                self.start_block_id + block_pos,
+                Arc::clone(block_device),
+            )?.lock().modify(0, |bitmap_block: &mut BitmapBlock| {
+                for bit in bit..block_end {
+                    let (_, bits64_pos, inner_pos) = decomposition(bit);
+                    bitmap_block[bits64_pos] |= 1u64 << inner_pos;
+                }
+            });
+            bit = block_end;
+        }
+        Ok(())
+    }
+    pub fn dealloc(&mut self, block_device: &Arc<dyn BlockDevice>, bit: usize) -> Result<()> {
+        self.first_empty = self.first_empty.min(bit);
         let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
         get_block_cache(
             self.start_block_id + block_pos,
             Arc::clone(block_device),
-        ).lock().modify(0, |bitmap_block: &mut BitmapBlock| {
+        )?.lock().modify(0, |bitmap_block: &mut BitmapBlock| {
             assert!(bitmap_block[bits64_pos] & (1u64 << inner_pos) > 0);
             bitmap_block[bits64_pos] -= 1u64 << inner_pos;
         });
+        Ok(())
+    }
+    /// Whether `bit` is set, for checking the bitmap against its users
+    pub fn get(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) -> Result<bool> {
+        let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
+        get_block_cache(
+            self.start_block_id + block_pos,
+            Arc::clone(block_device),
+        )?.lock().read(0, |bitmap_block: &BitmapBlock| {
+            Ok(bitmap_block[bits64_pos] & (1u64 << inner_pos) > 0)
+        })
+    }
+    /// Force `bit` to `value`, for repairing the bitmap
+    pub fn set(&mut self, block_device: &Arc<dyn BlockDevice>, bit: usize, value: bool) -> Result<()> {
+        if !value {
+            self.first_empty = self.first_empty.min(bit);
+        }
+        let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
+        get_block_cache(
+            self.start_block_id + block_pos,
+            Arc::clone(block_device),
+        )?.lock().modify(0, |bitmap_block: &mut BitmapBlock| {
+            if value {
+                bitmap_block[bits64_pos] |= 1u64 << inner_pos;
+            } else {
+                bitmap_block[bits64_pos] &= !(1u64 << inner_pos);
+            }
+        });
+        Ok(())
+    }
+    /// Bits set among those in use, for checking a count kept of them
+    pub fn count(&self, block_device: &Arc<dyn BlockDevice>) -> Result<usize> {
+        let mut count = 0;
+        for block_pos in 0..self.blocks {
+            let first_bit = block_pos * BLOCK_BITS;
+            count += get_block_cache(
+                self.start_block_id + block_pos,
+                Arc::clone(block_device),
+            )?.lock().read(0, |bitmap_block: &BitmapBlock| {
+                bitmap_block
+                    .iter()
+                    .enumerate()
+                    .map(|(bits64_pos, bits64)| {
+                        // bits past those in use are not counted
+                        let used = self.bits.saturating_sub(first_bit + bits64_pos * 64).min(64);
+                        let mask = if used == 64 { u64::MAX } else { (1u64 << used) - 1 };
+                        (bits64 & mask).count_ones() as usize
+                    })
+                    .sum::<usize>()
+            });
+        }
+        Ok(count)
     }
     pub fn maximum(&self) -> usize {
-        self.blocks * BLOCK_BITS
+        self.bits
     }
 }
 
 /// Return (block_pos, bits64_pos, inner_pos)
 fn decomposition(mut bit: usize) -> (usize, usize, usize) {
     let block_pos = bit / BLOCK_BITS;
-    bit = bit % BLOCK_BITS;
+    bit %= BLOCK_BITS;
     (block_pos, bit / 64, bit % 64)
 }
 
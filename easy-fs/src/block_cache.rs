@@ -1,10 +1,16 @@
 use super::{BLOCK_SZ, BlockDevice};
+use crate::error::Result;
+use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
-use alloc::collections::VecDeque;
+use alloc::vec::Vec;
 use lazy_static::lazy_static;
 use spin::Mutex;
 
-const BLOCK_CACHE_SIZE: usize = 16;
+/// Default number of cached blocks of a device
+pub const BLOCK_CACHE_SIZE: usize = 16;
+/// Entries `evict` looks at from the LRU end before giving up, which keeps
+/// it O(1) however many are pinned
+const EVICT_SCAN: usize = 8;
 
 lazy_static! {
     pub static ref BLOCK_CACHE_MANAGER: Mutex<BlockCacheManager> = Mutex::new(
@@ -12,10 +18,11 @@ lazy_static! {
     );
 }
 
+/// Fails only when the block is not cached and can not be read
 pub fn get_block_cache(
     block_id: usize,
     block_device: Arc<dyn BlockDevice>,
-) -> Arc<Mutex<BlockCache>> {
+) -> Result<Arc<Mutex<BlockCache>>> {
     BLOCK_CACHE_MANAGER.lock().get_block_cache(block_id, block_device)
 }
 
@@ -31,15 +38,25 @@ impl BlockCache {
     pub fn new(
         block_id: usize,
         block_device: Arc<dyn BlockDevice>
-    ) -> Self {
+    ) -> Result<Self> {
         let mut cache = [0u8; BLOCK_SZ];
-        block_device.read_block(block_id, &mut cache);
-        Self {
+        block_device.read_block(block_id, &mut cache)?;
+        Ok(Self {
             cache,
             block_id,
             block_device,
             modified: false,
-        }
+        })
+    }
+    pub fn block_id(&self) -> usize {
+        self.block_id
+    }
+    /// Drop the modifications, reading the block again from the device. It
+    /// stays modified if that fails.
+    fn reload(&mut self) -> Result<()> {
+        self.block_device.read_block(self.block_id, &mut self.cache)?;
+        self.modified = false;
+        Ok(())
     }
     fn addr_of_offset(&self, offset: usize) -> usize {
         &self.cache[offset] as *const _ as usize
@@ -57,11 +74,13 @@ impl BlockCache {
         let addr = self.addr_of_offset(offset);
         unsafe {&mut *(addr as *mut T)}
     }
-    pub fn sync(&mut self) {
+    /// Write the block back if modified, it stays modified if that fails
+    pub fn sync(&mut self) -> Result<()> {
         if self.modified {
+            self.block_device.write_block(self.block_id, &self.cache)?;
             self.modified = false;
-            self.block_device.write_block(self.block_id, &self.cache);
         }
+        Ok(())
     }
     pub fn read<T, V>(&self, offset: usize, f: impl FnOnce(&T) -> V) -> V {
         f(self.get_ref(offset))
@@ -72,54 +91,363 @@ impl BlockCache {
 }
 
 impl Drop for BlockCache {
+    /// Only the manager going away drops a modified block, nothing is left
+    /// to report an error to then
     fn drop(&mut self) {
-        self.sync();
+        let _ = self.sync();
     }
 }
 
+const NIL: usize = usize::MAX;
+
+/// What an operation on an inode left in modified blocks not made durable
+/// yet, ordered so that the larger one covers the smaller
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum InodeDirty {
+    /// Timestamps, mode, owner or links, not needed to read the data back
+    Metadata,
+    /// Contents, size or the blocks backing them
+    Data,
+}
+
+struct Entry {
+    block_id: usize,
+    device_id: usize,
+    cache: Arc<Mutex<BlockCache>>,
+    /// Neighbours in the LRU list of the device, the most recently used at
+    /// the head
+    prev: usize,
+    next: usize,
+}
+
+/// The LRU list of the cached blocks of a device
+struct DeviceCache {
+    capacity: usize,
+    head: usize,
+    tail: usize,
+    len: usize,
+}
+
+impl DeviceCache {
+    fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "Empty block cache!");
+        Self {
+            capacity,
+            head: NIL,
+            tail: NIL,
+            len: 0,
+        }
+    }
+}
+
+impl Entry {
+    fn evictable(&self) -> bool {
+        // the entries no one refers to can be locked here
+        Arc::strong_count(&self.cache) == 1 && !self.cache.lock().modified
+    }
+}
+
+/// Block caches indexed by a hash table over (block id, device) and kept in
+/// LRU order per device, each device having a capacity of its own, which
+/// makes lookup, touch and eviction O(1).
+///
+/// A modified block stays cached until it is synced, so that the journal
+/// sees it before it reaches its home location. Neither can a block still
+/// referred to outside the manager be evicted. Such pinned entries met at
+/// the LRU end are moved to the head, and when none of the first ones met
+/// can go the cache grows past its capacity rather than failing, shrinking
+/// back as entries become evictable.
 pub struct BlockCacheManager {
-    queue: VecDeque<(usize, Arc<Mutex<BlockCache>>)>,
+    /// LRU lists by device, one made with `BLOCK_CACHE_SIZE` for a device
+    /// whose capacity is not set
+    devices: BTreeMap<usize, DeviceCache>,
+    /// Entries by slot, `None` for a free slot
+    slots: Vec<Option<Entry>>,
+    free_slots: Vec<usize>,
+    /// Slots chained by the hash of their block id and device
+    buckets: Vec<Vec<usize>>,
+    len: usize,
+    /// Inodes with modifications pending by (device, inode id)
+    dirty_inodes: BTreeMap<(usize, u32), InodeDirty>,
+}
+
+fn hash(block_id: usize, device_id: usize) -> usize {
+    let h = (block_id ^ device_id.rotate_left(16)).wrapping_mul(0x9e37_79b9);
+    h ^ (h >> 15)
 }
 
 impl BlockCacheManager {
     pub fn new() -> Self {
-        Self { queue: VecDeque::new() }
+        let mut manager = Self {
+            devices: BTreeMap::new(),
+            slots: Vec::new(),
+            free_slots: Vec::new(),
+            buckets: Vec::new(),
+            len: 0,
+            dirty_inodes: BTreeMap::new(),
+        };
+        manager.rehash((2 * BLOCK_CACHE_SIZE).next_power_of_two());
+        manager
+    }
+    fn device(&mut self, device_id: usize) -> &mut DeviceCache {
+        self.devices.entry(device_id).or_insert_with(|| DeviceCache::new(BLOCK_CACHE_SIZE))
+    }
+    fn is_full(&mut self, device_id: usize) -> bool {
+        let device = self.device(device_id);
+        device.len >= device.capacity
+    }
+    /// Change the capacity of a device, evicting what is evictable beyond it
+    pub fn set_capacity(&mut self, device_id: usize, capacity: usize) {
+        assert!(capacity > 0, "Empty block cache!");
+        self.device(device_id).capacity = capacity;
+        while self.device(device_id).len > capacity && self.evict(device_id) {}
+    }
+    fn entry(&self, slot: usize) -> &Entry {
+        self.slots[slot].as_ref().unwrap()
+    }
+    fn entry_mut(&mut self, slot: usize) -> &mut Entry {
+        self.slots[slot].as_mut().unwrap()
+    }
+    fn bucket(&self, block_id: usize, device_id: usize) -> usize {
+        hash(block_id, device_id) & (self.buckets.len() - 1)
+    }
+    fn rehash(&mut self, bucket_count: usize) {
+        self.buckets = (0..bucket_count).map(|_| Vec::new()).collect();
+        for slot in 0..self.slots.len() {
+            if let Some(entry) = &self.slots[slot] {
+                let bucket = self.bucket(entry.block_id, entry.device_id);
+                self.buckets[bucket].push(slot);
+            }
+        }
+    }
+    fn find(&self, block_id: usize, device_id: usize) -> Option<usize> {
+        self.buckets[self.bucket(block_id, device_id)]
+            .iter()
+            .copied()
+            .find(|slot| {
+                let entry = self.entry(*slot);
+                entry.block_id == block_id && entry.device_id == device_id
+            })
+    }
+    fn unlink(&mut self, slot: usize) {
+        let (device_id, prev, next) = {
+            let entry = self.entry(slot);
+            (entry.device_id, entry.prev, entry.next)
+        };
+        if prev == NIL {
+            self.device(device_id).head = next;
+        } else {
+            self.entry_mut(prev).next = next;
+        }
+        if next == NIL {
+            self.device(device_id).tail = prev;
+        } else {
+            self.entry_mut(next).prev = prev;
+        }
+        self.device(device_id).len -= 1;
+    }
+    fn push_front(&mut self, slot: usize) {
+        let device_id = self.entry(slot).device_id;
+        let head = self.device(device_id).head;
+        {
+            let entry = self.entry_mut(slot);
+            entry.prev = NIL;
+            entry.next = head;
+        }
+        if head == NIL {
+            self.device(device_id).tail = slot;
+        } else {
+            self.entry_mut(head).prev = slot;
+        }
+        let device = self.device(device_id);
+        device.head = slot;
+        device.len += 1;
+    }
+    fn insert(&mut self, entry: Entry) {
+        let bucket = self.bucket(entry.block_id, entry.device_id);
+        let slot = match self.free_slots.pop() {
+            Some(slot) => {
+                self.slots[slot] = Some(entry);
+                slot
+            }
+            None => {
+                self.slots.push(Some(entry));
+                self.slots.len() - 1
+            }
+        };
+        self.buckets[bucket].push(slot);
+        self.push_front(slot);
+        self.len += 1;
+        if self.len > self.buckets.len() {
+            self.rehash(2 * self.buckets.len());
+        }
+    }
+    fn remove(&mut self, slot: usize) {
+        self.unlink(slot);
+        let entry = self.slots[slot].take().unwrap();
+        let bucket = self.bucket(entry.block_id, entry.device_id);
+        self.buckets[bucket].retain(|s| *s != slot);
+        self.free_slots.push(slot);
+        self.len -= 1;
+    }
+    /// Drop the least recently used entry of a device no one refers to and
+    /// not modified, looking at no more than `EVICT_SCAN` entries; false if
+    /// none of them can go
+    fn evict(&mut self, device_id: usize) -> bool {
+        for _ in 0..EVICT_SCAN {
+            let slot = self.device(device_id).tail;
+            if slot == NIL {
+                break;
+            }
+            if self.entry(slot).evictable() {
+                self.remove(slot);
+                return true;
+            }
+            // in use, which is as good as recently used
+            self.unlink(slot);
+            self.push_front(slot);
+        }
+        false
     }
     pub fn get_block_cache(
         &mut self,
         block_id: usize,
         block_device: Arc<dyn BlockDevice>,
-    ) -> Arc<Mutex<BlockCache>> {
-        if let Some(pair) = self.queue
-            .iter()
-            .find(|pair| pair.0 == block_id) {
-                Arc::clone(&pair.1)
+    ) -> Result<Arc<Mutex<BlockCache>>> {
+        let device_id = block_device.device_id();
+        if let Some(slot) = self.find(block_id, device_id) {
+            self.unlink(slot);
+            self.push_front(slot);
+            Ok(Arc::clone(&self.entry(slot).cache))
         } else {
-            // substitute
-            if self.queue.len() == BLOCK_CACHE_SIZE {
-                if let Some((idx, _)) = self.queue 
-                    .iter()
-                    .enumerate()
-                    .find(|(_, pair)| Arc::strong_count(&pair.1) == 1) {
-                        self.queue.drain(idx..=idx);
-                } else {
-                    panic!("Run out of BlockCache!");
-                }
-            }
-            // Load block into mem and push back
-            let block_cache = Arc::new(Mutex::new(
-                BlockCache::new(block_id, Arc::clone(&block_device))
+            // Load block into mem and push front
+            let cache = Arc::new(Mutex::new(
+                BlockCache::new(block_id, Arc::clone(&block_device))?
             ));
-            self.queue.push_back((block_id, Arc::clone(&block_cache)));
-            block_cache
+            while self.is_full(device_id) && self.evict(device_id) {}
+            self.insert(Entry {
+                block_id,
+                device_id,
+                cache: Arc::clone(&cache),
+                prev: NIL,
+                next: NIL,
+            });
+            Ok(cache)
+        }
+    }
+    /// Drop the evictable entries of a device whatever the capacity, and the
+    /// capacity set for it once nothing of it is left
+    fn release(&mut self, device_id: usize) {
+        for slot in 0..self.slots.len() {
+            let releasable = self.slots[slot]
+                .as_ref()
+                .map_or(false, |entry| entry.device_id == device_id && entry.evictable());
+            if releasable {
+                self.remove(slot);
+            }
+        }
+        if self.devices.get(&device_id).map_or(false, |device| device.len == 0) {
+            self.devices.remove(&device_id);
+            self.clear_dirty_inodes(device_id);
         }
     }
+    /// Drop the entry of a block, whatever refers to it
+    fn forget(&mut self, block_id: usize, device_id: usize) {
+        if let Some(slot) = self.find(block_id, device_id) {
+            self.remove(slot);
+        }
+    }
+    fn clear_dirty_inodes(&mut self, device_id: usize) {
+        self.dirty_inodes.retain(|(id, _), _| *id != device_id);
+    }
+    /// The cached blocks of a device, or of every device
+    fn caches(&self, device_id: Option<usize>) -> Vec<Arc<Mutex<BlockCache>>> {
+        self.slots
+            .iter()
+            .flatten()
+            .filter(|entry| device_id.map_or(true, |id| entry.device_id == id))
+            .map(|entry| Arc::clone(&entry.cache))
+            .collect()
+    }
 }
 
-/// Sync all block cache to block device
-pub fn block_cache_sync_all() {
-    let manager = BLOCK_CACHE_MANAGER.lock();
-    for (_, cache) in manager.queue.iter() {
-        cache.lock().sync();
+/// The modified block caches of a device, in block order so that what is
+/// logged does not depend on the cache's recency order
+pub fn dirty_block_caches(block_device: &Arc<dyn BlockDevice>) -> Vec<Arc<Mutex<BlockCache>>> {
+    let device_id = block_device.device_id();
+    let caches = BLOCK_CACHE_MANAGER.lock().caches(Some(device_id));
+    // lock the caches without the manager, which is locked under them elsewhere
+    let mut dirty: Vec<_> = caches.into_iter().filter(|cache| cache.lock().modified).collect();
+    dirty.sort_by_key(|cache| cache.lock().block_id());
+    dirty
+}
+
+/// Record what an operation on the inode `inode_id` of a device modified,
+/// kept until the device's modified blocks are made durable or dropped
+pub fn mark_inode_dirty(block_device: &Arc<dyn BlockDevice>, inode_id: u32, dirty: InodeDirty) {
+    let device_id = block_device.device_id();
+    let mut manager = BLOCK_CACHE_MANAGER.lock();
+    let marked = manager.dirty_inodes.entry((device_id, inode_id)).or_insert(dirty);
+    *marked = (*marked).max(dirty);
+}
+
+/// What the inode `inode_id` of a device has modified since the last
+/// commit, `None` if nothing
+pub fn inode_dirty(block_device: &Arc<dyn BlockDevice>, inode_id: u32) -> Option<InodeDirty> {
+    BLOCK_CACHE_MANAGER.lock().dirty_inodes.get(&(block_device.device_id(), inode_id)).copied()
+}
+
+/// Forget what the inodes of a device modified, once it is durable
+pub fn clear_dirty_inodes(block_device: &Arc<dyn BlockDevice>) {
+    BLOCK_CACHE_MANAGER.lock().clear_dirty_inodes(block_device.device_id());
+}
+
+/// Write the modified blocks of a device home, stopping at the first failure
+pub fn sync_block_caches(block_device: &Arc<dyn BlockDevice>) -> Result<()> {
+    for block_cache in dirty_block_caches(block_device) {
+        block_cache.lock().sync()?;
+    }
+    Ok(())
+}
+
+/// Sync all block cache to block device, stopping at the first failure
+pub fn block_cache_sync_all() -> Result<()> {
+    let caches = BLOCK_CACHE_MANAGER.lock().caches(None);
+    for cache in caches {
+        cache.lock().sync()?;
+    }
+    Ok(())
+}
+
+/// Give back the cache entries of a device going out of use, along with
+/// the references to the device they hold
+pub fn release_block_caches(block_device: &Arc<dyn BlockDevice>) {
+    BLOCK_CACHE_MANAGER.lock().release(block_device.device_id());
+}
+
+/// Forget the modifications of a device not synced yet. The modified blocks
+/// are read again in place, so that whoever refers to one sees what the
+/// device holds; one that can not be read is dropped from the cache, and
+/// read again when next used.
+pub fn discard_block_caches(block_device: &Arc<dyn BlockDevice>) {
+    let mut unread = Vec::new();
+    for cache in dirty_block_caches(block_device) {
+        let mut cache = cache.lock();
+        if cache.reload().is_err() {
+            // not to be written back when dropped
+            cache.modified = false;
+            unread.push(cache.block_id());
+        }
+    }
+    let mut manager = BLOCK_CACHE_MANAGER.lock();
+    for block_id in unread {
+        manager.forget(block_id, block_device.device_id());
     }
+    manager.clear_dirty_inodes(block_device.device_id());
+}
+
+/// Resize the block cache of a device, `BLOCK_CACHE_SIZE` blocks unless
+/// set, until its blocks are released
+pub fn set_block_cache_capacity(block_device: &Arc<dyn BlockDevice>, capacity: usize) {
+    BLOCK_CACHE_MANAGER.lock().set_capacity(block_device.device_id(), capacity);
 }
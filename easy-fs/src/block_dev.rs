@@ -1,10 +1,47 @@
 use core::any::Any;
 
+use crate::{error::Result, BLOCK_SZ};
+
 /// Trait for block devices
 /// which reads and writes data in the unit of blocks
+///
+/// A failed transfer is reported as `FsError::Io`. Only `device_id`,
+/// `read_block` and `write_block` are required, the rest have defaults
+/// built on them.
 pub trait BlockDevice: Send + Sync + Any {
+    /// Identifies the device to the block cache. Devices in use at the same
+    /// time must not share an id, which a device keeps while it is in use.
+    fn device_id(&self) -> usize;
     /// Read Data from block to buffer
-    fn read_block(&self, block_id: usize, buf: &mut [u8]);
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<()>;
     /// Write data from buffer to block
-    fn write_block(&self, block_id: usize, buf: &[u8]);
-}
\ No newline at end of file
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Result<()>;
+    /// Read consecutive blocks from `start_block_id` on, as many as `buf`
+    /// holds, which must be a whole number of blocks
+    fn read_blocks(&self, start_block_id: usize, buf: &mut [u8]) -> Result<()> {
+        assert_eq!(buf.len() % BLOCK_SZ, 0, "Not a whole number of blocks!");
+        for (i, block) in buf.chunks_mut(BLOCK_SZ).enumerate() {
+            self.read_block(start_block_id + i, block)?;
+        }
+        Ok(())
+    }
+    /// Write consecutive blocks from `start_block_id` on, as many as `buf`
+    /// holds, which must be a whole number of blocks
+    fn write_blocks(&self, start_block_id: usize, buf: &[u8]) -> Result<()> {
+        assert_eq!(buf.len() % BLOCK_SZ, 0, "Not a whole number of blocks!");
+        for (i, block) in buf.chunks(BLOCK_SZ).enumerate() {
+            self.write_block(start_block_id + i, block)?;
+        }
+        Ok(())
+    }
+    /// Barrier: the blocks written so far reach the medium before any
+    /// written afterwards. Nothing to do for a device without a volatile
+    /// write cache.
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+    /// Number of blocks of the device, `None` if it can not tell
+    fn num_blocks(&self) -> Option<usize> {
+        None
+    }
+}
@@ -0,0 +1,137 @@
+use alloc::{collections::BTreeMap, string::String};
+
+use crate::layout::DIRENT_SLOTS;
+
+/// Dirents kept indexed at once, over all the directories in the cache
+pub const DENTRY_CACHE_ENTRIES: usize = 4096;
+
+/// The dirents of a directory by name, and its runs of removed slots
+#[derive(Default)]
+pub struct DirIndex {
+    /// First slot, slots and inode id of each name
+    names: BTreeMap<String, (usize, usize, u32)>,
+    /// Slots of each run of removed slots by its first one. A run never
+    /// crosses a block, as a record must not.
+    free: BTreeMap<usize, usize>,
+}
+
+impl DirIndex {
+    /// First slot and inode id of the dirent `name`
+    pub fn get(&self, name: &str) -> Option<(usize, u32)> {
+        self.names.get(name).map(|(slot, _, inode_id)| (*slot, *inode_id))
+    }
+    /// The first run of removed slots holding `slots` of them, as its first
+    /// slot and its length
+    pub fn first_fit(&self, slots: usize) -> Option<(usize, usize)> {
+        self.free.iter().find(|(_, len)| **len >= slots).map(|(slot, len)| (*slot, *len))
+    }
+    /// Record the dirent `name` written over `slots` slots from `slot` on,
+    /// replacing what was there
+    pub fn insert(&mut self, name: &str, slot: usize, slots: usize, inode_id: u32) {
+        // split the run the dirent is taken from
+        if let Some((start, len)) = self.free.range(..slot + slots).next_back().map(|(start, len)| (*start, *len)) {
+            if start + len > slot {
+                self.free.remove(&start);
+                if start < slot {
+                    self.free.insert(start, slot - start);
+                }
+                if start + len > slot + slots {
+                    self.free.insert(slot + slots, start + len - slot - slots);
+                }
+            }
+        }
+        self.names.insert(String::from(name), (slot, slots, inode_id));
+    }
+    /// Record the dirent `name` as removed, returning the slots it leaves
+    pub fn remove(&mut self, name: &str) -> Option<(usize, usize)> {
+        let (slot, slots, _) = self.names.remove(name)?;
+        self.add_free(slot, slots);
+        Some((slot, slots))
+    }
+    /// Record removed slots, joined to the runs next to them in the block
+    pub fn add_free(&mut self, mut slot: usize, mut slots: usize) {
+        let block = slot / DIRENT_SLOTS;
+        if let Some((start, len)) = self.free.range(..slot).next_back().map(|(start, len)| (*start, *len)) {
+            if start + len == slot && start / DIRENT_SLOTS == block {
+                self.free.remove(&start);
+                slots += len;
+                slot = start;
+            }
+        }
+        if let Some(len) = self.free.get(&(slot + slots)).copied() {
+            if (slot + slots) / DIRENT_SLOTS == block {
+                self.free.remove(&(slot + slots));
+                slots += len;
+            }
+        }
+        self.free.insert(slot, slots);
+    }
+    fn len(&self) -> usize {
+        self.names.len() + self.free.len()
+    }
+}
+
+/// Indexes of the directories looked up recently, so that a lookup in a
+/// large directory does not read all its dirents every time.
+///
+/// An index mirrors what is on disk and can be dropped at any time, it is
+/// built again by a scan of the directory on the next lookup. Whoever
+/// modifies a dirent updates the index of its directory, if cached.
+#[derive(Default)]
+pub struct DentryCache {
+    /// Index of each directory by inode id, with the tick it was last used
+    dirs: BTreeMap<u32, (u64, DirIndex)>,
+    tick: u64,
+}
+
+impl DentryCache {
+    pub fn contains(&self, dir_id: u32) -> bool {
+        self.dirs.contains_key(&dir_id)
+    }
+    pub fn get_mut(&mut self, dir_id: u32) -> Option<&mut DirIndex> {
+        self.tick += 1;
+        let tick = self.tick;
+        self.dirs.get_mut(&dir_id).map(|(last_used, index)| {
+            *last_used = tick;
+            index
+        })
+    }
+    /// Cache the index of a directory, dropping the least recently used
+    /// ones beyond `DENTRY_CACHE_ENTRIES`; the new one is kept whatever
+    /// its size.
+    pub fn insert(&mut self, dir_id: u32, index: DirIndex) {
+        self.forget(dir_id);
+        self.evict(dir_id, index.len());
+        self.tick += 1;
+        self.dirs.insert(dir_id, (self.tick, index));
+    }
+    /// Bring the cache back within `DENTRY_CACHE_ENTRIES` after the index
+    /// of `dir_id` grew in place, by dropping the least recently used others
+    pub fn shrink(&mut self, dir_id: u32) {
+        self.evict(dir_id, 0);
+    }
+    /// Drop the least recently used indexes but that of `keep` until
+    /// `extra` more dirents fit
+    fn evict(&mut self, keep: u32, extra: usize) {
+        let mut entries: usize = self.dirs.values().map(|(_, index)| index.len()).sum();
+        while entries + extra > DENTRY_CACHE_ENTRIES {
+            let lru = self.dirs
+                .iter()
+                .filter(|(dir_id, _)| **dir_id != keep)
+                .min_by_key(|(_, (last_used, _))| *last_used)
+                .map(|(dir_id, _)| *dir_id);
+            match lru.and_then(|lru| self.dirs.remove(&lru)) {
+                Some((_, evicted)) => entries -= evicted.len(),
+                None => break,
+            }
+        }
+    }
+    /// Drop the index of a directory, for an inode given back
+    pub fn forget(&mut self, dir_id: u32) {
+        self.dirs.remove(&dir_id);
+    }
+    /// Drop every index, for dirents on disk changed behind the cache
+    pub fn clear(&mut self) {
+        self.dirs.clear();
+    }
+}
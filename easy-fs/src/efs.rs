@@ -1,7 +1,20 @@
-use alloc::sync::Arc;
+use alloc::{collections::BTreeSet, sync::Arc, vec, vec::Vec};
 use spin::Mutex;
 
-use crate::{bitmap::Bitmap, block_cache::get_block_cache, block_cache_sync_all, layout::{DiskInode, DiskInodeType, SuperBlock}, vfs::Inode, BlockDevice, DataBlock, BLOCK_SZ};
+use crate::{bitmap::{Bitmap, BLOCK_BITS}, error::{FsError, Result}, journal::{Journal, JOURNAL_BLOCKS, JOURNAL_CAPACITY}, block_cache::{dirty_block_caches, discard_block_caches, get_block_cache, release_block_caches, set_block_cache_capacity, sync_block_caches, BLOCK_CACHE_SIZE}, dcache::DentryCache, layout::{DirentFormat, DiskInode, DiskInodeType, SuperBlock, DIRENT_SLOTS, DIRENT_SZ, EFS_VERSION, KNOWN_FEATURES}, vfs::{Inode, OpenInodes, TXN_BLOCKS}, BlockDevice, DataBlock, BLOCK_SZ};
+
+/// Blocks zeroed by one write when an image is created
+const ZEROING_BLOCKS: usize = 64;
+
+/// When the blocks modified by an operation are made durable
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WritePolicy {
+    /// Every operation is committed as it ends
+    WriteThrough,
+    /// Operations are committed together by `sync`, or once the pending
+    /// ones take half of the journal, which any single one fits in
+    WriteBack,
+}
 
 pub struct EasyFileSystem {
     pub block_device: Arc<dyn BlockDevice>,
@@ -9,92 +22,263 @@ pub struct EasyFileSystem {
     pub data_bitmap: Bitmap,
     inode_area_start_block: u32,
     data_area_start_block: u32,
+    journal: Journal,
+    /// Seconds used to stamp inodes, no time passes unless set by the user
+    clock: fn() -> u32,
+    write_policy: WritePolicy,
+    /// A committed transaction dropped from the cache by `abort` could not
+    /// be read back, what is cached may be older than the image
+    stale: bool,
+    /// Indexes of the dirents of directories looked up recently
+    pub dentries: DentryCache,
+    /// Fixed by the features of the image
+    pub dirent_format: DirentFormat,
+    open_inodes: Arc<Mutex<OpenInodes>>,
+    /// Inodes whose last link is gone while some `Inode` still refers to
+    /// them, given back once none does
+    orphans: BTreeSet<u32>,
+}
+
+/// Usage of an image, as reported by `EasyFileSystem::stat`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct FsStat {
+    pub total_blocks: u32,
+    /// Blocks of the data area, the only ones files take
+    pub data_blocks: u32,
+    pub free_blocks: u32,
+    pub inodes: u32,
+    pub free_inodes: u32,
+    /// The max length of a dirent name
+    pub name_limit: u32,
+    /// Blocks modified in the cache and not made durable yet
+    pub dirty_blocks: u32,
+}
+
+fn frozen_clock() -> u32 {
+    0
 }
 
 impl EasyFileSystem {
+    /// Create an image with every feature, see `create_with_features`
     pub fn create (
         block_device: Arc<dyn BlockDevice>,
         total_blocks: u32,
         inode_bitmap_blocks: u32,
-    ) -> Arc<Mutex<Self>> {
+    ) -> Result<Arc<Mutex<Self>>> {
+        Self::create_with_features(block_device, total_blocks, inode_bitmap_blocks, KNOWN_FEATURES)
+    }
+    /// Create an image with the `FEATURE_*` flags `features` only, which
+    /// older versions of easy-fs may still mount
+    pub fn create_with_features(
+        block_device: Arc<dyn BlockDevice>,
+        total_blocks: u32,
+        inode_bitmap_blocks: u32,
+        features: u32,
+    ) -> Result<Arc<Mutex<Self>>> {
+        if inode_bitmap_blocks == 0 || features & !KNOWN_FEATURES != 0 {
+            return Err(FsError::Invalid);
+        }
         // calculate block size of areas & create bitmaps
-        let inode_bitmap = Bitmap::new(1, inode_bitmap_blocks as usize);
-        let inode_num = inode_bitmap.maximum();
+        let inode_num = inode_bitmap_blocks as usize * BLOCK_BITS;
+        let inode_bitmap = Bitmap::new(1, inode_bitmap_blocks as usize, inode_num);
         let inode_area_blocks = 
             ((inode_num * core::mem::size_of::<DiskInode>() + BLOCK_SZ - 1) / BLOCK_SZ) as u32;
         let inode_total_blocks = inode_bitmap_blocks + inode_area_blocks;
-        let data_total_blocks = total_blocks - 1 - inode_total_blocks;
+        // a data bitmap block and a block for the root directory at least
+        if total_blocks < 1 + inode_total_blocks + JOURNAL_BLOCKS + 2
+            || block_device.num_blocks().map_or(false, |blocks| total_blocks as usize > blocks) {
+            return Err(FsError::NoSpace);
+        }
+        let data_total_blocks = total_blocks - 1 - inode_total_blocks - JOURNAL_BLOCKS;
         let data_bitmap_blocks = (data_total_blocks + 4096) / 4097;
         let data_area_blocks = data_total_blocks - data_bitmap_blocks;
+        // the journal sits between the inode area and the data bitmap
+        let journal_start_block = 1 + inode_total_blocks;
         let data_bitmap = Bitmap::new(
-            (1 + inode_total_blocks) as usize,
+            (journal_start_block + JOURNAL_BLOCKS) as usize,
             data_bitmap_blocks as usize,
+            data_area_blocks as usize,
         );
         let mut efs = Self {
             block_device: Arc::clone(&block_device),
             inode_bitmap,
             data_bitmap,
             inode_area_start_block: 1 + inode_bitmap_blocks,
-            data_area_start_block: 1 + inode_total_blocks + data_bitmap_blocks,
+            data_area_start_block: journal_start_block + JOURNAL_BLOCKS + data_bitmap_blocks,
+            journal: Journal::new(journal_start_block, total_blocks),
+            clock: frozen_clock,
+            write_policy: WritePolicy::WriteThrough,
+            stale: false,
+            dentries: DentryCache::default(),
+            dirent_format: DirentFormat::of(features),
+            open_inodes: Arc::default(),
+            orphans: BTreeSet::new(),
         };
-        // clear all blocks
-        for i in 0..total_blocks {
-            get_block_cache(i as usize, Arc::clone(&block_device))
-            .lock()
-            .modify(0, |data_block: &mut DataBlock| {
-                for byte in data_block.iter_mut() {
-                    *byte = 0;
-                }
-            });
+        // clear all blocks on the device, what was cached of it is stale then
+        release_block_caches(&block_device);
+        let zeros = vec![0u8; ZEROING_BLOCKS * BLOCK_SZ];
+        for start in (0..total_blocks as usize).step_by(ZEROING_BLOCKS) {
+            let blocks = ZEROING_BLOCKS.min(total_blocks as usize - start);
+            block_device.write_blocks(start, &zeros[..blocks * BLOCK_SZ])?;
         }
         // initialize SuperBlock
-        get_block_cache(0, Arc::clone(&block_device))
+        get_block_cache(0, Arc::clone(&block_device))?
         .lock()
         .modify(0, |super_block: &mut SuperBlock| {
             super_block.initialize(
                 total_blocks,
                 inode_bitmap_blocks,
                 inode_area_blocks,
+                JOURNAL_BLOCKS,
                 data_bitmap_blocks,
-                data_area_blocks
+                data_area_blocks,
+                features,
             );
         });
         // write back immediately
-        // create an inode for root node "/"
-        assert_eq!(efs.alloc_inode(), 0);
+        // create an inode for root node "/", whose ".." points to itself
+        assert_eq!(efs.alloc_inode()?, 0);
         let (root_inode_block_id, root_inode_offset) = efs.get_disk_inode_pos(0);
+        let format = efs.dirent_format;
+        let root_size = DIRENT_SZ * match format {
+            DirentFormat::Fixed => 2,
+            DirentFormat::Records => DIRENT_SLOTS,
+        };
         get_block_cache(
             root_inode_block_id as usize,
             Arc::clone(&block_device),
-        )
+        )?
         .lock()
         .modify(root_inode_offset, |disk_inode: &mut DiskInode| {
-            disk_inode.initialize(DiskInodeType::Directory);
-        });
-        block_cache_sync_all();
-        Arc::new(Mutex::new(efs))
+            disk_inode.initialize(DiskInodeType::Directory, efs.now());
+            disk_inode.increase_size(root_size as u32)?;
+            disk_inode.alloc_blocks(0, root_size, || efs.alloc_data(), &block_device)?;
+            disk_inode.write_at(0, &format.encode(".", 0, 1, 0), &block_device)?;
+            disk_inode.write_at(DIRENT_SZ, &format.encode("..", 0, 1, root_size / DIRENT_SZ - 2), &block_device)?;
+            Ok(())
+        })?;
+        sync_block_caches(&block_device)?;
+        block_device.flush()?;
+        Ok(Arc::new(Mutex::new(efs)))
+    }
+    pub fn alloc_inode(&mut self) -> Result<u32> {
+        let inode_id = self.inode_bitmap.alloc(&self.block_device)?.ok_or(FsError::NoSpace)?;
+        self.modify_super_block(|super_block| super_block.free_inodes -= 1)?;
+        Ok(inode_id as u32)
     }
-    pub fn alloc_inode(&mut self) -> u32 {
-        self.inode_bitmap.alloc(&self.block_device).unwrap() as u32
+    /// Give back an inode whose last link is gone
+    pub fn dealloc_inode(&mut self, inode_id: u32) -> Result<()> {
+        self.dentries.forget(inode_id);
+        self.inode_bitmap.dealloc(&self.block_device, inode_id as usize)?;
+        self.modify_super_block(|super_block| super_block.free_inodes += 1)
+    }
+    /// Release the data and then the inode `inode_id`, whose last link is
+    /// gone, unless some `Inode` still refers to it: it is an orphan then,
+    /// left to `release_orphans`.
+    ///
+    /// At most `TXN_BLOCKS` data blocks are released per transaction, a
+    /// crash leaves an unlinked inode holding what is not released yet.
+    pub fn release_inode(&mut self, inode_id: u32) -> Result<()> {
+        if self.open_inodes.lock().is_open(inode_id) {
+            self.orphans.insert(inode_id);
+            return Ok(());
+        }
+        let (block_id, block_offset) = self.get_disk_inode_pos(inode_id);
+        let block_device = Arc::clone(&self.block_device);
+        loop {
+            let (size, data_blocks) = get_block_cache(block_id as usize, Arc::clone(&block_device))?
+                .lock()
+                .modify(block_offset, |disk_inode: &mut DiskInode| {
+                    let size = disk_inode.size.saturating_sub((TXN_BLOCKS * BLOCK_SZ) as u32);
+                    Ok::<_, FsError>((size, disk_inode.decrease_size(size, &block_device)?))
+                })?;
+            for data_block in data_blocks {
+                self.dealloc_data(data_block)?;
+            }
+            if size == 0 {
+                break;
+            }
+            self.commit()?;
+        }
+        self.dealloc_inode(inode_id)?;
+        self.orphans.remove(&inode_id);
+        self.commit()
+    }
+    /// Give back the orphans no `Inode` refers to any more
+    pub fn release_orphans(&mut self) -> Result<()> {
+        let closed: Vec<u32> = {
+            let open_inodes = self.open_inodes.lock();
+            self.orphans.iter().copied().filter(|inode_id| !open_inodes.is_open(*inode_id)).collect()
+        };
+        for inode_id in closed {
+            self.release_inode(inode_id)?;
+        }
+        Ok(())
     }
     /// Return a block ID not 'ID in the data area'.
-    pub fn alloc_data(&mut self) -> u32 {
-        self.data_bitmap.alloc(&self.block_device).unwrap() as u32 + self.data_area_start_block
+    ///
+    /// The block is zeroed here rather than when freed, so that freeing
+    /// only touches the bitmap.
+    pub fn alloc_data(&mut self) -> Result<u32> {
+        let (block_id, _) = self.alloc_data_run(None, 1)?;
+        self.zero_data(block_id)?;
+        Ok(block_id)
     }
-    /// dealloc a block_id
-    pub fn dealloc_data(&mut self, block_id: u32) {
+    /// Take up to `n` data blocks in a row, from the block `goal` on if
+    /// there is room, returning the first block ID and how many were taken.
+    ///
+    /// Unlike `alloc_data` the blocks are left as they are: each is zeroed
+    /// by `zero_data` when put to use, the ones left over given back.
+    pub fn alloc_data_run(&mut self, goal: Option<u32>, n: usize) -> Result<(u32, usize)> {
+        let goal = goal
+            .filter(|goal| *goal >= self.data_area_start_block)
+            .map(|goal| (goal - self.data_area_start_block) as usize);
+        let (first, len) = self.data_bitmap
+            .alloc_contiguous(&self.block_device, goal, n)?
+            .ok_or(FsError::NoSpace)?;
+        self.modify_super_block(|super_block| super_block.free_blocks -= len as u32)?;
+        Ok((first as u32 + self.data_area_start_block, len))
+    }
+    /// Zero a data block taken by `alloc_data_run`
+    pub fn zero_data(&self, block_id: u32) -> Result<()> {
         get_block_cache(
             block_id as usize,
             Arc::clone(&self.block_device)
-        )
+        )?
         .lock()
         .modify(0, |data_block: &mut DataBlock| {
             data_block.iter_mut().for_each(|p| {*p = 0;})
         });
+        Ok(())
+    }
+    /// dealloc a block_id
+    pub fn dealloc_data(&mut self, block_id: u32) -> Result<()> {
         self.data_bitmap.dealloc(
             &self.block_device, 
             (block_id - self.data_area_start_block) as usize,
-        );
+        )?;
+        self.modify_super_block(|super_block| super_block.free_blocks += 1)
+    }
+    /// Update the free counters, in the same transaction as the bitmaps
+    fn modify_super_block(&self, f: impl FnOnce(&mut SuperBlock)) -> Result<()> {
+        get_block_cache(0, Arc::clone(&self.block_device))?.lock().modify(0, f);
+        Ok(())
+    }
+    /// Usage of the image, from the free counters
+    pub fn stat(&self) -> Result<FsStat> {
+        let name_limit = self.name_limit() as u32;
+        let dirty_blocks = dirty_block_caches(&self.block_device).len() as u32;
+        Ok(get_block_cache(0, Arc::clone(&self.block_device))?
+            .lock()
+            .read(0, |super_block: &SuperBlock| FsStat {
+                total_blocks: super_block.total_blocks,
+                data_blocks: super_block.data_area_blocks,
+                free_blocks: super_block.free_blocks,
+                inodes: self.inode_bitmap.maximum() as u32,
+                free_inodes: super_block.free_inodes,
+                name_limit,
+                dirty_blocks,
+            }))
     }
     /// inner inode id
     pub fn get_disk_inode_pos(&self, inode_id: u32) -> (u32, usize) {
@@ -106,40 +290,205 @@ impl EasyFileSystem {
     pub fn get_data_block_id(&self, data_block_id: u32) -> u32 {
         self.data_area_start_block + data_block_id
     }
-    pub fn open(block_device: Arc<dyn BlockDevice>) -> Arc<Mutex<Self>> {
+    /// Open an image with a block cache of `BLOCK_CACHE_SIZE` blocks, see
+    /// `open_with_cache`
+    pub fn open(block_device: Arc<dyn BlockDevice>) -> Result<Arc<Mutex<Self>>> {
+        Self::open_with_cache(block_device, BLOCK_CACHE_SIZE)
+    }
+    /// Open an image keeping up to `cache_blocks` of its blocks cached, not
+    /// counting the ones in use or modified, which can not be evicted
+    pub fn open_with_cache(block_device: Arc<dyn BlockDevice>, cache_blocks: usize) -> Result<Arc<Mutex<Self>>> {
+        set_block_cache_capacity(&block_device, cache_blocks);
         // read SuperBlock
-        get_block_cache(0, Arc::clone(&block_device))
+        let efs = get_block_cache(0, Arc::clone(&block_device))?
             .lock()
             .read(0, |super_block: &SuperBlock| {
-                assert!(super_block.is_valid(), "Error loading EFS!");
+                if !super_block.is_valid() {
+                    return Err(FsError::Corrupt);
+                }
+                if block_device.num_blocks().map_or(false, |blocks| super_block.total_blocks as usize > blocks) {
+                    // cut off, the tail of the image is missing
+                    return Err(FsError::Corrupt);
+                }
+                let journal_blocks = match super_block.version() {
+                    // unversioned, with 28 direct blocks and no indirect3,
+                    // which reads as garbage block ids here
+                    0 => return Err(FsError::Unsupported),
+                    // the layout of now without the journal, its dirents
+                    // fixed as `features` reads 0
+                    1 => 0,
+                    EFS_VERSION => JOURNAL_BLOCKS,
+                    _ => return Err(FsError::Unsupported),
+                };
+                if super_block.journal_blocks != journal_blocks {
+                    return Err(FsError::Corrupt);
+                }
+                if super_block.features() & !KNOWN_FEATURES != 0 {
+                    return Err(FsError::Unsupported);
+                }
                 let inode_total_blocks = 
                     super_block.inode_bitmap_blocks + super_block.inode_area_blocks;
-                let efs = Self {
-                    block_device,
+                let journal_start_block = 1 + inode_total_blocks;
+                let data_bitmap_start_block = journal_start_block + journal_blocks;
+                Ok(Self {
+                    block_device: Arc::clone(&block_device),
                     inode_bitmap: Bitmap::new(
                         1,
-                        super_block.inode_bitmap_blocks as usize
+                        super_block.inode_bitmap_blocks as usize,
+                        super_block.inode_bitmap_blocks as usize * BLOCK_BITS,
                     ),
                     data_bitmap: Bitmap::new(
-                        (1 + inode_total_blocks) as usize,
+                        data_bitmap_start_block as usize,
                         super_block.data_bitmap_blocks as usize,
+                        super_block.data_area_blocks as usize,
                     ),
                     inode_area_start_block: 1 + super_block.inode_bitmap_blocks,
-                    data_area_start_block: 1 + inode_total_blocks + super_block.data_bitmap_blocks,
-                };
-                Arc::new(Mutex::new(efs))
-            })
+                    data_area_start_block: data_bitmap_start_block + super_block.data_bitmap_blocks,
+                    journal: match journal_blocks {
+                        0 => Journal::in_place(),
+                        _ => Journal::new(journal_start_block, super_block.total_blocks),
+                    },
+                    clock: frozen_clock,
+                    write_policy: WritePolicy::WriteThrough,
+                    stale: false,
+                    dentries: DentryCache::default(),
+                    dirent_format: DirentFormat::of(super_block.features()),
+                    open_inodes: Arc::default(),
+                    orphans: BTreeSet::new(),
+                })
+            });
+        let efs = efs.inspect_err(|_| release_block_caches(&block_device))?;
+        // finish the transaction a crash may have cut off, the fs releases
+        // its blocks when dropped on failure
+        efs.journal.replay(&efs.block_device)?;
+        // counters some other version failed to keep are set right
+        let free_inodes = (efs.inode_bitmap.maximum() - efs.inode_bitmap.count(&efs.block_device)?) as u32;
+        let free_blocks = (efs.data_bitmap.maximum() - efs.data_bitmap.count(&efs.block_device)?) as u32;
+        let stat = efs.stat()?;
+        if (stat.free_inodes, stat.free_blocks) != (free_inodes, free_blocks) {
+            efs.modify_super_block(|super_block| {
+                super_block.free_inodes = free_inodes;
+                super_block.free_blocks = free_blocks;
+            })?;
+            efs.sync()?;
+        }
+        Ok(Arc::new(Mutex::new(efs)))
+    }
+    /// End an operation modifying the fs, whose blocks are made durable as
+    /// a whole now or later depending on the write policy
+    pub fn commit(&self) -> Result<()> {
+        match self.write_policy {
+            WritePolicy::WriteThrough => self.sync(),
+            WritePolicy::WriteBack => {
+                if dirty_block_caches(&self.block_device).len() > JOURNAL_CAPACITY / 2 {
+                    self.sync()?;
+                }
+                Ok(())
+            }
+        }
+    }
+    /// Make every operation ended so far durable
+    pub fn sync(&self) -> Result<()> {
+        self.journal.commit(&self.block_device)
+    }
+    /// Whether more blocks are modified than one transaction can commit,
+    /// which leaves `abort` as the way out
+    pub(crate) fn outgrows_journal(&self) -> bool {
+        self.journal.is_logged() && dirty_block_caches(&self.block_device).len() > JOURNAL_CAPACITY
+    }
+    /// Drop what was modified since the last commit, for an operation cut
+    /// off half way by an I/O error. The fs is left as a crash would leave
+    /// it, so under `WritePolicy::WriteBack` the operations ended since the
+    /// last sync are lost as well.
+    pub fn abort(&mut self) {
+        discard_block_caches(&self.block_device);
+        self.dentries.clear();
+        // a committed transaction may have been dropped along
+        self.stale = self.journal.is_unfinished();
+        let _ = self.recover();
+    }
+    /// Read back what `abort` dropped of a committed transaction, to be
+    /// done before the fs is used again
+    pub fn recover(&mut self) -> Result<()> {
+        if self.stale {
+            self.journal.replay(&self.block_device)?;
+            self.stale = false;
+        }
+        Ok(())
+    }
+    /// The max length of a dirent name on this image
+    pub fn name_limit(&self) -> usize {
+        self.dirent_format.name_limit()
+    }
+    pub fn write_policy(&self) -> WritePolicy {
+        self.write_policy
+    }
+    /// Set the write policy, `WriteThrough` unless set
+    pub fn set_write_policy(&mut self, write_policy: WritePolicy) -> Result<()> {
+        self.write_policy = write_policy;
+        if write_policy == WritePolicy::WriteThrough {
+            self.sync()?;
+        }
+        Ok(())
+    }
+    /// Set the time source for inode timestamps
+    pub fn set_clock(&mut self, clock: fn() -> u32) {
+        self.clock = clock;
+    }
+    pub fn now(&self) -> u32 {
+        (self.clock)()
     }
     pub fn root_inode(efs: &Arc<Mutex<Self>>) -> Inode {
         let block_device = Arc::clone(&efs.lock().block_device);
+        let open_inodes = Arc::clone(&efs.lock().open_inodes);
         // acquire efs lock temporarily
         let (block_id, block_offset) = efs.lock().get_disk_inode_pos(0);
         Inode::new(
+            0,
             block_id,
             block_offset,
             Arc::clone(efs),
             block_device,
+            open_inodes,
         )
     }
+    /// The inode `inode_id` whatever refers to it, for inspecting an image;
+    /// `FsError::NotFound` unless it is taken
+    pub fn get_inode(efs: &Arc<Mutex<Self>>, inode_id: u32) -> Result<Inode> {
+        let fs = efs.lock();
+        if inode_id as usize >= fs.inode_bitmap.maximum()
+            || !fs.inode_bitmap.get(&fs.block_device, inode_id as usize)? {
+            return Err(FsError::NotFound);
+        }
+        let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
+        Ok(Inode::new(
+            inode_id,
+            block_id,
+            block_offset,
+            Arc::clone(efs),
+            Arc::clone(&fs.block_device),
+            Arc::clone(&fs.open_inodes),
+        ))
+    }
+    /// A copy of the super block as it is now
+    pub fn super_block(&self) -> Result<SuperBlock> {
+        Ok(get_block_cache(0, Arc::clone(&self.block_device))?
+            .lock()
+            .read(0, |super_block: &SuperBlock| *super_block))
+    }
+}
 
+impl Drop for EasyFileSystem {
+    /// Unmount: what the write policy left pending is made durable and the
+    /// blocks of the device leave the cache
+    fn drop(&mut self) {
+        // nothing is left to report a failure to, what is lost is lost
+        // as in a crash; no `Inode` is left to keep an orphan
+        if self.recover().and_then(|()| self.release_orphans()) == Err(FsError::Io) {
+            self.abort();
+        }
+        let _ = self.sync();
+        discard_block_caches(&self.block_device);
+        release_block_caches(&self.block_device);
+    }
 }
@@ -0,0 +1,60 @@
+use core::fmt;
+
+/// Errors of easy-fs operations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    /// No free inode or data block is left
+    NoSpace,
+    /// A name longer than `NAME_LENGTH_LIMIT`
+    NameTooLong,
+    /// A name that can not be a dirent, such as `.` or one with a `/`
+    InvalidName,
+    /// No entry of the name
+    NotFound,
+    /// A directory was expected
+    NotDir,
+    /// A regular file was expected
+    IsDir,
+    /// A directory to remove still has entries
+    NotEmpty,
+    /// An entry of the name already exists
+    Exists,
+    /// An operation that makes no sense, such as moving a directory below itself
+    Invalid,
+    /// Inodes of two different file systems
+    CrossDevice,
+    /// A size beyond `MAX_FILE_SIZE`
+    FileTooLarge,
+    /// An image of another layout version
+    Unsupported,
+    /// What is read from the image makes no sense
+    Corrupt,
+    /// The block device failed
+    Io,
+    /// In use, such as a mount point or a file system with files open
+    Busy,
+}
+
+pub type Result<T> = core::result::Result<T, FsError>;
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            FsError::NoSpace => "no space left on the image",
+            FsError::NameTooLong => "file name too long",
+            FsError::InvalidName => "invalid file name",
+            FsError::NotFound => "no such file or directory",
+            FsError::NotDir => "not a directory",
+            FsError::IsDir => "is a directory",
+            FsError::NotEmpty => "directory not empty",
+            FsError::Exists => "file exists",
+            FsError::Invalid => "invalid argument",
+            FsError::CrossDevice => "cross-device link",
+            FsError::FileTooLarge => "file too large",
+            FsError::Unsupported => "unsupported layout version, repack the image",
+            FsError::Corrupt => "corrupted image",
+            FsError::Io => "block device I/O error",
+            FsError::Busy => "device or resource busy",
+        })
+    }
+}
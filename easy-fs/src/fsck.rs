@@ -0,0 +1,526 @@
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec, vec::Vec};
+use core::fmt;
+
+use crate::{
+    bitmap::{Bitmap, BLOCK_BITS},
+    block_cache::{discard_block_caches, get_block_cache, release_block_caches, sync_block_caches},
+    error::{FsError, Result},
+    journal::{Journal, JOURNAL_BLOCKS},
+    layout::{
+        Dirent, DirentFormat, DiskInode, SuperBlock, DIRECT_BOUND, DIRENT_SZ, EFS_VERSION, KNOWN_FEATURES,
+        INDIRECT1_BOUND, INDIRECT2_BOUND, INODE_INDIRECT1_COUNT,
+    },
+    BlockDevice, DataBlock, BLOCK_SZ,
+};
+
+const INODE_SZ: usize = core::mem::size_of::<DiskInode>();
+
+type IndirectBlock = [u32; INODE_INDIRECT1_COUNT];
+
+/// An inconsistency found by `fsck`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsckProblem {
+    /// Not an easy-fs image of this version, or its areas do not add up;
+    /// nothing else is checked
+    BadSuperBlock(&'static str),
+    /// The root inode is not an allocated directory, nothing else is checked
+    BadRoot,
+    /// A committed transaction was never written home
+    PendingJournal { blocks: usize },
+    /// The journal header logs more blocks than the journal holds, or
+    /// blocks outside the image
+    BadJournal,
+    /// A dirent whose name is not terminated or not UTF-8
+    BadDirentName { dir: u32, index: usize },
+    /// A dirent referring to an inode out of range or not allocated
+    DanglingDirent { dir: u32, name: String, inode_id: u32 },
+    /// An inode of no known type
+    BadInodeType { inode_id: u32 },
+    /// An inode pointing to a block outside the data area
+    BadBlockPointer { inode_id: u32, block_id: u32 },
+    /// A block pointed to a second time, by the same inode or another
+    DoublyReferencedBlock { inode_id: u32, block_id: u32 },
+    /// An allocated inode no dirent leads to
+    OrphanInode { inode_id: u32 },
+    /// An inode whose link count differs from the dirents referring to it
+    WrongLinkCount { inode_id: u32, nlink: u32, links: u32 },
+    /// A block in use but free in the data bitmap
+    UnmarkedBlock { block_id: u32 },
+    /// A block taken in the data bitmap but used by no inode
+    LeakedBlock { block_id: u32 },
+    /// The free inode counter of the super block differs from the inodes
+    /// left free once repaired
+    WrongFreeInodes { free_inodes: u32, counted: u32 },
+    /// The free block counter of the super block, likewise
+    WrongFreeBlocks { free_blocks: u32, counted: u32 },
+}
+
+impl fmt::Display for FsckProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FsckProblem::BadSuperBlock(reason) => write!(f, "bad super block: {}", reason),
+            FsckProblem::BadRoot => write!(f, "root inode is not an allocated directory"),
+            FsckProblem::PendingJournal { blocks } => {
+                write!(f, "journal holds a committed transaction of {} blocks", blocks)
+            }
+            FsckProblem::BadJournal => write!(f, "journal header is corrupted"),
+            FsckProblem::BadDirentName { dir, index } => {
+                write!(f, "dirent {} of directory {} has a bad name", index, dir)
+            }
+            FsckProblem::DanglingDirent { dir, name, inode_id } => write!(
+                f, "dirent {:?} of directory {} refers to free inode {}", name, dir, inode_id
+            ),
+            FsckProblem::BadInodeType { inode_id } => {
+                write!(f, "inode {} has an unknown type", inode_id)
+            }
+            FsckProblem::BadBlockPointer { inode_id, block_id } => write!(
+                f, "inode {} points to block {} outside the data area", inode_id, block_id
+            ),
+            FsckProblem::DoublyReferencedBlock { inode_id, block_id } => write!(
+                f, "inode {} points to block {} already in use", inode_id, block_id
+            ),
+            FsckProblem::OrphanInode { inode_id } => {
+                write!(f, "inode {} is allocated but unreachable", inode_id)
+            }
+            FsckProblem::WrongLinkCount { inode_id, nlink, links } => write!(
+                f, "inode {} has link count {} but {} dirents", inode_id, nlink, links
+            ),
+            FsckProblem::UnmarkedBlock { block_id } => {
+                write!(f, "block {} is in use but free in the bitmap", block_id)
+            }
+            FsckProblem::LeakedBlock { block_id } => {
+                write!(f, "block {} is allocated but unused", block_id)
+            }
+            FsckProblem::WrongFreeInodes { free_inodes, counted } => write!(
+                f, "super block counts {} free inodes but {} are free", free_inodes, counted
+            ),
+            FsckProblem::WrongFreeBlocks { free_blocks, counted } => write!(
+                f, "super block counts {} free blocks but {} are free", free_blocks, counted
+            ),
+        }
+    }
+}
+
+/// What `fsck` found, and whether it was repaired
+pub struct FsckReport {
+    pub problems: Vec<FsckProblem>,
+    /// Every problem was repaired, false if any was left or can not be
+    pub repaired: bool,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Check an easy-fs image that is not in use, walking from the root inode,
+/// and repair what was found if `repair` is set.
+///
+/// Every block id is checked before it is read, so that a corrupted image
+/// is reported rather than crashing the checker. Repairs drop what can not
+/// be trusted: bad dirents are removed, bad block pointers become holes,
+/// unreachable inodes are freed and the bitmaps follow what is in use.
+///
+/// An error means the device failed, and the check was given up.
+pub fn fsck(block_device: &Arc<dyn BlockDevice>, repair: bool) -> Result<FsckReport> {
+    let report = check(block_device, repair);
+    // repairs not written back are of no use once the check failed
+    discard_block_caches(block_device);
+    release_block_caches(block_device);
+    report
+}
+
+fn check(block_device: &Arc<dyn BlockDevice>, repair: bool) -> Result<FsckReport> {
+    let checker = get_block_cache(0, Arc::clone(block_device))?
+        .lock()
+        .read(0, |super_block: &SuperBlock| {
+            Checker::check_super_block(super_block)?;
+            Ok(Checker::new(block_device, repair, super_block))
+        });
+    let mut checker = match checker {
+        Ok(checker) => checker,
+        Err(problem) => {
+            return Ok(FsckReport { problems: vec![problem], repaired: false });
+        }
+    };
+    checker.check_journal()?;
+    if !checker.check_root()? {
+        checker.problems.push(FsckProblem::BadRoot);
+        return Ok(FsckReport { problems: checker.problems, repaired: false });
+    }
+    checker.check_tree()?;
+    checker.check_inodes()?;
+    checker.check_data_bitmap()?;
+    checker.check_free_counts()?;
+    if repair {
+        sync_block_caches(block_device)?;
+        block_device.flush()?;
+    }
+    Ok(FsckReport {
+        repaired: repair || checker.problems.is_empty(),
+        problems: checker.problems,
+    })
+}
+
+struct Checker {
+    block_device: Arc<dyn BlockDevice>,
+    repair: bool,
+    problems: Vec<FsckProblem>,
+    inode_count: usize,
+    inode_bitmap: Bitmap,
+    inode_area_start_block: u32,
+    journal: Journal,
+    data_bitmap: Bitmap,
+    data_area_start_block: u32,
+    data_area_blocks: u32,
+    dirent_format: DirentFormat,
+    /// Free counters of the super block
+    free_inodes: u32,
+    free_blocks: u32,
+    /// Inodes reached from the root
+    visited: Vec<bool>,
+    /// Link count stored in each visited inode
+    nlink: Vec<u32>,
+    /// Dirents referring to each inode
+    links: Vec<u32>,
+    /// Data area blocks pointed to by a visited inode
+    used: Vec<bool>,
+    /// Directories visited but not scanned yet, with their size and blocks
+    dirs: Vec<(u32, usize, BTreeMap<usize, u32>)>,
+}
+
+impl Checker {
+    /// For a super block passing `check_super_block`
+    fn new(block_device: &Arc<dyn BlockDevice>, repair: bool, super_block: &SuperBlock) -> Self {
+        let total_blocks = super_block.total_blocks;
+        let inode_bitmap_blocks = super_block.inode_bitmap_blocks;
+        let inode_area_blocks = super_block.inode_area_blocks;
+        let data_bitmap_blocks = super_block.data_bitmap_blocks;
+        let data_area_blocks = super_block.data_area_blocks;
+        let inode_count = inode_bitmap_blocks as usize * BLOCK_BITS;
+        let journal_start_block = 1 + inode_bitmap_blocks + inode_area_blocks;
+        let data_bitmap_start_block = journal_start_block + super_block.journal_blocks;
+        Self {
+            block_device: Arc::clone(block_device),
+            repair,
+            problems: Vec::new(),
+            inode_count,
+            inode_bitmap: Bitmap::new(1, inode_bitmap_blocks as usize, inode_count),
+            inode_area_start_block: 1 + inode_bitmap_blocks,
+            journal: match super_block.journal_blocks {
+                0 => Journal::in_place(),
+                _ => Journal::new(journal_start_block, total_blocks),
+            },
+            data_bitmap: Bitmap::new(
+                data_bitmap_start_block as usize,
+                data_bitmap_blocks as usize,
+                data_area_blocks as usize,
+            ),
+            data_area_start_block: data_bitmap_start_block + data_bitmap_blocks,
+            data_area_blocks,
+            dirent_format: DirentFormat::of(super_block.features()),
+            free_inodes: super_block.free_inodes,
+            free_blocks: super_block.free_blocks,
+            visited: vec![false; inode_count],
+            nlink: vec![0; inode_count],
+            links: vec![0; inode_count],
+            used: vec![false; data_area_blocks as usize],
+            dirs: Vec::new(),
+        }
+    }
+    fn check_super_block(super_block: &SuperBlock) -> core::result::Result<(), FsckProblem> {
+        let bad = |reason| Err(FsckProblem::BadSuperBlock(reason));
+        if !super_block.is_valid() {
+            return bad("not an easy-fs image");
+        }
+        let journal_blocks = match super_block.version() {
+            1 => 0,
+            EFS_VERSION => JOURNAL_BLOCKS,
+            _ => return bad("unsupported layout version"),
+        };
+        if super_block.features() & !KNOWN_FEATURES != 0 {
+            return bad("unsupported features");
+        }
+        if super_block.journal_blocks != journal_blocks {
+            return bad("unexpected journal size");
+        }
+        if super_block.inode_bitmap_blocks == 0 {
+            return bad("no inode bitmap");
+        }
+        let inodes = super_block.inode_bitmap_blocks as usize * BLOCK_BITS;
+        if super_block.inode_area_blocks as usize != (inodes * INODE_SZ).div_ceil(BLOCK_SZ) {
+            return bad("inode area does not match the inode bitmap");
+        }
+        if (super_block.data_bitmap_blocks as usize * BLOCK_BITS) < super_block.data_area_blocks as usize {
+            return bad("data bitmap too small for the data area");
+        }
+        let areas = [
+            1,
+            super_block.inode_bitmap_blocks,
+            super_block.inode_area_blocks,
+            super_block.journal_blocks,
+            super_block.data_bitmap_blocks,
+            super_block.data_area_blocks,
+        ];
+        if areas.iter().map(|blocks| *blocks as u64).sum::<u64>() != super_block.total_blocks as u64 {
+            return bad("areas do not add up to the total blocks");
+        }
+        Ok(())
+    }
+    /// A committed transaction is installed on repair, as `open` would do
+    fn check_journal(&mut self) -> Result<()> {
+        let pending = match self.journal.pending(&self.block_device) {
+            Ok(pending) if pending.is_empty() => return Ok(()),
+            Ok(pending) => pending,
+            Err(FsError::Corrupt) => {
+                self.problems.push(FsckProblem::BadJournal);
+                if self.repair {
+                    self.journal.discard(&self.block_device)?;
+                }
+                return Ok(());
+            }
+            Err(error) => return Err(error),
+        };
+        self.problems.push(FsckProblem::PendingJournal { blocks: pending.len() });
+        if self.repair {
+            self.journal.replay(&self.block_device)?;
+        }
+        Ok(())
+    }
+    fn inode_pos(&self, inode_id: u32) -> (usize, usize) {
+        let inodes_per_block = (BLOCK_SZ / INODE_SZ) as u32;
+        let block_id = self.inode_area_start_block + inode_id / inodes_per_block;
+        (block_id as usize, (inode_id % inodes_per_block) as usize * INODE_SZ)
+    }
+    /// Whether an inode is allocated and of a known type
+    fn is_valid_inode(&self, inode_id: u32) -> Result<bool> {
+        let (block_id, offset) = self.inode_pos(inode_id);
+        Ok(self.inode_bitmap.get(&self.block_device, inode_id as usize)?
+            && get_block_cache(block_id, Arc::clone(&self.block_device))?
+                .lock()
+                .read(offset, |raw: &[u8; INODE_SZ]| DiskInode::is_valid_raw(raw)))
+    }
+    fn check_root(&mut self) -> Result<bool> {
+        if !self.is_valid_inode(0)? {
+            return Ok(false);
+        }
+        let (block_id, offset) = self.inode_pos(0);
+        Ok(get_block_cache(block_id, Arc::clone(&self.block_device))?
+            .lock()
+            .read(offset, |disk_inode: &DiskInode| disk_inode.is_dir()))
+    }
+    /// Visit every inode reachable from the root. The root links itself
+    /// through its `.` and `..`, like any directory through its `.`.
+    fn check_tree(&mut self) -> Result<()> {
+        self.visit(0)?;
+        while let Some((dir, size, blocks)) = self.dirs.pop() {
+            self.scan_dir(dir, size, &blocks)?;
+        }
+        Ok(())
+    }
+    /// Check the blocks of an inode reached for the first time, and queue it
+    /// if it is a directory
+    fn visit(&mut self, inode_id: u32) -> Result<()> {
+        self.visited[inode_id as usize] = true;
+        let (block_id, offset) = self.inode_pos(inode_id);
+        let (is_dir, nlink, size, mut direct, mut indirect) =
+            get_block_cache(block_id, Arc::clone(&self.block_device))?
+                .lock()
+                .read(offset, |disk_inode: &DiskInode| (
+                    disk_inode.is_dir(),
+                    disk_inode.nlink,
+                    disk_inode.size as usize,
+                    disk_inode.direct,
+                    [disk_inode.indirect1, disk_inode.indirect2, disk_inode.indirect3],
+                ));
+        self.nlink[inode_id as usize] = nlink;
+        let mut blocks = BTreeMap::new();
+        let mut changed = false;
+        for (i, block_id) in direct.iter_mut().enumerate() {
+            changed |= self.walk(inode_id, block_id, 0, i, &mut blocks)?;
+        }
+        let bases = [DIRECT_BOUND, INDIRECT1_BOUND, INDIRECT2_BOUND];
+        for (level, block_id) in indirect.iter_mut().enumerate() {
+            changed |= self.walk(inode_id, block_id, level as u32 + 1, bases[level], &mut blocks)?;
+        }
+        if changed && self.repair {
+            get_block_cache(block_id, Arc::clone(&self.block_device))?
+                .lock()
+                .modify(offset, |disk_inode: &mut DiskInode| {
+                    disk_inode.direct = direct;
+                    [disk_inode.indirect1, disk_inode.indirect2, disk_inode.indirect3] = indirect;
+                });
+        }
+        if is_dir {
+            self.dirs.push((inode_id, size, blocks));
+        }
+        Ok(())
+    }
+    /// Check the block `*block_id`, an indirect block of `level` or a data
+    /// block at level 0 mapping the `base`-th data block of the inode, and
+    /// the blocks under it. A bad pointer is set to 0 and true returned.
+    ///
+    /// The data blocks are collected into `blocks` by their index in the inode.
+    fn walk(
+        &mut self,
+        inode_id: u32,
+        block_id: &mut u32,
+        level: u32,
+        base: usize,
+        blocks: &mut BTreeMap<usize, u32>,
+    ) -> Result<bool> {
+        if *block_id == 0 {
+            return Ok(false);
+        }
+        if *block_id < self.data_area_start_block
+            || *block_id - self.data_area_start_block >= self.data_area_blocks {
+            self.problems.push(FsckProblem::BadBlockPointer { inode_id, block_id: *block_id });
+            *block_id = 0;
+            return Ok(true);
+        }
+        let i = (*block_id - self.data_area_start_block) as usize;
+        if self.used[i] {
+            self.problems.push(FsckProblem::DoublyReferencedBlock { inode_id, block_id: *block_id });
+            *block_id = 0;
+            return Ok(true);
+        }
+        self.used[i] = true;
+        if level == 0 {
+            blocks.insert(base, *block_id);
+            return Ok(false);
+        }
+        // data blocks mapped by one entry of this indirect block
+        let span = INODE_INDIRECT1_COUNT.pow(level - 1);
+        let mut entries = get_block_cache(*block_id as usize, Arc::clone(&self.block_device))?
+            .lock()
+            .read(0, |indirect_block: &IndirectBlock| *indirect_block);
+        let mut changed = false;
+        for (i, entry) in entries.iter_mut().enumerate() {
+            changed |= self.walk(inode_id, entry, level - 1, base + i * span, blocks)?;
+        }
+        if changed && self.repair {
+            get_block_cache(*block_id as usize, Arc::clone(&self.block_device))?
+                .lock()
+                .modify(0, |indirect_block: &mut IndirectBlock| *indirect_block = entries);
+        }
+        Ok(false)
+    }
+    /// Count the links of every dirent of a directory and visit their inodes,
+    /// removing the dirents that can not be followed
+    fn scan_dir(&mut self, dir: u32, size: usize, blocks: &BTreeMap<usize, u32>) -> Result<()> {
+        for offset in (0..size).step_by(BLOCK_SZ) {
+            // a hole reads as free dirents
+            let block_id = match blocks.get(&(offset / BLOCK_SZ)) {
+                Some(block_id) => *block_id as usize,
+                None => continue,
+            };
+            let data_block = get_block_cache(block_id, Arc::clone(&self.block_device))?
+                .lock()
+                .read(0, |data_block: &DataBlock| *data_block);
+            let len = (size - offset).min(BLOCK_SZ);
+            for dirent in self.dirent_format.decode(&data_block[..len], offset / DIRENT_SZ) {
+                let (slot, slots, problem) = match dirent {
+                    Dirent::Free { .. } => continue,
+                    Dirent::Corrupt { slot, slots } => {
+                        (slot, slots, FsckProblem::BadDirentName { dir, index: slot })
+                    }
+                    Dirent::Named { slot, slots, name, inode_number: inode_id } => {
+                        if inode_id as usize >= self.inode_count
+                            || !self.inode_bitmap.get(&self.block_device, inode_id as usize)? {
+                            let name = String::from(name);
+                            (slot, slots, FsckProblem::DanglingDirent { dir, name, inode_id })
+                        } else if !self.visited[inode_id as usize] && !self.is_valid_inode(inode_id)? {
+                            (slot, slots, FsckProblem::BadInodeType { inode_id })
+                        } else {
+                            self.links[inode_id as usize] += 1;
+                            if !self.visited[inode_id as usize] {
+                                self.visit(inode_id)?;
+                            }
+                            continue;
+                        }
+                    }
+                };
+                self.problems.push(problem);
+                if self.repair {
+                    let start = slot * DIRENT_SZ % BLOCK_SZ;
+                    let free = self.dirent_format.encode_free(slots);
+                    get_block_cache(block_id, Arc::clone(&self.block_device))?
+                        .lock()
+                        .modify(0, |data_block: &mut DataBlock| {
+                            data_block[start..start + free.len()].copy_from_slice(&free);
+                        });
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Unreachable inodes are freed, their blocks are then left unused
+    fn check_inodes(&mut self) -> Result<()> {
+        for inode_id in 0..self.inode_count as u32 {
+            let i = inode_id as usize;
+            if !self.visited[i] {
+                if self.inode_bitmap.get(&self.block_device, i)? {
+                    self.problems.push(FsckProblem::OrphanInode { inode_id });
+                    if self.repair {
+                        self.inode_bitmap.set(&self.block_device, i, false)?;
+                    }
+                }
+                continue;
+            }
+            if self.nlink[i] != self.links[i] {
+                self.problems.push(FsckProblem::WrongLinkCount {
+                    inode_id,
+                    nlink: self.nlink[i],
+                    links: self.links[i],
+                });
+                if self.repair {
+                    let (block_id, offset) = self.inode_pos(inode_id);
+                    let links = self.links[i];
+                    get_block_cache(block_id, Arc::clone(&self.block_device))?
+                        .lock()
+                        .modify(offset, |disk_inode: &mut DiskInode| disk_inode.nlink = links);
+                }
+            }
+        }
+        Ok(())
+    }
+    fn check_data_bitmap(&mut self) -> Result<()> {
+        for i in 0..self.data_area_blocks as usize {
+            let block_id = self.data_area_start_block + i as u32;
+            let marked = self.data_bitmap.get(&self.block_device, i)?;
+            if marked == self.used[i] {
+                continue;
+            }
+            self.problems.push(match marked {
+                false => FsckProblem::UnmarkedBlock { block_id },
+                true => FsckProblem::LeakedBlock { block_id },
+            });
+            if self.repair {
+                self.data_bitmap.set(&self.block_device, i, self.used[i])?;
+            }
+        }
+        Ok(())
+    }
+    /// The counters are checked against what the bitmaps are once
+    /// repaired, not against what they are now
+    fn check_free_counts(&mut self) -> Result<()> {
+        let free_inodes = self.visited.iter().filter(|visited| !**visited).count() as u32;
+        let free_blocks = self.used.iter().filter(|used| !**used).count() as u32;
+        if self.free_inodes != free_inodes {
+            self.problems.push(FsckProblem::WrongFreeInodes { free_inodes: self.free_inodes, counted: free_inodes });
+        }
+        if self.free_blocks != free_blocks {
+            self.problems.push(FsckProblem::WrongFreeBlocks { free_blocks: self.free_blocks, counted: free_blocks });
+        }
+        if self.repair && (self.free_inodes, self.free_blocks) != (free_inodes, free_blocks) {
+            get_block_cache(0, Arc::clone(&self.block_device))?
+                .lock()
+                .modify(0, |super_block: &mut SuperBlock| {
+                    super_block.free_inodes = free_inodes;
+                    super_block.free_blocks = free_blocks;
+                });
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,242 @@
+use alloc::{sync::Arc, vec, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{
+    block_cache::{clear_dirty_inodes, dirty_block_caches, get_block_cache, sync_block_caches},
+    error::{FsError, Result},
+    BlockDevice, DataBlock, BLOCK_SZ,
+};
+
+/// Blocks of the journal region, a header followed by the logged blocks
+pub const JOURNAL_BLOCKS: u32 = 128;
+/// The max number of blocks modified by one transaction
+pub const JOURNAL_CAPACITY: usize = JOURNAL_BLOCKS as usize - 1;
+
+#[repr(C)]
+struct JournalHeader {
+    /// Number of logged blocks of a committed transaction, 0 when every
+    /// transaction has reached its home blocks
+    count: u32,
+    /// Home block id of each logged block, in log order
+    block_ids: [u32; JOURNAL_CAPACITY],
+}
+
+// the header takes exactly the first block of the journal
+const _: () = assert!(core::mem::size_of::<JournalHeader>() == BLOCK_SZ);
+
+impl JournalHeader {
+    fn empty() -> Self {
+        Self {
+            count: 0,
+            block_ids: [0; JOURNAL_CAPACITY],
+        }
+    }
+    fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(
+                self as *const _ as usize as *const u8,
+                BLOCK_SZ,
+            )
+        }
+    }
+    fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            core::slice::from_raw_parts_mut(
+                self as *mut _ as usize as *mut u8,
+                BLOCK_SZ,
+            )
+        }
+    }
+}
+
+/// Write-ahead journal for the blocks modified through the block cache.
+///
+/// Journal blocks are read and written on the device directly, they never
+/// go through the block cache. An image of layout version 1 has no journal
+/// region, its blocks are written home in place with nothing logged.
+pub struct Journal {
+    start_block: u32,
+    /// Whether the image has a journal region
+    logged: bool,
+    /// Blocks of the image, which a logged home block id must be below
+    total_blocks: u32,
+    /// The header may hold a committed transaction not installed yet, whose
+    /// log must not be overwritten before it is
+    unfinished: AtomicBool,
+}
+
+impl Journal {
+    /// The journal of `JOURNAL_BLOCKS` blocks from `start_block` on
+    pub fn new(start_block: u32, total_blocks: u32) -> Self {
+        Self {
+            start_block,
+            logged: true,
+            total_blocks,
+            unfinished: AtomicBool::new(false),
+        }
+    }
+    /// No journal, for an image without the region
+    pub fn in_place() -> Self {
+        Self {
+            start_block: 0,
+            logged: false,
+            total_blocks: 0,
+            unfinished: AtomicBool::new(false),
+        }
+    }
+    /// Whether blocks are logged before they are written home, which bounds
+    /// a transaction to `JOURNAL_CAPACITY` blocks
+    pub fn is_logged(&self) -> bool {
+        self.logged
+    }
+    fn log_block(&self, index: usize) -> usize {
+        self.start_block as usize + 1 + index
+    }
+    /// A header logging more blocks than the journal holds, or a home block
+    /// outside the image or in the journal itself, is `FsError::Corrupt`
+    fn read_header(&self, block_device: &Arc<dyn BlockDevice>) -> Result<JournalHeader> {
+        let mut header = JournalHeader::empty();
+        if !self.logged {
+            return Ok(header);
+        }
+        block_device.read_block(self.start_block as usize, header.as_bytes_mut())?;
+        if header.count as usize > JOURNAL_CAPACITY {
+            return Err(FsError::Corrupt);
+        }
+        let journal = self.start_block..self.start_block + JOURNAL_BLOCKS;
+        if header.block_ids[..header.count as usize]
+            .iter()
+            .any(|block_id| *block_id >= self.total_blocks || journal.contains(block_id))
+        {
+            return Err(FsError::Corrupt);
+        }
+        Ok(header)
+    }
+    fn write_header(&self, header: &JournalHeader, block_device: &Arc<dyn BlockDevice>) -> Result<()> {
+        block_device.write_block(self.start_block as usize, header.as_bytes())
+    }
+    /// Read the logged blocks of a committed transaction in one go
+    fn read_log(&self, header: &JournalHeader, block_device: &Arc<dyn BlockDevice>) -> Result<Vec<u8>> {
+        let mut log = vec![0u8; header.count as usize * BLOCK_SZ];
+        block_device.read_blocks(self.log_block(0), &mut log)?;
+        Ok(log)
+    }
+    /// Empty the header once the transaction is installed, flushed before
+    /// the next transaction overwrites the log
+    fn finish(&self, block_device: &Arc<dyn BlockDevice>) -> Result<()> {
+        block_device.flush()?;
+        if !self.logged {
+            return Ok(());
+        }
+        self.write_header(&JournalHeader::empty(), block_device)?;
+        block_device.flush()?;
+        self.unfinished.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+    /// Make every block modified since the last commit durable at once.
+    ///
+    /// The blocks are logged first, then the header marks the transaction
+    /// committed, and only then are they written to their home blocks.
+    /// A crash before the header is written loses the whole transaction,
+    /// a crash after it is repaired by `replay`. Each step is flushed
+    /// before the next one starts.
+    ///
+    /// An error means the transaction is not committed. Once it is, a block
+    /// failing to reach its home stays modified and the transaction is
+    /// installed from the log by the next commit, so `Ok` is returned.
+    ///
+    /// A transaction outgrowing the journal is `FsError::NoSpace`, its
+    /// blocks are left modified for the caller to drop.
+    ///
+    /// Without a journal the blocks are written home straight away, a crash
+    /// half way leaving some of them written.
+    pub fn commit(&self, block_device: &Arc<dyn BlockDevice>) -> Result<()> {
+        if self.unfinished.load(Ordering::Relaxed) {
+            self.install(block_device)?;
+        }
+        let dirty = dirty_block_caches(block_device);
+        if dirty.is_empty() {
+            clear_dirty_inodes(block_device);
+            return Ok(());
+        }
+        if !self.logged {
+            sync_block_caches(block_device)?;
+            block_device.flush()?;
+            clear_dirty_inodes(block_device);
+            return Ok(());
+        }
+        if dirty.len() > JOURNAL_CAPACITY {
+            return Err(FsError::NoSpace);
+        }
+        let mut header = JournalHeader::empty();
+        header.count = dirty.len() as u32;
+        let mut log = vec![0u8; dirty.len() * BLOCK_SZ];
+        for (i, (block_cache, logged)) in dirty.iter().zip(log.chunks_mut(BLOCK_SZ)).enumerate() {
+            let block_cache = block_cache.lock();
+            header.block_ids[i] = block_cache.block_id() as u32;
+            block_cache.read(0, |data_block: &DataBlock| logged.copy_from_slice(data_block));
+        }
+        block_device.write_blocks(self.log_block(0), &log)?;
+        block_device.flush()?;
+        // commit point, which a failed header write may have reached or not
+        self.unfinished.store(true, Ordering::Relaxed);
+        self.write_header(&header, block_device)?;
+        block_device.flush()?;
+        clear_dirty_inodes(block_device);
+        for block_cache in dirty {
+            if block_cache.lock().sync().is_err() {
+                return Ok(());
+            }
+        }
+        let _ = self.finish(block_device);
+        Ok(())
+    }
+    /// Write a committed transaction from the log to its home blocks on the
+    /// device directly, the cached blocks being at least as new
+    fn install(&self, block_device: &Arc<dyn BlockDevice>) -> Result<()> {
+        let header = self.read_header(block_device)?;
+        let log = self.read_log(&header, block_device)?;
+        for (block_id, logged) in header.block_ids.iter().zip(log.chunks(BLOCK_SZ)) {
+            block_device.write_block(*block_id as usize, logged)?;
+        }
+        self.finish(block_device)
+    }
+    /// Whether a committed transaction may not be installed yet
+    pub fn is_unfinished(&self) -> bool {
+        self.unfinished.load(Ordering::Relaxed)
+    }
+    /// Home block ids of a committed transaction not installed yet
+    pub fn pending(&self, block_device: &Arc<dyn BlockDevice>) -> Result<Vec<u32>> {
+        let header = self.read_header(block_device)?;
+        Ok(header.block_ids.iter().take(header.count as usize).copied().collect())
+    }
+    /// Drop a committed transaction without installing it
+    pub fn discard(&self, block_device: &Arc<dyn BlockDevice>) -> Result<()> {
+        self.finish(block_device)
+    }
+    /// Install a transaction committed but cut off before reaching its home
+    /// blocks, which makes a replay after another crash harmless.
+    ///
+    /// It goes through the block cache, which must hold no modified block
+    /// of the device.
+    pub fn replay(&self, block_device: &Arc<dyn BlockDevice>) -> Result<()> {
+        let header = self.read_header(block_device)?;
+        if header.count == 0 {
+            self.unfinished.store(false, Ordering::Relaxed);
+            return Ok(());
+        }
+        let log = self.read_log(&header, block_device)?;
+        // the whole transaction is cached before any of it is written, so
+        // that what is read afterwards is up to date whatever fails
+        let mut installed = Vec::new();
+        for (block_id, logged) in header.block_ids.iter().zip(log.chunks(BLOCK_SZ)) {
+            let block_cache = get_block_cache(*block_id as usize, Arc::clone(block_device))?;
+            block_cache.lock().modify(0, |data_block: &mut DataBlock| data_block.copy_from_slice(logged));
+            installed.push(block_cache);
+        }
+        for block_cache in installed {
+            block_cache.lock().sync()?;
+        }
+        self.finish(block_device)
+    }
+}
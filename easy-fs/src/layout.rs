@@ -1,34 +1,76 @@
-use core::ops::Index;
-
 use alloc::sync::Arc;
-use alloc::vec::Vec;
-use crate::{block_cache::get_block_cache, BlockDevice, BLOCK_SZ};
+use alloc::{vec, vec::Vec};
+use core::convert::TryInto;
+use crate::{block_cache::get_block_cache, error::{FsError, Result}, BlockDevice, BLOCK_SZ};
 
 
 /// Magic number for sanity check
-const EFS_MAGIC: u32 = 0x3b800001;
+pub const EFS_MAGIC: u32 = 0x3b800001;
+/// On-disk layout version, bumped whenever the layout changes so that an
+/// image of another layout is refused instead of misread.
+///
+/// - 0: images without a version, with 28 direct blocks; `open` refuses them
+/// - 1: 22 direct blocks and an indirect3 block, still opened, with its
+///   blocks written in place
+/// - 2: a journal between the inode area and the data bitmap
+pub const EFS_VERSION: u32 = 2;
 /// The max number of direct inodes
-const INODE_DIRECT_COUNT: usize = 28;
+const INODE_DIRECT_COUNT: usize = 22;
 /// The max length of inode name
-const NAME_LENGTH_LIMIT: usize = 27;
+pub const NAME_LENGTH_LIMIT: usize = 27;
+/// The max length of inode name on an image with `FEATURE_LONG_NAMES`
+pub const LONG_NAME_LENGTH_LIMIT: usize = 255;
+/// Directories hold dirent records rather than fixed `DirEntry`s
+pub const FEATURE_LONG_NAMES: u32 = 1 << 0;
+/// Feature flags this version knows of, an image with others is refused
+pub const KNOWN_FEATURES: u32 = FEATURE_LONG_NAMES;
 /// The max number of indirect1 block
-const INODE_INDIRECT1_COUNT: usize = BLOCK_SZ / 4;
+pub const INODE_INDIRECT1_COUNT: usize = BLOCK_SZ / 4;
 /// The max number of indirect2 block
 const INODE_INDIRECT2_COUNT: usize = INODE_INDIRECT1_COUNT * INODE_INDIRECT1_COUNT;
+/// The max number of indirect3 block
+const INODE_INDIRECT3_COUNT: usize = INODE_INDIRECT2_COUNT * INODE_INDIRECT1_COUNT;
 /// The upper bound of direct inode index
-const DIRECT_BOUND: usize = INODE_DIRECT_COUNT;
+pub const DIRECT_BOUND: usize = INODE_DIRECT_COUNT;
 /// The upper bound of indirect1 inode index
-const INDIRECT1_BOUND: usize = DIRECT_BOUND + INODE_INDIRECT1_COUNT;
+pub const INDIRECT1_BOUND: usize = DIRECT_BOUND + INODE_INDIRECT1_COUNT;
 /// The upper bound of indirect2 inode indexs
-#[allow(unused)]
-const INDIRECT2_BOUND: usize = INDIRECT1_BOUND + INODE_INDIRECT2_COUNT;
+pub const INDIRECT2_BOUND: usize = INDIRECT1_BOUND + INODE_INDIRECT2_COUNT;
+/// The upper bound of indirect3 inode indexs
+const INDIRECT3_BOUND: usize = INDIRECT2_BOUND + INODE_INDIRECT3_COUNT;
+/// The max size of a file in bytes
+pub const MAX_FILE_SIZE: usize = INDIRECT3_BOUND * BLOCK_SZ;
 
+/// Number of data + indirect blocks a file of `size` bytes takes without holes
+pub fn file_blocks(size: usize) -> usize {
+    let data_blocks = size.div_ceil(BLOCK_SZ);
+    let mut blocks = data_blocks;
+    if data_blocks > DIRECT_BOUND {
+        blocks += 1;
+    }
+    if data_blocks > INDIRECT1_BOUND {
+        let mapped = data_blocks.min(INDIRECT2_BOUND) - INDIRECT1_BOUND;
+        blocks += 1 + mapped.div_ceil(INODE_INDIRECT1_COUNT);
+    }
+    if data_blocks > INDIRECT2_BOUND {
+        let mapped = data_blocks - INDIRECT2_BOUND;
+        blocks += 1 + mapped.div_ceil(INODE_INDIRECT2_COUNT) + mapped.div_ceil(INODE_INDIRECT1_COUNT);
+    }
+    blocks
+}
+
+/// Size of a dirent slot, a `DirEntry` fills one and a record whole ones
 pub const DIRENT_SZ: usize = 32;
+/// Dirent slots of a directory block
+pub const DIRENT_SLOTS: usize = BLOCK_SZ / DIRENT_SZ;
+/// Size of the header of a dirent record
+const DIR_RECORD_SZ: usize = 8;
 
 type IndirectBlock = [u32; BLOCK_SZ / 4];
 pub type DataBlock = [u8; BLOCK_SZ];
 
 #[repr(C)]
+#[derive(Clone, Copy, Debug)]
 pub struct SuperBlock {
     magic: u32,
     pub total_blocks: u32,
@@ -36,16 +78,29 @@ pub struct SuperBlock {
     pub inode_area_blocks: u32,
     pub data_bitmap_blocks: u32,
     pub data_area_blocks: u32,
+    /// Zero on images made before the field existed
+    version: u32,
+    pub journal_blocks: u32,
+    /// `FEATURE_*` flags, zero on images made before the field existed
+    features: u32,
+    /// Free inodes, kept along the inode bitmap and checked against it by
+    /// `open`, which corrects images made before the field existed
+    pub free_inodes: u32,
+    /// Free blocks of the data area, kept along the data bitmap the same way
+    pub free_blocks: u32,
 }
 
 impl SuperBlock {
+    #[allow(clippy::too_many_arguments)]
     pub fn initialize(
         &mut self,
         total_blocks: u32,
         inode_bitmap_blocks: u32,
         inode_area_blocks: u32,
+        journal_blocks: u32,
         data_bitmap_blocks: u32,
         data_area_blocks: u32,
+        features: u32,
     ) {
         *self = Self {
             magic: EFS_MAGIC,
@@ -54,15 +109,74 @@ impl SuperBlock {
             inode_area_blocks,
             data_bitmap_blocks,
             data_area_blocks,
+            version: EFS_VERSION,
+            journal_blocks,
+            features,
+            free_inodes: inode_bitmap_blocks * (BLOCK_SZ * 8) as u32,
+            free_blocks: data_area_blocks,
         }
     }
     /// Check if a super block is valid using efs magic
     pub fn is_valid(&self) -> bool {
         self.magic == EFS_MAGIC
     }
+    /// The layout version this image was made with
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+    pub fn features(&self) -> u32 {
+        self.features
+    }
 }
 
-#[derive(PartialEq, Clone, Copy)]
+/// The blocks of an inode as `DiskInode::block_map` reads them, for
+/// inspecting an image
+#[derive(Debug)]
+pub struct BlockMap {
+    /// Direct blocks up to the last one in use, 0 for a hole
+    pub direct: Vec<u32>,
+    pub indirect1: IndirectMap,
+    pub indirect2: IndirectMap,
+    pub indirect3: IndirectMap,
+}
+
+/// An indirect block and the blocks it maps
+#[derive(Debug, Default)]
+pub struct IndirectMap {
+    /// 0 for none
+    pub block_id: u32,
+    /// Its entries up to the last one in use, 0 for a hole
+    pub entries: Vec<u32>,
+    /// The map of each entry for a block above level 1
+    pub children: Vec<IndirectMap>,
+}
+
+impl IndirectMap {
+    fn read(block_id: u32, level: u32, block_device: &Arc<dyn BlockDevice>) -> Result<Self> {
+        if block_id == 0 {
+            return Ok(Self::default());
+        }
+        let mut entries = get_block_cache(block_id as usize, Arc::clone(block_device))?
+            .lock()
+            .read(0, |indirect_block: &IndirectBlock| indirect_block.to_vec());
+        let used = entries.iter().rposition(|entry| *entry != 0).map_or(0, |last| last + 1);
+        entries.truncate(used);
+        let mut children = Vec::new();
+        if level > 1 {
+            for entry in entries.iter() {
+                children.push(Self::read(*entry, level - 1, block_device)?);
+            }
+        }
+        Ok(Self {
+            block_id,
+            entries,
+            children,
+        })
+    }
+}
+
+#[repr(u16)]
+#[derive(PartialEq, Clone, Copy, Debug)]
 pub enum DiskInodeType {
     File,
     Directory,
@@ -74,17 +188,53 @@ pub struct DiskInode{
     pub direct: [u32; INODE_DIRECT_COUNT],
     pub indirect1: u32,  // point to a indirect1 block, every u32 point to a data block
     pub indirect2: u32,  // point to a indirect2 block, every u32 point to a indirect1 block
-    type_: DiskInodeType,  // 4 byte
+    pub indirect3: u32,  // point to a indirect3 block, every u32 point to a indirect2 block
+    type_: DiskInodeType,  // 2 byte
+    pub mode: u16,  // permission bits, e.g. 0o644
+    pub nlink: u32,  // number of dirents referring to this inode
+    pub uid: u16,
+    pub gid: u16,
+    pub atime: u32,  // seconds, last access of the contents
+    pub mtime: u32,  // seconds, last modification of the contents
+    pub ctime: u32,  // seconds, last change of the inode itself
 }
 
+// four inodes share a block, the layout must not grow
+const _: () = assert!(core::mem::size_of::<DiskInode>() == 128);
+
 impl DiskInode {
-    /// indirect1 and indirect2 block are allocated only then they are needed.
-    pub fn initialize(&mut self, type_: DiskInodeType) {
+    /// indirect1, indirect2 and indirect3 block are allocated only then they are needed.
+    ///
+    /// A new file is linked by its parent only, a new directory also by its own `.`.
+    pub fn initialize(&mut self, type_: DiskInodeType, now: u32) {
+        self.clear_blocks();
+        self.type_ = type_;
+        (self.mode, self.nlink) = match type_ {
+            DiskInodeType::File => (0o644, 1),
+            DiskInodeType::Directory => (0o755, 2),
+        };
+        self.uid = 0;
+        self.gid = 0;
+        self.atime = now;
+        self.mtime = now;
+        self.ctime = now;
+    }
+    pub fn type_(&self) -> DiskInodeType {
+        self.type_
+    }
+    /// Whether untrusted bytes hold a known inode type, to be checked before
+    /// they are read as a `DiskInode`
+    pub fn is_valid_raw(raw: &[u8]) -> bool {
+        let offset = core::mem::offset_of!(DiskInode, type_);
+        let type_ = u16::from_ne_bytes([raw[offset], raw[offset + 1]]);
+        type_ == DiskInodeType::File as u16 || type_ == DiskInodeType::Directory as u16
+    }
+    fn clear_blocks(&mut self) {
         self.size = 0;
         self.direct.iter_mut().for_each(|v| *v = 0);
         self.indirect1 = 0;
         self.indirect2 = 0;
-        self.type_ = type_;
+        self.indirect3 = 0;
     }
     pub fn is_dir(&self) -> bool {
         self.type_ == DiskInodeType::Directory
@@ -92,214 +242,304 @@ impl DiskInode {
     pub fn is_file(&self) -> bool {
         self.type_ == DiskInodeType::File
     }
-    pub fn get_block_id(&self, inner_id: u32, block_device: &Arc<dyn BlockDevice>) -> u32 {
+    /// Block id of the `inner_id`-th data block, 0 for a hole.
+    ///
+    /// A block past what an inode can map is `FsError::Corrupt`, only a
+    /// size no image should hold reaches it.
+    pub fn get_block_id(&self, inner_id: u32, block_device: &Arc<dyn BlockDevice>) -> Result<u32> {
         let inner_id = inner_id as usize;
-        if inner_id < INODE_DIRECT_COUNT {
-            self.direct[inner_id]
+        if inner_id >= INDIRECT3_BOUND {
+            return Err(FsError::Corrupt);
+        }
+        if inner_id < DIRECT_BOUND {
+            Ok(self.direct[inner_id])
         } else if inner_id < INDIRECT1_BOUND {
-            get_block_cache(
-                self.indirect1 as usize,
-                Arc::clone(block_device),
-            )
-            .lock()
-            .read(0, |indirect_block: &IndirectBlock| {
-                indirect_block[inner_id - INODE_DIRECT_COUNT]
-            })
+            Self::lookup_indirect(self.indirect1, 1, inner_id - DIRECT_BOUND, block_device)
+        } else if inner_id < INDIRECT2_BOUND {
+            Self::lookup_indirect(self.indirect2, 2, inner_id - INDIRECT1_BOUND, block_device)
         } else {
-            let last = inner_id - INDIRECT1_BOUND;
-            let indirect1 = get_block_cache(
-                self.indirect2 as usize,
-                Arc::clone(block_device),
-            )
-            .lock()
-            .read(0, |indirect_block: &IndirectBlock| {
-                indirect_block[last / INODE_INDIRECT1_COUNT]
-            });
-            get_block_cache(
-                indirect1 as usize,
-                Arc::clone(block_device),
-            )
-            .lock()
-            .read(0, |indirect_block: &IndirectBlock| {
-                indirect_block[last % INODE_INDIRECT1_COUNT]
-            })
+            Self::lookup_indirect(self.indirect3, 3, inner_id - INDIRECT2_BOUND, block_device)
         }
     }
+    /// The tree of blocks mapped by the inode, holes included
+    pub fn block_map(&self, block_device: &Arc<dyn BlockDevice>) -> Result<BlockMap> {
+        let used = self.direct.iter().rposition(|&block_id| block_id != 0).map_or(0, |last| last + 1);
+        Ok(BlockMap {
+            direct: self.direct[..used].to_vec(),
+            indirect1: IndirectMap::read(self.indirect1, 1, block_device)?,
+            indirect2: IndirectMap::read(self.indirect2, 2, block_device)?,
+            indirect3: IndirectMap::read(self.indirect3, 3, block_device)?,
+        })
+    }
+    /// Walk down from an indirect block of `level` to the `index`-th data block
+    /// mapped under it, stopping at a hole on the way.
+    fn lookup_indirect(
+        mut block_id: u32,
+        level: u32,
+        mut index: usize,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<u32> {
+        for level in (0..level).rev() {
+            if block_id == 0 {
+                return Ok(0);
+            }
+            let span = INODE_INDIRECT1_COUNT.pow(level);
+            block_id = get_block_cache(block_id as usize, Arc::clone(block_device))?
+                .lock()
+                .read(0, |indirect_block: &IndirectBlock| indirect_block[index / span]);
+            index %= span;
+        }
+        Ok(block_id)
+    }
     pub fn data_blocks(&self) -> u32 {
         Self::_data_blocks(self.size)
     }
     fn _data_blocks(size: u32) -> u32 {
         (size + BLOCK_SZ as u32 - 1) / BLOCK_SZ as u32
     }
-    /// Return number of data + indirect blocks needed include indirect1/2
-    pub fn total_blocks(size: u32) -> u32 {
-        let data_blocks = Self::_data_blocks(size) as usize;
-        let mut total = data_blocks as usize;
-        // indirect1
-        if data_blocks > INODE_DIRECT_COUNT {
-            total += 1;
+    /// Number of data + indirect blocks allocated, holes excluded
+    pub fn allocated_blocks(&self, block_device: &Arc<dyn BlockDevice>) -> Result<u32> {
+        let mut blocks = self.direct.iter().filter(|block_id| **block_id != 0).count();
+        for (block_id, level) in [(self.indirect1, 1), (self.indirect2, 2), (self.indirect3, 3)] {
+            blocks += Self::count_indirect(block_id, level, block_device)?;
         }
-        // indirect2
-        if data_blocks > INDIRECT1_BOUND {
-            total += 1;
-            // sub indirect1
-            total += (data_blocks - INDIRECT1_BOUND + INODE_INDIRECT1_COUNT - 1) / INODE_INDIRECT1_COUNT;
+        Ok(blocks as u32)
+    }
+    /// Count an indirect block of `level` and the blocks mapped under it
+    fn count_indirect(block_id: u32, level: u32, block_device: &Arc<dyn BlockDevice>) -> Result<usize> {
+        if block_id == 0 {
+            return Ok(0);
         }
-        total as u32
+        let entries = get_block_cache(block_id as usize, Arc::clone(block_device))?
+            .lock()
+            .read(0, |indirect_block: &IndirectBlock| *indirect_block);
+        let mut blocks = 1;
+        for block_id in entries.iter().filter(|block_id| **block_id != 0) {
+            blocks += match level {
+                1 => 1,
+                _ => Self::count_indirect(*block_id, level - 1, block_device)?,
+            };
+        }
+        Ok(blocks)
     }
-    pub fn blocks_num_needed(&self, new_size: u32) -> u32 {
+    /// Grow size to `new_size`, the new range is a hole until written.
+    pub fn increase_size(&mut self, new_size: u32) -> Result<()> {
         assert!(new_size >= self.size);
-        Self::total_blocks(new_size) - Self::total_blocks(self.size)
+        if new_size as usize > MAX_FILE_SIZE {
+            return Err(FsError::FileTooLarge);
+        }
+        self.size = new_size;
+        Ok(())
     }
-    pub fn increase_size(
+    /// Back the bytes `[offset, end)` with data blocks, filling the holes on
+    /// the way with blocks from `alloc`, indirect blocks included.
+    ///
+    /// Blocks from `alloc` must be zeroed. Blocks are backed in order, so
+    /// when `alloc` fails a prefix of the range is left backed.
+    pub fn alloc_blocks(
         &mut self,
-        new_size: u32,
-        new_blocks: Vec<u32>,  // allocated by upper-layer disk-block-manager, efs block id
+        offset: usize,
+        end: usize,
+        mut alloc: impl FnMut() -> Result<u32>,
         block_device: &Arc<dyn BlockDevice>,
-    ) {
-        let mut current_blocks = self.data_blocks();
-        self.size = new_size;
-        let mut total_blocks = self.data_blocks();
-        let mut new_blocks = new_blocks.into_iter();
-        // fill direct
-        while current_blocks < total_blocks.min(INODE_DIRECT_COUNT as u32) {
-            self.direct[current_blocks as usize] = new_blocks.next().unwrap();
-            current_blocks += 1;
-        }
-        // alloc indirect1
-        if total_blocks > INODE_DIRECT_COUNT as u32 {
-            if current_blocks == INODE_DIRECT_COUNT as u32 {
-                self.indirect1 = new_blocks.next().unwrap();
-            }
-            current_blocks -= INODE_DIRECT_COUNT as u32;
-            total_blocks -= INODE_DIRECT_COUNT as u32;
+    ) -> Result<()> {
+        assert!(end <= self.size as usize);
+        if offset >= end {
+            return Ok(());
+        }
+        for inner_id in offset / BLOCK_SZ..end.div_ceil(BLOCK_SZ) {
+            self.alloc_block(inner_id, &mut alloc, block_device)?;
+        }
+        Ok(())
+    }
+    /// Where to look for a block to back the `inner_id`-th data block: the
+    /// one after the block before it, or after the first block of the file,
+    /// so that a file grows in sequence. `None` for an empty file.
+    pub fn alloc_goal(&self, inner_id: u32, block_device: &Arc<dyn BlockDevice>) -> Result<Option<u32>> {
+        let before = match inner_id {
+            0 => 0,
+            _ => self.get_block_id(inner_id - 1, block_device)?,
+        };
+        Ok([before, self.direct[0]].iter().copied().find(|block_id| *block_id != 0).map(|block_id| block_id + 1))
+    }
+    /// Bytes of `[offset, end)` backed from `offset` on before a hole
+    pub fn backed_len(&self, offset: usize, end: usize, block_device: &Arc<dyn BlockDevice>) -> Result<usize> {
+        let end = end.min(self.size as usize);
+        let mut pos = offset;
+        while pos < end && self.get_block_id((pos / BLOCK_SZ) as u32, block_device)? != 0 {
+            pos = (pos / BLOCK_SZ + 1) * BLOCK_SZ;
+        }
+        Ok(pos.min(end).saturating_sub(offset))
+    }
+    fn alloc_block(
+        &mut self,
+        inner_id: usize,
+        alloc: &mut impl FnMut() -> Result<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<()> {
+        if inner_id >= INDIRECT3_BOUND {
+            return Err(FsError::Corrupt);
+        }
+        let (root, level, mut index) = if inner_id < DIRECT_BOUND {
+            (&mut self.direct[inner_id], 0, 0)
+        } else if inner_id < INDIRECT1_BOUND {
+            (&mut self.indirect1, 1, inner_id - DIRECT_BOUND)
+        } else if inner_id < INDIRECT2_BOUND {
+            (&mut self.indirect2, 2, inner_id - INDIRECT1_BOUND)
         } else {
-            return;
+            (&mut self.indirect3, 3, inner_id - INDIRECT2_BOUND)
+        };
+        if *root == 0 {
+            *root = alloc()?;
         }
-        // fill indirect1
-        get_block_cache(self.indirect1 as usize, Arc::clone(&block_device))
-            .lock()
-            .modify(0, |indirect_block: &mut IndirectBlock| {
-                while current_blocks < total_blocks.min(INODE_INDIRECT1_COUNT as u32) {
-                    indirect_block[current_blocks as usize] = new_blocks.next().unwrap();
-                    current_blocks += 1;
-                }
-            });
-        if total_blocks > INODE_INDIRECT1_COUNT as u32 {
-            if current_blocks == INODE_INDIRECT1_COUNT as u32 {
-                self.indirect2 = new_blocks.next().unwrap();
+        let mut block_id = *root;
+        for level in (0..level).rev() {
+            let span = INODE_INDIRECT1_COUNT.pow(level);
+            let block_cache = get_block_cache(block_id as usize, Arc::clone(block_device))?;
+            let mut block_cache = block_cache.lock();
+            block_id = block_cache.read(0, |indirect_block: &IndirectBlock| indirect_block[index / span]);
+            if block_id == 0 {
+                block_id = alloc()?;
+                block_cache.modify(0, |indirect_block: &mut IndirectBlock| {
+                    indirect_block[index / span] = block_id;
+                });
             }
-            current_blocks -= INODE_INDIRECT1_COUNT as u32;
-            total_blocks -= INODE_INDIRECT1_COUNT as u32;
-        }
-        // fill indirect2 from (a0, b0) -> (a1, b1)
-        let mut a0 = current_blocks / INODE_INDIRECT1_COUNT as u32;
-        let mut b0 = current_blocks % INODE_INDIRECT1_COUNT as u32;
-        let a1 = total_blocks / INODE_INDIRECT1_COUNT as u32;
-        let b1 = total_blocks % INODE_INDIRECT1_COUNT as u32;
-        
-        get_block_cache(
-            self.indirect2 as usize, 
-            Arc::clone(block_device),
-        )
-        .lock()
-        .modify(0, |indirect2_block: &mut IndirectBlock| {
-            while a0 < a1 || (a0 == a1 && b0 < b1) {
-                if b0 == 0 {
-                    indirect2_block[a0 as usize] = new_blocks.next().unwrap();
-                }
-                get_block_cache(
-                    indirect2_block[a0 as usize] as usize,
-                    Arc::clone(&block_device),
-                )
+            index %= span;
+        }
+        Ok(())
+    }
+    /// Shrink size to `new_size` and return efs block-ids that should be
+    /// deallocated: the tail data blocks and the indirect blocks left empty,
+    /// holes skipped.
+    ///
+    /// The kept part of the last block is zeroed past `new_size`, so that
+    /// growing the file again reads zeros there.
+    pub fn decrease_size(
+        &mut self,
+        new_size: u32,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<Vec<u32>> {
+        assert!(new_size <= self.size);
+        let old_blocks = self.data_blocks() as usize;
+        let new_blocks = Self::_data_blocks(new_size) as usize;
+        let mut v: Vec<u32> = Vec::new();
+        let tail = new_size as usize % BLOCK_SZ;
+        let tail_block_id = match tail != 0 && new_size < self.size {
+            true => self.get_block_id(new_blocks as u32 - 1, block_device)?,
+            false => 0,
+        };
+        if tail_block_id != 0 {
+            get_block_cache(tail_block_id as usize, Arc::clone(block_device))?
                 .lock()
-                .modify(0, |indirect1_block: &mut IndirectBlock| {
-                    indirect1_block[b0 as usize] = new_blocks.next().unwrap();
+                .modify(0, |data_block: &mut DataBlock| {
+                    data_block[tail..].iter_mut().for_each(|p| *p = 0);
                 });
-                b0 += 1;
-                if b0 == INODE_INDIRECT1_COUNT as u32 {
-                    b0 = 0;
-                    a0 += 1;
-                }
+        }
+        self.size = new_size;
+        // release direct
+        for i in new_blocks..old_blocks.min(DIRECT_BOUND) {
+            if self.direct[i] != 0 {
+                v.push(self.direct[i]);
+                self.direct[i] = 0;
             }
-        })
+        }
+        // release indirect1
+        if old_blocks > DIRECT_BOUND {
+            Self::release_indirect(
+                self.indirect1,
+                1,
+                new_blocks.max(DIRECT_BOUND) - DIRECT_BOUND,
+                old_blocks.min(INDIRECT1_BOUND) - DIRECT_BOUND,
+                &mut v,
+                block_device,
+            )?;
+            if new_blocks <= DIRECT_BOUND && self.indirect1 != 0 {
+                v.push(self.indirect1);
+                self.indirect1 = 0;
+            }
+        }
+        // release indirect2
+        if old_blocks > INDIRECT1_BOUND {
+            Self::release_indirect(
+                self.indirect2,
+                2,
+                new_blocks.max(INDIRECT1_BOUND) - INDIRECT1_BOUND,
+                old_blocks.min(INDIRECT2_BOUND) - INDIRECT1_BOUND,
+                &mut v,
+                block_device,
+            )?;
+            if new_blocks <= INDIRECT1_BOUND && self.indirect2 != 0 {
+                v.push(self.indirect2);
+                self.indirect2 = 0;
+            }
+        }
+        // release indirect3
+        if old_blocks > INDIRECT2_BOUND {
+            Self::release_indirect(
+                self.indirect3,
+                3,
+                new_blocks.max(INDIRECT2_BOUND) - INDIRECT2_BOUND,
+                old_blocks - INDIRECT2_BOUND,
+                &mut v,
+                block_device,
+            )?;
+            if new_blocks <= INDIRECT2_BOUND && self.indirect3 != 0 {
+                v.push(self.indirect3);
+                self.indirect3 = 0;
+            }
+        }
+        Ok(v)
     }
-    /// Clear size to zero and return efs block-ids that should be deallocated.
-    /// 
-    /// We will clear the block contents to zero later
-    pub fn clear_size(&mut self, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
-        let mut total_blocks = self.data_blocks() as usize;
-        let mut v: Vec<u32> = Vec::new();
-        // clear direct
-        let mut cur_blocks: usize = 0;
-        while cur_blocks < INODE_DIRECT_COUNT.min(total_blocks) {
-            v.push(self.direct[cur_blocks]);
-            cur_blocks += 1;
-        }
-        if total_blocks > INODE_DIRECT_COUNT {
-            cur_blocks = 0;
-            v.push(self.indirect1);
-            total_blocks -= INODE_DIRECT_COUNT;
-        } else {
-            self.initialize(self.type_.clone());
-            return v;
+    /// Release the tail `[start, end)` of the data blocks mapped under an
+    /// indirect block of `level` (1 for indirect1), where `end` is the end
+    /// of the mapped blocks. Released data blocks and the sub indirect blocks
+    /// left empty are pushed into `v`, and their pointers zeroed. Holes are
+    /// skipped.
+    fn release_indirect(
+        block_id: u32,
+        level: u32,
+        start: usize,
+        end: usize,
+        v: &mut Vec<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<()> {
+        if start >= end || block_id == 0 {
+            return Ok(());
         }
-        // clear indirect1
-        get_block_cache(self.indirect1 as usize, Arc::clone(&block_device))
-            .lock()
-            .read(0, |indirect_block: &IndirectBlock| {
-                let mut indir1_blocks = indirect_block.into_iter();
-                while cur_blocks < total_blocks.min(INODE_INDIRECT1_COUNT) {
-                    cur_blocks += 1;
-                    v.push(*(indir1_blocks.next().unwrap()));
-                }
-            });
-        if total_blocks > INODE_INDIRECT1_COUNT {
-            cur_blocks = 0;
-            v.push(self.indirect2);
-            total_blocks -= INODE_INDIRECT1_COUNT;
-        } else {
-            self.initialize(self.type_.clone());
-            return v;
+        // data blocks mapped by one entry of this indirect block
+        let span = INODE_INDIRECT1_COUNT.pow(level - 1);
+        let block_cache = get_block_cache(block_id as usize, Arc::clone(block_device))?;
+        let mut entries = block_cache.lock().read(0, |indirect_block: &IndirectBlock| *indirect_block);
+        let released = entries
+            .iter_mut()
+            .enumerate()
+            .take(end.div_ceil(span))
+            .skip(start / span);
+        for (i, entry) in released {
+            let first = i * span;
+            let sub_start = start.max(first) - first;
+            if level > 1 {
+                let sub_end = end.min(first + span) - first;
+                Self::release_indirect(*entry, level - 1, sub_start, sub_end, v, block_device)?;
+            }
+            if sub_start == 0 && *entry != 0 {
+                v.push(*entry);
+                *entry = 0;
+            }
         }
-        get_block_cache(self.indirect2 as usize, Arc::clone(&block_device))
-            .lock()
-            .read(0, |indirect2_block: &IndirectBlock| {
-                let mut a0 = 0;
-                let mut b0 = 0;
-                let a1 = total_blocks / INODE_INDIRECT1_COUNT;
-                let b1 = total_blocks % INODE_INDIRECT1_COUNT;
-                while a0 < a1 || (a0 == a1 && b0 < b1) {
-                    if b0 == 0 {
-                        v.push(indirect2_block[a0]);
-                    }
-                    get_block_cache(indirect2_block[a0] as usize, Arc::clone(block_device))
-                        .lock()
-                        .read(0, |indirect1_block: &IndirectBlock| {
-                            v.push(indirect1_block[b0]);
-                            cur_blocks += 1;
-                        });
-                    b0 += 1;
-                    if b0 == INODE_INDIRECT1_COUNT {
-                        b0 = 0;
-                        a0 += 1;
-                    }
-                }
-            });
-        assert!(cur_blocks == total_blocks);
-        self.initialize(self.type_.clone());
-        v
+        block_cache.lock().modify(0, |indirect_block: &mut IndirectBlock| *indirect_block = entries);
+        Ok(())
     }
     pub fn read_at(
         &self,
         offset: usize,
         buf: &mut [u8],
         block_device: &Arc<dyn BlockDevice>,
-    ) -> usize {
+    ) -> Result<usize> {
         let mut start = offset;
         let end = (offset + buf.len()).min(self.size as usize);
         if start >= end {
-            return 0;
+            return Ok(0);
         }
         let mut start_block = start / BLOCK_SZ;  // 0-based
         let mut read_size = 0usize;
@@ -310,49 +550,51 @@ impl DiskInode {
             // read and update read size
             let block_read_size = end_current_block - start;
             let dst = &mut buf[read_size..read_size + block_read_size];
-            get_block_cache(
-                self.get_block_id(
-                    start_block as u32,
-                    block_device,
-                ) as usize,
-                Arc::clone(block_device),
-            )
-            .lock()
-            .read(0, |data_block: &DataBlock| {
-                let src = &data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_read_size];
-                dst.copy_from_slice(src);
-            });
+            let block_id = self.get_block_id(start_block as u32, block_device)?;
+            if block_id == 0 {
+                // a hole reads as zeros
+                dst.fill(0);
+            } else {
+                get_block_cache(block_id as usize, Arc::clone(block_device))?
+                    .lock()
+                    .read(0, |data_block: &DataBlock| {
+                        let src = &data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_read_size];
+                        dst.copy_from_slice(src);
+                    });
+            }
             read_size += block_read_size;
             // move to next block
             if end_current_block == end { break; }
             start_block += 1;
             start = end_current_block;
         }
-        read_size
+        Ok(read_size)
     }
+    /// The written range must be backed by `alloc_blocks` first
     pub fn write_at(
         &mut self, 
         offset: usize, 
         buf: &[u8], 
         block_device: &Arc<dyn BlockDevice>
-    ) -> usize {
+    ) -> Result<usize> {
         let mut start = offset;
         let end = (start + buf.len()).min(self.size as usize);
         if start >= end {
-            return 0;
+            return Ok(0);
         }
         let mut write_size = 0;
         loop {
             let start_block = start / BLOCK_SZ;
             let end_current_block = ((start_block + 1) * BLOCK_SZ).min(end);
             // write and update write size
-            let cur_block_id = self.get_block_id(start_block as u32, block_device) as usize;
+            let cur_block_id = self.get_block_id(start_block as u32, block_device)? as usize;
+            assert_ne!(cur_block_id, 0, "write to a hole");
             let block_write_sz = end_current_block - start;
-            get_block_cache(cur_block_id, Arc::clone(&block_device))
+            get_block_cache(cur_block_id, Arc::clone(block_device))?
                 .lock()
                 .modify(0, |data_block: &mut DataBlock| {
                     let src = &buf[write_size..write_size + block_write_sz];
-                    (&mut data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_write_sz]).copy_from_slice(src);
+                    data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_write_sz].copy_from_slice(src);
                 });
             write_size += block_write_sz;
             start = end_current_block;
@@ -360,7 +602,7 @@ impl DiskInode {
                 break;
             }
         }
-        write_size
+        Ok(write_size)
     }
 }
 
@@ -371,15 +613,11 @@ pub struct DirEntry {
 }
 
 impl DirEntry {
-    pub fn empty() -> Self {
-        Self {
-            name: [0u8; NAME_LENGTH_LIMIT + 1],
-            inode_number: 0,
-        }
-    }
+    /// `name` must fit in `NAME_LENGTH_LIMIT` bytes
     pub fn new(name: &str, inode_number: u32) -> Self {
+        assert!(name.len() <= NAME_LENGTH_LIMIT, "Dirent name too long!");
         let mut bytes = [0u8; NAME_LENGTH_LIMIT + 1];
-        (&mut bytes[..name.len()]).copy_from_slice(name.as_bytes());
+        bytes[..name.len()].copy_from_slice(name.as_bytes());
         Self {
             name: bytes,
             inode_number,
@@ -393,19 +631,153 @@ impl DirEntry {
             )
         }
     }
-    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
-        unsafe {
-            core::slice::from_raw_parts_mut(
-                self as *mut _ as usize as *mut u8, 
-                DIRENT_SZ,
-            )
+}
+
+/// How the dirents of the directories of an image are laid out
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DirentFormat {
+    /// A `DirEntry` per slot, a removed one is left with an empty name
+    Fixed,
+    /// Records of whole slots, ext2-like, which never cross a block. A
+    /// record is a header with the inode number, the record length and the
+    /// name length, followed by the name; a removed one has no name.
+    Records,
+}
+
+impl DirentFormat {
+    pub fn of(features: u32) -> Self {
+        if features & FEATURE_LONG_NAMES != 0 {
+            DirentFormat::Records
+        } else {
+            DirentFormat::Fixed
         }
     }
-    pub fn name(&self) -> &str {
-        let len = (0usize..).find(|i| self.name[*i] == 0).unwrap();
-        core::str::from_utf8(&self.name[..len]).unwrap()
+    pub fn name_limit(self) -> usize {
+        match self {
+            DirentFormat::Fixed => NAME_LENGTH_LIMIT,
+            DirentFormat::Records => LONG_NAME_LENGTH_LIMIT,
+        }
     }
-    pub fn inode_number(&self) -> u32 {
-        self.inode_number
+    /// Slots taken by a dirent whose name is `name_len` bytes
+    pub fn slots(self, name_len: usize) -> usize {
+        match self {
+            DirentFormat::Fixed => 1,
+            DirentFormat::Records => (DIR_RECORD_SZ + name_len).div_ceil(DIRENT_SZ),
+        }
+    }
+    /// Slots a directory grows by when it has no room left, a record must
+    /// not cross a block
+    pub fn grow_slots(self) -> usize {
+        match self {
+            DirentFormat::Fixed => 1,
+            DirentFormat::Records => DIRENT_SLOTS,
+        }
+    }
+    /// Bytes of the dirent `name` taking `slots` slots, and of a removed one
+    /// taking the `spare` slots after it, which `Fixed` leaves as they are
+    pub fn encode(self, name: &str, inode_number: u32, slots: usize, spare: usize) -> Vec<u8> {
+        match self {
+            DirentFormat::Fixed => Vec::from(DirEntry::new(name, inode_number).as_bytes()),
+            DirentFormat::Records => {
+                assert!(name.len() <= LONG_NAME_LENGTH_LIMIT, "Dirent name too long!");
+                let mut bytes = vec![0u8; slots * DIRENT_SZ];
+                bytes[..DIR_RECORD_SZ].copy_from_slice(&record_header(inode_number, slots, name.len()));
+                bytes[DIR_RECORD_SZ..DIR_RECORD_SZ + name.len()].copy_from_slice(name.as_bytes());
+                if spare > 0 {
+                    bytes.extend_from_slice(&record_header(0, spare, 0));
+                }
+                bytes
+            }
+        }
+    }
+    /// Bytes marking the `slots` slots from a dirent on as removed
+    pub fn encode_free(self, slots: usize) -> Vec<u8> {
+        match self {
+            DirentFormat::Fixed => vec![0u8; slots * DIRENT_SZ],
+            DirentFormat::Records => Vec::from(record_header(0, slots, 0)),
+        }
+    }
+    /// The dirents of `chunk`, a part of a directory starting at a block
+    /// boundary, whose first slot is `first_slot`
+    pub fn decode(self, chunk: &[u8], first_slot: usize) -> Dirents<'_> {
+        Dirents {
+            format: self,
+            chunk,
+            first_slot,
+            slot: 0,
+        }
+    }
+}
+
+fn record_header(inode_number: u32, slots: usize, name_len: usize) -> [u8; DIR_RECORD_SZ] {
+    let mut header = [0u8; DIR_RECORD_SZ];
+    header[..4].copy_from_slice(&inode_number.to_ne_bytes());
+    header[4..6].copy_from_slice(&((slots * DIRENT_SZ) as u16).to_ne_bytes());
+    header[6] = name_len as u8;
+    header
+}
+
+/// A dirent as found in a directory, slots counted from its start
+#[derive(Debug, PartialEq, Eq)]
+pub enum Dirent<'a> {
+    /// Slots left by removed dirents
+    Free { slot: usize, slots: usize },
+    Named { slot: usize, slots: usize, name: &'a str, inode_number: u32 },
+    /// A name not terminated or not UTF-8, or for a record whose length
+    /// makes no sense, the rest of its block
+    Corrupt { slot: usize, slots: usize },
+}
+
+/// Iterator over the dirents of a part of a directory
+pub struct Dirents<'a> {
+    format: DirentFormat,
+    chunk: &'a [u8],
+    first_slot: usize,
+    /// Next slot of the chunk
+    slot: usize,
+}
+
+impl<'a> Iterator for Dirents<'a> {
+    type Item = Dirent<'a>;
+    fn next(&mut self) -> Option<Dirent<'a>> {
+        let chunk_slots = self.chunk.len() / DIRENT_SZ;
+        if self.slot >= chunk_slots {
+            return None;
+        }
+        let bytes = &self.chunk[self.slot * DIRENT_SZ..];
+        let (slots, name, inode_number) = match self.format {
+            DirentFormat::Fixed => {
+                let name = &bytes[..NAME_LENGTH_LIMIT + 1];
+                let inode_number = u32::from_ne_bytes(bytes[NAME_LENGTH_LIMIT + 1..DIRENT_SZ].try_into().unwrap());
+                let len = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+                let name = match name[NAME_LENGTH_LIMIT] {
+                    0 => Some(&name[..len]),
+                    _ => None,
+                };
+                (1, name, inode_number)
+            }
+            DirentFormat::Records => {
+                let block_slots = DIRENT_SLOTS - self.slot % DIRENT_SLOTS;
+                let slots_left = block_slots.min(chunk_slots - self.slot);
+                let inode_number = u32::from_ne_bytes(bytes[..4].try_into().unwrap());
+                let rec_len = u16::from_ne_bytes(bytes[4..6].try_into().unwrap()) as usize;
+                let name_len = bytes[6] as usize;
+                if rec_len == 0 || rec_len % DIRENT_SZ != 0 || rec_len / DIRENT_SZ > slots_left {
+                    (slots_left, None, 0)
+                } else if DIR_RECORD_SZ + name_len > rec_len {
+                    (rec_len / DIRENT_SZ, None, 0)
+                } else {
+                    let name = &bytes[DIR_RECORD_SZ..DIR_RECORD_SZ + name_len];
+                    (rec_len / DIRENT_SZ, Some(name), inode_number)
+                }
+            }
+        };
+        let slot = self.first_slot + self.slot;
+        self.slot += slots;
+        Some(match name.map(core::str::from_utf8) {
+            Some(Ok("")) => Dirent::Free { slot, slots },
+            Some(Ok(name)) => Dirent::Named { slot, slots, name, inode_number },
+            _ => Dirent::Corrupt { slot, slots },
+        })
     }
 }
@@ -3,16 +3,22 @@ extern crate alloc;
 extern crate spin;
 extern crate lazy_static;
 
+mod error;
 mod block_cache;
 mod block_dev;
 mod layout;
 mod bitmap;
+mod journal;
+mod dcache;
 mod efs;
 mod vfs;
+mod fsck;
 
 pub const BLOCK_SZ: usize = 512;
 pub use block_dev::BlockDevice;
-pub use layout::DataBlock;
-pub use efs::EasyFileSystem;
-pub use vfs::Inode;
-pub use block_cache::block_cache_sync_all;
+pub use error::{FsError, Result};
+pub use layout::{file_blocks, BlockMap, DataBlock, DiskInodeType, IndirectMap, SuperBlock, EFS_MAGIC, EFS_VERSION, FEATURE_LONG_NAMES, LONG_NAME_LENGTH_LIMIT, MAX_FILE_SIZE, NAME_LENGTH_LIMIT};
+pub use efs::{EasyFileSystem, FsStat, WritePolicy};
+pub use vfs::{Inode, InodeStat};
+pub use fsck::{fsck, FsckProblem, FsckReport};
+pub use block_cache::{block_cache_sync_all, set_block_cache_capacity, BLOCK_CACHE_SIZE};
@@ -1,195 +1,886 @@
 
 // easy-fs/src/vfs.rs
-use alloc::{string::String, sync::Arc, vec::Vec};
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
 use spin::{Mutex, MutexGuard};
 
 use layout::DiskInode;
-use efs::EasyFileSystem;
+use efs::{EasyFileSystem, FsStat};
 use block_dev::BlockDevice;
 
 
-use crate::{block_cache::get_block_cache, block_cache_sync_all, layout::{DirEntry, DiskInodeType, DIRENT_SZ}};
+use crate::{block_cache::{get_block_cache, inode_dirty, mark_inode_dirty, InodeDirty}, dcache::DirIndex, error::{FsError, Result}, layout::{BlockMap, Dirent, DirentFormat, DiskInodeType, DIRENT_SZ, MAX_FILE_SIZE}, BLOCK_SZ};
+
+/// Data blocks written or released by one transaction, which keeps any
+/// transaction well within the journal
+pub(crate) const TXN_BLOCKS: usize = 32;
+
+/// Metadata of an inode, as reported by `Inode::stat`
+#[derive(Debug)]
+pub struct InodeStat {
+    pub inode_id: u32,
+    pub type_: DiskInodeType,
+    pub mode: u16,
+    pub nlink: u32,
+    pub uid: u16,
+    pub gid: u16,
+    pub size: u32,
+    /// Blocks held by the inode, indirect blocks included
+    pub blocks: u32,
+    pub atime: u32,
+    pub mtime: u32,
+    pub ctime: u32,
+}
+
+/// Number of `Inode`s referring to each inode of a fs. It has a lock of
+/// its own, so that an `Inode` can be dropped with the fs locked.
+#[derive(Default)]
+pub struct OpenInodes(BTreeMap<u32, usize>);
+
+impl OpenInodes {
+    pub fn is_open(&self, inode_id: u32) -> bool {
+        self.0.contains_key(&inode_id)
+    }
+    fn open(&mut self, inode_id: u32) {
+        *self.0.entry(inode_id).or_insert(0) += 1;
+    }
+    /// Whether the last `Inode` of `inode_id` is gone
+    fn close(&mut self, inode_id: u32) -> bool {
+        let count = self.0.get_mut(&inode_id).unwrap();
+        *count -= 1;
+        if *count == 0 {
+            self.0.remove(&inode_id);
+            return true;
+        }
+        false
+    }
+}
 
 pub struct Inode {
+    inode_id: u32,
     block_id: usize,
     block_offset: usize,
     fs: Arc<Mutex<EasyFileSystem>>,
     block_device: Arc<dyn BlockDevice>,
+    open_inodes: Arc<Mutex<OpenInodes>>,
 }
 
 impl Inode {
-    fn read_disk_inode<V>(&self, f: impl FnOnce(&DiskInode) -> V) -> V {
+    fn read_disk_inode<V>(&self, f: impl FnOnce(&DiskInode) -> Result<V>) -> Result<V> {
         get_block_cache(
             self.block_id,
             Arc::clone(&self.block_device),
-        )
+        )?
         .lock()
         .read(self.block_offset, f)
     }
-    fn modify_disk_inode<V>(&self, f: impl FnOnce(&mut DiskInode) -> V) -> V {
+    fn modify_disk_inode<V>(&self, f: impl FnOnce(&mut DiskInode) -> Result<V>) -> Result<V> {
+        mark_inode_dirty(&self.block_device, self.inode_id, InodeDirty::Metadata);
         get_block_cache(
             self.block_id,
             Arc::clone(&self.block_device),
-        ).lock().modify(self.block_offset, f)
+        )?.lock().modify(self.block_offset, f)
+    }
+    /// Note a change of contents, size or blocks, which `fdatasync` must
+    /// make durable unlike a change of the disk inode alone
+    fn mark_data_dirty(&self) {
+        mark_inode_dirty(&self.block_device, self.inode_id, InodeDirty::Data);
     }
     pub fn new(
+        inode_id: u32,
         block_id: u32,
         block_offset: usize,
         fs: Arc<Mutex<EasyFileSystem>>,
         block_device: Arc<dyn BlockDevice>,
+        open_inodes: Arc<Mutex<OpenInodes>>,
     ) -> Self {
+        open_inodes.lock().open(inode_id);
         Self {
+            inode_id,
             block_id: block_id as usize,
             block_offset,
             fs,
             block_device,
+            open_inodes,
+        }
+    }
+    /// Run an operation modifying the fs with the fs locked. One cut off
+    /// half way by an I/O error, or whose transaction outgrows the journal,
+    /// has what it modified dropped, rather than committed along with the
+    /// next operation.
+    fn locked<V>(&self, op: impl FnOnce(&mut MutexGuard<EasyFileSystem>) -> Result<V>) -> Result<V> {
+        let mut fs = self.fs.lock();
+        fs.recover()?;
+        fs.release_orphans()?;
+        let result = op(&mut fs);
+        match result {
+            Err(FsError::Io) => fs.abort(),
+            Err(_) if fs.outgrows_journal() => fs.abort(),
+            _ => {}
         }
+        result
+    }
+    /// |Assume Locked| build the vfs inode of `inode_id` on the same fs
+    fn get_inode(&self, inode_id: u32, fs: &MutexGuard<EasyFileSystem>) -> Arc<Inode> {
+        let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
+        Arc::new(Self::new(
+            inode_id,
+            block_id,
+            block_offset,
+            Arc::clone(&self.fs),
+            Arc::clone(&self.block_device),
+            Arc::clone(&self.open_inodes),
+        ))
+    }
+    pub fn inode_id(&self) -> u32 {
+        self.inode_id
+    }
+    pub fn is_dir(&self) -> Result<bool> {
+        self.read_disk_inode(|disk_inode| Ok(disk_inode.is_dir()))
+    }
+    pub fn is_file(&self) -> Result<bool> {
+        self.read_disk_inode(|disk_inode| Ok(disk_inode.is_file()))
+    }
+    /// Number of directory entries referring to this inode
+    pub fn nlink(&self) -> Result<u32> {
+        self.read_disk_inode(|disk_inode| Ok(disk_inode.nlink))
+    }
+    pub fn stat(&self) -> Result<InodeStat> {
+        let mut fs = self.fs.lock();
+        fs.recover()?;
+        self.read_disk_inode(|disk_inode| Ok(InodeStat {
+            inode_id: self.inode_id,
+            type_: disk_inode.type_(),
+            mode: disk_inode.mode,
+            nlink: disk_inode.nlink,
+            uid: disk_inode.uid,
+            gid: disk_inode.gid,
+            size: disk_inode.size,
+            blocks: disk_inode.allocated_blocks(&self.block_device)?,
+            atime: disk_inode.atime,
+            mtime: disk_inode.mtime,
+            ctime: disk_inode.ctime,
+        }))
+    }
+    /// The blocks backing this inode, for inspecting an image
+    pub fn block_map(&self) -> Result<BlockMap> {
+        let mut fs = self.fs.lock();
+        fs.recover()?;
+        self.read_disk_inode(|disk_inode| disk_inode.block_map(&self.block_device))
+    }
+    /// Usage of the fs this inode is on
+    pub fn statfs(&self) -> Result<FsStat> {
+        let mut fs = self.fs.lock();
+        fs.recover()?;
+        fs.stat()
+    }
+    /// Change the permission bits
+    pub fn chmod(&self, mode: u16) -> Result<()> {
+        self.locked(|fs| {
+            self.modify_disk_inode(|disk_inode| {
+                disk_inode.mode = mode & 0o7777;
+                disk_inode.ctime = fs.now();
+                Ok(())
+            })?;
+            fs.commit()
+        })
+    }
+    pub fn chown(&self, uid: u16, gid: u16) -> Result<()> {
+        self.locked(|fs| {
+            self.modify_disk_inode(|disk_inode| {
+                disk_inode.uid = uid;
+                disk_inode.gid = gid;
+                disk_inode.ctime = fs.now();
+                Ok(())
+            })?;
+            fs.commit()
+        })
+    }
+    /// Set access and modification time explicitly, like `utimes`
+    pub fn set_times(&self, atime: u32, mtime: u32) -> Result<()> {
+        self.locked(|fs| {
+            self.modify_disk_inode(|disk_inode| {
+                disk_inode.atime = atime;
+                disk_inode.mtime = mtime;
+                disk_inode.ctime = fs.now();
+                Ok(())
+            })?;
+            fs.commit()
+        })
+    }
+    /// |Assume Locked| the index of the dirents of this directory, whose
+    /// disk inode is `dir_inode`, built by a scan of them unless cached.
+    ///
+    /// The first of several dirents of the same name is the one indexed, as
+    /// a scan would find it, and names a corrupted dirent may have are not.
+    fn dir_index<'a>(
+        &self,
+        dir_inode: &DiskInode,
+        fs: &'a mut MutexGuard<EasyFileSystem>,
+    ) -> Result<&'a mut DirIndex> {
+        // assert it is a directory
+        assert!(dir_inode.is_dir());
+        if !fs.dentries.contains(self.inode_id) {
+            let mut index = DirIndex::default();
+            self.for_each_dirent(dir_inode, fs.dirent_format, |dirent| {
+                match dirent {
+                    Dirent::Free { slot, slots } => index.add_free(slot, slots),
+                    Dirent::Named { slot, slots, name, inode_number } => {
+                        if index.get(name).is_none() {
+                            index.insert(name, slot, slots, inode_number);
+                        }
+                    }
+                    Dirent::Corrupt { .. } => {}
+                }
+                Ok(())
+            })?;
+            fs.dentries.insert(self.inode_id, index);
+        }
+        Ok(fs.dentries.get_mut(self.inode_id).unwrap())
+    }
+    /// |Assume Locked| find the slot index and inode id of the dirent `name`
+    /// of this directory
+    fn find_dirent(
+        &self,
+        name: &str,
+        dir_inode: &DiskInode,
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) -> Result<Option<(usize, u32)>> {
+        Ok(self.dir_index(dir_inode, fs)?.get(name))
     }
     fn find_inode_id(
         &self,
         name: &str,
-        disk_inode: &DiskInode,
-    ) -> Option<u32> {
-        // assert it is a directory
-        assert!(disk_inode.is_dir());
-        let file_count = (disk_inode.size as usize) / DIRENT_SZ;
-        let mut dirent = DirEntry::empty();
-        for i in 0..file_count {
-            assert_eq!(
-                disk_inode.read_at(
-                    DIRENT_SZ * i,
-                    dirent.as_bytes_mut(),
-                    &self.block_device,
-                ),
-                DIRENT_SZ,
-            );
-            if dirent.name() == name {
-                return Some(dirent.inode_number() as u32);
-            }
-        }
-        None
-    }
-    pub fn find(&self, name: &str) -> Option<Arc<Inode>> {
-        let fs = self.fs.lock();
+        dir_inode: &DiskInode,
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) -> Result<Option<u32>> {
+        Ok(self.find_dirent(name, dir_inode, fs)?.map(|(_, inode_id)| inode_id))
+    }
+    /// |Assume Locked| call `f` on the dirents of a directory disk inode in
+    /// order, stopping at the first error
+    fn for_each_dirent(
+        &self,
+        dir_inode: &DiskInode,
+        format: DirentFormat,
+        mut f: impl FnMut(Dirent) -> Result<()>,
+    ) -> Result<()> {
+        let mut buf = [0u8; BLOCK_SZ];
+        for offset in (0..dir_inode.size as usize).step_by(BLOCK_SZ) {
+            let len = dir_inode.read_at(offset, &mut buf, &self.block_device)?;
+            for dirent in format.decode(&buf[..len], offset / DIRENT_SZ) {
+                f(dirent)?;
+            }
+        }
+        Ok(())
+    }
+    /// |Assume Locked| whether a directory holds nothing but `.` and `..`
+    fn is_empty_dir(&self, disk_inode: &DiskInode, format: DirentFormat) -> Result<bool> {
+        let mut empty = true;
+        self.for_each_dirent(disk_inode, format, |dirent| {
+            match dirent {
+                Dirent::Named { name: "." | "..", .. } | Dirent::Free { .. } => {}
+                _ => empty = false,
+            }
+            Ok(())
+        })?;
+        Ok(empty)
+    }
+    /// Find an inode by a `/`-separated path relative to this directory.
+    ///
+    /// A leading `/` starts the lookup from the root directory, and `.`/`..`
+    /// are resolved through the entries every directory carries.
+    pub fn find(&self, path: &str) -> Result<Arc<Inode>> {
+        let mut fs = self.fs.lock();
+        fs.recover()?;
+        let mut inode_id = if path.starts_with('/') { 0 } else { self.inode_id };
+        for name in path.split('/').filter(|name| !name.is_empty() && *name != ".") {
+            if name.len() > fs.name_limit() {
+                return Err(FsError::NameTooLong);
+            }
+            let dir = self.get_inode(inode_id, &fs);
+            inode_id = dir.read_disk_inode(|disk_inode| {
+                if !disk_inode.is_dir() {
+                    return Err(FsError::NotDir);
+                }
+                if disk_inode.nlink == 0 {
+                    // removed while open, even its ".." may be gone
+                    return Err(FsError::NotFound);
+                }
+                dir.find_inode_id(name, disk_inode, &mut fs)?.ok_or(FsError::NotFound)
+            })?;
+        }
+        Ok(self.get_inode(inode_id, &fs))
+    }
+    /// List names in this directory, `.`, `..` and removed entries excluded
+    pub fn ls(&self) -> Result<Vec<String>> {
+        let mut fs = self.fs.lock();
+        fs.recover()?;
         self.read_disk_inode(|disk_inode| {
-            self.find_inode_id(name, disk_inode)
-                .map(|inode_id| {
-                    let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
-                    Arc::new(Self::new(
-                        block_id,
-                        block_offset,
-                        Arc::clone(&self.fs),
-                        Arc::clone(&self.block_device),
-                    ))
+            if !disk_inode.is_dir() {
+                return Err(FsError::NotDir);
+            }
+            let mut v: Vec<String> = Vec::new();
+            self.for_each_dirent(disk_inode, fs.dirent_format, |dirent| {
+                match dirent {
+                    Dirent::Named { name: "." | "..", .. } | Dirent::Free { .. } => {}
+                    Dirent::Named { name, .. } => v.push(String::from(name)),
+                    Dirent::Corrupt { .. } => return Err(FsError::Corrupt),
+                }
+                Ok(())
+            })?;
+            Ok(v)
+        })
+    }
+    /// Create a regular file named `name` in this directory
+    pub fn create(&self, name: &str) -> Result<Arc<Inode>> {
+        self.create_inode(name, DiskInodeType::File)
+    }
+    /// Create a sub-directory named `name` with its `.` and `..` entries
+    pub fn mkdir(&self, name: &str) -> Result<Arc<Inode>> {
+        self.create_inode(name, DiskInodeType::Directory)
+    }
+    fn create_inode(&self, name: &str, type_: DiskInodeType) -> Result<Arc<Inode>> {
+        self.locked(|fs| {
+            check_name(name, fs.name_limit())?;
+            self.read_disk_inode(|dir_inode| {
+                if !dir_inode.is_dir() {
+                    Err(FsError::NotDir)
+                } else if dir_inode.nlink == 0 {
+                    // removed while open, nothing is added to it
+                    Err(FsError::NotFound)
+                } else if self.find_inode_id(name, dir_inode, fs)?.is_some() {
+                    Err(FsError::Exists)
+                } else {
+                    Ok(())
+                }
+            })?;
+            // create a new inode
+            let new_inode_id = fs.alloc_inode()?;
+            // initialize inode
+            let new_inode = self.get_inode(new_inode_id, fs);
+            let result = new_inode
+                .modify_disk_inode(|disk_inode| {
+                    disk_inode.initialize(type_, fs.now());
+                    if type_ == DiskInodeType::Directory {
+                        new_inode.add_dirent(".", new_inode_id, disk_inode, fs)?;
+                        new_inode.add_dirent("..", self.inode_id, disk_inode, fs)?;
+                    }
+                    Ok(())
                 })
+                .and_then(|()| self.modify_disk_inode(|dir_inode| {
+                    self.add_dirent(name, new_inode_id, dir_inode, fs)?;
+                    dir_inode.mtime = fs.now();
+                    dir_inode.ctime = fs.now();
+                    if type_ == DiskInodeType::Directory {
+                        // linked by the ".." of the new directory
+                        dir_inode.nlink += 1;
+                    }
+                    Ok(())
+                }));
+            match result {
+                // dropped whole by `locked`
+                Err(FsError::Io) => return Err(FsError::Io),
+                Err(error) => {
+                    // nothing is committed yet, give back what the new inode took
+                    new_inode.modify_disk_inode(|disk_inode| {
+                        for data_block in disk_inode.decrease_size(0, &self.block_device)? {
+                            fs.dealloc_data(data_block)?;
+                        }
+                        Ok(())
+                    })?;
+                    fs.dealloc_inode(new_inode_id)?;
+                    fs.commit()?;
+                    return Err(error);
+                }
+                Ok(()) => {}
+            }
+            fs.commit()?;
+            Ok(new_inode)
         })
     }
-    pub fn ls(&self) -> Vec<String> {
-        let _fs = self.fs.lock();
-        self.read_disk_inode(|disk_inode| {
-            let file_count = (disk_inode.size as usize) / DIRENT_SZ;
-            let mut dirent = DirEntry::empty();
-            let mut v: Vec<String> = Vec::new();
-            for i in 0..file_count {
-                assert_eq!(
-                    disk_inode.read_at(
-                        DIRENT_SZ * i,
-                        dirent.as_bytes_mut(),
-                        &self.block_device,
-                    ), DIRENT_SZ,
-                );
-                v.push(String::from(dirent.name()));
-            }
-            v
+    /// |Assume Locked| put a dirent into the first removed slot of this
+    /// directory, whose disk inode is `dir_inode`, or append it at the end
+    fn add_dirent(
+        &self,
+        name: &str,
+        inode_id: u32,
+        dir_inode: &mut DiskInode,
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) -> Result<()> {
+        self.mark_data_dirty();
+        let format = fs.dirent_format;
+        let slots = format.slots(name.len());
+        let (slot, run) = match self.dir_index(dir_inode, fs)?.first_fit(slots) {
+            Some(run) => run,
+            None => {
+                // increase size
+                let slot = dir_inode.size as usize / DIRENT_SZ;
+                let grown = format.grow_slots();
+                self.alloc_range(slot * DIRENT_SZ, (slot + grown) * DIRENT_SZ, dir_inode, fs)?;
+                self.dir_index(dir_inode, fs)?.add_free(slot, grown);
+                (slot, grown)
+            }
+        };
+        dir_inode.write_at(
+            slot * DIRENT_SZ,
+            &format.encode(name, inode_id, slots, run - slots),
+            &self.block_device,
+        )?;
+        self.dir_index(dir_inode, fs)?.insert(name, slot, slots, inode_id);
+        fs.dentries.shrink(self.inode_id);
+        Ok(())
+    }
+    /// |Assume Locked| remove the dirent `name` of this directory, whose
+    /// disk inode is `dir_inode`, leaving its slots to later dirents
+    fn remove_dirent(
+        &self,
+        name: &str,
+        dir_inode: &mut DiskInode,
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) -> Result<()> {
+        self.mark_data_dirty();
+        let format = fs.dirent_format;
+        let (slot, slots) = self.dir_index(dir_inode, fs)?.remove(name).ok_or(FsError::NotFound)?;
+        dir_inode.write_at(slot * DIRENT_SZ, &format.encode_free(slots), &self.block_device)?;
+        Ok(())
+    }
+    /// |Assume Locked| drop a link of `inode_id`, reclaiming its data and
+    /// the inode itself once no link is left.
+    ///
+    /// Once the last link is gone, the operation so far is committed and the
+    /// data released in transactions of its own; a crash in between leaves
+    /// an unlinked inode still holding blocks, but no dangling entry. An
+    /// inode some `Inode` still refers to is an orphan until that is dropped.
+    fn drop_link(&self, inode_id: u32, fs: &mut MutexGuard<EasyFileSystem>) -> Result<()> {
+        let unused = self.get_inode(inode_id, fs).modify_disk_inode(|disk_inode| {
+            // a removed directory loses its "." as well
+            disk_inode.nlink = if disk_inode.is_dir() {
+                0
+            } else {
+                disk_inode.nlink.saturating_sub(1)
+            };
+            disk_inode.ctime = fs.now();
+            Ok(disk_inode.nlink == 0)
+        })?;
+        if unused {
+            fs.commit()?;
+            fs.release_inode(inode_id)?;
+        }
+        Ok(())
+    }
+    /// Remove the file `name` from this directory. Its inode and data are
+    /// reclaimed together with the last link, or once the last `Inode` of
+    /// it is dropped if any is left, which can still read and write it.
+    pub fn unlink(&self, name: &str) -> Result<()> {
+        self.remove_entry(name, false)
+    }
+    /// Remove the empty sub-directory `name`
+    pub fn rmdir(&self, name: &str) -> Result<()> {
+        self.remove_entry(name, true)
+    }
+    fn remove_entry(&self, name: &str, is_dir: bool) -> Result<()> {
+        self.locked(|fs| {
+            check_name(name, fs.name_limit())?;
+            let inode_id = self.read_disk_inode(|dir_inode| {
+                if !dir_inode.is_dir() {
+                    return Err(FsError::NotDir);
+                }
+                self.find_inode_id(name, dir_inode, fs)?.ok_or(FsError::NotFound)
+            })?;
+            self.get_inode(inode_id, fs).read_disk_inode(|disk_inode| {
+                match (is_dir, disk_inode.is_dir()) {
+                    (true, false) => Err(FsError::NotDir),
+                    (true, true) if !self.is_empty_dir(disk_inode, fs.dirent_format)? => Err(FsError::NotEmpty),
+                    (false, true) => Err(FsError::IsDir),
+                    _ => Ok(()),
+                }
+            })?;
+            self.modify_disk_inode(|dir_inode| {
+                self.remove_dirent(name, dir_inode, fs)?;
+                dir_inode.mtime = fs.now();
+                dir_inode.ctime = fs.now();
+                if is_dir {
+                    // the ".." of the removed directory is gone
+                    dir_inode.nlink -= 1;
+                }
+                Ok(())
+            })?;
+            self.drop_link(inode_id, fs)?;
+            fs.commit()
         })
     }
-    pub fn create(&self, name: &str) -> Option<Arc<Inode>> {
-        let mut fs = self.fs.lock();
-        if self.read_disk_inode(|root_inode| {
-            assert!(root_inode.is_dir());
-            self.find_inode_id(name, root_inode)
-        }).is_some() {
-            return None;
-        }
-        // create a new file
-        // alloc an inode with an indirect block
-        let new_inode_id = fs.alloc_inode();
-        // initialize inode
-        let (new_inode_block_id, new_inode_block_offset) 
-            = fs.get_disk_inode_pos(new_inode_id);
-        get_block_cache(new_inode_block_id as usize, Arc::clone(&self.block_device))
-            .lock()
-            .modify(new_inode_block_offset, |disk_inode: &mut DiskInode| {
-                disk_inode.initialize(DiskInodeType::File);
-            });
-        self.modify_disk_inode(|root_inode| {
-            // append file in the dirent
-            let file_count = (root_inode.size as usize) / DIRENT_SZ;
-            let new_size = (file_count + 1) * DIRENT_SZ;
-            // increase size
-            self.increase_size(new_size as u32, root_inode, &mut fs);
-            let dirent = DirEntry::new(name, new_inode_id);
-            root_inode.write_at(
-                file_count * DIRENT_SZ,
-                dirent.as_bytes(), 
-                &self.block_device,
-            );
-        });
-        
-        let (block_id, block_offset) = fs.get_disk_inode_pos(new_inode_id);
-        block_cache_sync_all();
-        // return inode
-        Some(Arc::new(Self::new(
-            block_id,
-            block_offset,
-            self.fs.clone(),
-            self.block_device.clone(),
-        )))
+    /// Add a hard link `name` in this directory to the regular file `target`
+    pub fn link(&self, name: &str, target: &Inode) -> Result<()> {
+        if !Arc::ptr_eq(&self.fs, &target.fs) {
+            return Err(FsError::CrossDevice);
+        }
+        self.locked(|fs| {
+            check_name(name, fs.name_limit())?;
+            if target.read_disk_inode(|disk_inode| Ok(disk_inode.is_dir()))? {
+                return Err(FsError::IsDir);
+            }
+            self.read_disk_inode(|dir_inode| {
+                if !dir_inode.is_dir() {
+                    Err(FsError::NotDir)
+                } else if dir_inode.nlink == 0 {
+                    Err(FsError::NotFound)
+                } else if self.find_inode_id(name, dir_inode, fs)?.is_some() {
+                    Err(FsError::Exists)
+                } else {
+                    Ok(())
+                }
+            })?;
+            if target.read_disk_inode(|disk_inode| Ok(disk_inode.nlink))? == 0 {
+                // an orphan is not linked back
+                return Err(FsError::NotFound);
+            }
+            self.modify_disk_inode(|dir_inode| {
+                self.add_dirent(name, target.inode_id, dir_inode, fs)?;
+                dir_inode.mtime = fs.now();
+                dir_inode.ctime = fs.now();
+                Ok(())
+            })?;
+            target.modify_disk_inode(|disk_inode| {
+                disk_inode.nlink += 1;
+                disk_inode.ctime = fs.now();
+                Ok(())
+            })?;
+            fs.commit()
+        })
+    }
+    /// Move the entry `old_name` of this directory to `new_name` in `new_dir`.
+    ///
+    /// An existing file at the destination is replaced; directories can not
+    /// be replaced, nor moved below themselves.
+    pub fn rename(&self, old_name: &str, new_dir: &Inode, new_name: &str) -> Result<()> {
+        if !Arc::ptr_eq(&self.fs, &new_dir.fs) {
+            return Err(FsError::CrossDevice);
+        }
+        self.locked(|fs| {
+            check_name(old_name, fs.name_limit())?;
+            check_name(new_name, fs.name_limit())?;
+            let inode_id = self.read_disk_inode(|dir_inode| {
+                if !dir_inode.is_dir() {
+                    return Err(FsError::NotDir);
+                }
+                self.find_inode_id(old_name, dir_inode, fs)?.ok_or(FsError::NotFound)
+            })?;
+            match new_dir.read_disk_inode(|dir_inode| Ok((dir_inode.is_dir(), dir_inode.nlink)))? {
+                (false, _) => return Err(FsError::NotDir),
+                (true, 0) => return Err(FsError::NotFound),
+                _ => {}
+            }
+            let moved = self.get_inode(inode_id, fs);
+            let is_dir = moved.read_disk_inode(|disk_inode| Ok(disk_inode.is_dir()))?;
+            if is_dir && self.is_ancestor(inode_id, new_dir.inode_id, fs)? {
+                return Err(FsError::Invalid);
+            }
+            // a moved directory is re-parented through its ".."
+            let reparent = is_dir && self.inode_id != new_dir.inode_id;
+            if reparent && moved.read_disk_inode(|disk_inode| moved.find_inode_id("..", disk_inode, fs))?.is_none() {
+                return Err(FsError::Corrupt);
+            }
+            // dropped last, when the entries are consistent again
+            let mut replaced = None;
+            match new_dir.read_disk_inode(|dir_inode| new_dir.find_dirent(new_name, dir_inode, fs))? {
+                Some((_, replaced_id)) if replaced_id == inode_id => return Ok(()),
+                Some((_, replaced_id)) => {
+                    let replaced_is_dir = self.get_inode(replaced_id, fs)
+                        .read_disk_inode(|disk_inode| Ok(disk_inode.is_dir()))?;
+                    match (is_dir, replaced_is_dir) {
+                        (true, true) => return Err(FsError::Exists),
+                        (true, false) => return Err(FsError::NotDir),
+                        (false, true) => return Err(FsError::IsDir),
+                        (false, false) => {}
+                    }
+                    new_dir.modify_disk_inode(|dir_inode| {
+                        // the same name takes the slots it leaves
+                        new_dir.remove_dirent(new_name, dir_inode, fs)?;
+                        new_dir.add_dirent(new_name, inode_id, dir_inode, fs)?;
+                        dir_inode.mtime = fs.now();
+                        dir_inode.ctime = fs.now();
+                        Ok(())
+                    })?;
+                    replaced = Some(replaced_id);
+                }
+                None => {
+                    new_dir.modify_disk_inode(|dir_inode| {
+                        new_dir.add_dirent(new_name, inode_id, dir_inode, fs)?;
+                        dir_inode.mtime = fs.now();
+                        dir_inode.ctime = fs.now();
+                        Ok(())
+                    })?;
+                }
+            }
+            self.modify_disk_inode(|dir_inode| {
+                self.remove_dirent(old_name, dir_inode, fs)?;
+                dir_inode.mtime = fs.now();
+                dir_inode.ctime = fs.now();
+                Ok(())
+            })?;
+            moved.modify_disk_inode(|disk_inode| {
+                disk_inode.ctime = fs.now();
+                Ok(())
+            })?;
+            if reparent {
+                moved.modify_disk_inode(|disk_inode| {
+                    moved.remove_dirent("..", disk_inode, fs)?;
+                    moved.add_dirent("..", new_dir.inode_id, disk_inode, fs)
+                })?;
+                self.modify_disk_inode(|dir_inode| {
+                    dir_inode.nlink -= 1;
+                    Ok(())
+                })?;
+                new_dir.modify_disk_inode(|dir_inode| {
+                    dir_inode.nlink += 1;
+                    Ok(())
+                })?;
+            }
+            if let Some(replaced_id) = replaced {
+                self.drop_link(replaced_id, fs)?;
+            }
+            fs.commit()
+        })
+    }
+    /// |Assume Locked| whether `ancestor_id` is `inode_id` or one of its parents
+    fn is_ancestor(
+        &self,
+        ancestor_id: u32,
+        mut inode_id: u32,
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) -> Result<bool> {
+        loop {
+            if inode_id == ancestor_id {
+                return Ok(true);
+            }
+            if inode_id == 0 {
+                return Ok(false);
+            }
+            let inode = self.get_inode(inode_id, fs);
+            inode_id = inode
+                .read_disk_inode(|disk_inode| inode.find_inode_id("..", disk_inode, fs))?
+                .ok_or(FsError::Corrupt)?;
+        }
     }
-    /// |Assume Locked| increase the size of a disk inode
-    fn increase_size(
+    /// |Assume Locked| grow to `end` if needed and back the bytes
+    /// `[offset, end)` with data blocks, what is skipped is left as a hole.
+    ///
+    /// Out of space, the size is put back to cover no more than the bytes
+    /// backed from `offset` on, and the blocks past it are given back.
+    /// Blocks are looked for after the ones backing the file already.
+    fn alloc_range(
         &self,
-        new_size: u32,
+        offset: usize,
+        end: usize,
         disk_inode: &mut DiskInode,
         fs: &mut MutexGuard<EasyFileSystem>,
-    ) {
-        if new_size < disk_inode.size {
-            return;
+    ) -> Result<()> {
+        self.mark_data_dirty();
+        let size = disk_inode.size;
+        if end > size as usize {
+            disk_inode.increase_size(end as u32)?;
         }
-        let blocks_needed = disk_inode.blocks_num_needed(new_size);
-        let mut v: Vec<u32> = Vec::new();
-        for _ in 0..blocks_needed {
-            v.push(fs.alloc_data());
+        // blocks are taken in runs near the ones before, and handed out in
+        // order so that the range is laid out in sequence
+        let wanted = end.div_ceil(BLOCK_SZ).saturating_sub(offset / BLOCK_SZ);
+        let mut goal = disk_inode.alloc_goal((offset / BLOCK_SZ) as u32, &self.block_device)?;
+        let (mut next, mut left, mut taken) = (0, 0, 0);
+        let result = disk_inode.alloc_blocks(offset, end, || {
+            if left == 0 {
+                let (first, len) = fs.alloc_data_run(goal, wanted.saturating_sub(taken).max(1))?;
+                next = first;
+                left = len;
+            }
+            fs.zero_data(next)?;
+            let block_id = next;
+            next += 1;
+            left -= 1;
+            taken += 1;
+            goal = Some(next);
+            Ok(block_id)
+        }, &self.block_device);
+        // the part of the last run not needed, for holes already backed
+        for block_id in next..next + left as u32 {
+            fs.dealloc_data(block_id)?;
+        }
+        if result == Err(FsError::NoSpace) {
+            let backed_end = offset + disk_inode.backed_len(offset, end, &self.block_device)?;
+            let new_size = size.max(backed_end as u32);
+            if disk_inode.size > new_size {
+                for data_block in disk_inode.decrease_size(new_size, &self.block_device)? {
+                    fs.dealloc_data(data_block)?;
+                }
+            }
         }
-        disk_inode.increase_size(new_size, v, &self.block_device);
+        result
     }
-    pub fn clear(&self) {
-        let mut fs = self.fs.lock();
-        self.modify_disk_inode(|disk_inode| {
-            let size = disk_inode.size;
-            // vector of efs block id
-            let data_blocks_dealloc = disk_inode.clear_size(&self.block_device);
-            assert!(data_blocks_dealloc.len() == DiskInode::total_blocks(size) as usize);
-            for data_block in data_blocks_dealloc.into_iter() {
-                fs.dealloc_data(data_block);
-            }
-        });
-        block_cache_sync_all();
-    }
-    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
-        let _fs = self.fs.lock();
-        self.read_disk_inode(|disk_inode| {
-            disk_inode.read_at(offset, buf, &self.block_device)
+    /// |Assume Locked| shrink to `new_size`, releasing at most `TXN_BLOCKS`
+    /// data blocks per transaction; a crash leaves the size somewhere between
+    fn shrink(&self, new_size: u32, fs: &mut MutexGuard<EasyFileSystem>) -> Result<()> {
+        loop {
+            self.mark_data_dirty();
+            let size = self.modify_disk_inode(|disk_inode| {
+                let size = disk_inode.size
+                    .saturating_sub((TXN_BLOCKS * BLOCK_SZ) as u32)
+                    .max(new_size);
+                for data_block in disk_inode.decrease_size(size, &self.block_device)? {
+                    fs.dealloc_data(data_block)?;
+                }
+                Ok(size)
+            })?;
+            fs.commit()?;
+            if size == new_size {
+                return Ok(());
+            }
+        }
+    }
+    pub fn clear(&self) -> Result<()> {
+        self.truncate(0)
+    }
+    /// Set the size of a regular file to `new_size`, growing with a hole
+    /// and releasing the tail blocks on shrink
+    pub fn truncate(&self, new_size: u32) -> Result<()> {
+        if new_size as usize > MAX_FILE_SIZE {
+            return Err(FsError::FileTooLarge);
+        }
+        self.locked(|fs| {
+            let (is_dir, size) = self.read_disk_inode(|disk_inode| Ok((disk_inode.is_dir(), disk_inode.size)))?;
+            if is_dir {
+                return Err(FsError::IsDir);
+            }
+            if new_size < size {
+                self.shrink(new_size, fs)?;
+            }
+            self.modify_disk_inode(|disk_inode| {
+                if new_size > disk_inode.size {
+                    self.mark_data_dirty();
+                    disk_inode.increase_size(new_size)?;
+                }
+                disk_inode.mtime = fs.now();
+                disk_inode.ctime = fs.now();
+                Ok(())
+            })?;
+            fs.commit()
         })
     }
-    pub fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
-        let mut fs = self.fs.lock();
-        let size = self.modify_disk_inode(|disk_inode| {
-            self.increase_size((offset + buf.len()) as u32, disk_inode, &mut fs);
-            disk_inode.write_at(offset, buf, &self.block_device)
-        });
-        block_cache_sync_all();
-        size
+    /// The access time is updated the relatime way: only when it is not
+    /// newer than the last change or is a day old, sparing most reads a commit
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.locked(|fs| {
+            let now = fs.now();
+            let (size, stale) = self.read_disk_inode(|disk_inode| {
+                let atime = disk_inode.atime;
+                let stale = now != atime && (atime <= disk_inode.mtime
+                    || atime <= disk_inode.ctime
+                    || now >= atime.saturating_add(24 * 3600));
+                Ok((disk_inode.read_at(offset, buf, &self.block_device)?, stale))
+            })?;
+            if stale {
+                self.modify_disk_inode(|disk_inode| {
+                    disk_inode.atime = now;
+                    Ok(())
+                })?;
+                fs.commit()?;
+            }
+            Ok(size)
+        })
+    }
+    /// Write to a regular file, cut short at `MAX_FILE_SIZE` or when the
+    /// image is full; an error is returned only if nothing is written.
+    ///
+    /// Every `TXN_BLOCKS` data blocks are committed on their own, so a crash
+    /// or an I/O error may leave a prefix of a large write.
+    pub fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        self.locked(|fs| {
+            if self.read_disk_inode(|disk_inode| Ok(disk_inode.is_dir()))? {
+                return Err(FsError::IsDir);
+            }
+            if offset >= MAX_FILE_SIZE && !buf.is_empty() {
+                return Err(FsError::FileTooLarge);
+            }
+            let end = (offset + buf.len()).min(MAX_FILE_SIZE);
+            let mut start = offset;
+            while start < end {
+                let txn_end = end.min((start / BLOCK_SZ + TXN_BLOCKS) * BLOCK_SZ);
+                let (written_end, result) = self.modify_disk_inode(|disk_inode| {
+                    let result = self.alloc_range(start, txn_end, disk_inode, fs);
+                    // out of space, what is backed before the first hole is written
+                    let written_end = match result {
+                        Ok(()) => txn_end,
+                        Err(FsError::NoSpace) => {
+                            start + disk_inode.backed_len(start, txn_end, &self.block_device)?
+                        }
+                        Err(error) => return Err(error),
+                    };
+                    if written_end > start {
+                        self.mark_data_dirty();
+                        disk_inode.mtime = fs.now();
+                        disk_inode.ctime = fs.now();
+                        disk_inode.write_at(start, &buf[start - offset..written_end - offset], &self.block_device)?;
+                    }
+                    Ok((written_end, result))
+                })?;
+                fs.commit()?;
+                if let Err(error) = result {
+                    if written_end == offset {
+                        return Err(error);
+                    }
+                    return Ok(written_end - offset);
+                }
+                start = txn_end;
+            }
+            Ok(end.saturating_sub(offset))
+        })
+    }
+    /// Make what was done to this inode durable, which only matters under
+    /// `WritePolicy::WriteBack`. The journal commits the operations pending
+    /// on the fs as a whole, so those on other inodes go along; nothing is
+    /// written if this inode has nothing pending.
+    pub fn fsync(&self) -> Result<()> {
+        self.sync_if(InodeDirty::Metadata)
+    }
+    /// As `fsync`, but a change of timestamps, mode, owner or links alone
+    /// is left pending, as it is not needed to read the data back
+    pub fn fdatasync(&self) -> Result<()> {
+        self.sync_if(InodeDirty::Data)
+    }
+    fn sync_if(&self, dirty: InodeDirty) -> Result<()> {
+        self.locked(|fs| {
+            if inode_dirty(&self.block_device, self.inode_id) >= Some(dirty) {
+                fs.sync()?;
+            }
+            Ok(())
+        })
+    }
+    /// Make every operation on the fs ended so far durable
+    pub fn sync_fs(&self) -> Result<()> {
+        self.locked(|fs| fs.sync())
+    }
+}
+
+impl Drop for Inode {
+    /// The last `Inode` of an orphan gives it back, or leaves that to the
+    /// next operation on the fs if it is locked meanwhile
+    fn drop(&mut self) {
+        if self.open_inodes.lock().close(self.inode_id) {
+            if let Some(mut fs) = self.fs.try_lock() {
+                // nothing is left to report a failure to, the orphan is kept
+                // and tried again by the next operation
+                if fs.recover().and_then(|()| fs.release_orphans()) == Err(FsError::Io) {
+                    fs.abort();
+                }
+            }
+        }
     }
 }
 
+/// A dirent name must be a single path component fitting in a dirent,
+/// of at most `name_limit` bytes
+fn check_name(name: &str, name_limit: usize) -> Result<()> {
+    if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+        Err(FsError::InvalidName)
+    } else if name.len() > name_limit {
+        Err(FsError::NameTooLong)
+    } else {
+        Ok(())
+    }
+}
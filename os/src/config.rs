@@ -4,6 +4,8 @@ pub const USER_STACK_SIZE: usize = 4096 * 2;
 pub const KERNEL_STACK_SIZE: usize = 4096 * 2;
 
 pub const KERNEL_HEAP_SIZE:usize = 0x20_0000;
+/// Blocks of the root file system kept cached, 128 KiB of the kernel heap
+pub const BLOCK_CACHE_BLOCKS: usize = 256;
 pub const PAGE_SIZE_BITS: usize = 0xc;
 pub const PAGE_SIZE: usize = 0x1000;
 
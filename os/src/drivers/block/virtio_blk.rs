@@ -5,11 +5,14 @@ use crate::mm::{
 };
 use crate::sync::UPSafeCell;
 use alloc::vec::Vec;
+use easy_fs::{FsError, BLOCK_SZ};
 use lazy_static::*;
 use virtio_drivers::{Hal, VirtIOBlk, VirtIOHeader};
 
 #[allow(unused)]
 const VIRTIO0: usize = 0x10001000;
+/// Offset of the device specific config space in a virtio-mmio device
+const VIRTIO_CONFIG_OFFSET: usize = 0x100;
 
 pub struct VirtIOBlock(UPSafeCell<VirtIOBlk<'static, VirtioHal>>);
 
@@ -18,17 +21,31 @@ lazy_static! {
 }
 
 impl BlockDevice for VirtIOBlock {
-    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+    /// The MMIO address of the device, there being one at it
+    fn device_id(&self) -> usize {
+        VIRTIO0
+    }
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> easy_fs::Result<()> {
         self.0
             .exclusive_access()
             .read_block(block_id, buf)
-            .expect("Error when reading VirtIOBlk");
+            .map_err(|_| FsError::Io)
     }
-    fn write_block(&self, block_id: usize, buf: &[u8]) {
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> easy_fs::Result<()> {
         self.0
             .exclusive_access()
             .write_block(block_id, buf)
-            .expect("Error when writing VirtIOBlk");
+            .map_err(|_| FsError::Io)
+    }
+    /// The driver has no flush request, a request completed before it
+    /// returns is all the ordering it gives
+    fn flush(&self) -> easy_fs::Result<()> {
+        Ok(())
+    }
+    fn num_blocks(&self) -> Option<usize> {
+        // the capacity in 512-byte sectors leads the config space
+        let capacity = unsafe { ((VIRTIO0 + VIRTIO_CONFIG_OFFSET) as *const u64).read_volatile() };
+        Some(capacity as usize * 512 / BLOCK_SZ)
     }
 }
 
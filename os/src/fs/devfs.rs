@@ -0,0 +1,203 @@
+//! devfs, a pseudo file system of device nodes: a flat directory of the
+//! devices the kernel has, each read and written through its driver.
+
+use alloc::{string::{String, ToString}, sync::Arc, vec::Vec};
+use core::any::Any;
+use easy_fs::{FsError, BLOCK_SZ, LONG_NAME_LENGTH_LIMIT};
+use crate::drivers::BLOCK_DEVICE;
+use crate::random::{add_entropy, fill_random};
+use crate::sbi::console_putchar;
+use super::stdio::getchar;
+use super::vfs::{FileSystem, VfsInode};
+use super::{Stat, StatFs, StatMode};
+
+/// `StatFs::type_` of a devfs, the magic number Linux gives it
+const DEVFS_SUPER_MAGIC: i64 = 0x1373;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Device {
+    /// Reads nothing, takes whatever is written
+    Null,
+    /// Reads zeros, takes whatever is written
+    Zero,
+    /// Reads the kernel RNG, what is written is entropy for it
+    Random,
+    Console,
+    /// The raw virtio disk, the root file system
+    Disk,
+}
+
+struct DevNode {
+    name: &'static str,
+    device: Device,
+    /// Device numbers of the node on Linux
+    major: u64,
+    minor: u64,
+}
+
+/// The nodes of the directory, by name
+static DEV_NODES: &[DevNode] = &[
+    DevNode { name: "console", device: Device::Console, major: 5, minor: 1 },
+    DevNode { name: "null", device: Device::Null, major: 1, minor: 3 },
+    DevNode { name: "random", device: Device::Random, major: 1, minor: 8 },
+    DevNode { name: "tty", device: Device::Console, major: 5, minor: 0 },
+    DevNode { name: "urandom", device: Device::Random, major: 1, minor: 9 },
+    DevNode { name: "vda", device: Device::Disk, major: 254, minor: 0 },
+    DevNode { name: "zero", device: Device::Zero, major: 1, minor: 5 },
+];
+
+/// The directory, or one of its nodes by index
+pub struct DevInode {
+    node: Option<usize>,
+}
+
+impl DevInode {
+    fn new(node: Option<usize>) -> Arc<dyn VfsInode> {
+        Arc::new(Self { node })
+    }
+    fn device(&self) -> Result<Device, FsError> {
+        match self.node {
+            Some(index) => Ok(DEV_NODES[index].device),
+            None => Err(FsError::IsDir),
+        }
+    }
+}
+
+fn disk_size() -> usize {
+    BLOCK_DEVICE.num_blocks().unwrap_or(0) * BLOCK_SZ
+}
+
+/// What is on the disk at `offset`, around the block cache of the file
+/// system mounted on it, which `sync` brings the disk up to
+fn read_disk(offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+    let end = disk_size().min(offset.saturating_add(buf.len()));
+    let mut block = [0u8; BLOCK_SZ];
+    let mut pos = offset;
+    while pos < end {
+        let block_offset = pos % BLOCK_SZ;
+        let len = (BLOCK_SZ - block_offset).min(end - pos);
+        BLOCK_DEVICE.read_block(pos / BLOCK_SZ, &mut block)?;
+        buf[pos - offset..pos - offset + len].copy_from_slice(&block[block_offset..block_offset + len]);
+        pos += len;
+    }
+    Ok(end.saturating_sub(offset))
+}
+
+impl VfsInode for DevInode {
+    fn stat(&self) -> Result<Stat, FsError> {
+        let index = match self.node {
+            Some(index) => index,
+            None => {
+                return Ok(Stat {
+                    ino: 1,
+                    mode: StatMode::DIR.bits() | 0o755,
+                    nlink: 2,
+                    ..Default::default()
+                })
+            }
+        };
+        let node = &DEV_NODES[index];
+        let (mode, size) = match node.device {
+            Device::Disk => (StatMode::BLOCK.bits() | 0o660, disk_size()),
+            _ => (StatMode::CHAR.bits() | 0o666, 0),
+        };
+        Ok(Stat {
+            ino: index as u64 + 2,
+            mode,
+            nlink: 1,
+            // the encoding Linux has for numbers this small
+            rdev: node.major << 8 | node.minor,
+            size: size as i64,
+            blksize: BLOCK_SZ as u32,
+            ..Default::default()
+        })
+    }
+    fn is_dir(&self) -> Result<bool, FsError> {
+        Ok(self.node.is_none())
+    }
+    fn lookup(&self, name: &str) -> Result<Arc<dyn VfsInode>, FsError> {
+        if self.node.is_some() {
+            return Err(FsError::NotDir);
+        }
+        match DEV_NODES.iter().position(|node| node.name == name) {
+            Some(index) => Ok(DevInode::new(Some(index))),
+            None => Err(FsError::NotFound),
+        }
+    }
+    fn ls(&self) -> Result<Vec<String>, FsError> {
+        if self.node.is_some() {
+            return Err(FsError::NotDir);
+        }
+        Ok(DEV_NODES.iter().map(|node| node.name.to_string()).collect())
+    }
+    /// The console waits for a character and reads one at a time
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        match self.device()? {
+            Device::Null => Ok(0),
+            Device::Zero => {
+                buf.fill(0);
+                Ok(buf.len())
+            }
+            Device::Random => {
+                fill_random(buf);
+                Ok(buf.len())
+            }
+            Device::Console if buf.is_empty() => Ok(0),
+            Device::Console => {
+                buf[0] = getchar();
+                Ok(1)
+            }
+            Device::Disk => read_disk(offset, buf),
+        }
+    }
+    /// The disk is written only by the file system mounted on it, it is
+    /// `FsError::Busy` otherwise
+    fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize, FsError> {
+        match self.device()? {
+            Device::Null | Device::Zero => {}
+            Device::Random => {
+                for chunk in buf.chunks(8) {
+                    let mut bytes = [0u8; 8];
+                    bytes[..chunk.len()].copy_from_slice(chunk);
+                    add_entropy(usize::from_le_bytes(bytes));
+                }
+            }
+            Device::Console => {
+                for &byte in buf {
+                    console_putchar(byte as usize);
+                }
+            }
+            Device::Disk => return Err(FsError::Busy),
+        }
+        Ok(buf.len())
+    }
+    /// A device has no size to change, opening it with `TRUNC` is fine
+    fn truncate(&self, _len: usize) -> Result<(), FsError> {
+        self.device().map(|_| ())
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+pub struct DevFs;
+
+impl FileSystem for DevFs {
+    fn root(&self) -> Arc<dyn VfsInode> {
+        DevInode::new(None)
+    }
+    fn statfs(&self) -> Result<StatFs, FsError> {
+        Ok(StatFs {
+            type_: DEVFS_SUPER_MAGIC,
+            bsize: BLOCK_SZ as i64,
+            namelen: LONG_NAME_LENGTH_LIMIT as i64,
+            frsize: BLOCK_SZ as i64,
+            ..Default::default()
+        })
+    }
+}
+
+/// Make a devfs, the source being only a name
+pub fn mount_devfs(_source: &str) -> Result<Arc<dyn FileSystem>, FsError> {
+    Ok(Arc::new(DevFs))
+}
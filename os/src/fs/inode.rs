@@ -1,156 +1,158 @@
-use alloc::{sync::Arc, vec::Vec};
-use easy_fs::{EasyFileSystem, Inode};
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::any::Any;
+use easy_fs::{DiskInodeType, EasyFileSystem, FsError, Inode, WritePolicy, BLOCK_SZ, EFS_MAGIC};
 use lazy_static::lazy_static;
-use log::debug;
-use crate::{drivers::BLOCK_DEVICE, sync::UPSafeCell};
-use super::{File, UserBuffer};
+use crate::{config::BLOCK_CACHE_BLOCKS, drivers::BLOCK_DEVICE, timer::get_time_ms};
+use super::vfs::{FileSystem, VfsInode};
+use super::{Stat, StatFs, StatMode};
 
-/// A wrapper around a filesystem inode
-/// to implement File trait atop
+/// The easy-fs backend of the VFS, a wrapper around an inode of the image
 pub struct OSInode {
-    readable: bool,
-    writable: bool,
-    inner: UPSafeCell<OSInodeInner>,
-}
-
-pub struct OSInodeInner {
-    offset: usize,
     inode: Arc<Inode>,
 }
 
 impl OSInode {
     /// Construct an OS inode from a inode
-    pub fn new(readable: bool, writable: bool, inode: Arc<Inode>) -> Self {
-        Self {
-            readable,
-            writable,
-            inner: unsafe {UPSafeCell::new(OSInodeInner { offset: 0, inode, })}
-        }
+    pub fn new(inode: Arc<Inode>) -> Arc<dyn VfsInode> {
+        Arc::new(Self { inode })
     }
-    /// Read all data inside an inode into vector
-    pub fn read_all(&self) -> Vec<u8> {
-        let mut inner = self.inner.exclusive_access();
-        let mut buffer = [0u8; 512];
-        let mut v: Vec<u8> = Vec::new();
-
-        loop {
-            let len = inner.inode.read_at(inner.offset, &mut buffer);
-            if len == 0 {
-                break;
-            }
-            inner.offset += len;
-            v.extend_from_slice(&buffer[..len]);
+    /// The easy-fs inode of another VFS inode, which must be of easy-fs
+    fn of(other: &dyn VfsInode) -> Result<&Inode, FsError> {
+        match other.as_any().downcast_ref::<OSInode>() {
+            Some(other) => Ok(&other.inode),
+            None => Err(FsError::CrossDevice),
         }
-
-        v
     }
 }
 
-impl File for OSInode {
-    fn read(&self, mut buf: UserBuffer) -> usize {
-        let mut inner = self.inner.exclusive_access();
-        let mut total_read_size = 0usize;
-        for slice in buf.buffers.iter_mut() {
-            let read_size = inner.inode.read_at(inner.offset, *slice);
-            if read_size == 0 {
-                break;
-            }
-            total_read_size += read_size;
-            inner.offset += read_size;
-        }
-        total_read_size
-    }
-    fn write(&self, buf: UserBuffer) -> usize {
-        let mut inner = self.inner.exclusive_access();
-        let mut total_write_size = 0usize;
-        debug!("write buffer len:{:?}", buf.len());
-        for slice in buf.buffers.iter() {
-            let write_size = inner.inode.write_at(inner.offset, *slice);
-            assert_eq!(write_size, slice.len());
-            inner.offset += write_size;
-            total_write_size += write_size;
-        }
-        total_write_size
+impl VfsInode for OSInode {
+    fn stat(&self) -> Result<Stat, FsError> {
+        let stat = self.inode.stat()?;
+        let type_mode = match stat.type_ {
+            DiskInodeType::File => StatMode::FILE,
+            DiskInodeType::Directory => StatMode::DIR,
+        };
+        Ok(Stat {
+            ino: stat.inode_id as u64,
+            mode: type_mode.bits() | stat.mode as u32,
+            nlink: stat.nlink,
+            uid: stat.uid as u32,
+            gid: stat.gid as u32,
+            size: stat.size as i64,
+            blksize: BLOCK_SZ as u32,
+            blocks: stat.blocks as u64,
+            atime_sec: stat.atime as i64,
+            mtime_sec: stat.mtime as i64,
+            ctime_sec: stat.ctime as i64,
+            ..Default::default()
+        })
+    }
+    fn is_dir(&self) -> Result<bool, FsError> {
+        self.inode.is_dir()
+    }
+    fn lookup(&self, name: &str) -> Result<Arc<dyn VfsInode>, FsError> {
+        self.inode.find(name).map(OSInode::new)
+    }
+    fn ls(&self) -> Result<Vec<String>, FsError> {
+        self.inode.ls()
+    }
+    fn create(&self, name: &str) -> Result<Arc<dyn VfsInode>, FsError> {
+        self.inode.create(name).map(OSInode::new)
+    }
+    fn mkdir(&self, name: &str) -> Result<Arc<dyn VfsInode>, FsError> {
+        self.inode.mkdir(name).map(OSInode::new)
+    }
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        self.inode.unlink(name)
+    }
+    fn rmdir(&self, name: &str) -> Result<(), FsError> {
+        self.inode.rmdir(name)
+    }
+    fn link(&self, name: &str, target: &dyn VfsInode) -> Result<(), FsError> {
+        self.inode.link(name, OSInode::of(target)?)
+    }
+    fn rename(&self, old_name: &str, new_dir: &dyn VfsInode, new_name: &str) -> Result<(), FsError> {
+        self.inode.rename(old_name, OSInode::of(new_dir)?, new_name)
+    }
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        self.inode.read_at(offset, buf)
     }
-    fn readable(&self) -> bool {
-        self.readable
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize, FsError> {
+        self.inode.write_at(offset, buf)
     }
-    fn writable(&self) -> bool {
-        self.writable
+    fn truncate(&self, len: usize) -> Result<(), FsError> {
+        let len = u32::try_from(len).map_err(|_| FsError::FileTooLarge)?;
+        self.inode.truncate(len)
+    }
+    fn fsync(&self, data_only: bool) -> Result<(), FsError> {
+        if data_only {
+            self.inode.fdatasync()
+        } else {
+            self.inode.fsync()
+        }
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
     }
 }
 
-lazy_static! {
-    pub static ref ROOT_INODE: Arc<Inode> = {
-        let efs = EasyFileSystem::open(BLOCK_DEVICE.clone());
-        Arc::new(EasyFileSystem::root_inode(&efs))
-    };
+/// The easy-fs image on the block device
+pub struct EasyFs {
+    root_inode: Arc<Inode>,
 }
 
-pub fn list_apps() {
-    println!("/**** APPS ****");
-    for app in ROOT_INODE.ls() {
-        println!("{}", app);
+impl FileSystem for EasyFs {
+    fn root(&self) -> Arc<dyn VfsInode> {
+        OSInode::new(self.root_inode.clone())
+    }
+    fn statfs(&self) -> Result<StatFs, FsError> {
+        let stat = self.root_inode.statfs()?;
+        Ok(StatFs {
+            type_: EFS_MAGIC as i64,
+            bsize: BLOCK_SZ as i64,
+            blocks: stat.data_blocks as u64,
+            bfree: stat.free_blocks as u64,
+            bavail: stat.free_blocks as u64,
+            files: stat.inodes as u64,
+            ffree: stat.free_inodes as u64,
+            namelen: stat.name_limit as i64,
+            frsize: BLOCK_SZ as i64,
+            ..Default::default()
+        })
+    }
+    fn sync(&self) -> Result<(), FsError> {
+        self.root_inode.sync_fs()
     }
-    println!("**************/")
 }
 
-bitflags! {
-    pub struct OpenFlags: u32 {
-        const RDONLY = 0;
-        const WRONLY = 1 << 0;
-        const RDWR = 1 << 1;
-        const CREATE = 1 << 9;
-        const TRUNC = 1 << 10;
-    }
+lazy_static! {
+    /// Opened once, however many times it is mounted. Operations are made
+    /// durable by `sync`, `fsync` and shutdown, or once the journal fills up.
+    static ref EASY_FS: Arc<EasyFs> = {
+        let efs = EasyFileSystem::open_with_cache(BLOCK_DEVICE.clone(), BLOCK_CACHE_BLOCKS)
+            .expect("Error loading EFS!");
+        // without an RTC, inodes are stamped with seconds since boot
+        efs.lock().set_clock(|| (get_time_ms() / 1000) as u32);
+        efs.lock().set_write_policy(WritePolicy::WriteBack).expect("Error loading EFS!");
+        Arc::new(EasyFs {
+            root_inode: Arc::new(EasyFileSystem::root_inode(&efs)),
+        })
+    };
 }
 
-impl OpenFlags {
-    /// Do not check validity for simplicity
-    /// Return (readable, writable)
-    pub fn read_write(&self) -> (bool, bool) {
-        if self.is_empty() {  // read only
-            (true, false) 
-        } else if self.contains(Self::WRONLY) {
-            (false, true)
-        } else {
-            (true, true)
-        }
-    }
+/// Blocks of the easy-fs image modified in the cache and not on the disk
+/// yet, which `sync` writes out; 0 if that can not be told
+pub fn dirty_blocks() -> usize {
+    EASY_FS.root_inode.statfs().map_or(0, |stat| stat.dirty_blocks as usize)
 }
 
-pub fn open_file(name: &str, flags: OpenFlags) -> Option<Arc<OSInode>> {
-    let (readable, writable) = flags.read_write();
-    if flags.contains(OpenFlags::CREATE) {
-        if let Some(inode) = ROOT_INODE.find(name) {
-            // clear size
-            inode.clear();
-            Some(Arc::new(OSInode::new(
-                readable,
-                writable,
-                inode,
-            )))
-        } else {
-            ROOT_INODE.create(name)
-                .map(|inode| {
-                    Arc::new(OSInode::new(
-                        readable,
-                        writable,
-                        inode,
-                    ))
-                })
-        }
-    } else {
-        ROOT_INODE.find(name)
-            .map(|inode| {
-                if flags.contains(OpenFlags::TRUNC) {
-                    inode.clear()
-                }
-                Arc::new(OSInode::new(
-                    readable,
-                    writable,
-                    inode,
-                ))
-            })
+/// The source naming the block device, the root file system
+pub const ROOT_SOURCE: &str = "/dev/vda";
+
+/// Mount the easy-fs image of `source`, the block device being the only one
+pub fn mount_easy_fs(source: &str) -> Result<Arc<dyn FileSystem>, FsError> {
+    if source != ROOT_SOURCE {
+        return Err(FsError::NotFound);
     }
+    Ok(EASY_FS.clone())
 }
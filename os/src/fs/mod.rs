@@ -1,7 +1,14 @@
+use alloc::string::String;
 use crate::mm::UserBuffer;
+use easy_fs::FsError;
+mod devfs;
 mod inode;
+mod mount;
 pub mod stdio;
 mod pipe;
+mod procfs;
+mod tmpfs;
+mod vfs;
 
 /// File trait
 pub trait File: Send + Sync {
@@ -9,12 +16,101 @@ pub trait File: Send + Sync {
     fn readable(&self) -> bool;
     /// If writable
     fn writable(&self) -> bool;
-    /// Read file to `UserBuffer`
-    fn read(&self, buf: UserBuffer) -> usize;
-    /// Write `UserBuffer` to file
-    fn write(&self, buf: UserBuffer) -> usize;
+    /// Read file to `UserBuffer`, an error only if nothing is read
+    fn read(&self, buf: UserBuffer) -> Result<usize, FsError>;
+    /// Write `UserBuffer` to file, an error only if nothing is written
+    fn write(&self, buf: UserBuffer) -> Result<usize, FsError>;
+    /// What the file is, as `/proc/<pid>/fd` shows it: the path it was
+    /// opened by, or its kind
+    fn name(&self) -> String;
+    /// Metadata of the file, `FsError::Invalid` if it is not backed by an
+    /// inode
+    fn stat(&self) -> Result<Stat, FsError> {
+        Err(FsError::Invalid)
+    }
+    /// Change the file size to `len`, `FsError::Invalid` if it has no size
+    /// to change
+    fn truncate(&self, _len: usize) -> Result<(), FsError> {
+        Err(FsError::Invalid)
+    }
+    /// Make what was done to the file durable, only what is needed to read
+    /// its data back if `data_only`; `FsError::Invalid` if it is not backed
+    /// by a file system
+    fn fsync(&self, _data_only: bool) -> Result<(), FsError> {
+        Err(FsError::Invalid)
+    }
 }
 
-pub use inode::{open_file, OpenFlags};
+/// File status in the layout of Linux `struct stat`
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct Stat {
+    /// ID of the device containing the file
+    pub dev: u64,
+    /// Inode number
+    pub ino: u64,
+    /// File type and permission bits, see `StatMode`
+    pub mode: u32,
+    /// Number of hard links
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub rdev: u64,
+    __pad: u64,
+    /// Total size in bytes
+    pub size: i64,
+    /// Block size for filesystem I/O
+    pub blksize: u32,
+    __pad2: i32,
+    /// Number of 512B blocks allocated
+    pub blocks: u64,
+    pub atime_sec: i64,
+    pub atime_nsec: i64,
+    pub mtime_sec: i64,
+    pub mtime_nsec: i64,
+    pub ctime_sec: i64,
+    pub ctime_nsec: i64,
+    __unused: [u32; 2],
+}
+
+/// File system status in the layout of Linux `struct statfs`
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct StatFs {
+    /// Type of the file system, its magic number
+    pub type_: i64,
+    /// Block size for filesystem I/O
+    pub bsize: i64,
+    /// Number of data blocks
+    pub blocks: u64,
+    /// Free data blocks
+    pub bfree: u64,
+    /// Free data blocks an unprivileged user may take
+    pub bavail: u64,
+    /// Number of inodes
+    pub files: u64,
+    /// Free inodes
+    pub ffree: u64,
+    pub fsid: [i32; 2],
+    /// Max length of a file name
+    pub namelen: i64,
+    pub frsize: i64,
+    pub flags: i64,
+    __spare: [i64; 4],
+}
+
+bitflags! {
+    /// File type bits of `Stat::mode`, the rest are permission bits
+    pub struct StatMode: u32 {
+        const FIFO = 0o010000;
+        const CHAR = 0o020000;
+        const DIR  = 0o040000;
+        const BLOCK = 0o060000;
+        const FILE = 0o100000;
+    }
+}
+
+pub use mount::{mount, umount};
+pub use vfs::{link_file, open_file, rename_file, statfs, sync_all, unlink_file, OpenFlags};
 pub use pipe::{Pipe, make_pipe};
-pub use inode::list_apps;
+pub use vfs::list_apps;
@@ -0,0 +1,193 @@
+//! The mount table, attaching file systems to the namespace at directories
+//! of those mounted before them, the root file system first.
+
+use alloc::{string::{String, ToString}, sync::Arc, vec::Vec};
+use easy_fs::FsError;
+use lazy_static::lazy_static;
+use log::warn;
+use crate::sync::UPSafeCell;
+use super::devfs::mount_devfs;
+use super::inode::{mount_easy_fs, ROOT_SOURCE};
+use super::procfs::mount_procfs;
+use super::tmpfs::mount_tmpfs;
+use super::vfs::{path_names, Dentry, FileSystem};
+
+/// Make a file system of a type from the source given to `mount`
+type MakeFs = fn(&str) -> Result<Arc<dyn FileSystem>, FsError>;
+
+/// The file system types `mount` knows, by name
+static FS_TYPES: &[(&str, MakeFs)] = &[
+    ("easyfs", mount_easy_fs),
+    ("tmpfs", mount_tmpfs),
+    ("proc", mount_procfs),
+    ("devfs", mount_devfs),
+];
+
+/// Mounted at boot after the root, as `(source, target, fstype)`; a target
+/// missing on the root file system is made
+static BOOT_MOUNTS: &[(&str, &str, &str)] = &[
+    ("tmpfs", "/tmp", "tmpfs"),
+    ("proc", "/proc", "proc"),
+    ("devfs", "/dev", "devfs"),
+];
+
+/// A file system attached to the namespace
+pub struct Mount {
+    /// Names of the mount point from the root, none for the root
+    pub path: Vec<String>,
+    pub fstype: &'static str,
+    pub source: String,
+    /// Device number `stat` reports for its inodes
+    pub dev: u64,
+    pub fs: Arc<dyn FileSystem>,
+}
+
+impl Mount {
+    /// Whether the mount point is `names`, or below it if `below` is set
+    fn is_at(&self, names: &[&str], below: bool) -> bool {
+        (self.path.len() == names.len() || below && self.path.len() > names.len())
+            && names.iter().zip(self.path.iter()).all(|(name, own)| name == own)
+    }
+    /// Whether `names` is at or below the mount point
+    fn holds(&self, names: &[&str]) -> bool {
+        self.path.len() <= names.len()
+            && self.path.iter().zip(names.iter()).all(|(own, name)| own == name)
+    }
+}
+
+pub struct MountTable {
+    mounts: Vec<Arc<Mount>>,
+    next_dev: u64,
+}
+
+impl MountTable {
+    fn new() -> Self {
+        let mut table = Self {
+            mounts: Vec::new(),
+            next_dev: 1,
+        };
+        let root_fs = mount_easy_fs(ROOT_SOURCE).expect("Error loading EFS!");
+        table.add(&[], "easyfs", ROOT_SOURCE, root_fs);
+        for &(source, target, fstype) in BOOT_MOUNTS {
+            if let Err(error) = table.add_at_boot(source, target, fstype) {
+                warn!("cannot mount {} on {}: {}", source, target, error);
+            }
+        }
+        table
+    }
+    fn add(&mut self, names: &[&str], fstype: &'static str, source: &str, fs: Arc<dyn FileSystem>) {
+        self.mounts.push(Arc::new(Mount {
+            path: names.iter().map(|name| name.to_string()).collect(),
+            fstype,
+            source: source.to_string(),
+            dev: self.next_dev,
+            fs,
+        }));
+        self.next_dev += 1;
+    }
+    /// Mount as `mount` does, making the target directory on the root file
+    /// system first if it is missing
+    fn add_at_boot(&mut self, source: &str, target: &str, fstype: &str) -> Result<(), FsError> {
+        let &(fstype, make_fs) = FS_TYPES.iter().find(|(name, _)| *name == fstype).unwrap();
+        let names = path_names(target);
+        let mut dir = self.mounts[0].fs.root();
+        for name in &names {
+            dir = match dir.lookup(name) {
+                Err(FsError::NotFound) => dir.mkdir(name)?,
+                result => result?,
+            };
+        }
+        if !dir.is_dir()? {
+            return Err(FsError::NotDir);
+        }
+        let fs = make_fs(source)?;
+        self.add(&names, fstype, source, fs);
+        Ok(())
+    }
+    /// The mounts in the order they were made
+    pub fn mounts(&self) -> Vec<Arc<Mount>> {
+        self.mounts.clone()
+    }
+    /// The mount `names` is reached through, the deepest one holding it
+    fn mount_of(&self, names: &[&str]) -> Arc<Mount> {
+        self.mounts
+            .iter()
+            .filter(|mount| mount.holds(names))
+            .max_by_key(|mount| mount.path.len())
+            .unwrap()
+            .clone()
+    }
+    /// Whether a file system is mounted at `names` or below
+    fn is_busy(&self, names: &[&str]) -> bool {
+        self.mounts.iter().any(|mount| mount.is_at(names, true))
+    }
+}
+
+lazy_static! {
+    pub static ref MOUNT_TABLE: UPSafeCell<MountTable> = unsafe { UPSafeCell::new(MountTable::new()) };
+}
+
+fn resolve(names: &[&str]) -> Result<Dentry, FsError> {
+    let mount = MOUNT_TABLE.exclusive_access().mount_of(names);
+    let mut inode = mount.fs.root();
+    for name in &names[mount.path.len()..] {
+        inode = inode.lookup(name)?;
+    }
+    Ok(Dentry { mount, inode })
+}
+
+/// Resolve `path`, going into the file systems mounted on the way
+pub fn lookup(path: &str) -> Result<Dentry, FsError> {
+    resolve(&path_names(path))
+}
+
+/// Resolve the directory holding the last name of `path`, for that name
+/// to be added or removed there. Mount points and what is above them can
+/// not be removed or moved, they are `FsError::Busy`.
+pub fn lookup_parent(path: &str) -> Result<(Dentry, &str), FsError> {
+    let names = path_names(path);
+    let (name, parent) = names.split_last().ok_or(FsError::Busy)?;
+    if MOUNT_TABLE.exclusive_access().is_busy(&names) {
+        return Err(FsError::Busy);
+    }
+    Ok((resolve(parent)?, *name))
+}
+
+/// Attach a new file system of type `fstype` made from `source` at the
+/// directory `target`, hiding what it holds until unmounted
+pub fn mount(source: &str, target: &str, fstype: &str) -> Result<(), FsError> {
+    let &(fstype, make_fs) = FS_TYPES
+        .iter()
+        .find(|(name, _)| *name == fstype)
+        .ok_or(FsError::Invalid)?;
+    let names = path_names(target);
+    if !resolve(&names)?.inode.is_dir()? {
+        return Err(FsError::NotDir);
+    }
+    if MOUNT_TABLE.exclusive_access().mounts.iter().any(|mount| mount.is_at(&names, false)) {
+        return Err(FsError::Busy);
+    }
+    let fs = make_fs(source)?;
+    MOUNT_TABLE.exclusive_access().add(&names, fstype, source, fs);
+    Ok(())
+}
+
+/// Detach the file system mounted at `target`, made durable first. One
+/// with files open or mounts below it is `FsError::Busy`, as is the root.
+pub fn umount(target: &str) -> Result<(), FsError> {
+    let names = path_names(target);
+    let mut table = MOUNT_TABLE.exclusive_access();
+    let index = table
+        .mounts
+        .iter()
+        .position(|mount| mount.is_at(&names, false))
+        .ok_or(FsError::Invalid)?;
+    let below = table.mounts.iter().filter(|mount| mount.is_at(&names, true)).count();
+    // the table holds the only reference once every file of it is closed
+    if names.is_empty() || below > 1 || Arc::strong_count(&table.mounts[index]) > 1 {
+        return Err(FsError::Busy);
+    }
+    table.mounts[index].fs.sync()?;
+    table.mounts.remove(index);
+    Ok(())
+}
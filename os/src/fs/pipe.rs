@@ -1,6 +1,8 @@
+use easy_fs::FsError;
 use super::File;
 use crate::mm::UserBuffer;
 use crate::sync::UPSafeCell;
+use alloc::string::String;
 use alloc::sync::{Arc, Weak};
 
 use crate::task::suspend_current_and_run_next;
@@ -112,7 +114,10 @@ impl File for Pipe {
     fn writable(&self) -> bool {
         self.writable
     }
-    fn read(&self, buf: UserBuffer) -> usize {
+    fn name(&self) -> String {
+        String::from("pipe")
+    }
+    fn read(&self, buf: UserBuffer) -> Result<usize, FsError> {
         assert!(self.readable());
         let want_to_read = buf.len();
         let mut buf_iter = buf.into_iter();
@@ -122,7 +127,7 @@ impl File for Pipe {
             let loop_read = ring_buffer.available_read();
             if loop_read == 0 {
                 if ring_buffer.all_write_ends_closed() {
-                    return already_read;
+                    return Ok(already_read);
                 }
                 drop(ring_buffer);
                 suspend_current_and_run_next();
@@ -135,15 +140,15 @@ impl File for Pipe {
                     }
                     already_read += 1;
                     if already_read == want_to_read {
-                        return want_to_read;
+                        return Ok(want_to_read);
                     }
                 } else {
-                    return already_read;
+                    return Ok(already_read);
                 }
             }
         }
     }
-    fn write(&self, buf: UserBuffer) -> usize {
+    fn write(&self, buf: UserBuffer) -> Result<usize, FsError> {
         assert!(self.writable());
         let want_to_write = buf.len();
         let mut buf_iter = buf.into_iter();
@@ -162,10 +167,10 @@ impl File for Pipe {
                     ring_buffer.write_byte(unsafe { *byte_ref });
                     already_write += 1;
                     if already_write == want_to_write {
-                        return want_to_write;
+                        return Ok(want_to_write);
                     }
                 } else {
-                    return already_write;
+                    return Ok(already_write);
                 }
             }
         }
@@ -0,0 +1,340 @@
+//! procfs, a pseudo file system showing the processes and the state of the
+//! kernel as text. Nothing is stored: what a file holds is made each time it
+//! is read, and a process is gone from it once it exits.
+
+use alloc::{format, string::{String, ToString}, sync::Arc, vec::Vec};
+use core::any::Any;
+use easy_fs::{FsError, BLOCK_SZ, LONG_NAME_LENGTH_LIMIT};
+use crate::config::PAGE_SIZE;
+use crate::mm::{frame_remaining, frame_total, heap_usage, MapPermission};
+use crate::task::{current_process, pid2process, pids, TaskStatus};
+use crate::timer::get_time_ms;
+use super::inode::dirty_blocks;
+use super::mount::MOUNT_TABLE;
+use super::vfs::{FileSystem, VfsInode};
+use super::{Stat, StatFs, StatMode};
+
+/// `StatFs::type_` of a procfs, the magic number Linux gives it
+const PROC_SUPER_MAGIC: i64 = 0x9fa0;
+
+#[derive(Clone, Copy)]
+enum Node {
+    Root,
+    Meminfo,
+    Mounts,
+    Uptime,
+    /// The directory of a process by pid
+    Process(usize),
+    Cmdline(usize),
+    FdDir(usize),
+    /// A file descriptor of a process, reading as what it refers to
+    Fd(usize, usize),
+    Maps(usize),
+    Status(usize),
+    Threads(usize),
+}
+
+/// Files of the root besides the directories of processes
+static ROOT_ENTRIES: &[(&str, Node)] = &[
+    ("meminfo", Node::Meminfo),
+    ("mounts", Node::Mounts),
+    ("uptime", Node::Uptime),
+];
+
+/// Entries of the directory of a process
+static PROCESS_ENTRIES: &[(&str, fn(usize) -> Node)] = &[
+    ("cmdline", Node::Cmdline),
+    ("fd", Node::FdDir),
+    ("maps", Node::Maps),
+    ("status", Node::Status),
+    ("threads", Node::Threads),
+];
+
+pub struct ProcInode {
+    node: Node,
+}
+
+impl ProcInode {
+    fn new(node: Node) -> Arc<dyn VfsInode> {
+        Arc::new(Self { node })
+    }
+    fn is_dir(&self) -> bool {
+        matches!(self.node, Node::Root | Node::Process(_) | Node::FdDir(_))
+    }
+    /// `FsError::NotFound` for a node of a process that has exited
+    fn check_alive(&self) -> Result<(), FsError> {
+        match self.node {
+            Node::Root | Node::Meminfo | Node::Mounts | Node::Uptime => Ok(()),
+            Node::Process(pid)
+            | Node::Cmdline(pid)
+            | Node::FdDir(pid)
+            | Node::Fd(pid, _)
+            | Node::Maps(pid)
+            | Node::Status(pid)
+            | Node::Threads(pid) => pid2process(pid).map(|_| ()).ok_or(FsError::NotFound),
+        }
+    }
+    /// Inode numbers of a process start at its pid shifted up, so those of
+    /// the kernel files below never meet them
+    fn ino(&self) -> u64 {
+        let process_ino = |pid: usize| (pid as u64 + 1) << 32;
+        match self.node {
+            Node::Root => 1,
+            Node::Meminfo => 2,
+            Node::Mounts => 3,
+            Node::Uptime => 4,
+            Node::Process(pid) => process_ino(pid),
+            Node::Cmdline(pid) => process_ino(pid) + 1,
+            Node::FdDir(pid) => process_ino(pid) + 2,
+            Node::Maps(pid) => process_ino(pid) + 3,
+            Node::Status(pid) => process_ino(pid) + 4,
+            Node::Threads(pid) => process_ino(pid) + 5,
+            Node::Fd(pid, fd) => process_ino(pid) + 16 + fd as u64,
+        }
+    }
+    /// What the file holds right now
+    fn content(&self) -> Result<String, FsError> {
+        match self.node {
+            Node::Meminfo => Ok(meminfo()),
+            Node::Mounts => Ok(mounts()),
+            Node::Uptime => {
+                let ms = get_time_ms();
+                Ok(format!("{}.{:02}\n", ms / 1000, ms % 1000 / 10))
+            }
+            Node::Cmdline(pid) => cmdline(pid),
+            Node::Fd(pid, fd) => fd_name(pid, fd),
+            Node::Maps(pid) => maps(pid),
+            Node::Status(pid) => status(pid),
+            Node::Threads(pid) => threads(pid),
+            Node::Root | Node::Process(_) | Node::FdDir(_) => Err(FsError::IsDir),
+        }
+    }
+}
+
+fn meminfo() -> String {
+    let (heap_total, heap_used) = heap_usage();
+    let mut text = String::new();
+    for (name, bytes) in [
+        ("MemTotal:", frame_total() * PAGE_SIZE),
+        ("MemFree:", frame_remaining() * PAGE_SIZE),
+        ("HeapTotal:", heap_total),
+        ("HeapUsed:", heap_used),
+        // of the root file system, written out by `sync`
+        ("Dirty:", dirty_blocks() * BLOCK_SZ),
+    ] {
+        text += &format!("{:<16}{:>8} kB\n", name, bytes / 1024);
+    }
+    text
+}
+
+/// A line of `source target fstype options` for each mount, as Linux does
+fn mounts() -> String {
+    let mounts = MOUNT_TABLE.exclusive_access().mounts();
+    let mut text = String::new();
+    for mount in mounts {
+        text += &format!("{} /{} {} rw 0 0\n", mount.source, mount.path.join("/"), mount.fstype);
+    }
+    text
+}
+
+/// The arguments, each ending with a NUL
+fn cmdline(pid: usize) -> Result<String, FsError> {
+    let process = pid2process(pid).ok_or(FsError::NotFound)?;
+    let inner = process.inner_exclusive_access();
+    Ok(inner.cmdline.iter().map(|arg| format!("{}\0", arg)).collect())
+}
+
+fn fd_name(pid: usize, fd: usize) -> Result<String, FsError> {
+    let process = pid2process(pid).ok_or(FsError::NotFound)?;
+    let file = match process.inner_exclusive_access().fd_table.get(fd) {
+        Some(Some(file)) => file.clone(),
+        _ => return Err(FsError::NotFound),
+    };
+    Ok(format!("{}\n", file.name()))
+}
+
+/// A line of `start-end permissions` for each area of the address space
+fn maps(pid: usize) -> Result<String, FsError> {
+    let process = pid2process(pid).ok_or(FsError::NotFound)?;
+    let areas = process.inner_exclusive_access().memory_set.areas();
+    let mut text = String::new();
+    for (start, end, perm) in areas {
+        let flag = |bit: MapPermission, c: char| if perm.contains(bit) { c } else { '-' };
+        text += &format!(
+            "{:08x}-{:08x} {}{}{}p\n",
+            start.0,
+            end.0,
+            flag(MapPermission::R, 'r'),
+            flag(MapPermission::W, 'w'),
+            flag(MapPermission::X, 'x'),
+        );
+    }
+    Ok(text)
+}
+
+fn status(pid: usize) -> Result<String, FsError> {
+    let process = pid2process(pid).ok_or(FsError::NotFound)?;
+    let inner = process.inner_exclusive_access();
+    let name = match inner.cmdline.first() {
+        Some(arg) => arg.rsplit('/').next().unwrap(),
+        None => "",
+    };
+    let ppid = match inner.parent.as_ref().and_then(|parent| parent.upgrade()) {
+        Some(parent) => parent.getpid(),
+        None => 0,
+    };
+    let mut threads = 0;
+    let mut runnable = false;
+    for task in inner.tasks.iter().flatten() {
+        let task_inner = task.inner_exclusive_access();
+        if task_inner.exit_code.is_none() {
+            threads += 1;
+            runnable |= task_inner.task_status != TaskStatus::Blocked;
+        }
+    }
+    let vm_size: usize = inner
+        .memory_set
+        .areas()
+        .iter()
+        .map(|(start, end, _)| end.0 - start.0)
+        .sum();
+    Ok(format!(
+        "Name:\t{}\nState:\t{}\nPid:\t{}\nPPid:\t{}\nThreads:\t{}\nFDSize:\t{}\nVmSize:\t{} kB\n",
+        name,
+        if runnable { "R (running)" } else { "S (sleeping)" },
+        pid,
+        ppid,
+        threads,
+        inner.fd_table.len(),
+        vm_size / 1024,
+    ))
+}
+
+/// A line of `tid state` for each thread not yet waited for
+fn threads(pid: usize) -> Result<String, FsError> {
+    let process = pid2process(pid).ok_or(FsError::NotFound)?;
+    let inner = process.inner_exclusive_access();
+    let mut text = String::new();
+    for (tid, task) in inner.tasks.iter().enumerate() {
+        if let Some(task) = task {
+            let task_inner = task.inner_exclusive_access();
+            let state = match (task_inner.exit_code, task_inner.task_status) {
+                (Some(_), _) => "exited",
+                (None, TaskStatus::Running) => "running",
+                (None, TaskStatus::Ready) => "ready",
+                (None, TaskStatus::Blocked) => "blocked",
+            };
+            text += &format!("{} {}\n", tid, state);
+        }
+    }
+    Ok(text)
+}
+
+impl VfsInode for ProcInode {
+    fn stat(&self) -> Result<Stat, FsError> {
+        self.check_alive()?;
+        let (mode, nlink) = if ProcInode::is_dir(self) {
+            (StatMode::DIR.bits() | 0o555, 2)
+        } else {
+            (StatMode::FILE.bits() | 0o444, 1)
+        };
+        // the size is not known before it is read, as on Linux
+        Ok(Stat {
+            ino: self.ino(),
+            mode,
+            nlink,
+            blksize: PAGE_SIZE as u32,
+            ..Default::default()
+        })
+    }
+    fn is_dir(&self) -> Result<bool, FsError> {
+        Ok(ProcInode::is_dir(self))
+    }
+    fn lookup(&self, name: &str) -> Result<Arc<dyn VfsInode>, FsError> {
+        self.check_alive()?;
+        let node = match self.node {
+            Node::Root if name == "self" => Some(Node::Process(current_process().getpid())),
+            Node::Root => match ROOT_ENTRIES.iter().find(|(entry, _)| *entry == name) {
+                Some(&(_, node)) => Some(node),
+                None => name
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|pid| pid2process(*pid).is_some())
+                    .map(Node::Process),
+            },
+            Node::Process(pid) => PROCESS_ENTRIES
+                .iter()
+                .find(|(entry, _)| *entry == name)
+                .map(|(_, node)| node(pid)),
+            Node::FdDir(pid) => {
+                let process = pid2process(pid).ok_or(FsError::NotFound)?;
+                let inner = process.inner_exclusive_access();
+                name.parse::<usize>()
+                    .ok()
+                    .filter(|fd| matches!(inner.fd_table.get(*fd), Some(Some(_))))
+                    .map(|fd| Node::Fd(pid, fd))
+            }
+            _ => return Err(FsError::NotDir),
+        };
+        node.map(ProcInode::new).ok_or(FsError::NotFound)
+    }
+    fn ls(&self) -> Result<Vec<String>, FsError> {
+        self.check_alive()?;
+        match self.node {
+            Node::Root => {
+                let mut names: Vec<String> = ROOT_ENTRIES.iter().map(|(name, _)| name.to_string()).collect();
+                names.push(String::from("self"));
+                names.extend(pids().iter().map(|pid| pid.to_string()));
+                Ok(names)
+            }
+            Node::Process(_) => Ok(PROCESS_ENTRIES.iter().map(|(name, _)| name.to_string()).collect()),
+            Node::FdDir(pid) => {
+                let process = pid2process(pid).ok_or(FsError::NotFound)?;
+                let inner = process.inner_exclusive_access();
+                Ok(inner
+                    .fd_table
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, file)| file.is_some())
+                    .map(|(fd, _)| fd.to_string())
+                    .collect())
+            }
+            _ => Err(FsError::NotDir),
+        }
+    }
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        let content = self.content()?;
+        let content = content.as_bytes();
+        if offset >= content.len() {
+            return Ok(0);
+        }
+        let len = buf.len().min(content.len() - offset);
+        buf[..len].copy_from_slice(&content[offset..offset + len]);
+        Ok(len)
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+pub struct ProcFs;
+
+impl FileSystem for ProcFs {
+    fn root(&self) -> Arc<dyn VfsInode> {
+        ProcInode::new(Node::Root)
+    }
+    fn statfs(&self) -> Result<StatFs, FsError> {
+        Ok(StatFs {
+            type_: PROC_SUPER_MAGIC,
+            bsize: PAGE_SIZE as i64,
+            namelen: LONG_NAME_LENGTH_LIMIT as i64,
+            frsize: PAGE_SIZE as i64,
+            ..Default::default()
+        })
+    }
+}
+
+/// Make a procfs, the source being only a name
+pub fn mount_procfs(_source: &str) -> Result<Arc<dyn FileSystem>, FsError> {
+    Ok(Arc::new(ProcFs))
+}
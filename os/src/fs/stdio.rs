@@ -1,9 +1,29 @@
+use alloc::string::String;
+use easy_fs::FsError;
 use super::File;
-use crate::{console, task::suspend_current_and_run_next, mm::UserBuffer, sbi::console_getchar};
+use crate::{console, task::suspend_current_and_run_next, mm::UserBuffer, sbi::{console_getchar, console_putchar}};
+use crate::{random::add_entropy, timer::get_time};
 
 pub struct Stdin;
 pub struct Stdout;
 
+/// Wait for a character from the console, when it is typed going to the RNG
+pub fn getchar() -> u8 {
+    // busy loop
+    let mut c: usize;
+    loop {
+        c = console_getchar();
+        if c == 0 {
+            suspend_current_and_run_next();
+            continue;
+        } else {
+            break;
+        }
+    }
+    add_entropy(get_time() ^ c);
+    c as u8
+}
+
 impl File for Stdin {
     fn readable(&self) -> bool {
         true
@@ -11,28 +31,20 @@ impl File for Stdin {
     fn writable(&self) -> bool {
         false
     }
-    fn read(&self, mut buf: UserBuffer) -> usize {
+    fn read(&self, mut buf: UserBuffer) -> Result<usize, FsError> {
         assert_eq!(buf.len(), 1);
-        // busy loop
-        let mut c: usize;
-        loop {
-            c = console_getchar();
-            if c == 0 {
-                suspend_current_and_run_next();
-                continue;
-            } else {
-                break;
-            }
-        }
-        let ch = c as u8;
+        let ch = getchar();
         unsafe {
             buf.buffers[0].as_mut_ptr().write_volatile(ch);
         }
-        1
+        Ok(1)
     }
-    fn write(&self, buf: UserBuffer) -> usize {
+    fn write(&self, buf: UserBuffer) -> Result<usize, FsError> {
         panic!("Cannot write to stdin!");
     }
+    fn name(&self) -> String {
+        String::from("/dev/console")
+    }
 }
 
 impl File for Stdout {
@@ -42,13 +54,19 @@ impl File for Stdout {
     fn writable(&self) -> bool {
         true
     }
-    fn read(&self, buf: UserBuffer) -> usize {
+    fn read(&self, buf: UserBuffer) -> Result<usize, FsError> {
         panic!("Cannot read from stdout!");
     }
-    fn write(&self, buf: UserBuffer) -> usize {
+    fn write(&self, buf: UserBuffer) -> Result<usize, FsError> {
+        // bytes go out as they are, text or not
         for buffer in buf.buffers.iter() {
-            print!("{}", core::str::from_utf8(*buffer).unwrap());
+            for &byte in buffer.iter() {
+                console_putchar(byte as usize);
+            }
         }
-        buf.len()
+        Ok(buf.len())
+    }
+    fn name(&self) -> String {
+        String::from("/dev/console")
     }
 }
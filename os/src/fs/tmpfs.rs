@@ -0,0 +1,447 @@
+//! tmpfs, a file system kept in frames of memory. Nothing of it reaches
+//! the disk and all of it is gone once unmounted, which makes it the place
+//! for scratch files.
+
+use alloc::{collections::{btree_map::Entry, BTreeMap}, string::{String, ToString}, sync::{Arc, Weak}, vec::Vec};
+use core::any::Any;
+use easy_fs::{FsError, LONG_NAME_LENGTH_LIMIT};
+use crate::config::PAGE_SIZE;
+use crate::mm::{frame_alloc, frame_remaining, FrameTracker};
+use crate::sync::UPSafeCell;
+use crate::timer::get_time_ms;
+use super::vfs::{FileSystem, VfsInode};
+use super::{Stat, StatFs, StatMode};
+
+/// `StatFs::type_` of a tmpfs, the magic number Linux gives it
+const TMPFS_MAGIC: i64 = 0x01021994;
+
+/// What the inodes of a tmpfs share: the pages they may take and the
+/// inode numbers handed out
+struct Space {
+    /// Pages of file data the tmpfs may hold at most
+    limit: usize,
+    used: UPSafeCell<usize>,
+    next_ino: UPSafeCell<u64>,
+}
+
+impl Space {
+    /// A zeroed page of file data, `FsError::NoSpace` past the limit or
+    /// once memory runs out
+    fn alloc_page(self: &Arc<Self>) -> Result<Page, FsError> {
+        let mut used = self.used.exclusive_access();
+        if *used >= self.limit {
+            return Err(FsError::NoSpace);
+        }
+        let frame = frame_alloc().ok_or(FsError::NoSpace)?;
+        *used += 1;
+        Ok(Page {
+            frame,
+            space: self.clone(),
+        })
+    }
+    fn alloc_ino(&self) -> u64 {
+        let mut next_ino = self.next_ino.exclusive_access();
+        *next_ino += 1;
+        *next_ino - 1
+    }
+}
+
+/// A page of file data, given back to its tmpfs when dropped
+struct Page {
+    frame: FrameTracker,
+    space: Arc<Space>,
+}
+
+impl Page {
+    fn bytes(&self) -> &'static mut [u8] {
+        self.frame.ppn.get_bytes_array()
+    }
+}
+
+impl Drop for Page {
+    fn drop(&mut self) {
+        *self.space.used.exclusive_access() -= 1;
+    }
+}
+
+enum Content {
+    /// Pages by index, a missing one is a hole reading as zeros
+    File { size: usize, pages: BTreeMap<usize, Page> },
+    Dir(BTreeMap<String, Arc<TmpInode>>),
+}
+
+/// An inode of a tmpfs, kept as long as it is linked or open
+pub struct TmpInode {
+    ino: u64,
+    space: Arc<Space>,
+    /// The inode itself, for a directory to take another link of it
+    this: Weak<TmpInode>,
+    inner: UPSafeCell<TmpInodeInner>,
+}
+
+pub struct TmpInodeInner {
+    nlink: u32,
+    atime: i64,
+    mtime: i64,
+    ctime: i64,
+    content: Content,
+}
+
+/// Without an RTC, inodes are stamped with seconds since boot
+fn now() -> i64 {
+    (get_time_ms() / 1000) as i64
+}
+
+/// A dirent name must be a single path component of at most
+/// `LONG_NAME_LENGTH_LIMIT` bytes
+fn check_name(name: &str) -> Result<(), FsError> {
+    if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+        Err(FsError::InvalidName)
+    } else if name.len() > LONG_NAME_LENGTH_LIMIT {
+        Err(FsError::NameTooLong)
+    } else {
+        Ok(())
+    }
+}
+
+impl TmpInode {
+    fn new(space: &Arc<Space>, content: Content) -> Arc<Self> {
+        let now = now();
+        let nlink = match content {
+            Content::File { .. } => 1,
+            Content::Dir(_) => 2,
+        };
+        Arc::new_cyclic(|this| Self {
+            ino: space.alloc_ino(),
+            space: space.clone(),
+            this: this.clone(),
+            inner: unsafe {
+                UPSafeCell::new(TmpInodeInner {
+                    nlink,
+                    atime: now,
+                    mtime: now,
+                    ctime: now,
+                    content,
+                })
+            },
+        })
+    }
+    /// The tmpfs inode of another VFS inode, which must be of the same tmpfs
+    fn of<'a>(&self, other: &'a dyn VfsInode) -> Result<&'a TmpInode, FsError> {
+        match other.as_any().downcast_ref::<TmpInode>() {
+            Some(other) if Arc::ptr_eq(&self.space, &other.space) => Ok(other),
+            _ => Err(FsError::CrossDevice),
+        }
+    }
+    fn is_dir(&self) -> bool {
+        matches!(self.inner.exclusive_access().content, Content::Dir(_))
+    }
+    /// Whether `other` is this directory or one below it
+    fn holds(&self, other: &TmpInode) -> bool {
+        if core::ptr::eq(self, other) {
+            return true;
+        }
+        match &self.inner.exclusive_access().content {
+            Content::Dir(entries) => entries.values().any(|entry| entry.holds(other)),
+            Content::File { .. } => false,
+        }
+    }
+    /// The inode of the entry `name` of this directory, if there is one
+    fn entry(&self, name: &str) -> Result<Option<Arc<TmpInode>>, FsError> {
+        match &self.inner.exclusive_access().content {
+            Content::Dir(entries) => Ok(entries.get(name).cloned()),
+            Content::File { .. } => Err(FsError::NotDir),
+        }
+    }
+    /// Change the entries and the link count of this directory, stamping
+    /// it as modified if that succeeds
+    fn modify_entries<T>(
+        &self,
+        f: impl FnOnce(&mut BTreeMap<String, Arc<TmpInode>>, &mut u32) -> Result<T, FsError>,
+    ) -> Result<T, FsError> {
+        let mut inner = self.inner.exclusive_access();
+        let inner = &mut *inner;
+        let result = match &mut inner.content {
+            Content::Dir(entries) => f(entries, &mut inner.nlink)?,
+            Content::File { .. } => return Err(FsError::NotDir),
+        };
+        inner.mtime = now();
+        inner.ctime = inner.mtime;
+        Ok(result)
+    }
+    /// Add a link to this inode, or drop one; a directory has no more
+    /// links once its entry is gone
+    fn change_nlink(&self, add: bool) {
+        let mut inner = self.inner.exclusive_access();
+        inner.nlink = match (add, &inner.content) {
+            (true, _) => inner.nlink + 1,
+            (false, Content::File { .. }) => inner.nlink - 1,
+            (false, Content::Dir(_)) => 0,
+        };
+        inner.ctime = now();
+    }
+    fn add_entry(&self, name: &str, content: Content) -> Result<Arc<TmpInode>, FsError> {
+        check_name(name)?;
+        self.modify_entries(|entries, nlink| {
+            if entries.contains_key(name) {
+                return Err(FsError::Exists);
+            }
+            if let Content::Dir(_) = content {
+                // the ".." of the new directory
+                *nlink += 1;
+            }
+            let inode = TmpInode::new(&self.space, content);
+            entries.insert(name.to_string(), inode.clone());
+            Ok(inode)
+        })
+    }
+    fn remove_entry(&self, name: &str, is_dir: bool) -> Result<(), FsError> {
+        check_name(name)?;
+        let inode = self.entry(name)?.ok_or(FsError::NotFound)?;
+        match (is_dir, &inode.inner.exclusive_access().content) {
+            (true, Content::File { .. }) => return Err(FsError::NotDir),
+            (true, Content::Dir(entries)) if !entries.is_empty() => return Err(FsError::NotEmpty),
+            (false, Content::Dir(_)) => return Err(FsError::IsDir),
+            _ => {}
+        }
+        self.modify_entries(|entries, nlink| {
+            entries.remove(name);
+            if is_dir {
+                // the ".." of the removed directory is gone
+                *nlink -= 1;
+            }
+            Ok(())
+        })?;
+        // what is still open of it stays until closed
+        inode.change_nlink(false);
+        Ok(())
+    }
+}
+
+impl VfsInode for TmpInode {
+    fn stat(&self) -> Result<Stat, FsError> {
+        let inner = self.inner.exclusive_access();
+        let (mode, size, pages) = match &inner.content {
+            Content::File { size, pages } => (StatMode::FILE.bits() | 0o644, *size, pages.len()),
+            Content::Dir(_) => (StatMode::DIR.bits() | 0o755, 0, 0),
+        };
+        Ok(Stat {
+            ino: self.ino,
+            mode,
+            nlink: inner.nlink,
+            size: size as i64,
+            blksize: PAGE_SIZE as u32,
+            blocks: (pages * PAGE_SIZE / 512) as u64,
+            atime_sec: inner.atime,
+            mtime_sec: inner.mtime,
+            ctime_sec: inner.ctime,
+            ..Default::default()
+        })
+    }
+    fn is_dir(&self) -> Result<bool, FsError> {
+        Ok(TmpInode::is_dir(self))
+    }
+    fn lookup(&self, name: &str) -> Result<Arc<dyn VfsInode>, FsError> {
+        match self.entry(name)? {
+            Some(inode) => Ok(inode),
+            None => Err(FsError::NotFound),
+        }
+    }
+    fn ls(&self) -> Result<Vec<String>, FsError> {
+        match &self.inner.exclusive_access().content {
+            Content::Dir(entries) => Ok(entries.keys().cloned().collect()),
+            Content::File { .. } => Err(FsError::NotDir),
+        }
+    }
+    fn create(&self, name: &str) -> Result<Arc<dyn VfsInode>, FsError> {
+        Ok(self.add_entry(name, Content::File {
+            size: 0,
+            pages: BTreeMap::new(),
+        })?)
+    }
+    fn mkdir(&self, name: &str) -> Result<Arc<dyn VfsInode>, FsError> {
+        Ok(self.add_entry(name, Content::Dir(BTreeMap::new()))?)
+    }
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        self.remove_entry(name, false)
+    }
+    fn rmdir(&self, name: &str) -> Result<(), FsError> {
+        self.remove_entry(name, true)
+    }
+    fn link(&self, name: &str, target: &dyn VfsInode) -> Result<(), FsError> {
+        let target = self.of(target)?;
+        check_name(name)?;
+        if target.is_dir() {
+            return Err(FsError::IsDir);
+        }
+        let target = target.this.upgrade().unwrap();
+        self.modify_entries(|entries, _| {
+            if entries.contains_key(name) {
+                return Err(FsError::Exists);
+            }
+            entries.insert(name.to_string(), target.clone());
+            Ok(())
+        })?;
+        target.change_nlink(true);
+        Ok(())
+    }
+    /// An existing file at the destination is replaced; directories can not
+    /// be replaced, nor moved below themselves
+    fn rename(&self, old_name: &str, new_dir: &dyn VfsInode, new_name: &str) -> Result<(), FsError> {
+        let new_dir = self.of(new_dir)?;
+        check_name(old_name)?;
+        check_name(new_name)?;
+        let inode = self.entry(old_name)?.ok_or(FsError::NotFound)?;
+        let replaced = new_dir.entry(new_name)?;
+        let is_dir = inode.is_dir();
+        if is_dir && inode.holds(new_dir) {
+            return Err(FsError::Invalid);
+        }
+        if let Some(replaced) = &replaced {
+            if Arc::ptr_eq(replaced, &inode) {
+                return Ok(());
+            }
+            match (is_dir, replaced.is_dir()) {
+                (true, true) => return Err(FsError::Exists),
+                (true, false) => return Err(FsError::NotDir),
+                (false, true) => return Err(FsError::IsDir),
+                (false, false) => {}
+            }
+        }
+        // a moved directory takes its ".." along
+        let reparent = is_dir && !core::ptr::eq(self, new_dir);
+        self.modify_entries(|entries, nlink| {
+            entries.remove(old_name);
+            if reparent {
+                *nlink -= 1;
+            }
+            Ok(())
+        })?;
+        new_dir.modify_entries(|entries, nlink| {
+            entries.insert(new_name.to_string(), inode.clone());
+            if reparent {
+                *nlink += 1;
+            }
+            Ok(())
+        })?;
+        inode.inner.exclusive_access().ctime = now();
+        if let Some(replaced) = replaced {
+            replaced.change_nlink(false);
+        }
+        Ok(())
+    }
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        let mut inner = self.inner.exclusive_access();
+        let (size, pages) = match &inner.content {
+            Content::File { size, pages } => (*size, pages),
+            Content::Dir(_) => return Err(FsError::IsDir),
+        };
+        let end = size.min(offset.saturating_add(buf.len()));
+        let mut pos = offset;
+        while pos < end {
+            let page_offset = pos % PAGE_SIZE;
+            let len = (PAGE_SIZE - page_offset).min(end - pos);
+            let dst = &mut buf[pos - offset..pos - offset + len];
+            match pages.get(&(pos / PAGE_SIZE)) {
+                Some(page) => dst.copy_from_slice(&page.bytes()[page_offset..page_offset + len]),
+                None => dst.fill(0),
+            }
+            pos += len;
+        }
+        inner.atime = now();
+        Ok(end.saturating_sub(offset))
+    }
+    /// Cut short once the tmpfs is full; an error is returned only if
+    /// nothing is written
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize, FsError> {
+        let end = offset.checked_add(buf.len()).ok_or(FsError::FileTooLarge)?;
+        let mut inner = self.inner.exclusive_access();
+        let (size, pages) = match &mut inner.content {
+            Content::File { size, pages } => (size, pages),
+            Content::Dir(_) => return Err(FsError::IsDir),
+        };
+        let mut pos = offset;
+        while pos < end {
+            let page_offset = pos % PAGE_SIZE;
+            let len = (PAGE_SIZE - page_offset).min(end - pos);
+            let index = pos / PAGE_SIZE;
+            if let Entry::Vacant(entry) = pages.entry(index) {
+                match self.space.alloc_page() {
+                    Ok(page) => {
+                        entry.insert(page);
+                    }
+                    Err(error) if pos == offset => return Err(error),
+                    Err(_) => break,
+                }
+            }
+            pages[&index].bytes()[page_offset..page_offset + len]
+                .copy_from_slice(&buf[pos - offset..pos - offset + len]);
+            pos += len;
+        }
+        *size = (*size).max(pos);
+        inner.mtime = now();
+        inner.ctime = inner.mtime;
+        Ok(pos - offset)
+    }
+    /// Pages past the new size are freed, growing leaves a hole
+    fn truncate(&self, len: usize) -> Result<(), FsError> {
+        let mut inner = self.inner.exclusive_access();
+        let (size, pages) = match &mut inner.content {
+            Content::File { size, pages } => (size, pages),
+            Content::Dir(_) => return Err(FsError::IsDir),
+        };
+        if len < *size {
+            pages.split_off(&len.div_ceil(PAGE_SIZE));
+            // a later grow must read zeros past the end
+            if let Some(page) = pages.get(&(len / PAGE_SIZE)) {
+                page.bytes()[len % PAGE_SIZE..].fill(0);
+            }
+        }
+        *size = len;
+        inner.mtime = now();
+        inner.ctime = inner.mtime;
+        Ok(())
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// A tmpfs instance, each mount a new and empty one
+pub struct TmpFs {
+    root: Arc<TmpInode>,
+    space: Arc<Space>,
+}
+
+impl FileSystem for TmpFs {
+    fn root(&self) -> Arc<dyn VfsInode> {
+        self.root.clone()
+    }
+    fn statfs(&self) -> Result<StatFs, FsError> {
+        let used = *self.space.used.exclusive_access();
+        // frames taken by processes are not there to fill either
+        let free = (self.space.limit - used).min(frame_remaining());
+        Ok(StatFs {
+            type_: TMPFS_MAGIC,
+            bsize: PAGE_SIZE as i64,
+            blocks: self.space.limit as u64,
+            bfree: free as u64,
+            bavail: free as u64,
+            namelen: LONG_NAME_LENGTH_LIMIT as i64,
+            frsize: PAGE_SIZE as i64,
+            ..Default::default()
+        })
+    }
+}
+
+/// Make a tmpfs, the source being only a name; it may take half the
+/// frames free at the time for file data
+pub fn mount_tmpfs(_source: &str) -> Result<Arc<dyn FileSystem>, FsError> {
+    let space = Arc::new(Space {
+        limit: frame_remaining() / 2,
+        used: unsafe { UPSafeCell::new(0) },
+        next_ino: unsafe { UPSafeCell::new(1) },
+    });
+    let root = TmpInode::new(&space, Content::Dir(BTreeMap::new()));
+    Ok(Arc::new(TmpFs { root, space }))
+}
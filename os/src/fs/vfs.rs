@@ -0,0 +1,325 @@
+//! The file system independent layer. Paths resolve from the root through
+//! the mount table to inodes of whatever file system is mounted there, and
+//! open files read and write those inodes without knowing which one it is.
+
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+use core::any::Any;
+use easy_fs::FsError;
+use log::debug;
+use crate::sync::UPSafeCell;
+use super::mount::{lookup, lookup_parent, Mount, MOUNT_TABLE};
+use super::{File, Stat, StatFs, StatMode, UserBuffer};
+
+/// An inode of a mounted file system.
+///
+/// What a file system does not support is `FsError::Invalid`, directory
+/// operations on anything but a directory are `FsError::NotDir`.
+pub trait VfsInode: Send + Sync {
+    /// Metadata of the inode, `dev` left to the VFS
+    fn stat(&self) -> Result<Stat, FsError>;
+    fn is_dir(&self) -> Result<bool, FsError> {
+        Ok(self.stat()?.mode & 0o170000 == StatMode::DIR.bits())
+    }
+    /// The entry `name` of this directory
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn VfsInode>, FsError> {
+        Err(FsError::NotDir)
+    }
+    /// Names in this directory, `.` and `..` excluded
+    fn ls(&self) -> Result<Vec<String>, FsError> {
+        Err(FsError::NotDir)
+    }
+    fn create(&self, _name: &str) -> Result<Arc<dyn VfsInode>, FsError> {
+        Err(FsError::Invalid)
+    }
+    fn mkdir(&self, _name: &str) -> Result<Arc<dyn VfsInode>, FsError> {
+        Err(FsError::Invalid)
+    }
+    /// What is still open of a removed file stays usable until closed
+    fn unlink(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::Invalid)
+    }
+    fn rmdir(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::Invalid)
+    }
+    /// Add the entry `name` for `target`, an inode of the same file system
+    fn link(&self, _name: &str, _target: &dyn VfsInode) -> Result<(), FsError> {
+        Err(FsError::Invalid)
+    }
+    /// Move the entry `old_name` to `new_name` of `new_dir`, a directory of
+    /// the same file system
+    fn rename(&self, _old_name: &str, _new_dir: &dyn VfsInode, _new_name: &str) -> Result<(), FsError> {
+        Err(FsError::Invalid)
+    }
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, FsError>;
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::Invalid)
+    }
+    fn truncate(&self, _len: usize) -> Result<(), FsError> {
+        Err(FsError::Invalid)
+    }
+    /// Make what was done to the inode durable, nothing to do for a file
+    /// system kept in memory
+    fn fsync(&self, _data_only: bool) -> Result<(), FsError> {
+        Ok(())
+    }
+    /// For a file system to tell its own inodes when given another one
+    fn as_any(&self) -> &dyn Any;
+}
+
+/// A file system instance, what a mount attaches to the namespace
+pub trait FileSystem: Send + Sync {
+    fn root(&self) -> Arc<dyn VfsInode>;
+    fn statfs(&self) -> Result<StatFs, FsError>;
+    /// Make every operation ended so far durable
+    fn sync(&self) -> Result<(), FsError> {
+        Ok(())
+    }
+}
+
+/// What a path resolves to: an inode and the mount it was reached through
+#[derive(Clone)]
+pub struct Dentry {
+    pub mount: Arc<Mount>,
+    pub inode: Arc<dyn VfsInode>,
+}
+
+impl Dentry {
+    pub fn stat(&self) -> Result<Stat, FsError> {
+        let mut stat = self.inode.stat()?;
+        stat.dev = self.mount.dev;
+        Ok(stat)
+    }
+}
+
+/// A file opened by path, reading and writing at an offset of its own.
+/// Its mount can not be unmounted while it is open.
+pub struct OpenFile {
+    readable: bool,
+    writable: bool,
+    /// The path it was opened by, from the root
+    path: String,
+    inner: UPSafeCell<OpenFileInner>,
+}
+
+pub struct OpenFileInner {
+    offset: usize,
+    dentry: Dentry,
+}
+
+impl OpenFile {
+    pub fn new(readable: bool, writable: bool, path: String, dentry: Dentry) -> Self {
+        Self {
+            readable,
+            writable,
+            path,
+            inner: unsafe { UPSafeCell::new(OpenFileInner { offset: 0, dentry }) },
+        }
+    }
+    /// Read all data inside an inode into vector
+    pub fn read_all(&self) -> Result<Vec<u8>, FsError> {
+        let mut inner = self.inner.exclusive_access();
+        let mut buffer = [0u8; 512];
+        let mut v: Vec<u8> = Vec::new();
+
+        loop {
+            let len = inner.dentry.inode.read_at(inner.offset, &mut buffer)?;
+            if len == 0 {
+                break;
+            }
+            inner.offset += len;
+            v.extend_from_slice(&buffer[..len]);
+        }
+
+        Ok(v)
+    }
+}
+
+impl File for OpenFile {
+    fn read(&self, mut buf: UserBuffer) -> Result<usize, FsError> {
+        // not held while reading, as a device may wait and switch tasks
+        let (inode, mut offset) = {
+            let inner = self.inner.exclusive_access();
+            (inner.dentry.inode.clone(), inner.offset)
+        };
+        let mut total_read_size = 0usize;
+        for slice in buf.buffers.iter_mut() {
+            let read_size = match inode.read_at(offset, *slice) {
+                Ok(read_size) => read_size,
+                // what is read so far is reported, the error comes next time
+                Err(_) if total_read_size > 0 => break,
+                Err(error) => return Err(error),
+            };
+            if read_size == 0 {
+                break;
+            }
+            total_read_size += read_size;
+            offset += read_size;
+            // the end of a file, or all a device has for now
+            if read_size < slice.len() {
+                break;
+            }
+        }
+        self.inner.exclusive_access().offset = offset;
+        Ok(total_read_size)
+    }
+    fn write(&self, buf: UserBuffer) -> Result<usize, FsError> {
+        let mut inner = self.inner.exclusive_access();
+        let mut total_write_size = 0usize;
+        debug!("write buffer len:{:?}", buf.len());
+        for slice in buf.buffers.iter() {
+            let write_size = match inner.dentry.inode.write_at(inner.offset, *slice) {
+                Ok(write_size) => write_size,
+                // what is written so far is reported, the error comes next time
+                Err(_) if total_write_size > 0 => break,
+                Err(error) => return Err(error),
+            };
+            inner.offset += write_size;
+            total_write_size += write_size;
+            if write_size < slice.len() {
+                break;
+            }
+        }
+        Ok(total_write_size)
+    }
+    fn readable(&self) -> bool {
+        self.readable
+    }
+    fn writable(&self) -> bool {
+        self.writable
+    }
+    fn name(&self) -> String {
+        self.path.clone()
+    }
+    fn truncate(&self, len: usize) -> Result<(), FsError> {
+        self.inner.exclusive_access().dentry.inode.truncate(len)
+    }
+    fn fsync(&self, data_only: bool) -> Result<(), FsError> {
+        self.inner.exclusive_access().dentry.inode.fsync(data_only)
+    }
+    fn stat(&self) -> Result<Stat, FsError> {
+        self.inner.exclusive_access().dentry.stat()
+    }
+}
+
+/// The names of `path` from the root, `.` and `..` resolved as written.
+/// Every path is taken from the root, the only working directory there is.
+pub fn path_names(path: &str) -> Vec<&str> {
+    let mut names = Vec::new();
+    for name in path.split('/') {
+        match name {
+            "" | "." => {}
+            ".." => {
+                names.pop();
+            }
+            name => names.push(name),
+        }
+    }
+    names
+}
+
+pub fn list_apps() {
+    println!("/**** APPS ****");
+    for app in lookup("/").unwrap().inode.ls().unwrap() {
+        println!("{}", app);
+    }
+    println!("**************/")
+}
+
+bitflags! {
+    pub struct OpenFlags: u32 {
+        const RDONLY = 0;
+        const WRONLY = 1 << 0;
+        const RDWR = 1 << 1;
+        const CREATE = 1 << 9;
+        const TRUNC = 1 << 10;
+    }
+}
+
+impl OpenFlags {
+    /// Do not check validity for simplicity
+    /// Return (readable, writable)
+    pub fn read_write(&self) -> (bool, bool) {
+        if self.is_empty() {  // read only
+            (true, false)
+        } else if self.contains(Self::WRONLY) {
+            (false, true)
+        } else {
+            (true, true)
+        }
+    }
+}
+
+pub fn open_file(path: &str, flags: OpenFlags) -> Result<Arc<OpenFile>, FsError> {
+    let (readable, writable) = flags.read_write();
+    let dentry = if flags.contains(OpenFlags::CREATE) {
+        match lookup(path) {
+            Ok(dentry) => {
+                // clear size
+                dentry.inode.truncate(0)?;
+                dentry
+            }
+            Err(FsError::NotFound) => {
+                let (dir, name) = lookup_parent(path)?;
+                let inode = dir.inode.create(name)?;
+                Dentry { mount: dir.mount, inode }
+            }
+            Err(error) => return Err(error),
+        }
+    } else {
+        let dentry = lookup(path)?;
+        if flags.contains(OpenFlags::TRUNC) {
+            dentry.inode.truncate(0)?;
+        }
+        dentry
+    };
+    Ok(Arc::new(OpenFile::new(
+        readable,
+        writable,
+        format!("/{}", path_names(path).join("/")),
+        dentry,
+    )))
+}
+
+/// Remove a file, or an empty directory if `is_dir` is set. A file open
+/// elsewhere is freed once its last descriptor is closed.
+pub fn unlink_file(path: &str, is_dir: bool) -> Result<(), FsError> {
+    let (dir, name) = lookup_parent(path)?;
+    if is_dir {
+        dir.inode.rmdir(name)
+    } else {
+        dir.inode.unlink(name)
+    }
+}
+
+/// Create a hard link `new_path` to the file at `old_path`
+pub fn link_file(old_path: &str, new_path: &str) -> Result<(), FsError> {
+    let target = lookup(old_path)?;
+    let (dir, name) = lookup_parent(new_path)?;
+    if !Arc::ptr_eq(&target.mount, &dir.mount) {
+        return Err(FsError::CrossDevice);
+    }
+    dir.inode.link(name, &*target.inode)
+}
+
+pub fn rename_file(old_path: &str, new_path: &str) -> Result<(), FsError> {
+    let (old_dir, old_name) = lookup_parent(old_path)?;
+    let (new_dir, new_name) = lookup_parent(new_path)?;
+    if !Arc::ptr_eq(&old_dir.mount, &new_dir.mount) {
+        return Err(FsError::CrossDevice);
+    }
+    old_dir.inode.rename(old_name, &*new_dir.inode, new_name)
+}
+
+/// Usage of the file system holding `path`
+pub fn statfs(path: &str) -> Result<StatFs, FsError> {
+    lookup(path)?.mount.fs.statfs()
+}
+
+/// Make every operation on the file systems ended so far durable
+pub fn sync_all() -> Result<(), FsError> {
+    let mounts = MOUNT_TABLE.exclusive_access().mounts();
+    for mount in mounts {
+        mount.fs.sync()?;
+    }
+    Ok(())
+}
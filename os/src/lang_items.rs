@@ -16,5 +16,8 @@ fn panic(info: &PanicInfo) -> ! {
     } else {
         println!("Panicked: {}", info.message().unwrap());
     }
+    // the file systems are not synced, a panic may come half way through
+    // an operation whose blocks must not be committed; the journal brings
+    // them back to the last commit instead
     shutdown(true)
 }
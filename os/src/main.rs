@@ -16,6 +16,7 @@ pub mod syscall;
 pub mod trap;
 pub mod task;
 mod timer;
+mod random;
 mod fs;
 mod drivers;
 #[path = "boards/qemu.rs"]
@@ -40,6 +40,7 @@ trait FrameAllocator {
 }
 
 pub struct StackFrameAllocator {
+    start: usize, // first ppn of the memory managed
     current: usize, // start ppn of free memory
     end: usize, // end ppn of free memory
     recycled: Vec<usize>,
@@ -48,6 +49,7 @@ pub struct StackFrameAllocator {
 impl FrameAllocator for StackFrameAllocator {
     fn new() -> Self {
         Self {
+            start: 0,
             current: 0,
             end: 0,
             recycled: Vec::new(),
@@ -81,9 +83,18 @@ impl FrameAllocator for StackFrameAllocator {
 
 impl StackFrameAllocator {
     pub fn init(&mut self, l: PhysPageNum, r: PhysPageNum) {
+        self.start = l.0;
         self.current = l.0;
         self.end = r.0;
     }
+    /// Number of frames managed, allocated or not
+    pub fn total(&self) -> usize {
+        self.end - self.start
+    }
+    /// Number of frames that can still be allocated
+    pub fn remaining(&self) -> usize {
+        self.end - self.current + self.recycled.len()
+    }
 }
 
 use crate::{config::MEMORY_END, mm::address::PhysAddr, sync::UPSafeCell};
@@ -111,6 +122,18 @@ pub fn frame_alloc() -> Option<FrameTracker> {
         .map(|ppn| FrameTracker::new(ppn))
 }
 
+pub fn frame_total() -> usize {
+    FRAME_ALLOCATOR
+        .exclusive_access()
+        .total()
+}
+
+pub fn frame_remaining() -> usize {
+    FRAME_ALLOCATOR
+        .exclusive_access()
+        .remaining()
+}
+
 pub fn frame_dealloc(ppn: PhysPageNum) {
     FRAME_ALLOCATOR
         .exclusive_access()
@@ -14,6 +14,12 @@ pub fn init_heap() {
     }
 }
 
+/// Bytes of the kernel heap, and those allocated
+pub fn heap_usage() -> (usize, usize) {
+    let heap = HEAP_ALLOCATOR.lock();
+    (heap.stats_total_bytes(), heap.stats_alloc_actual())
+}
+
 #[alloc_error_handler]
 pub fn handle_alloc_error(layout: core::alloc::Layout) -> ! {
     panic!("Heap allocation error, layout = {:?}", layout);
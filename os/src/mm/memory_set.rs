@@ -171,6 +171,22 @@ impl MemorySet {
         }
         self.areas.push(map_area);
     }
+    /// Start, end and permission of each area, by start
+    pub fn areas(&self) -> Vec<(VirtAddr, VirtAddr, MapPermission)> {
+        let mut areas: Vec<(VirtAddr, VirtAddr, MapPermission)> = self
+            .areas
+            .iter()
+            .map(|area| {
+                (
+                    area.vpn_range.get_start().into(),
+                    area.vpn_range.get_end().into(),
+                    area.map_perm,
+                )
+            })
+            .collect();
+        areas.sort_by_key(|(start, _, _)| start.0);
+        areas
+    }
     /// Assume that no conflicts
     pub fn insert_framed_area(&mut self, start_va: VirtAddr, end_va: VirtAddr, permission: MapPermission) {
         self.push(MapArea::new(
@@ -7,7 +7,8 @@ mod memory_set;
 pub use memory_set::{KERNEL_SPACE, remap_test, MemorySet, MapPermission, kernel_token};
 pub use address::{PhysPageNum, PhysAddr, VirtAddr, VirtPageNum, StepByOne};
 pub use page_table::{translated_byte_buffer, translated_str, translated_refmut, translated_ref, UserBuffer, PageTable};
-pub use frame_allocator::{frame_alloc, frame_dealloc, FrameTracker};
+pub use frame_allocator::{frame_alloc, frame_dealloc, frame_remaining, frame_total, FrameTracker};
+pub use heap_allocator::heap_usage;
 
 pub fn init() {
     heap_allocator::init_heap();  // enable rust data-structure
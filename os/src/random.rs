@@ -0,0 +1,108 @@
+//! The kernel random number generator. Output is the ChaCha20 keystream of a
+//! key that entropy is folded into: the time at boot, the jitter of timer
+//! interrupts and console input, and what is written to `/dev/random`.
+//! The key is replaced after every request, so what was handed out can not
+//! be recomputed from a later state.
+
+use lazy_static::lazy_static;
+use crate::sync::UPSafeCell;
+use crate::timer::get_time;
+
+const CHACHA_CONSTANTS: [u32; 4] = [0x61707865, 0x3320646e, 0x79622d32, 0x6b206574];
+
+/// The ChaCha20 block of `key` at `counter`, the nonce being zero
+fn chacha20_block(key: &[u32; 8], counter: u64) -> [u32; 16] {
+    let mut input = [0u32; 16];
+    input[..4].copy_from_slice(&CHACHA_CONSTANTS);
+    input[4..12].copy_from_slice(key);
+    input[12] = counter as u32;
+    input[13] = (counter >> 32) as u32;
+    let mut x = input;
+    fn quarter_round(x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+        x[a] = x[a].wrapping_add(x[b]);
+        x[d] = (x[d] ^ x[a]).rotate_left(16);
+        x[c] = x[c].wrapping_add(x[d]);
+        x[b] = (x[b] ^ x[c]).rotate_left(12);
+        x[a] = x[a].wrapping_add(x[b]);
+        x[d] = (x[d] ^ x[a]).rotate_left(8);
+        x[c] = x[c].wrapping_add(x[d]);
+        x[b] = (x[b] ^ x[c]).rotate_left(7);
+    }
+    for _ in 0..10 {
+        quarter_round(&mut x, 0, 4, 8, 12);
+        quarter_round(&mut x, 1, 5, 9, 13);
+        quarter_round(&mut x, 2, 6, 10, 14);
+        quarter_round(&mut x, 3, 7, 11, 15);
+        quarter_round(&mut x, 0, 5, 10, 15);
+        quarter_round(&mut x, 1, 6, 11, 12);
+        quarter_round(&mut x, 2, 7, 8, 13);
+        quarter_round(&mut x, 3, 4, 9, 14);
+    }
+    for (word, input) in x.iter_mut().zip(input.iter()) {
+        *word = word.wrapping_add(*input);
+    }
+    x
+}
+
+pub struct Rng {
+    key: [u32; 8],
+    counter: u64,
+    /// Entropy gathered since the last request, folded into the key then
+    pool: [u32; 16],
+    pool_index: usize,
+}
+
+impl Rng {
+    fn new() -> Self {
+        let mut rng = Self {
+            key: [0; 8],
+            counter: 0,
+            pool: [0; 16],
+            pool_index: 0,
+        };
+        rng.add_entropy(get_time());
+        rng
+    }
+    pub fn add_entropy(&mut self, value: usize) {
+        let index = self.pool_index;
+        self.pool[index] = self.pool[index].rotate_left(7) ^ value as u32;
+        self.pool[(index + 1) % 16] ^= (value as u64 >> 32) as u32;
+        self.pool_index = (index + 2) % 16;
+    }
+    /// Replace the key with keystream, which also takes in what the pool has
+    fn rekey(&mut self) {
+        for (i, word) in self.key.iter_mut().enumerate() {
+            *word ^= self.pool[i] ^ self.pool[i + 8];
+        }
+        self.pool = [0; 16];
+        let block = chacha20_block(&self.key, self.counter);
+        self.counter += 1;
+        self.key.copy_from_slice(&block[..8]);
+    }
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        self.add_entropy(get_time());
+        self.rekey();
+        for chunk in buf.chunks_mut(64) {
+            let block = chacha20_block(&self.key, self.counter);
+            self.counter += 1;
+            for (bytes, word) in chunk.chunks_mut(4).zip(block.iter()) {
+                bytes.copy_from_slice(&word.to_le_bytes()[..bytes.len()]);
+            }
+        }
+        self.rekey();
+    }
+}
+
+lazy_static! {
+    pub static ref RNG: UPSafeCell<Rng> = unsafe { UPSafeCell::new(Rng::new()) };
+}
+
+/// Fold `value`, of which some bits are hard to guess, into the RNG
+pub fn add_entropy(value: usize) {
+    RNG.exclusive_access().add_entropy(value);
+}
+
+/// Fill `buf` with random bytes
+pub fn fill_random(buf: &mut [u8]) {
+    RNG.exclusive_access().fill(buf);
+}
@@ -3,9 +3,10 @@
 use core::arch;
 
 use alloc::sync::Arc;
+use easy_fs::FsError;
 use log::debug;
 
-use crate::fs::{make_pipe, open_file, OpenFlags};
+use crate::fs::{link_file, make_pipe, mount, open_file, rename_file, statfs, sync_all, umount, unlink_file, OpenFlags, Stat, StatFs};
 use crate::mm::{translated_refmut, translated_str, UserBuffer};
 use crate::task::signals::SignalFlags;
 use crate::task::{current_process, current_task, suspend_current_and_run_next};
@@ -14,6 +15,55 @@ use crate::sbi::console_getchar;
 
 const FD_STDOUT: usize = 1;
 const FD_STDIN: usize = 0;
+/// Resolve paths from the root, the only working directory we have
+const AT_FDCWD: isize = -100;
+/// `unlinkat` flag to remove a directory instead of a file
+const AT_REMOVEDIR: u32 = 0x200;
+
+const ENOENT: isize = 2;
+const EIO: isize = 5;
+const EBUSY: isize = 16;
+const EEXIST: isize = 17;
+const EXDEV: isize = 18;
+const ENOTDIR: isize = 20;
+const EISDIR: isize = 21;
+const EINVAL: isize = 22;
+const EFBIG: isize = 27;
+const ENOSPC: isize = 28;
+const ENAMETOOLONG: isize = 36;
+const ENOTEMPTY: isize = 39;
+const EUCLEAN: isize = 117;
+
+/// The negated errno a file system error is reported to user space as
+pub fn fs_errno(error: FsError) -> isize {
+    -match error {
+        FsError::NoSpace => ENOSPC,
+        FsError::NameTooLong => ENAMETOOLONG,
+        FsError::InvalidName | FsError::Invalid | FsError::Unsupported => EINVAL,
+        FsError::NotFound => ENOENT,
+        FsError::NotDir => ENOTDIR,
+        FsError::IsDir => EISDIR,
+        FsError::NotEmpty => ENOTEMPTY,
+        FsError::Exists => EEXIST,
+        FsError::CrossDevice => EXDEV,
+        FsError::FileTooLarge => EFBIG,
+        FsError::Corrupt => EUCLEAN,
+        FsError::Io => EIO,
+        FsError::Busy => EBUSY,
+    }
+}
+
+/// Copy a kernel value to user space, where it may straddle pages
+fn copy_to_user<T>(token: usize, dst: *mut T, value: &T) {
+    let src = unsafe {
+        core::slice::from_raw_parts(value as *const T as *const u8, core::mem::size_of::<T>())
+    };
+    let mut copied = 0;
+    for buffer in translated_byte_buffer(token, dst as *const u8, src.len()) {
+        buffer.copy_from_slice(&src[copied..copied + buffer.len()]);
+        copied += buffer.len();
+    }
+}
 
 /// write buf of length `len` to a file with `fd`
 pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
@@ -30,7 +80,10 @@ pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
         let file = file.clone();
         // release current task TCB manually to avoid multi-borrow
         drop(inner);
-        file.write(UserBuffer::new(translated_byte_buffer(token, buf, len))) as isize
+        match file.write(UserBuffer::new(translated_byte_buffer(token, buf, len))) {
+            Ok(write_size) => write_size as isize,
+            Err(error) => fs_errno(error),
+        }
     } else {
         -1
     }
@@ -50,7 +103,10 @@ pub fn sys_read(fd: usize, buf: *const u8, len: usize) -> isize {
         }
         // release current task TCB manually to avoid multi-borrow
         drop(inner);
-        file.read(UserBuffer::new(translated_byte_buffer(token, buf, len))) as isize
+        match file.read(UserBuffer::new(translated_byte_buffer(token, buf, len))) {
+            Ok(read_size) => read_size as isize,
+            Err(error) => fs_errno(error),
+        }
     } else {
         -1
     }
@@ -60,11 +116,105 @@ pub fn sys_open(path: *const u8, flags: u32) -> isize {
     let process = current_process();
     let token = current_user_token();
     let path = translated_str(token, path);
-    if let Some(inode) = open_file(path.as_str(), OpenFlags::from_bits(flags).unwrap()) {
-        let mut inner = process.inner_exclusive_access();
-        let fd = inner.alloc_fd();
-        inner.fd_table[fd] = Some(inode);
-        fd as isize
+    match open_file(path.as_str(), OpenFlags::from_bits(flags).unwrap()) {
+        Ok(inode) => {
+            let mut inner = process.inner_exclusive_access();
+            let fd = inner.alloc_fd();
+            inner.fd_table[fd] = Some(inode);
+            fd as isize
+        }
+        Err(error) => fs_errno(error),
+    }
+}
+
+pub fn sys_fstat(fd: usize, st: *mut Stat) -> isize {
+    let token = current_user_token();
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    if fd >= inner.fd_table.len() {
+        return -1;
+    }
+    if let Some(file) = &inner.fd_table[fd] {
+        let file = file.clone();
+        // release current task TCB manually to avoid multi-borrow
+        drop(inner);
+        match file.stat() {
+            Ok(stat) => {
+                copy_to_user(token, st, &stat);
+                0
+            }
+            Err(error) => fs_errno(error),
+        }
+    } else {
+        -1
+    }
+}
+
+pub fn sys_statfs(path: *const u8, buf: *mut StatFs) -> isize {
+    let token = current_user_token();
+    let path = translated_str(token, path);
+    match statfs(path.as_str()) {
+        Ok(stat) => {
+            copy_to_user(token, buf, &stat);
+            0
+        }
+        Err(error) => fs_errno(error),
+    }
+}
+
+pub fn sys_ftruncate(fd: usize, len: usize) -> isize {
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    if fd >= inner.fd_table.len() {
+        return -1;
+    }
+    if let Some(file) = &inner.fd_table[fd] {
+        if !file.writable() {
+            return -1;
+        }
+        let file = file.clone();
+        // release current task TCB manually to avoid multi-borrow
+        drop(inner);
+        match file.truncate(len) {
+            Ok(()) => 0,
+            Err(error) => fs_errno(error),
+        }
+    } else {
+        -1
+    }
+}
+
+pub fn sys_sync() -> isize {
+    match sync_all() {
+        Ok(()) => 0,
+        Err(error) => fs_errno(error),
+    }
+}
+
+pub fn sys_fsync(fd: usize) -> isize {
+    fsync(fd, false)
+}
+
+/// As `sys_fsync`, leaving out timestamps and other metadata not needed to
+/// read the data back
+pub fn sys_fdatasync(fd: usize) -> isize {
+    fsync(fd, true)
+}
+
+fn fsync(fd: usize, data_only: bool) -> isize {
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    if fd >= inner.fd_table.len() {
+        return -1;
+    }
+    if let Some(file) = &inner.fd_table[fd] {
+        let file = file.clone();
+        // release current task TCB manually to avoid multi-borrow
+        drop(inner);
+        match file.fsync(data_only) {
+            Ok(()) => 0,
+            Err(error) => fs_errno(error),
+        }
     } else {
         -1
     }
@@ -110,3 +260,80 @@ pub fn sys_dup(fd: usize) -> isize {
     inner.fd_table[new_fd] = Some(Arc::clone(inner.fd_table[fd].as_ref().unwrap()));
     new_fd as isize
 }
+
+pub fn sys_unlinkat(dirfd: isize, path: *const u8, flags: u32) -> isize {
+    if dirfd != AT_FDCWD {
+        return -1;
+    }
+    let token = current_user_token();
+    let path = translated_str(token, path);
+    match unlink_file(path.as_str(), flags & AT_REMOVEDIR != 0) {
+        Ok(()) => 0,
+        Err(error) => fs_errno(error),
+    }
+}
+
+pub fn sys_linkat(
+    olddirfd: isize,
+    oldpath: *const u8,
+    newdirfd: isize,
+    newpath: *const u8,
+    _flags: u32,
+) -> isize {
+    if olddirfd != AT_FDCWD || newdirfd != AT_FDCWD {
+        return -1;
+    }
+    let token = current_user_token();
+    let oldpath = translated_str(token, oldpath);
+    let newpath = translated_str(token, newpath);
+    match link_file(oldpath.as_str(), newpath.as_str()) {
+        Ok(()) => 0,
+        Err(error) => fs_errno(error),
+    }
+}
+
+pub fn sys_renameat(
+    olddirfd: isize,
+    oldpath: *const u8,
+    newdirfd: isize,
+    newpath: *const u8,
+) -> isize {
+    if olddirfd != AT_FDCWD || newdirfd != AT_FDCWD {
+        return -1;
+    }
+    let token = current_user_token();
+    let oldpath = translated_str(token, oldpath);
+    let newpath = translated_str(token, newpath);
+    match rename_file(oldpath.as_str(), newpath.as_str()) {
+        Ok(()) => 0,
+        Err(error) => fs_errno(error),
+    }
+}
+
+/// Mount a file system of type `fstype` made from `source` at `target`;
+/// no flags or data are understood
+pub fn sys_mount(
+    source: *const u8,
+    target: *const u8,
+    fstype: *const u8,
+    _flags: u32,
+    _data: *const u8,
+) -> isize {
+    let token = current_user_token();
+    let source = translated_str(token, source);
+    let target = translated_str(token, target);
+    let fstype = translated_str(token, fstype);
+    match mount(source.as_str(), target.as_str(), fstype.as_str()) {
+        Ok(()) => 0,
+        Err(error) => fs_errno(error),
+    }
+}
+
+pub fn sys_umount2(target: *const u8, _flags: u32) -> isize {
+    let token = current_user_token();
+    let target = translated_str(token, target);
+    match umount(target.as_str()) {
+        Ok(()) => 0,
+        Err(error) => fs_errno(error),
+    }
+}
@@ -2,6 +2,7 @@
 use crate::{fs::{open_file, OpenFlags}, mm::{translated_ref, translated_refmut, translated_str}, task::{action::SignalAction, add_task, current_process, current_task, current_user_token, exit_current_and_run_next, pid2process, signals::{SignalFlags, MAX_SIG}, suspend_current_and_run_next}, timer::get_time_ms};
 use alloc::{string::String, sync::Arc, vec::Vec};
 use log::*;
+use super::fs::fs_errno;
 
 pub fn sys_exit(exit_code: i32) -> ! {
     exit_current_and_run_next(exit_code);
@@ -49,15 +50,19 @@ pub fn sys_exec(path: *const u8, mut args: *const usize) -> isize {
             args = args.add(1);
         }
     }
-    if let Some(app_inode) = open_file(path.as_str(), OpenFlags::RDONLY) {
-        let all_data = app_inode.read_all();
-        let process = current_process();
-        let argc = args_vec.len();
-        process.exec(all_data.as_slice(), args_vec);
-        // return argc because cx.x[10] will be covered with it later
-        argc as isize
-    } else {
-        -1
+    match open_file(path.as_str(), OpenFlags::RDONLY) {
+        Ok(app_inode) => {
+            let all_data = match app_inode.read_all() {
+                Ok(all_data) => all_data,
+                Err(error) => return fs_errno(error),
+            };
+            let process = current_process();
+            let argc = args_vec.len();
+            process.exec(all_data.as_slice(), args_vec);
+            // return argc because cx.x[10] will be covered with it later
+            argc as isize
+        }
+        Err(error) => fs_errno(error),
     }
 }
 
@@ -64,6 +64,11 @@ pub fn pid2process(pid: usize) -> Option<Arc<ProcessControlBlock>> {
     map.get(&pid).map(Arc::clone)
 }
 
+/// Pids of the processes alive, in order
+pub fn pids() -> Vec<usize> {
+    PID2PCB.exclusive_access().keys().copied().collect()
+}
+
 pub fn insert_into_pid2process(pid: usize, process: Arc<ProcessControlBlock>) {
     PID2PCB.exclusive_access().insert(pid, process);
 }
@@ -9,11 +9,13 @@ pub mod action;
 mod id;
 mod process;
 
+use alloc::string::String;
 use alloc::sync::Arc;
+use alloc::vec;
 use alloc::vec::Vec;
 use id::TaskUserRes;
 use manager::remove_task;
-pub use manager::{add_task, pid2process, remove_from_pid2process, wakeup_task};
+pub use manager::{add_task, pid2process, pids, remove_from_pid2process, wakeup_task};
 use process::ProcessControlBlock;
 use processor::PROCESSOR;
 pub use processor::{current_task, schedule, take_current_task, current_user_token, current_kstack_top, current_trap_cx_user_va, current_trap_cx, current_process};
@@ -21,7 +23,7 @@ pub use context::TaskContext;
 pub use task::{TaskControlBlock, TaskStatus};
 use lazy_static::lazy_static;
 use signals::{SignalFlags, MAX_SIG};
-use crate::fs::{open_file, OpenFlags, list_apps};
+use crate::fs::{open_file, sync_all, OpenFlags, list_apps};
 use crate::sbi::shutdown;
 use crate::timer::remove_timer;
 use crate::trap::TrapContext;
@@ -68,6 +70,10 @@ pub fn exit_current_and_run_next(exit_code: i32) {
                 "[kernel] Idle process exit with exit_code {} ...",
                 exit_code,
             );
+            // what the write policy left pending must not be lost
+            if let Err(error) = sync_all() {
+                println!("[kernel] Failed to sync file systems: {:?}", error);
+            }
             if exit_code != 0 {
                 shutdown(true);
             } else {
@@ -139,8 +145,8 @@ pub fn remove_inactive_task(task: Arc<TaskControlBlock>) {
 lazy_static! {
     pub static ref INITPROC: Arc<ProcessControlBlock> = {
         let inode = open_file("initproc", OpenFlags::RDONLY).unwrap();
-        let v = inode.read_all();
-        ProcessControlBlock::new(v.as_slice())
+        let v = inode.read_all().unwrap();
+        ProcessControlBlock::new(v.as_slice(), vec![String::from("initproc")])
     };
 }
 
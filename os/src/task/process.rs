@@ -19,6 +19,8 @@ pub struct ProcessControlBlockInner {
     pub parent: Option<Weak<ProcessControlBlock>>,
     pub children: Vec<Arc<ProcessControlBlock>>,
     pub exit_code: i32,
+    /// Arguments of the program running, as given to exec
+    pub cmdline: Vec<String>,
     pub fd_table: Vec<Option<Arc<dyn File + Send + Sync>>>,
     pub mutex_list: Vec<Option<Arc<dyn Mutex>>>,
     pub semaphore_list: Vec<Option<Arc<Semaphore>>>,
@@ -60,7 +62,7 @@ impl ProcessControlBlock {
     pub fn inner_exclusive_access(&self) -> RefMut<'_, ProcessControlBlockInner> {
         self.inner.exclusive_access()
     }
-    pub fn new(elf_data: &[u8]) -> Arc<Self> {
+    pub fn new(elf_data: &[u8], cmdline: Vec<String>) -> Arc<Self> {
         // memory_set with elf program headers/trampoline/trap context/user stack
         let (memory_set, ustack_base, entry_point) = MemorySet::from_elf(elf_data);
         // alloc a pid
@@ -75,6 +77,7 @@ impl ProcessControlBlock {
                     parent: None,
                     children: Vec::new(),
                     exit_code: 0,
+                    cmdline,
                     fd_table: vec![
                         Some(Arc::new(Stdin)),
                         Some(Arc::new(Stdout)),
@@ -124,6 +127,7 @@ impl ProcessControlBlock {
         let new_token = memory_set.token();
         // substitute memory_set
         self.inner_exclusive_access().memory_set = memory_set;
+        self.inner_exclusive_access().cmdline = args.clone();
         // then we alloc user resource for main thread again
         // since memory_set has been changed
         let task = self.inner_exclusive_access().get_task(0);
@@ -195,6 +199,7 @@ impl ProcessControlBlock {
                     parent: Some(Arc::downgrade(self)),
                     children: Vec::new(),
                     exit_code: 0,
+                    cmdline: parent.cmdline.clone(),
                     fd_table: new_fd_table,
                     signals: parent.signals,
                     tasks: Vec::new(),  // todo
@@ -14,7 +14,7 @@
 
 mod context;
 
-use crate::{syscall::syscall, task::{check_signals_of_current, current_add_signal, current_trap_cx_user_va, current_user_token, exit_current_and_run_next, signals::SignalFlags, suspend_current_and_run_next}, timer::{check_timer, set_next_trigger}};
+use crate::{syscall::syscall, task::{check_signals_of_current, current_add_signal, current_trap_cx_user_va, current_user_token, exit_current_and_run_next, signals::SignalFlags, suspend_current_and_run_next}, random::add_entropy, timer::{check_timer, get_time, set_next_trigger}};
 use core::arch::{asm, global_asm};
 use riscv::register::{
     sie, mtvec::TrapMode, scause::{self, Exception, Trap, Interrupt}, sepc, stval, stvec
@@ -72,13 +72,18 @@ pub fn trap_handler() -> ! {
             let mut cx: &mut TrapContext = current_trap_cx();
             cx.sepc += 4;
             // get system call return value
-            let result = syscall(cx.x[17], [cx.x[10], cx.x[11], cx.x[12]]);
+            let result = syscall(
+                cx.x[17],
+                [cx.x[10], cx.x[11], cx.x[12], cx.x[13], cx.x[14], cx.x[15]],
+            );
             // cx is changed during sys_exec, so we call it again
             cx = current_trap_cx();
             cx.x[10] = result as usize; 
         }
         Trap::Interrupt(Interrupt::SupervisorTimer) => {
             set_next_trigger();
+            // how late the interrupt is taken varies a little
+            add_entropy(get_time());
             check_timer();
             suspend_current_and_run_next();
         }
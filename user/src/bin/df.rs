@@ -0,0 +1,29 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{statfs, StatFs};
+
+#[no_mangle]
+pub fn main(argc: usize, argv: &[&str]) -> i32 {
+    // an argument is followed by its NUL already
+    let (path, name) = if argc > 1 { (argv[1], argv[1]) } else { ("/\0", "/") };
+    let mut stat = StatFs::default();
+    if statfs(path, &mut stat) < 0 {
+        println!("df: cannot stat {}", name);
+        return -1;
+    }
+    let kib = |blocks: u64| blocks * stat.bsize as u64 / 1024;
+    println!("{:>10} {:>10} {:>10} {:>8} {:>8}", "KiB", "Used", "Free", "Inodes", "IFree");
+    println!(
+        "{:>10} {:>10} {:>10} {:>8} {:>8}",
+        kib(stat.blocks),
+        kib(stat.blocks - stat.bfree),
+        kib(stat.bavail),
+        stat.files,
+        stat.ffree,
+    );
+    0
+}
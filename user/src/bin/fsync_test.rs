@@ -0,0 +1,52 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{close, fdatasync, fsync, open, read, sync, unlink, write, OpenFlags};
+
+/// The `Dirty` line of /proc/meminfo: what the root file system has
+/// modified in its cache and not written to the disk yet
+fn dirty_kib() -> usize {
+    let fd = open("/proc/meminfo\0", OpenFlags::RDONLY);
+    assert!(fd >= 0);
+    let mut buffer = [0u8; 512];
+    let len = read(fd as usize, &mut buffer);
+    close(fd as usize);
+    assert!(len > 0);
+    let text = core::str::from_utf8(&buffer[..len as usize]).unwrap();
+    let line = text.lines().find(|line| line.starts_with("Dirty:")).unwrap();
+    line["Dirty:".len()..].trim().trim_end_matches("kB").trim().parse().unwrap()
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let path = "/fsync_test\0";
+    let fd = open(path, OpenFlags::CREATE | OpenFlags::WRONLY);
+    assert!(fd > 0);
+    let fd = fd as usize;
+    let data = [0x5au8; 4096];
+    // the root file system is write-back: a write stays in the cache until
+    // one of them writes it out
+    assert_eq!(write(fd, &data), data.len() as isize);
+    assert!(dirty_kib() >= 4);
+    assert_eq!(fsync(fd), 0);
+    assert_eq!(dirty_kib(), 0);
+
+    assert_eq!(write(fd, &data), data.len() as isize);
+    assert!(dirty_kib() >= 4);
+    assert_eq!(fdatasync(fd), 0);
+    assert_eq!(dirty_kib(), 0);
+
+    assert_eq!(write(fd, &data), data.len() as isize);
+    assert!(dirty_kib() >= 4);
+    assert_eq!(sync(), 0);
+    assert_eq!(dirty_kib(), 0);
+
+    close(fd);
+    assert_eq!(unlink(path), 0);
+    assert_eq!(sync(), 0);
+    println!("fsync_test passed!");
+    0
+}
@@ -0,0 +1,21 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::mount;
+
+#[no_mangle]
+pub fn main(argc: usize, argv: &[&str]) -> i32 {
+    if argc != 4 {
+        println!("usage: mount <source> <target> <fstype>");
+        return -1;
+    }
+    // an argument is followed by its NUL already
+    if mount(argv[1], argv[2], argv[3]) < 0 {
+        println!("mount: cannot mount {} on {}", argv[1], argv[2]);
+        return -1;
+    }
+    0
+}
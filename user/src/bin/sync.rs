@@ -0,0 +1,16 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::sync;
+
+#[no_mangle]
+pub fn main() -> i32 {
+    if sync() < 0 {
+        println!("sync: failed");
+        return -1;
+    }
+    0
+}
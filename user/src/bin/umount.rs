@@ -0,0 +1,21 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::umount;
+
+#[no_mangle]
+pub fn main(argc: usize, argv: &[&str]) -> i32 {
+    if argc != 2 {
+        println!("usage: umount <target>");
+        return -1;
+    }
+    // an argument is followed by its NUL already
+    if umount(argv[1]) < 0 {
+        println!("umount: cannot unmount {}", argv[1]);
+        return -1;
+    }
+    0
+}
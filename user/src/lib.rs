@@ -68,6 +68,60 @@ bitflags! {
     }
 }
 
+/// File status filled by `fstat`, in the layout of Linux `struct stat`
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct Stat {
+    pub dev: u64,
+    pub ino: u64,
+    pub mode: u32,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub rdev: u64,
+    __pad: u64,
+    pub size: i64,
+    pub blksize: u32,
+    __pad2: i32,
+    pub blocks: u64,
+    pub atime_sec: i64,
+    pub atime_nsec: i64,
+    pub mtime_sec: i64,
+    pub mtime_nsec: i64,
+    pub ctime_sec: i64,
+    pub ctime_nsec: i64,
+    __unused: [u32; 2],
+}
+
+/// File system status filled by `statfs`, in the layout of Linux
+/// `struct statfs`
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct StatFs {
+    pub type_: i64,
+    pub bsize: i64,
+    pub blocks: u64,
+    pub bfree: u64,
+    pub bavail: u64,
+    pub files: u64,
+    pub ffree: u64,
+    pub fsid: [i32; 2],
+    pub namelen: i64,
+    pub frsize: i64,
+    pub flags: i64,
+    __spare: [i64; 4],
+}
+
+bitflags! {
+    /// File type bits of `Stat::mode`, the rest are permission bits
+    pub struct StatMode: u32 {
+        const FIFO = 0o010000;
+        const CHAR = 0o020000;
+        const DIR  = 0o040000;
+        const FILE = 0o100000;
+    }
+}
+
 pub fn dup(fd: usize) -> isize {
     sys_dup(fd)
 }
@@ -77,6 +131,29 @@ pub fn open(path: &str, flags: OpenFlags) -> isize {
 pub fn close(fd: usize) -> isize {
     sys_close(fd)
 }
+/// `unlinkat` flag to remove an empty directory instead of a file
+const AT_REMOVEDIR: u32 = 0x200;
+
+pub fn unlink(path: &str) -> isize {
+    sys_unlinkat(path, 0)
+}
+pub fn rmdir(path: &str) -> isize {
+    sys_unlinkat(path, AT_REMOVEDIR)
+}
+pub fn link(old_path: &str, new_path: &str) -> isize {
+    sys_linkat(old_path, new_path)
+}
+pub fn rename(old_path: &str, new_path: &str) -> isize {
+    sys_renameat(old_path, new_path)
+}
+/// Mount a file system of type `fstype` made from `source` at `target`,
+/// each string ending with a NUL
+pub fn mount(source: &str, target: &str, fstype: &str) -> isize {
+    sys_mount(source, target, fstype)
+}
+pub fn umount(target: &str) -> isize {
+    sys_umount2(target, 0)
+}
 pub fn pipe(pipe_fd: &mut [usize]) -> isize {
     sys_pipe(pipe_fd)
 }
@@ -86,6 +163,24 @@ pub fn read(fd: usize, buf: &mut [u8]) -> isize {
 pub fn write(fd: usize, buf: &[u8]) -> isize {
     sys_write(fd, buf)
 }
+pub fn ftruncate(fd: usize, len: usize) -> isize {
+    sys_ftruncate(fd, len)
+}
+pub fn fstat(fd: usize, st: &mut Stat) -> isize {
+    sys_fstat(fd, st)
+}
+pub fn statfs(path: &str, buf: &mut StatFs) -> isize {
+    sys_statfs(path, buf)
+}
+pub fn sync() -> isize {
+    sys_sync()
+}
+pub fn fsync(fd: usize) -> isize {
+    sys_fsync(fd)
+}
+pub fn fdatasync(fd: usize) -> isize {
+    sys_fdatasync(fd)
+}
 pub fn exit(exit_code: i32) -> ! {
     sys_exit(exit_code);
 }
@@ -1,11 +1,24 @@
 use core::arch::asm;
 
+use crate::{Stat, StatFs};
+
 const SYSCALL_DUP: usize = 24;
+const SYSCALL_UNLINKAT: usize = 35;
+const SYSCALL_LINKAT: usize = 37;
+const SYSCALL_RENAMEAT: usize = 38;
+const SYSCALL_UMOUNT2: usize = 39;
+const SYSCALL_MOUNT: usize = 40;
+const SYSCALL_STATFS: usize = 43;
+const SYSCALL_FTRUNCATE: usize = 46;
 const SYSCALL_OPEN: usize = 56;
 const SYSCALL_CLOSE: usize = 57;
 const SYSCALL_PIPE: usize = 59;
 const SYSCALL_READ: usize = 63;
 const SYSCALL_WRITE: usize = 64;
+const SYSCALL_FSTAT: usize = 80;
+const SYSCALL_SYNC: usize = 81;
+const SYSCALL_FSYNC: usize = 82;
+const SYSCALL_FDATASYNC: usize = 83;
 const SYSCALL_EXIT: usize = 93;
 const SYSCALL_SLEEP: usize = 101;
 const SYSCALL_YIELD: usize = 124;
@@ -42,6 +55,26 @@ fn syscall(id: usize, args: [usize; 3]) -> isize {
     ret
 }
 
+fn syscall6(id: usize, args: [usize; 6]) -> isize {
+    let mut ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("x10") args[0] => ret,
+            in("x11") args[1],
+            in("x12") args[2],
+            in("x13") args[3],
+            in("x14") args[4],
+            in("x15") args[5],
+            in("x17") id
+        );
+    }
+    ret
+}
+
+/// Resolve paths from the root directory
+const AT_FDCWD: isize = -100;
+
 pub fn sys_dup(fd: usize) -> isize {
     syscall(SYSCALL_DUP, [fd, 0, 0])
 }
@@ -54,6 +87,59 @@ pub fn sys_close(fd: usize) -> isize {
     syscall(SYSCALL_CLOSE, [fd, 0, 0])
 }
 
+pub fn sys_unlinkat(path: &str, flags: u32) -> isize {
+    syscall(
+        SYSCALL_UNLINKAT,
+        [AT_FDCWD as usize, path.as_ptr() as usize, flags as usize],
+    )
+}
+
+pub fn sys_linkat(old_path: &str, new_path: &str) -> isize {
+    syscall6(
+        SYSCALL_LINKAT,
+        [
+            AT_FDCWD as usize,
+            old_path.as_ptr() as usize,
+            AT_FDCWD as usize,
+            new_path.as_ptr() as usize,
+            0,
+            0,
+        ],
+    )
+}
+
+pub fn sys_renameat(old_path: &str, new_path: &str) -> isize {
+    syscall6(
+        SYSCALL_RENAMEAT,
+        [
+            AT_FDCWD as usize,
+            old_path.as_ptr() as usize,
+            AT_FDCWD as usize,
+            new_path.as_ptr() as usize,
+            0,
+            0,
+        ],
+    )
+}
+
+pub fn sys_umount2(target: &str, flags: u32) -> isize {
+    syscall(SYSCALL_UMOUNT2, [target.as_ptr() as usize, flags as usize, 0])
+}
+
+pub fn sys_mount(source: &str, target: &str, fstype: &str) -> isize {
+    syscall6(
+        SYSCALL_MOUNT,
+        [
+            source.as_ptr() as usize,
+            target.as_ptr() as usize,
+            fstype.as_ptr() as usize,
+            0,
+            0,
+            0,
+        ],
+    )
+}
+
 pub fn sys_pipe(pipe: &mut [usize]) -> isize {
     syscall(SYSCALL_PIPE, [pipe.as_mut_ptr() as usize, 0, 0])
 }
@@ -69,6 +155,30 @@ pub fn sys_write(fd: usize, buffer: &[u8]) -> isize {
     syscall(SYSCALL_WRITE, [fd, buffer.as_ptr() as usize, buffer.len()])
 }
 
+pub fn sys_ftruncate(fd: usize, len: usize) -> isize {
+    syscall(SYSCALL_FTRUNCATE, [fd, len, 0])
+}
+
+pub fn sys_fstat(fd: usize, st: &mut Stat) -> isize {
+    syscall(SYSCALL_FSTAT, [fd, st as *mut _ as usize, 0])
+}
+
+pub fn sys_statfs(path: &str, buf: &mut StatFs) -> isize {
+    syscall(SYSCALL_STATFS, [path.as_ptr() as usize, buf as *mut _ as usize, 0])
+}
+
+pub fn sys_sync() -> isize {
+    syscall(SYSCALL_SYNC, [0, 0, 0])
+}
+
+pub fn sys_fsync(fd: usize) -> isize {
+    syscall(SYSCALL_FSYNC, [fd, 0, 0])
+}
+
+pub fn sys_fdatasync(fd: usize) -> isize {
+    syscall(SYSCALL_FDATASYNC, [fd, 0, 0])
+}
+
 pub fn sys_exit(exit_code: i32) -> ! {
     syscall(SYSCALL_EXIT, [exit_code as usize, 0, 0]);
     panic!("sys_exit never returns!");